@@ -1 +1,409 @@
-#![no_std]
\ No newline at end of file
+#![cfg_attr(not(test), no_std)]
+
+pub mod options;
+
+/// Maximum number of hostname bytes copied out of option 12
+pub const HOSTNAME_LEN: usize = 32;
+/// Maximum number of bytes copied out of the option 82 circuit-id and remote-id sub-options
+pub const AGENT_ID_LEN: usize = 32;
+/// Maximum number of option codes copied out of the option 55 parameter request list
+pub const PARAMETER_LIST_LEN: usize = 32;
+/// Maximum number of bytes copied out of the option 60 vendor class identifier
+pub const VENDOR_CLASS_LEN: usize = 32;
+/// Maximum number of bytes of options the parser doesn't know that are passed on
+pub const UNKNOWN_OPTIONS_LEN: usize = 64;
+
+// BOOTP op codes, what the fixed header says the message is
+pub const BOOTREQUEST: u8 = 1;
+pub const BOOTREPLY: u8 = 2;
+
+// DHCP message types, carried in option 53
+pub const DHCP_DISCOVER: u8 = 1;
+pub const DHCP_OFFER: u8 = 2;
+pub const DHCP_REQUEST: u8 = 3;
+pub const DHCP_DECLINE: u8 = 4;
+pub const DHCP_ACK: u8 = 5;
+pub const DHCP_NAK: u8 = 6;
+pub const DHCP_RELEASE: u8 = 7;
+pub const DHCP_INFORM: u8 = 8;
+
+/// A DHCP message seen by the eBPF program, sent to userspace over the `EVENTS` perf array.
+///
+/// Addresses are in host byte order.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DhcpEvent {
+    pub ifindex: u32,
+    pub xid: u32,
+    pub your_address: u32,
+    /// giaddr, zero unless the message went through a relay
+    pub relay_address: u32,
+    /// Option 54, zero if absent
+    pub server_id: u32,
+    /// Option 51 in seconds, only valid when `has_lease_time` is set
+    pub lease_time: u32,
+    /// ciaddr, set by clients that already hold an address, e.g. when renewing
+    pub client_address: u32,
+    /// For replies to a request the program saw, how long the server took to answer
+    pub response_time_us: u32,
+    /// 802.1Q id of the outer tag, zero for untagged frames
+    pub vlan: u16,
+    pub client_mac: [u8; 6],
+    /// Option 53, zero for plain BOOTP
+    pub message_type: u8,
+    /// `BOOTREQUEST` from a client or `BOOTREPLY` from a server
+    pub op: u8,
+    /// Set for BOOTP messages, without the DHCP magic cookie or option 53
+    pub bootp: u8,
+    /// Set for replies to a request the program saw, `request_type` and
+    /// `response_time_us` are only valid then
+    pub answered: u8,
+    /// Option 53 of the request the reply answers
+    pub request_type: u8,
+    /// Set when the message carried option 51. A lease time of zero is one a server can
+    /// hand out.
+    pub has_lease_time: u8,
+    pub hostname_len: u8,
+    /// Option 12, `hostname_len` bytes are valid
+    pub hostname: [u8; HOSTNAME_LEN],
+    /// Option 82 sub-option 1, `circuit_id_len` bytes are valid
+    pub circuit_id: [u8; AGENT_ID_LEN],
+    /// Option 82 sub-option 2, `remote_id_len` bytes are valid
+    pub remote_id: [u8; AGENT_ID_LEN],
+    pub circuit_id_len: u8,
+    pub remote_id_len: u8,
+    /// Option 55 in the order the client asked, `parameter_list_len` bytes are valid
+    pub parameter_list: [u8; PARAMETER_LIST_LEN],
+    /// Option 60, `vendor_class_len` bytes are valid
+    pub vendor_class: [u8; VENDOR_CLASS_LEN],
+    pub parameter_list_len: u8,
+    pub vendor_class_len: u8,
+    pub unknown_options_len: u8,
+    /// Options other than the ones above as they appear in the packet, code and length
+    /// byte included, until it's full. The last one may be cut short.
+    pub unknown_options: [u8; UNKNOWN_OPTIONS_LEN],
+    /// `bpf_ktime_get_ns()` when the program parsed it. Events from different CPUs reach
+    /// userspace through different perf buffers, this puts them back in order.
+    pub timestamp_ns: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for DhcpEvent {}
+
+/// Counters kept by the eBPF program per interface in the `STATS` map
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stat {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+    /// Option 53 carried a message type outside of the ones above
+    UnknownType,
+    /// Packet ended before the DHCP header or an option did
+    Truncated,
+    /// Neither the DHCP magic cookie nor a BOOTP op code
+    BadCookie,
+    /// An option's length ran past the end of the UDP payload
+    OptionOverrun,
+    /// Server messages dropped on untrusted interfaces
+    RogueDropped,
+    /// Client traffic dropped for not matching a binding
+    SourceGuardDropped,
+    /// ARP replies and gratuitous ARPs dropped for not matching a binding
+    ArpRejected,
+    /// IPv4 header length or total length that doesn't add up
+    BadIpHeader,
+    /// UDP length shorter than the UDP header or longer than the IP payload
+    BadUdpLength,
+    /// IPv4 header or UDP checksum mismatch
+    BadChecksum,
+    /// DHCP sent as IP fragments, only whole datagrams are parsed
+    Fragmented,
+    /// DISCOVERs and REQUESTs over a client's or the interface's rate limit
+    RateLimited,
+    /// BOOTP messages, without the DHCP magic cookie or option 53
+    Bootp,
+    /// Every frame the program ran on, DHCP or not
+    Packets,
+    /// Frames that still carried an 802.1Q or 802.1ad tag when the program saw them
+    VlanTagged,
+    /// Frames the NIC or the stack had already taken the tag off, with the tag recovered
+    /// from the skb
+    VlanRecovered,
+    /// UDP datagrams on interfaces verifying their checksum that were too long to verify
+    UnverifiedChecksum,
+}
+
+pub const STAT_COUNT: usize = 25;
+
+impl Stat {
+    pub const ALL: [Stat; STAT_COUNT] = [
+        Stat::Discover,
+        Stat::Offer,
+        Stat::Request,
+        Stat::Decline,
+        Stat::Ack,
+        Stat::Nak,
+        Stat::Release,
+        Stat::Inform,
+        Stat::UnknownType,
+        Stat::Truncated,
+        Stat::BadCookie,
+        Stat::OptionOverrun,
+        Stat::RogueDropped,
+        Stat::SourceGuardDropped,
+        Stat::ArpRejected,
+        Stat::BadIpHeader,
+        Stat::BadUdpLength,
+        Stat::BadChecksum,
+        Stat::Fragmented,
+        Stat::RateLimited,
+        Stat::Bootp,
+        Stat::Packets,
+        Stat::VlanTagged,
+        Stat::VlanRecovered,
+        Stat::UnverifiedChecksum,
+    ];
+
+    pub fn for_message_type(message_type: u8) -> Stat {
+        match message_type {
+            DHCP_DISCOVER => Stat::Discover,
+            DHCP_OFFER => Stat::Offer,
+            DHCP_REQUEST => Stat::Request,
+            DHCP_DECLINE => Stat::Decline,
+            DHCP_ACK => Stat::Ack,
+            DHCP_NAK => Stat::Nak,
+            DHCP_RELEASE => Stat::Release,
+            DHCP_INFORM => Stat::Inform,
+            _ => Stat::UnknownType,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stat::Discover => "discover",
+            Stat::Offer => "offer",
+            Stat::Request => "request",
+            Stat::Decline => "decline",
+            Stat::Ack => "ack",
+            Stat::Nak => "nak",
+            Stat::Release => "release",
+            Stat::Inform => "inform",
+            Stat::UnknownType => "unknown_type",
+            Stat::Truncated => "truncated",
+            Stat::BadCookie => "bad_cookie",
+            Stat::OptionOverrun => "option_overrun",
+            Stat::RogueDropped => "rogue_dropped",
+            Stat::SourceGuardDropped => "source_guard_dropped",
+            Stat::ArpRejected => "arp_rejected",
+            Stat::BadIpHeader => "bad_ip_header",
+            Stat::BadUdpLength => "bad_udp_length",
+            Stat::BadChecksum => "bad_checksum",
+            Stat::Fragmented => "fragmented",
+            Stat::RateLimited => "rate_limited",
+            Stat::Bootp => "bootp",
+            Stat::Packets => "packets",
+            Stat::VlanTagged => "vlan_tagged",
+            Stat::VlanRecovered => "vlan_recovered",
+            Stat::UnverifiedChecksum => "unverified_checksum",
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatsKey {
+    pub ifindex: u32,
+    /// A `Stat` discriminant
+    pub stat: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for StatsKey {}
+
+/// Per interface settings in the `IFACES` map, keyed by ifindex. Interfaces without an
+/// entry are treated as trusted.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IfaceConfig {
+    pub flags: u32,
+    /// DISCOVERs and REQUESTs per second allowed from each client, zero for no limit
+    pub client_rate: u32,
+    /// How many a client may send in a burst above `client_rate`
+    pub client_burst: u32,
+    /// Same for all clients on the interface together
+    pub interface_rate: u32,
+    pub interface_burst: u32,
+}
+
+/// DHCP servers are allowed to answer on the interface, server messages arriving on an
+/// untrusted interface are dropped
+pub const IFACE_TRUSTED: u32 = 1 << 0;
+/// IPv4 traffic arriving on the interface must come from an address and MAC bound
+/// together in `BINDINGS`, only applies to untrusted interfaces
+pub const IFACE_SOURCE_GUARD: u32 = 1 << 1;
+/// ARP replies and gratuitous ARPs arriving on the interface must carry a sender address
+/// and MAC bound together in `BINDINGS`, only applies to untrusted interfaces
+pub const IFACE_ARP_INSPECTION: u32 = 1 << 2;
+/// DHCP frames arriving on the interface are mirrored to userspace over `CAPTURES`
+pub const IFACE_CAPTURE: u32 = 1 << 3;
+/// DISCOVERs and REQUESTs over the rate limits are dropped rather than just counted
+pub const IFACE_RATE_LIMIT_DROP: u32 = 1 << 4;
+/// Nothing arriving on the interface is dropped, what would have been is still counted and
+/// reported
+pub const IFACE_LOG_ONLY: u32 = 1 << 5;
+/// UDP checksums of DHCP arriving on the interface are verified. Only set in native XDP
+/// mode, in TC and generic XDP locally sent traffic still has its checksum left to the NIC.
+pub const IFACE_VERIFY_UDP_CHECKSUM: u32 = 1 << 6;
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for IfaceConfig {}
+
+/// Value of the `BINDINGS` map, keyed by the leased IPv4 address in host byte order.
+///
+/// Other eBPF programs look up addresses in the pinned map to tell which device traffic
+/// is from, so the layout only ever grows at the end: `mac` at offset 0, `vlan` at 6,
+/// `ifindex` at 8, `expires_at` at 16, 24 bytes in all. `examples/flow-tag` has it as a C
+/// struct.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Binding {
+    pub mac: [u8; 6],
+    /// 802.1Q id the lease was handed out on, zero when untagged
+    pub vlan: u16,
+    /// Interface the lease was handed out on
+    pub ifindex: u32,
+    pub _reserved: u32,
+    /// `bpf_ktime_get_ns()` at which the lease runs out, zero for infinite leases. Userspace
+    /// removes the binding soon after.
+    pub expires_at: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Binding {}
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+
+/// An ARP packet dropped by Dynamic ARP Inspection, sent to userspace over the
+/// `ARP_EVENTS` perf array.
+///
+/// Addresses are in host byte order.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ArpEvent {
+    pub ifindex: u32,
+    pub sender_address: u32,
+    pub target_address: u32,
+    pub operation: u16,
+    /// 802.1Q id of the outer tag, zero for untagged frames
+    pub vlan: u16,
+    pub sender_mac: [u8; 6],
+    pub _padding: [u8; 2],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ArpEvent {}
+
+/// Key of the `ARP_REJECTS` map counting dropped ARPs per sender
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArpRejectKey {
+    pub sender_mac: [u8; 6],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ArpRejectKey {}
+
+/// Key of the `CLIENT_BUCKETS` map
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientKey {
+    pub ifindex: u32,
+    pub mac: [u8; 6],
+    pub _padding: [u8; 2],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ClientKey {}
+
+/// A token bucket, in nanosecond tokens so refilling doesn't need a division
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TokenBucket {
+    pub tokens: u64,
+    /// `bpf_ktime_get_ns` of the last refill
+    pub updated: u64,
+    /// Set while over the limit, so userspace hears about it once per episode
+    pub limited: u32,
+    pub _padding: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for TokenBucket {}
+
+pub const RATE_LIMIT_CLIENT: u8 = 1;
+pub const RATE_LIMIT_INTERFACE: u8 = 2;
+
+/// A client or an interface going over its rate limit, sent to userspace over the
+/// `RATE_EVENTS` perf array
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RateLimitEvent {
+    pub ifindex: u32,
+    /// The limit that was exceeded, per second
+    pub rate: u32,
+    /// 802.1Q id of the outer tag, zero for untagged frames
+    pub vlan: u16,
+    /// The client whose message went over the limit
+    pub client_mac: [u8; 6],
+    /// `RATE_LIMIT_CLIENT` or `RATE_LIMIT_INTERFACE`
+    pub scope: u8,
+    pub message_type: u8,
+    /// Whether the messages over the limit are being dropped
+    pub dropping: u8,
+    pub _padding: [u8; 5],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RateLimitEvent {}
+
+/// Value of the `TRANSACTIONS` map, the last request seen with a given xid
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Transaction {
+    /// `bpf_ktime_get_ns` of the request
+    pub requested_at: u64,
+    pub client_mac: [u8; 6],
+    /// Option 53 of the request
+    pub message_type: u8,
+    pub _padding: u8,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Transaction {}
+
+/// Frames captured are cut off after this many bytes
+pub const CAPTURE_SNAPLEN: u32 = 1518;
+
+/// Precedes every frame sent over the `CAPTURES` perf array, the first `captured` bytes of
+/// the frame follow it
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CaptureHeader {
+    /// `bpf_ktime_get_ns`, i.e. CLOCK_MONOTONIC
+    pub timestamp: u64,
+    pub ifindex: u32,
+    /// Length of the frame on the wire
+    pub len: u32,
+    pub captured: u32,
+    pub _padding: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for CaptureHeader {}
@@ -0,0 +1,357 @@
+//! The walk over the options following the fixed DHCP header. It runs in the eBPF program,
+//! where it has to stay inside the UDP payload and finish within a fixed number of
+//! iterations whatever the length bytes claim, and it lives here so it can be tested
+//! against a plain buffer.
+
+use core::mem;
+
+use crate::{DhcpEvent, Stat, UNKNOWN_OPTIONS_LEN};
+
+/// Where the options start in a DHCP message, after the 236 byte fixed header and the
+/// magic cookie
+pub const OPTIONS_OFFSET: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_HOSTNAME: u8 = 12;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_VENDOR_CLASS: u8 = 60;
+const OPTION_RELAY_AGENT_INFO: u8 = 82;
+const OPTION_END: u8 = 255;
+
+// Option 82 sub-options
+const AGENT_CIRCUIT_ID: u8 = 1;
+const AGENT_REMOTE_ID: u8 = 2;
+
+// Upper bounds for the option walk, the verifier needs both
+const MAX_OPTIONS: usize = 70;
+const MAX_OPTIONS_LEN: usize = 1200;
+const MAX_AGENT_SUBOPTIONS: usize = 8;
+
+/// What the option walk reads from, the packet in the eBPF program
+pub trait Bytes {
+    /// `size_of::<T>()` bytes at `offset`, `None` past the end
+    fn load<T: Copy>(&self, offset: usize) -> Option<T>;
+}
+
+/// Walk the options of the DHCP message at `dhcp_offset`, `udp_payload_size` bytes long,
+/// filling in the event. Returns the counter to bump when the options are malformed.
+#[inline(always)]
+pub fn read_options<B: Bytes>(
+    bytes: &B,
+    dhcp_offset: usize,
+    udp_payload_size: usize,
+    event: &mut DhcpEvent,
+) -> Result<(), Stat> {
+    let mut offset = OPTIONS_OFFSET;
+
+    for _ in 0..MAX_OPTIONS {
+        if offset >= udp_payload_size || offset > MAX_OPTIONS_LEN {
+            break;
+        }
+
+        let opt_type: u8 = bytes.load(dhcp_offset + offset).ok_or(Stat::Truncated)?;
+        if opt_type == OPTION_END {
+            break;
+        }
+        // Pad is the only option without a length byte
+        if opt_type == OPTION_PAD {
+            offset += 1;
+            continue;
+        }
+
+        // The length byte has to be in the payload as much as the value
+        if offset + 2 > udp_payload_size {
+            return Err(Stat::OptionOverrun);
+        }
+        let length: u8 = bytes
+            .load(dhcp_offset + offset + 1)
+            .ok_or(Stat::Truncated)?;
+        if offset + 2 + length as usize > udp_payload_size {
+            return Err(Stat::OptionOverrun);
+        }
+        let value = dhcp_offset + offset + 2;
+        let length = length as usize;
+
+        match opt_type {
+            OPTION_MESSAGE_TYPE => {
+                if let Some(message_type) = load_value(bytes, value, length)? {
+                    event.message_type = message_type;
+                }
+            }
+            OPTION_LEASE_TIME => {
+                if let Some(lease_time) = load_value(bytes, value, length)? {
+                    event.lease_time = u32::from_be_bytes(lease_time);
+                    event.has_lease_time = 1;
+                }
+            }
+            OPTION_SERVER_ID => {
+                if let Some(server_id) = load_value(bytes, value, length)? {
+                    event.server_id = u32::from_be_bytes(server_id);
+                }
+            }
+            OPTION_HOSTNAME => {
+                event.hostname_len = copy_bytes(bytes, value, length, &mut event.hostname);
+            }
+            OPTION_PARAMETER_LIST => {
+                event.parameter_list_len =
+                    copy_bytes(bytes, value, length, &mut event.parameter_list);
+            }
+            OPTION_VENDOR_CLASS => {
+                event.vendor_class_len = copy_bytes(bytes, value, length, &mut event.vendor_class);
+            }
+            OPTION_RELAY_AGENT_INFO => read_relay_agent_info(bytes, value, length, event),
+            _ => append_unknown(bytes, dhcp_offset + offset, 2 + length, event),
+        }
+
+        offset += 2 + length;
+    }
+
+    Ok(())
+}
+
+/// A fixed size option value, `None` when the option is too short to hold it rather than
+/// reading on into the next one
+#[inline(always)]
+fn load_value<B: Bytes, T: Copy>(
+    bytes: &B,
+    offset: usize,
+    length: usize,
+) -> Result<Option<T>, Stat> {
+    if length < mem::size_of::<T>() {
+        return Ok(None);
+    }
+    bytes.load(offset).map(Some).ok_or(Stat::Truncated)
+}
+
+/// Copy up to `N` bytes of an option value into `dst`, returns the number of bytes copied
+#[inline(always)]
+fn copy_bytes<B: Bytes, const N: usize>(
+    bytes: &B,
+    offset: usize,
+    length: usize,
+    dst: &mut [u8; N],
+) -> u8 {
+    let mut copied = 0;
+
+    for (i, byte) in dst.iter_mut().enumerate() {
+        if i >= length {
+            break;
+        }
+        match bytes.load::<u8>(offset + i) {
+            Some(c) => *byte = c,
+            None => break,
+        }
+        copied += 1;
+    }
+
+    copied
+}
+
+/// Append the `length` bytes of an option at `offset`, code and length byte included, to
+/// the event's unknown options for as long as there's room
+#[inline(always)]
+fn append_unknown<B: Bytes>(bytes: &B, offset: usize, length: usize, event: &mut DhcpEvent) {
+    let start = event.unknown_options_len as usize;
+
+    for i in 0..UNKNOWN_OPTIONS_LEN {
+        let index = start + i;
+        if i >= length || index >= UNKNOWN_OPTIONS_LEN {
+            break;
+        }
+        match bytes.load::<u8>(offset + i) {
+            Some(byte) => event.unknown_options[index] = byte,
+            None => break,
+        }
+        event.unknown_options_len += 1;
+    }
+}
+
+/// Walk the sub-options of option 82, picking out circuit-id and remote-id
+#[inline(always)]
+fn read_relay_agent_info<B: Bytes>(bytes: &B, offset: usize, length: usize, event: &mut DhcpEvent) {
+    let mut sub_offset = 0;
+
+    for _ in 0..MAX_AGENT_SUBOPTIONS {
+        // Every sub-option has a code and a length byte
+        if sub_offset + 2 > length {
+            break;
+        }
+
+        let (code, sub_length) = match (
+            bytes.load::<u8>(offset + sub_offset),
+            bytes.load::<u8>(offset + sub_offset + 1),
+        ) {
+            (Some(code), Some(sub_length)) => (code, sub_length as usize),
+            _ => break,
+        };
+        let value = offset + sub_offset + 2;
+        // Don't walk past the end of option 82 itself
+        let sub_length = sub_length.min(length - sub_offset - 2);
+
+        match code {
+            AGENT_CIRCUIT_ID => {
+                event.circuit_id_len = copy_bytes(bytes, value, sub_length, &mut event.circuit_id);
+            }
+            AGENT_REMOTE_ID => {
+                event.remote_id_len = copy_bytes(bytes, value, sub_length, &mut event.remote_id);
+            }
+            _ => {}
+        }
+
+        sub_offset += 2 + sub_length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use proptest::{collection::vec, prelude::*};
+
+    use super::*;
+    use crate::{AGENT_ID_LEN, HOSTNAME_LEN, PARAMETER_LIST_LEN, VENDOR_CLASS_LEN};
+
+    /// Every load the walk could make: two bytes per option, and the most any one value
+    /// reads, option 82 with all its sub-options copied in full. Unknown options are
+    /// copied once at most.
+    const MAX_LOADS: usize =
+        MAX_OPTIONS * (2 + MAX_AGENT_SUBOPTIONS * (2 + AGENT_ID_LEN)) + UNKNOWN_OPTIONS_LEN;
+
+    /// A buffer keeping track of how far and how often the walk reads
+    struct Recorder<'a> {
+        data: &'a [u8],
+        end: Cell<usize>,
+        loads: Cell<usize>,
+    }
+
+    impl Bytes for Recorder<'_> {
+        fn load<T: Copy>(&self, offset: usize) -> Option<T> {
+            let size = mem::size_of::<T>();
+            self.loads.set(self.loads.get() + 1);
+            self.end.set(self.end.get().max(offset + size));
+
+            let bytes = self.data.get(offset..offset + size)?;
+            Some(unsafe { (bytes.as_ptr() as *const T).read_unaligned() })
+        }
+    }
+
+    fn event() -> DhcpEvent {
+        unsafe { mem::zeroed() }
+    }
+
+    /// Options with length bytes that have nothing to do with the values following them
+    fn option() -> impl Strategy<Value = Vec<u8>> {
+        let known = prop::sample::select(vec![
+            OPTION_PAD,
+            OPTION_HOSTNAME,
+            OPTION_LEASE_TIME,
+            OPTION_MESSAGE_TYPE,
+            OPTION_SERVER_ID,
+            OPTION_PARAMETER_LIST,
+            OPTION_VENDOR_CLASS,
+            OPTION_RELAY_AGENT_INFO,
+            OPTION_END,
+        ]);
+        let sub_option = (0u8..4, any::<u8>(), vec(any::<u8>(), 0..48))
+            .prop_map(|(code, length, value)| [vec![code, length], value].concat());
+        let relay_agent_info = vec(sub_option, 0..12).prop_map(|sub_options| sub_options.concat());
+
+        prop_oneof![
+            (known, any::<u8>(), vec(any::<u8>(), 0..64)),
+            (any::<u8>(), any::<u8>(), vec(any::<u8>(), 0..300)),
+            (Just(OPTION_RELAY_AGENT_INFO), any::<u8>(), relay_agent_info),
+        ]
+        .prop_map(|(code, length, value)| [vec![code, length], value].concat())
+    }
+
+    /// A DHCP message at some offset into the frame, with `udp_payload_size` cut anywhere
+    /// short of the end of the frame, as the program checks it against data_end first
+    fn message() -> impl Strategy<Value = (Vec<u8>, usize, usize)> {
+        (
+            0usize..64,
+            vec(option(), 0..100),
+            vec(any::<u8>(), 0..32),
+            any::<usize>(),
+        )
+            .prop_map(|(dhcp_offset, options, trailer, payload)| {
+                let mut frame = vec![0; dhcp_offset + OPTIONS_OFFSET];
+                frame.extend(options.concat());
+                frame.extend(trailer);
+                let udp_payload_size = payload % (frame.len() - dhcp_offset + 1);
+
+                (frame, dhcp_offset, udp_payload_size)
+            })
+    }
+
+    fn read(options: &[u8]) -> DhcpEvent {
+        let frame = [&[0; OPTIONS_OFFSET][..], options].concat();
+        let recorder = Recorder {
+            data: &frame,
+            end: Cell::new(0),
+            loads: Cell::new(0),
+        };
+        let mut event = event();
+        assert_eq!(read_options(&recorder, 0, frame.len(), &mut event), Ok(()));
+        event
+    }
+
+    #[test]
+    fn lease_time_of_zero_is_there() {
+        let event = read(&[OPTION_LEASE_TIME, 4, 0, 0, 0, 0, OPTION_END]);
+        assert_eq!((event.has_lease_time, event.lease_time), (1, 0));
+
+        let event = read(&[OPTION_LEASE_TIME, 4, 0, 0, 0x0e, 0x10, OPTION_END]);
+        assert_eq!((event.has_lease_time, event.lease_time), (1, 3600));
+
+        // Too short to hold one
+        let event = read(&[OPTION_LEASE_TIME, 2, 0x0e, 0x10, OPTION_END]);
+        assert_eq!(event.has_lease_time, 0);
+
+        let event = read(&[OPTION_SERVER_ID, 4, 10, 0, 0, 1, OPTION_END]);
+        assert_eq!(event.has_lease_time, 0);
+    }
+
+    proptest! {
+        #[test]
+        fn stays_inside_the_payload((frame, dhcp_offset, udp_payload_size) in message()) {
+            let recorder = Recorder { data: &frame, end: Cell::new(0), loads: Cell::new(0) };
+            let mut event = event();
+
+            let result = read_options(&recorder, dhcp_offset, udp_payload_size, &mut event);
+
+            if recorder.loads.get() > 0 {
+                prop_assert!(recorder.end.get() <= dhcp_offset + udp_payload_size);
+            }
+            // Every load inside the payload succeeds, the frame is at least that long
+            prop_assert_ne!(result, Err(Stat::Truncated));
+        }
+
+        #[test]
+        fn terminates_within_the_cap((frame, dhcp_offset, udp_payload_size) in message()) {
+            let recorder = Recorder { data: &frame, end: Cell::new(0), loads: Cell::new(0) };
+            let mut event = event();
+
+            let _ = read_options(&recorder, dhcp_offset, udp_payload_size, &mut event);
+
+            prop_assert!(recorder.loads.get() <= MAX_LOADS);
+        }
+
+        #[test]
+        fn fills_the_event_within_bounds((frame, dhcp_offset, udp_payload_size) in message()) {
+            let recorder = Recorder { data: &frame, end: Cell::new(0), loads: Cell::new(0) };
+            let mut event = event();
+
+            let _ = read_options(&recorder, dhcp_offset, udp_payload_size, &mut event);
+
+            prop_assert!(event.hostname_len as usize <= HOSTNAME_LEN);
+            prop_assert!(event.circuit_id_len as usize <= AGENT_ID_LEN);
+            prop_assert!(event.remote_id_len as usize <= AGENT_ID_LEN);
+            prop_assert!(event.parameter_list_len as usize <= PARAMETER_LIST_LEN);
+            prop_assert!(event.vendor_class_len as usize <= VENDOR_CLASS_LEN);
+            prop_assert!(event.unknown_options_len as usize <= UNKNOWN_OPTIONS_LEN);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{
+    context::{ptr_at, Packet},
+    maps::{count, count_arp_reject, is_arp_inspected, is_bound, ARP_EVENTS},
+    snoop::Verdict,
+};
+use dhcp_common::{ArpEvent, Stat, ARP_REPLY};
+
+const ARPHRD_ETHER: u16 = 1;
+const ETH_P_IP: u16 = 0x0800;
+
+/// Dynamic ARP Inspection, drop replies and gratuitous ARPs whose sender address isn't
+/// leased to the sender MAC and tell userspace about them
+#[inline(always)]
+pub fn inspect<C: Packet>(ctx: &C, offset: usize, vlan: u16) -> Verdict {
+    let ifindex = ctx.ifindex();
+    if !is_arp_inspected(ifindex) {
+        return Verdict::Pass;
+    }
+
+    let arp = match ptr_at::<ArpHdr>(ctx, offset) {
+        Some(arp) => arp,
+        None => return Verdict::Pass,
+    };
+    let arp = unsafe { &*arp };
+    // Only Ethernet/IPv4 ARP says anything about DHCP leases
+    if u16::from_be(arp.hardware_type) != ARPHRD_ETHER
+        || u16::from_be(arp.protocol_type) != ETH_P_IP
+        || arp.hardware_length != 6
+        || arp.protocol_length != 4
+    {
+        return Verdict::Pass;
+    }
+
+    let operation = u16::from_be(arp.operation);
+    let sender_address = u32::from_be_bytes(arp.sender_address);
+    let target_address = u32::from_be_bytes(arp.target_address);
+
+    // Probes from clients checking whether an address is taken carry no sender address
+    if sender_address == 0 {
+        return Verdict::Pass;
+    }
+    let gratuitous = sender_address == target_address;
+    if operation != ARP_REPLY && !gratuitous {
+        return Verdict::Pass;
+    }
+    if is_bound(sender_address, &arp.sender_mac) {
+        return Verdict::Pass;
+    }
+
+    count(ifindex, Stat::ArpRejected);
+    count_arp_reject(arp.sender_mac);
+
+    let event = ArpEvent {
+        ifindex,
+        sender_address,
+        target_address,
+        operation,
+        vlan,
+        sender_mac: arp.sender_mac,
+        _padding: [0; 2],
+    };
+    unsafe { ARP_EVENTS.output(ctx, &event, 0) };
+
+    Verdict::Drop
+}
+
+#[repr(C)]
+pub struct ArpHdr {
+    hardware_type: u16,
+    protocol_type: u16,
+    hardware_length: u8,
+    protocol_length: u8,
+    operation: u16,
+    sender_mac: [u8; 6],
+    sender_address: [u8; 4],
+    target_mac: [u8; 6],
+    target_address: [u8; 4],
+}
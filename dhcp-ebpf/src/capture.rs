@@ -0,0 +1,28 @@
+use aya_bpf::helpers::bpf_ktime_get_ns;
+use dhcp_common::{CaptureHeader, CAPTURE_SNAPLEN};
+
+use crate::{
+    context::Packet,
+    maps::{is_captured, CAPTURES},
+};
+
+/// Mirror the whole frame to userspace if capturing is enabled on the interface
+#[inline(always)]
+pub fn capture<C: Packet>(ctx: &C, ifindex: u32) {
+    if !is_captured(ifindex) {
+        return;
+    }
+
+    let len = (ctx.data_end() - ctx.data()) as u32;
+    let header = CaptureHeader {
+        timestamp: unsafe { bpf_ktime_get_ns() },
+        ifindex,
+        len,
+        captured: len.min(CAPTURE_SNAPLEN),
+        _padding: 0,
+    };
+
+    // The upper half of the output flags is BPF_F_CTX_LEN_MASK, the kernel appends that
+    // many bytes of the packet to the sample
+    unsafe { CAPTURES.output(ctx, &header, header.captured) };
+}
@@ -0,0 +1,83 @@
+use crate::context::{load, Packet};
+
+const IPPROTO_UDP: u32 = 17;
+
+// Upper bounds for the checksum loops, the verifier needs both. UDP payloads up to a 1500
+// byte MTU are verified, larger ones can only come from jumbo frames and aren't.
+const MAX_IP_HDR_WORDS: usize = 30;
+const MAX_UDP_WORDS: usize = 740;
+
+pub enum UdpChecksum {
+    /// Right, or not computed by the sender
+    Ok,
+    Bad,
+    /// Too long to check
+    Unverified,
+}
+
+/// Whether the one's complement sum of the IPv4 header, checksum included, comes out
+/// right
+#[inline(always)]
+pub fn ip_header_ok<C: Packet>(ctx: &C, offset: usize, len: usize) -> bool {
+    let mut sum = 0u32;
+
+    for i in 0..MAX_IP_HDR_WORDS {
+        if i * 2 >= len {
+            break;
+        }
+        match load::<u16>(ctx, offset + i * 2) {
+            Some(word) => sum += u16::from_be(word) as u32,
+            None => return false,
+        }
+    }
+
+    fold(sum) == 0xffff
+}
+
+/// Same for the UDP datagram and its pseudo header, addresses in host byte order. A zero
+/// checksum means the sender didn't compute one.
+#[inline(always)]
+pub fn udp<C: Packet>(ctx: &C, source: u32, dest: u32, offset: usize, len: usize) -> UdpChecksum {
+    let checksum: u16 = match load(ctx, offset + 6) {
+        Some(checksum) => checksum,
+        None => return UdpChecksum::Bad,
+    };
+    if checksum == 0 {
+        return UdpChecksum::Ok;
+    }
+    if len > MAX_UDP_WORDS * 2 {
+        return UdpChecksum::Unverified;
+    }
+
+    let mut sum = (source >> 16) + (source & 0xffff) + (dest >> 16) + (dest & 0xffff);
+    sum += IPPROTO_UDP + len as u32;
+
+    for i in 0..MAX_UDP_WORDS {
+        if i * 2 + 1 >= len {
+            break;
+        }
+        match load::<u16>(ctx, offset + i * 2) {
+            Some(word) => sum += u16::from_be(word) as u32,
+            None => return UdpChecksum::Bad,
+        }
+    }
+    // An odd trailing byte is padded with a zero
+    if len % 2 == 1 {
+        match load::<u8>(ctx, offset + len - 1) {
+            Some(byte) => sum += (byte as u32) << 8,
+            None => return UdpChecksum::Bad,
+        }
+    }
+
+    if fold(sum) == 0xffff {
+        UdpChecksum::Ok
+    } else {
+        UdpChecksum::Bad
+    }
+}
+
+#[inline(always)]
+fn fold(mut sum: u32) -> u32 {
+    sum = (sum & 0xffff) + (sum >> 16);
+    (sum & 0xffff) + (sum >> 16)
+}
@@ -0,0 +1,96 @@
+use aya_bpf::{
+    programs::{TcContext, XdpContext},
+    BpfContext,
+};
+use core::mem;
+use dhcp_common::options::Bytes;
+
+/// Direct packet access shared by the XDP and TC programs, so both can run the same
+/// parsing code
+pub trait Packet: BpfContext {
+    fn data(&self) -> usize;
+    fn data_end(&self) -> usize;
+    /// Interface the packet arrived on
+    fn ifindex(&self) -> u32;
+    /// VLAN id of the tag taken off the frame before the program ran, 0 when there was
+    /// none or there's no telling
+    fn stripped_vlan(&self) -> u16;
+}
+
+impl Packet for XdpContext {
+    #[inline(always)]
+    fn data(&self) -> usize {
+        XdpContext::data(self)
+    }
+
+    #[inline(always)]
+    fn data_end(&self) -> usize {
+        XdpContext::data_end(self)
+    }
+
+    #[inline(always)]
+    fn ifindex(&self) -> u32 {
+        unsafe { (*self.ctx).ingress_ifindex }
+    }
+
+    #[inline(always)]
+    fn stripped_vlan(&self) -> u16 {
+        // Only the rx metadata kfuncs of recent kernels hand XDP the stripped tag
+        0
+    }
+}
+
+impl Packet for TcContext {
+    #[inline(always)]
+    fn data(&self) -> usize {
+        TcContext::data(self)
+    }
+
+    #[inline(always)]
+    fn data_end(&self) -> usize {
+        TcContext::data_end(self)
+    }
+
+    #[inline(always)]
+    fn ifindex(&self) -> u32 {
+        unsafe { (*self.skb.skb).ifindex }
+    }
+
+    #[inline(always)]
+    fn stripped_vlan(&self) -> u16 {
+        let skb = unsafe { &*self.skb.skb };
+        if skb.vlan_present == 0 {
+            return 0;
+        }
+        (skb.vlan_tci & 0x0fff) as u16
+    }
+}
+
+/// The packet as the option parser in dhcp-common reads it
+pub struct PacketBytes<'a, C>(pub &'a C);
+
+impl<C: Packet> Bytes for PacketBytes<'_, C> {
+    #[inline(always)]
+    fn load<T: Copy>(&self, offset: usize) -> Option<T> {
+        load(self.0, offset)
+    }
+}
+
+#[inline(always)]
+pub fn ptr_at<T>(ctx: &impl Packet, offset: usize) -> Option<*const T> {
+    let start = ctx.data();
+    let end = ctx.data_end();
+    let len = mem::size_of::<T>();
+
+    if start + offset + len > end {
+        return None;
+    }
+
+    Some((start + offset) as *const T)
+}
+
+#[inline(always)]
+pub fn load<T: Copy>(ctx: &impl Packet, offset: usize) -> Option<T> {
+    let ptr = ptr_at::<T>(ctx, offset)?;
+    Some(unsafe { ptr.read_unaligned() })
+}
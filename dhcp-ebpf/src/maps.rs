@@ -0,0 +1,159 @@
+use aya_bpf::{
+    macros::map,
+    maps::{HashMap, LruHashMap, LruPerCpuHashMap, PerCpuArray, PerCpuHashMap, PerfEventArray},
+};
+use dhcp_common::{
+    ArpEvent, ArpRejectKey, Binding, CaptureHeader, ClientKey, DhcpEvent, IfaceConfig,
+    RateLimitEvent, Stat, StatsKey, TokenBucket, Transaction, IFACE_ARP_INSPECTION, IFACE_CAPTURE,
+    IFACE_LOG_ONLY, IFACE_SOURCE_GUARD, IFACE_TRUSTED, IFACE_VERIFY_UDP_CHECKSUM,
+};
+
+#[map(name = "EVENTS")]
+pub static mut EVENTS: PerfEventArray<DhcpEvent> = PerfEventArray::with_max_entries(1024, 0);
+
+#[map(name = "ARP_EVENTS")]
+pub static mut ARP_EVENTS: PerfEventArray<ArpEvent> = PerfEventArray::with_max_entries(1024, 0);
+
+/// Mirrored DHCP frames, the frame bytes are appended to each header by the kernel
+#[map(name = "CAPTURES")]
+pub static mut CAPTURES: PerfEventArray<CaptureHeader> = PerfEventArray::with_max_entries(1024, 0);
+
+// LRU so a flood of random sender MACs can't fill it up
+#[map(name = "ARP_REJECTS")]
+pub static mut ARP_REJECTS: LruPerCpuHashMap<ArpRejectKey, u64> =
+    LruPerCpuHashMap::with_max_entries(4096, 0);
+
+#[map(name = "RATE_EVENTS")]
+pub static mut RATE_EVENTS: PerfEventArray<RateLimitEvent> =
+    PerfEventArray::with_max_entries(1024, 0);
+
+// Shared between CPUs so the limit holds for the client as a whole, LRU so a starvation
+// attack cycling through random MACs can't fill it up. That's what the interface bucket
+// is for.
+#[map(name = "CLIENT_BUCKETS")]
+pub static mut CLIENT_BUCKETS: LruHashMap<ClientKey, TokenBucket> =
+    LruHashMap::with_max_entries(16384, 0);
+
+#[map(name = "INTERFACE_BUCKETS")]
+pub static mut INTERFACE_BUCKETS: HashMap<u32, TokenBucket> = HashMap::with_max_entries(256, 0);
+
+// IFACES, BINDINGS and TRUSTED_SERVERS are pinned so `dhcp config` can change them while
+// the program runs
+
+#[map(name = "IFACES")]
+pub static mut IFACES: HashMap<u32, IfaceConfig> = HashMap::pinned(256, 0);
+
+#[map(name = "STATS")]
+pub static mut STATS: PerCpuHashMap<StatsKey, u64> = PerCpuHashMap::with_max_entries(1024, 0);
+
+/// Leased addresses and the clients holding them, maintained by userspace
+#[map(name = "BINDINGS")]
+pub static mut BINDINGS: HashMap<u32, Binding> = HashMap::pinned(65536, 0);
+
+/// DHCP servers allowed to hand out leases, only read by userspace. It's here so it can be
+/// pinned next to the rest.
+#[map(name = "TRUSTED_SERVERS")]
+pub static mut TRUSTED_SERVERS: HashMap<u32, u8> = HashMap::pinned(64, 0);
+
+/// The last request seen for each xid, for matching up replies. LRU so clients that never
+/// get an answer don't fill it up.
+#[map(name = "TRANSACTIONS")]
+pub static mut TRANSACTIONS: LruHashMap<u32, Transaction> = LruHashMap::with_max_entries(1024, 0);
+
+// Events are assembled here instead of on the stack, they'll outgrow the 512 byte limit
+#[map(name = "SCRATCH")]
+pub static mut SCRATCH: PerCpuArray<DhcpEvent> = PerCpuArray::with_max_entries(1, 0);
+
+#[inline(always)]
+pub fn is_trusted(ifindex: u32) -> bool {
+    match unsafe { IFACES.get(&ifindex) } {
+        Some(config) => config.flags & IFACE_TRUSTED != 0,
+        None => true,
+    }
+}
+
+#[inline(always)]
+pub fn is_captured(ifindex: u32) -> bool {
+    match unsafe { IFACES.get(&ifindex) } {
+        Some(config) => config.flags & IFACE_CAPTURE != 0,
+        None => false,
+    }
+}
+
+#[inline(always)]
+pub fn is_log_only(ifindex: u32) -> bool {
+    match unsafe { IFACES.get(&ifindex) } {
+        Some(config) => config.flags & IFACE_LOG_ONLY != 0,
+        None => false,
+    }
+}
+
+#[inline(always)]
+pub fn verifies_udp_checksum(ifindex: u32) -> bool {
+    match unsafe { IFACES.get(&ifindex) } {
+        Some(config) => config.flags & IFACE_VERIFY_UDP_CHECKSUM != 0,
+        None => false,
+    }
+}
+
+/// Whether `flag` is set for an untrusted interface
+#[inline(always)]
+fn is_enforced(ifindex: u32, flag: u32) -> bool {
+    match unsafe { IFACES.get(&ifindex) } {
+        Some(config) => config.flags & flag != 0 && config.flags & IFACE_TRUSTED == 0,
+        None => false,
+    }
+}
+
+#[inline(always)]
+pub fn is_source_guarded(ifindex: u32) -> bool {
+    is_enforced(ifindex, IFACE_SOURCE_GUARD)
+}
+
+#[inline(always)]
+pub fn is_arp_inspected(ifindex: u32) -> bool {
+    is_enforced(ifindex, IFACE_ARP_INSPECTION)
+}
+
+/// Whether `address` is leased to `mac`
+#[inline(always)]
+pub fn is_bound(address: u32, mac: &[u8; 6]) -> bool {
+    let binding = match unsafe { BINDINGS.get(&address) } {
+        Some(binding) => binding,
+        None => return false,
+    };
+
+    // Array comparison turns into a memcmp call, which BPF doesn't have
+    binding.mac.iter().zip(mac.iter()).all(|(a, b)| a == b)
+}
+
+#[inline(always)]
+pub fn count(ifindex: u32, stat: Stat) {
+    let key = StatsKey {
+        ifindex,
+        stat: stat as u32,
+    };
+
+    unsafe {
+        match STATS.get_ptr_mut(&key) {
+            Some(value) => *value += 1,
+            None => {
+                let _ = STATS.insert(&key, &1, 0);
+            }
+        }
+    }
+}
+
+#[inline(always)]
+pub fn count_arp_reject(sender_mac: [u8; 6]) {
+    let key = ArpRejectKey { sender_mac };
+
+    unsafe {
+        match ARP_REJECTS.get_ptr_mut(&key) {
+            Some(value) => *value += 1,
+            None => {
+                let _ = ARP_REJECTS.insert(&key, &1, 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use aya_bpf::helpers::bpf_ktime_get_ns;
+use dhcp_common::{
+    ClientKey, DhcpEvent, RateLimitEvent, Stat, TokenBucket, DHCP_DISCOVER, DHCP_REQUEST,
+    IFACE_LOG_ONLY, IFACE_RATE_LIMIT_DROP, RATE_LIMIT_CLIENT, RATE_LIMIT_INTERFACE,
+};
+
+use crate::{
+    context::Packet,
+    maps::{count, CLIENT_BUCKETS, IFACES, INTERFACE_BUCKETS, RATE_EVENTS},
+    snoop::Verdict,
+};
+
+const NS_PER_TOKEN: u64 = 1_000_000_000;
+
+enum Take {
+    Conform,
+    /// The first message over the limit since the bucket last had tokens
+    Exceeded,
+    StillExceeded,
+}
+
+/// Hold DISCOVERs and REQUESTs to the per client and per interface rate limits, telling
+/// userspace when either is first exceeded
+#[inline(always)]
+pub fn limit<C: Packet>(ctx: &C, ifindex: u32, event: &DhcpEvent) -> Verdict {
+    if event.message_type != DHCP_DISCOVER && event.message_type != DHCP_REQUEST {
+        return Verdict::Pass;
+    }
+    let config = match unsafe { IFACES.get(&ifindex) } {
+        Some(config) => *config,
+        None => return Verdict::Pass,
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+    let dropping = config.flags & IFACE_RATE_LIMIT_DROP != 0 && config.flags & IFACE_LOG_ONLY == 0;
+
+    let mut over = false;
+    if config.client_rate != 0 {
+        let key = ClientKey {
+            ifindex,
+            mac: event.client_mac,
+            _padding: [0; 2],
+        };
+        let take = match unsafe { CLIENT_BUCKETS.get_ptr_mut(&key) } {
+            Some(bucket) => take(
+                unsafe { &mut *bucket },
+                config.client_rate,
+                config.client_burst,
+                now,
+            ),
+            None => {
+                let _ = unsafe { CLIENT_BUCKETS.insert(&key, &full(config.client_burst, now), 0) };
+                Take::Conform
+            }
+        };
+        over |= report(
+            ctx,
+            take,
+            RATE_LIMIT_CLIENT,
+            config.client_rate,
+            event,
+            dropping,
+        );
+    }
+    if config.interface_rate != 0 {
+        let take = match unsafe { INTERFACE_BUCKETS.get_ptr_mut(&ifindex) } {
+            Some(bucket) => take(
+                unsafe { &mut *bucket },
+                config.interface_rate,
+                config.interface_burst,
+                now,
+            ),
+            None => {
+                let bucket = full(config.interface_burst, now);
+                let _ = unsafe { INTERFACE_BUCKETS.insert(&ifindex, &bucket, 0) };
+                Take::Conform
+            }
+        };
+        over |= report(
+            ctx,
+            take,
+            RATE_LIMIT_INTERFACE,
+            config.interface_rate,
+            event,
+            dropping,
+        );
+    }
+
+    if !over {
+        return Verdict::Pass;
+    }
+    count(ifindex, Stat::RateLimited);
+    if dropping {
+        Verdict::Drop
+    } else {
+        Verdict::Pass
+    }
+}
+
+/// A bucket that just let one message through
+#[inline(always)]
+fn full(burst: u32, now: u64) -> TokenBucket {
+    TokenBucket {
+        tokens: capacity(burst) - NS_PER_TOKEN,
+        updated: now,
+        limited: 0,
+        _padding: 0,
+    }
+}
+
+#[inline(always)]
+fn capacity(burst: u32) -> u64 {
+    burst.max(1) as u64 * NS_PER_TOKEN
+}
+
+/// Refill the bucket for the time since the last message and take a token out of it.
+/// Buckets are shared between CPUs without locking, concurrent messages may both get a
+/// token that was only there once, it's close enough for spotting floods.
+#[inline(always)]
+fn take(bucket: &mut TokenBucket, rate: u32, burst: u32, now: u64) -> Take {
+    let elapsed = now.saturating_sub(bucket.updated);
+    bucket.tokens = bucket
+        .tokens
+        .saturating_add(elapsed.saturating_mul(rate as u64))
+        .min(capacity(burst));
+    bucket.updated = now;
+
+    if bucket.tokens >= NS_PER_TOKEN {
+        bucket.tokens -= NS_PER_TOKEN;
+        bucket.limited = 0;
+        Take::Conform
+    } else if bucket.limited == 0 {
+        bucket.limited = 1;
+        Take::Exceeded
+    } else {
+        Take::StillExceeded
+    }
+}
+
+/// Returns whether the message is over the limit
+#[inline(always)]
+fn report<C: Packet>(
+    ctx: &C,
+    take: Take,
+    scope: u8,
+    rate: u32,
+    event: &DhcpEvent,
+    dropping: bool,
+) -> bool {
+    match take {
+        Take::Conform => false,
+        Take::StillExceeded => true,
+        Take::Exceeded => {
+            let alert = RateLimitEvent {
+                ifindex: event.ifindex,
+                rate,
+                vlan: event.vlan,
+                client_mac: event.client_mac,
+                scope,
+                message_type: event.message_type,
+                dropping: dropping as u8,
+                _padding: [0; 5],
+            };
+            unsafe { RATE_EVENTS.output(ctx, &alert, 0) };
+            true
+        }
+    }
+}
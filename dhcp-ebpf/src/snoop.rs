@@ -0,0 +1,392 @@
+use crate::{
+    arp,
+    bindings::{ethhdr, iphdr, udphdr},
+    capture::capture,
+    checksum::{self, UdpChecksum},
+    context::{ptr_at, Packet, PacketBytes},
+    maps::{
+        count, is_bound, is_log_only, is_source_guarded, is_trusted, verifies_udp_checksum, EVENTS,
+        SCRATCH, TRANSACTIONS,
+    },
+    ratelimit,
+};
+use aya_bpf::helpers::bpf_ktime_get_ns;
+use aya_log_ebpf::trace;
+use core::mem;
+use dhcp_common::{
+    options::{read_options, OPTIONS_OFFSET},
+    DhcpEvent, Stat, Transaction, BOOTREPLY, BOOTREQUEST,
+};
+
+/// What to do with a packet once it's been looked at, each program type maps this to its
+/// own return codes
+pub enum Verdict {
+    Pass,
+    Drop,
+}
+
+const IPPROTO_UDP: u8 = 0x0011;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_ARP: u16 = 0x0806;
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88a8;
+const ETH_HDR_LEN: usize = mem::size_of::<ethhdr>();
+const IP_HDR_LEN: usize = mem::size_of::<iphdr>();
+/// With the maximum of 40 bytes of options
+const MAX_IP_HDR_LEN: usize = 60;
+const UDP_HDR_LEN: usize = mem::size_of::<udphdr>();
+const VLAN_HDR_LEN: usize = mem::size_of::<VlanHdr>();
+
+// Only the outer tag is reported, an inner one is skipped over
+const MAX_VLAN_TAGS: usize = 2;
+
+const DHCP_MAGIC_COOKIE: u32 = 0x63825363;
+
+const IP_MORE_FRAGMENTS: u16 = 0x2000;
+const IP_FRAGMENT_OFFSET: u16 = 0x1fff;
+
+// The parser reads options from here on
+const _: () = assert!(mem::size_of::<DhcpPacket>() == OPTIONS_OFFSET);
+
+/// Parse a DHCP message out of the packet, report it to userspace and decide what happens
+/// to the packet
+#[inline(always)]
+pub fn snoop<C: Packet>(ctx: &C) -> Verdict {
+    let verdict = match try_snoop(ctx) {
+        Ok(verdict) => verdict,
+        Err(verdict) => verdict,
+    };
+
+    // Everything up to here still happened, counters and events included
+    match verdict {
+        Verdict::Drop if is_log_only(ctx.ifindex()) => Verdict::Pass,
+        verdict => verdict,
+    }
+}
+
+#[inline(always)]
+fn try_snoop<C: Packet>(ctx: &C) -> Result<Verdict, Verdict> {
+    // For userspace to hold against the interface's rx_packets
+    count(ctx.ifindex(), Stat::Packets);
+    let eth = ptr_at::<ethhdr>(ctx, 0).ok_or(Verdict::Pass)?;
+
+    let mut proto = unsafe { u16::from_be((*eth).h_proto) };
+    let mut l3_offset = ETH_HDR_LEN;
+    let mut vlan = 0;
+
+    for _ in 0..MAX_VLAN_TAGS {
+        if proto != ETH_P_8021Q && proto != ETH_P_8021AD {
+            break;
+        }
+
+        let tag = ptr_at::<VlanHdr>(ctx, l3_offset).ok_or(Verdict::Pass)?;
+        if vlan == 0 {
+            vlan = unsafe { u16::from_be((*tag).tci) } & 0x0fff;
+        }
+        proto = unsafe { u16::from_be((*tag).proto) };
+        l3_offset += VLAN_HDR_LEN;
+    }
+    // NICs offloading VLAN stripping hand the program untagged frames
+    if l3_offset > ETH_HDR_LEN {
+        count(ctx.ifindex(), Stat::VlanTagged);
+    }
+    // The outer tag when it's been taken off already, which the stack always does before
+    // TC runs
+    let stripped = ctx.stripped_vlan();
+    if stripped != 0 {
+        vlan = stripped;
+        count(ctx.ifindex(), Stat::VlanRecovered);
+    }
+
+    if proto == ETH_P_ARP {
+        return Ok(arp::inspect(ctx, l3_offset, vlan));
+    }
+    if proto != ETH_P_IP {
+        return Ok(Verdict::Pass);
+    }
+
+    let ip = ptr_at::<iphdr>(ctx, l3_offset).ok_or(Verdict::Pass)?;
+    let ifindex = ctx.ifindex();
+    // Later fragments carry no UDP header, there's no telling whether they're DHCP
+    let fragment = unsafe { u16::from_be((*ip).frag_off) };
+    if unsafe { (*ip).protocol } != IPPROTO_UDP || fragment & IP_FRAGMENT_OFFSET != 0 {
+        return Ok(guard_source(ifindex, eth, ip));
+    }
+
+    let ip_hdr_len = unsafe { (*ip).ihl() } as usize * 4;
+    let ip_len = unsafe { u16::from_be((*ip).tot_len) } as usize;
+    if ip_hdr_len < IP_HDR_LEN || ip_hdr_len > MAX_IP_HDR_LEN || ip_len < ip_hdr_len {
+        // Still has to get past source guard, but nothing more can be made of it
+        count(ifindex, Stat::BadIpHeader);
+        return Ok(guard_source(ifindex, eth, ip));
+    }
+    let l4_offset = l3_offset + ip_hdr_len;
+
+    let udp = ptr_at::<udphdr>(ctx, l4_offset).ok_or(Verdict::Pass)?;
+    let source_port = unsafe { u16::from_be((*udp).source) };
+    let dest_port = unsafe { u16::from_be((*udp).dest) };
+
+    // Including what's about to be dropped, those are the interesting ones
+    if source_port == 67 || (source_port == 68 && dest_port == 67) {
+        capture(ctx, ifindex);
+    }
+
+    // DHCP traffic goes like,
+    // 68 port on client to 67 port on server
+    // Clients need to get through to obtain a binding in the first place, they're only
+    // held to the rate limits
+    if source_port == 68 && dest_port == 67 {
+        let event = parse(ctx, ifindex, vlan, ip, udp, l3_offset, l4_offset)?;
+        // A flood isn't reported message by message, the rate limit event covers it
+        if let Verdict::Drop = ratelimit::limit(ctx, ifindex, event) {
+            return Ok(Verdict::Drop);
+        }
+        record_request(event);
+        report(ctx, event);
+        return Ok(Verdict::Pass);
+    }
+    if source_port != 67 {
+        return Ok(guard_source(ifindex, eth, ip));
+    }
+
+    // Nothing but clients should be behind an untrusted port
+    let rogue = !is_trusted(ifindex);
+    if rogue {
+        count(ifindex, Stat::RogueDropped);
+        // Nothing is going to be dropped, report the message so userspace can tell whether
+        // the server is one of the trusted ones
+        if !is_log_only(ifindex) {
+            return Ok(Verdict::Drop);
+        }
+    }
+
+    let event = parse(ctx, ifindex, vlan, ip, udp, l3_offset, l4_offset)?;
+    answer(event);
+    report(ctx, event);
+
+    // Turned into a pass by `snoop`
+    if rogue {
+        return Ok(Verdict::Drop);
+    }
+    Ok(Verdict::Pass)
+}
+
+/// Check the headers and parse the DHCP message into the scratch event. Packets that can't
+/// be parsed are counted and let through.
+#[inline(always)]
+fn parse<C: Packet>(
+    ctx: &C,
+    ifindex: u32,
+    vlan: u16,
+    ip: *const iphdr,
+    udp: *const udphdr,
+    l3_offset: usize,
+    l4_offset: usize,
+) -> Result<&'static mut DhcpEvent, Verdict> {
+    let ip_hdr_len = l4_offset - l3_offset;
+    let ip_len = unsafe { u16::from_be((*ip).tot_len) } as usize;
+
+    if unsafe { u16::from_be((*ip).frag_off) } & IP_MORE_FRAGMENTS != 0 {
+        count(ifindex, Stat::Fragmented);
+        return Err(Verdict::Pass);
+    }
+
+    // What udp.len claims has to fit in the IP payload, and the IP payload in the frame
+    let udp_len = unsafe { u16::from_be((*udp).len) } as usize;
+    if udp_len < UDP_HDR_LEN
+        || udp_len > ip_len - ip_hdr_len
+        || ctx.data() + l4_offset + udp_len > ctx.data_end()
+    {
+        count(ifindex, Stat::BadUdpLength);
+        return Err(Verdict::Pass);
+    }
+
+    if !checksum::ip_header_ok(ctx, l3_offset, ip_hdr_len) {
+        count(ifindex, Stat::BadChecksum);
+        return Err(Verdict::Pass);
+    }
+    // Off unless asked for, traffic sent from the host itself usually has the UDP checksum
+    // left to the NIC and only partly filled in by the time the program sees it
+    if verifies_udp_checksum(ifindex) {
+        match checksum::udp(
+            ctx,
+            source_address(ip),
+            dest_address(ip),
+            l4_offset,
+            udp_len,
+        ) {
+            UdpChecksum::Ok => {}
+            UdpChecksum::Bad => {
+                count(ifindex, Stat::BadChecksum);
+                return Err(Verdict::Pass);
+            }
+            UdpChecksum::Unverified => count(ifindex, Stat::UnverifiedChecksum),
+        }
+    }
+
+    let dhcp_offset = l4_offset + UDP_HDR_LEN;
+
+    let dhcp = match ptr_at::<DhcpPacket>(ctx, dhcp_offset) {
+        Some(dhcp) => dhcp,
+        None => {
+            count(ifindex, Stat::Truncated);
+            return Err(Verdict::Pass);
+        }
+    };
+    let op = unsafe { (*dhcp).operation_type };
+    let cookie = unsafe { u32::from_be((*dhcp).magic_cookie) } == DHCP_MAGIC_COOKIE;
+    if !cookie && op != BOOTREQUEST && op != BOOTREPLY {
+        count(ifindex, Stat::BadCookie);
+        return Err(Verdict::Pass);
+    }
+
+    let event = unsafe { SCRATCH.get_ptr_mut(0) }.ok_or(Verdict::Pass)?;
+    let event = unsafe { &mut *event };
+
+    event.timestamp_ns = unsafe { bpf_ktime_get_ns() };
+    event.ifindex = ifindex;
+    event.vlan = vlan;
+    event.op = op;
+    event.xid = unsafe { u32::from_be((*dhcp).transaction_id) };
+    event.client_address = unsafe { u32::from_be((*dhcp).client_address) };
+    event.your_address = unsafe { u32::from_be((*dhcp).your_address) };
+    event.relay_address = unsafe { u32::from_be((*dhcp).relay_agent_address) };
+    event.client_mac = unsafe { (*dhcp).client_hardware_address };
+    event.server_id = 0;
+    event.lease_time = 0;
+    event.has_lease_time = 0;
+    event.message_type = 0;
+    event.hostname_len = 0;
+    event.circuit_id_len = 0;
+    event.remote_id_len = 0;
+    event.parameter_list_len = 0;
+    event.vendor_class_len = 0;
+    event.unknown_options_len = 0;
+    event.answered = 0;
+    event.request_type = 0;
+    event.response_time_us = 0;
+
+    // Plain BOOTP (RFC 951) puts anything in the vendor area, there are no options to read
+    if !cookie {
+        event.bootp = 1;
+        return Ok(event);
+    }
+
+    // Checked against data_end above, so the option walk stays inside the packet either way
+    let udp_payload_size = udp_len - UDP_HDR_LEN;
+
+    if let Err(stat) = read_options(&PacketBytes(ctx), dhcp_offset, udp_payload_size, event) {
+        count(ifindex, stat);
+        return Err(Verdict::Pass);
+    }
+    // BOOTP with RFC 1497 vendor extensions has the cookie, but no option 53
+    event.bootp = (event.message_type == 0) as u8;
+
+    Ok(event)
+}
+
+/// Count the message and send it to userspace
+#[inline(always)]
+fn report<C: Packet>(ctx: &C, event: &DhcpEvent) {
+    trace!(
+        ctx,
+        "dhcp message type {} xid {:x}",
+        event.message_type,
+        event.xid
+    );
+
+    let stat = if event.bootp != 0 {
+        Stat::Bootp
+    } else {
+        Stat::for_message_type(event.message_type)
+    };
+    count(event.ifindex, stat);
+    unsafe { EVENTS.output(ctx, event, 0) };
+}
+
+/// Remember a client's request by its xid, for `answer` to find
+#[inline(always)]
+fn record_request(event: &DhcpEvent) {
+    let transaction = Transaction {
+        requested_at: unsafe { bpf_ktime_get_ns() },
+        client_mac: event.client_mac,
+        message_type: event.message_type,
+        _padding: 0,
+    };
+    let _ = unsafe { TRANSACTIONS.insert(&event.xid, &transaction, 0) };
+}
+
+/// Fill in the request a server's reply answers and how long it took, if the request went
+/// through here
+#[inline(always)]
+fn answer(event: &mut DhcpEvent) {
+    let transaction = match unsafe { TRANSACTIONS.get(&event.xid) } {
+        Some(transaction) => *transaction,
+        None => return,
+    };
+    // Another client that happened to pick the same xid
+    let same_client = transaction
+        .client_mac
+        .iter()
+        .zip(event.client_mac.iter())
+        .all(|(a, b)| a == b);
+    if !same_client {
+        return;
+    }
+
+    let elapsed = unsafe { bpf_ktime_get_ns() }.saturating_sub(transaction.requested_at);
+    event.answered = 1;
+    event.request_type = transaction.message_type;
+    event.response_time_us = (elapsed / 1000).min(u32::MAX as u64) as u32;
+}
+
+/// IP Source Guard, drop traffic whose source address isn't leased to the source MAC
+#[inline(always)]
+fn guard_source(ifindex: u32, eth: *const ethhdr, ip: *const iphdr) -> Verdict {
+    if !is_source_guarded(ifindex) {
+        return Verdict::Pass;
+    }
+
+    if is_bound(source_address(ip), unsafe { &(*eth).h_source }) {
+        return Verdict::Pass;
+    }
+
+    count(ifindex, Stat::SourceGuardDropped);
+    Verdict::Drop
+}
+
+/// In host byte order
+#[inline(always)]
+fn source_address(ip: *const iphdr) -> u32 {
+    u32::from_be(unsafe { (*ip).__bindgen_anon_1.addrs.saddr })
+}
+
+#[inline(always)]
+fn dest_address(ip: *const iphdr) -> u32 {
+    u32::from_be(unsafe { (*ip).__bindgen_anon_1.addrs.daddr })
+}
+
+#[repr(C)]
+pub struct VlanHdr {
+    tci: u16,
+    proto: u16,
+}
+
+#[repr(C)]
+pub struct DhcpPacket {
+    operation_type: u8,
+    hardware_type: u8,
+    hardware_address_length: u8,
+    hops: u8,
+    transaction_id: u32,
+    seconds_elapsed: u16,
+    flags: u16,
+    client_address: u32,
+    your_address: u32,
+    next_server_address: u32,
+    relay_agent_address: u32,
+    client_hardware_address: [u8; 6],
+    _client_hardware_padding: [u8; 10],
+    _bootp_legacy: [u8; 192],
+    magic_cookie: u32,
+}
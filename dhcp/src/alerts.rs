@@ -0,0 +1,559 @@
+//! Threshold rules on the daemon's own metrics, e.g. `nak_rate > 5/min for 10m`, for
+//! alerting on routers that don't run a monitoring stack. Alerts are events, and reach
+//! whoever should hear about them through the sinks and hooks.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use log::{info, warn};
+use serde::{de, Deserialize, Deserializer};
+use tokio::time::interval;
+
+use dhcp_common::Stat;
+
+use crate::{
+    events::{Alert, AlertState, Event},
+    metrics,
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(15);
+/// Rates are taken over this much of the past
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AlertConfig {
+    pub name: String,
+    pub rule: Rule,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Above,
+    AtLeast,
+    Below,
+    AtMost,
+}
+
+impl Comparison {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Above => value > threshold,
+            Comparison::AtLeast => value >= threshold,
+            Comparison::Below => value < threshold,
+            Comparison::AtMost => value <= threshold,
+        }
+    }
+}
+
+/// `METRIC[{LABEL="VALUE",...}] OP THRESHOLD[/UNIT] [for DURATION]`. Metrics are the
+/// `dhcp stats` counters and the Prometheus metrics without the `dhcp_snoop_` prefix,
+/// summed over the samples matching the labels. `_rate` makes it how fast one goes up.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// As written in the config
+    source: String,
+    metric: String,
+    labels: Vec<(String, String)>,
+    rate: bool,
+    comparison: Comparison,
+    threshold: f64,
+    /// Seconds per unit of the threshold, 1 but for rates
+    unit: f64,
+    /// How long the comparison has to hold before the alert fires
+    sustained: Duration,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (expr, sustained) = match s.split_once(" for ") {
+            Some((expr, duration)) => (
+                expr,
+                humantime::parse_duration(duration.trim())
+                    .map_err(|e| format!("invalid duration {:?}: {}", duration.trim(), e))?,
+            ),
+            None => (s, Duration::ZERO),
+        };
+
+        let at = expr
+            .find(|c| c == '<' || c == '>')
+            .ok_or_else(|| format!("{:?} compares nothing, expected <, <=, > or >=", s))?;
+        let (selector, rest) = expr.split_at(at);
+        let (comparison, threshold) = match rest.as_bytes() {
+            [b'>', b'=', ..] => (Comparison::AtLeast, &rest[2..]),
+            [b'<', b'=', ..] => (Comparison::AtMost, &rest[2..]),
+            [b'>', ..] => (Comparison::Above, &rest[1..]),
+            _ => (Comparison::Below, &rest[1..]),
+        };
+
+        let selector = selector.trim();
+        let (name, labels) = match selector.split_once('{') {
+            Some((name, labels)) => {
+                let labels = labels
+                    .strip_suffix('}')
+                    .ok_or_else(|| format!("unclosed labels in {:?}", selector))?;
+                (name, parse_labels(labels)?)
+            }
+            None => (selector, Vec::new()),
+        };
+        let (metric, rate) = match name.strip_suffix("_rate") {
+            Some(metric) => (metric, true),
+            None => (name, false),
+        };
+        if metric.is_empty() {
+            return Err(format!("no metric in {:?}", s));
+        }
+        if !is_metric(metric) {
+            return Err(format!(
+                "{} isn't a metric, expected a `dhcp stats` counter or a Prometheus metric \
+                 without the {}_ prefix",
+                metric,
+                metrics::NAMESPACE
+            ));
+        }
+
+        let threshold = threshold.trim();
+        let (value, unit) = match threshold.split_once('/') {
+            Some((value, unit)) if rate => (value, per(unit.trim())?),
+            Some(_) => return Err(format!("{} isn't a rate, it can't go by time", metric)),
+            None => (threshold, 1.0),
+        };
+        let threshold = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid threshold {:?}", value.trim()))?;
+
+        Ok(Rule {
+            source: s.to_owned(),
+            metric: metric.to_owned(),
+            labels,
+            rate,
+            comparison,
+            threshold,
+            unit,
+            sustained,
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// `name="value", ...`
+fn parse_labels(s: &str) -> Result<Vec<(String, String)>, String> {
+    s.split(',')
+        .filter(|label| !label.trim().is_empty())
+        .map(|label| {
+            let (name, value) = label
+                .split_once('=')
+                .ok_or_else(|| format!("invalid label {:?}, expected name=\"value\"", label))?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            Ok((name.trim().to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+fn is_metric(name: &str) -> bool {
+    Stat::ALL.iter().any(|stat| stat.name() == name) || metrics::FAMILIES.contains(&name)
+}
+
+/// Seconds in a unit of time
+fn per(unit: &str) -> Result<f64, String> {
+    Ok(match unit {
+        "s" | "sec" | "second" => 1.0,
+        "m" | "min" | "minute" => 60.0,
+        "h" | "hour" => 3600.0,
+        _ => return Err(format!("invalid unit {:?}, expected s, min or h", unit)),
+    })
+}
+
+/// One sample of a metric
+struct Series {
+    name: String,
+    labels: Vec<(&'static str, String)>,
+    value: f64,
+}
+
+/// Everything rules can refer to, as it is now
+fn series(backend: &Backend) -> Result<Vec<Series>, anyhow::Error> {
+    let mut series = Vec::new();
+
+    for interface in backend.stats.read()? {
+        for stat in Stat::ALL {
+            series.push(Series {
+                name: stat.name().to_owned(),
+                labels: vec![("interface", interface.interface.clone())],
+                value: interface.get(stat) as f64,
+            });
+        }
+    }
+    for family in metrics::collect(backend)? {
+        let name = family
+            .name
+            .strip_prefix(metrics::NAMESPACE)
+            .and_then(|name| name.strip_prefix('_'))
+            .unwrap_or(&family.name)
+            .to_owned();
+        for sample in family.samples {
+            series.push(Series {
+                name: name.clone(),
+                labels: sample.labels,
+                value: sample.value,
+            });
+        }
+    }
+
+    Ok(series)
+}
+
+/// A rule and what it has been up to
+struct Watch {
+    config: AlertConfig,
+    /// The metric over the last `RATE_WINDOW`, for rates
+    history: VecDeque<(Instant, f64)>,
+    /// Since when the comparison has held
+    holding_since: Option<Instant>,
+    firing: bool,
+}
+
+impl Watch {
+    fn new(config: AlertConfig) -> Watch {
+        Watch {
+            config,
+            history: VecDeque::new(),
+            holding_since: None,
+            firing: false,
+        }
+    }
+
+    /// What the rule compares now in units of the threshold, `None` while there's nothing
+    /// to compare yet. Metrics without samples, e.g. `server_check_up` before the first
+    /// check, have nothing to compare either.
+    fn value(&mut self, series: &[Series], now: Instant) -> Option<f64> {
+        let rule = &self.config.rule;
+        let matching: Vec<f64> = series
+            .iter()
+            .filter(|series| series.name == rule.metric)
+            .filter(|series| {
+                rule.labels.iter().all(|(name, value)| {
+                    series
+                        .labels
+                        .iter()
+                        .any(|(label, v)| *label == name.as_str() && v == value)
+                })
+            })
+            .map(|series| series.value)
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        let total: f64 = matching.into_iter().sum();
+        if !rule.rate {
+            return Some(total);
+        }
+
+        // A counter going down was reset, with the program reloaded say
+        if self.history.back().map_or(false, |&(_, last)| total < last) {
+            self.history.clear();
+        }
+        self.history.push_back((now, total));
+        // Keep the last sample from before the window, for the rate to cover all of it
+        while self
+            .history
+            .get(1)
+            .map_or(false, |&(at, _)| now.duration_since(at) >= RATE_WINDOW)
+        {
+            self.history.pop_front();
+        }
+
+        let &(since, first) = self.history.front()?;
+        let elapsed = now.duration_since(since).as_secs_f64();
+        (elapsed > 0.0).then(|| (total - first) / elapsed * rule.unit)
+    }
+
+    /// The alert to raise when it starts or stops firing
+    fn evaluate(&mut self, series: &[Series], now: Instant) -> Option<Alert> {
+        let value = self.value(series, now)?;
+        let rule = &self.config.rule;
+
+        if !rule.comparison.holds(value, rule.threshold) {
+            self.holding_since = None;
+            if !self.firing {
+                return None;
+            }
+            self.firing = false;
+            return Some(self.alert(AlertState::Resolved, value));
+        }
+
+        let since = *self.holding_since.get_or_insert(now);
+        if self.firing || now.duration_since(since) < rule.sustained {
+            return None;
+        }
+        self.firing = true;
+        Some(self.alert(AlertState::Firing, value))
+    }
+
+    fn alert(&self, state: AlertState, value: f64) -> Alert {
+        Alert {
+            name: self.config.name.clone(),
+            rule: self.config.rule.to_string(),
+            state,
+            value,
+            at: SystemTime::now(),
+        }
+    }
+}
+
+/// The alert rules, all evaluated on one task
+pub struct AlertSink {
+    pub alerts: Vec<AlertConfig>,
+}
+
+impl sinks::Sink for AlertSink {
+    fn name(&self) -> String {
+        "alerts".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(self.alerts, ctx.backend.clone()).map(Ok).boxed()
+    }
+}
+
+/// Evaluate the rules every `EVALUATION_INTERVAL`, forever
+async fn run(alerts: Vec<AlertConfig>, backend: Backend) {
+    let mut watches: Vec<Watch> = alerts.into_iter().map(Watch::new).collect();
+    let mut ticks = interval(EVALUATION_INTERVAL);
+
+    loop {
+        ticks.tick().await;
+        let series = match series(&backend) {
+            Ok(series) => series,
+            Err(e) => {
+                warn!("failed to read metrics for alerts: {:#}", e);
+                continue;
+            }
+        };
+        let now = Instant::now();
+
+        for watch in &mut watches {
+            if let Some(alert) = watch.evaluate(&series, now) {
+                match alert.state {
+                    AlertState::Firing => warn!("{}", alert),
+                    AlertState::Resolved => info!("{}", alert),
+                }
+                backend.state.lock().unwrap().emit(Event::Alert(alert));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch(rule: &str) -> Watch {
+        Watch::new(AlertConfig {
+            name: "test".to_owned(),
+            rule: rule.parse().unwrap(),
+        })
+    }
+
+    fn series(name: &str, labels: &[(&'static str, &str)], value: f64) -> Series {
+        Series {
+            name: name.to_owned(),
+            labels: labels
+                .iter()
+                .map(|(label, value)| (*label, value.to_string()))
+                .collect(),
+            value,
+        }
+    }
+
+    fn at(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    fn assert_rate(rate: Option<f64>, expected: f64) {
+        let rate = rate.expect("no rate");
+        assert!(
+            (rate - expected).abs() < 1e-9,
+            "{} isn't {}",
+            rate,
+            expected
+        );
+    }
+
+    #[test]
+    fn rule_grammar() {
+        let rule: Rule = "nak_rate > 5/min for 10m".parse().unwrap();
+        assert_eq!(rule.metric, "nak");
+        assert!(rule.rate);
+        assert_eq!(rule.comparison, Comparison::Above);
+        assert_eq!(rule.threshold, 5.0);
+        assert_eq!(rule.unit, 60.0);
+        assert_eq!(rule.sustained, Duration::from_secs(600));
+        assert_eq!(rule.to_string(), "nak_rate > 5/min for 10m");
+
+        let rule: Rule = r#"messages_total{interface="eth0", type=nak} >= 3"#
+            .parse()
+            .unwrap();
+        assert_eq!(rule.metric, "messages_total");
+        assert_eq!(
+            rule.labels,
+            vec![
+                ("interface".to_owned(), "eth0".to_owned()),
+                ("type".to_owned(), "nak".to_owned())
+            ]
+        );
+        assert!(!rule.rate);
+        assert_eq!(rule.comparison, Comparison::AtLeast);
+        assert_eq!(rule.sustained, Duration::ZERO);
+
+        let rule: Rule = "server_check_up <= 0".parse().unwrap();
+        assert_eq!(rule.comparison, Comparison::AtMost);
+        let rule: Rule = "active_leases < 1".parse().unwrap();
+        assert_eq!(rule.comparison, Comparison::Below);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for rule in [
+            "active_leases",
+            "> 5",
+            "active_leases > many",
+            "active_leases > 5/min",
+            "nak_rate > 5/day",
+            "messages_total{type=\"nak\" > 5",
+            "active_leases > 5 for ever",
+            // Not a metric, which only the lack of samples would have told at runtime
+            "naks_rate > 5/min",
+            "dhcp_snoop_active_leases > 5",
+        ] {
+            assert!(rule.parse::<Rule>().is_err(), "{:?} parsed", rule);
+        }
+    }
+
+    #[test]
+    fn metrics_without_samples_compare_nothing() {
+        let mut watch = watch("server_check_up < 1");
+        let now = Instant::now();
+        assert_eq!(watch.value(&[series("active_leases", &[], 3.0)], now), None);
+        assert!(watch.evaluate(&[], now).is_none());
+        assert_eq!(
+            watch.value(
+                &[series("server_check_up", &[("server", "10.0.0.1")], 0.0)],
+                now
+            ),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    fn labels_select_the_samples_summed() {
+        let mut watch = watch(r#"messages_total{type="nak"} > 0"#);
+        let series = [
+            series(
+                "messages_total",
+                &[("interface", "eth0"), ("type", "nak")],
+                2.0,
+            ),
+            series(
+                "messages_total",
+                &[("interface", "eth1"), ("type", "nak")],
+                3.0,
+            ),
+            series(
+                "messages_total",
+                &[("interface", "eth0"), ("type", "ack")],
+                7.0,
+            ),
+        ];
+        assert_eq!(watch.value(&series, Instant::now()), Some(5.0));
+    }
+
+    #[test]
+    fn rate_is_taken_over_the_window() {
+        let mut watch = watch("nak_rate > 5/min");
+        let start = Instant::now();
+        let nak = |value| [series("nak", &[("interface", "eth0")], value)];
+
+        // Nothing to take a rate of with a single sample
+        assert_eq!(watch.value(&nak(100.0), start), None);
+        assert_rate(watch.value(&nak(110.0), at(start, 15)), 40.0);
+        assert_rate(watch.value(&nak(130.0), at(start, 30)), 60.0);
+        // Quiet from here on, the burst at the start leaves the window
+        assert_rate(watch.value(&nak(130.0), at(start, 60)), 30.0);
+        assert_rate(watch.value(&nak(130.0), at(start, 75)), 20.0);
+        assert_rate(watch.value(&nak(130.0), at(start, 90)), 0.0);
+        assert!(watch.history.len() <= (RATE_WINDOW.as_secs() / 15 + 1) as usize);
+    }
+
+    #[test]
+    fn counter_reset_starts_the_rate_over() {
+        let mut watch = watch("nak_rate > 5/min");
+        let start = Instant::now();
+        let nak = |value| [series("nak", &[], value)];
+
+        assert_eq!(watch.value(&nak(500.0), start), None);
+        assert_rate(watch.value(&nak(510.0), at(start, 15)), 40.0);
+        // The program was reloaded, its counters start from zero
+        assert_eq!(watch.value(&nak(2.0), at(start, 30)), None);
+        assert_rate(watch.value(&nak(4.0), at(start, 45)), 8.0);
+    }
+
+    #[test]
+    fn fires_after_holding_for_the_duration_and_resolves() {
+        let mut watch = watch("active_leases > 2 for 30s");
+        let start = Instant::now();
+        let leases = |value| [series("active_leases", &[], value)];
+
+        assert!(watch.evaluate(&leases(3.0), start).is_none());
+        assert!(watch.evaluate(&leases(3.0), at(start, 15)).is_none());
+        // A dip starts the wait over
+        assert!(watch.evaluate(&leases(1.0), at(start, 20)).is_none());
+        assert!(watch.evaluate(&leases(3.0), at(start, 30)).is_none());
+        assert!(watch.evaluate(&leases(3.0), at(start, 45)).is_none());
+
+        let alert = watch.evaluate(&leases(4.0), at(start, 60)).unwrap();
+        assert_eq!(alert.state, AlertState::Firing);
+        assert_eq!(alert.value, 4.0);
+        // Raised once while it keeps holding
+        assert!(watch.evaluate(&leases(5.0), at(start, 75)).is_none());
+
+        let alert = watch.evaluate(&leases(2.0), at(start, 90)).unwrap();
+        assert_eq!(alert.state, AlertState::Resolved);
+        assert!(watch.evaluate(&leases(2.0), at(start, 105)).is_none());
+    }
+
+    #[test]
+    fn fires_right_away_without_a_duration() {
+        let mut watch = watch("rogue_offers_total >= 1");
+        let alert = watch
+            .evaluate(&[series("rogue_offers_total", &[], 1.0)], Instant::now())
+            .unwrap();
+        assert_eq!(alert.state, AlertState::Firing);
+    }
+}
@@ -0,0 +1,509 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    str::FromStr,
+    sync::Arc,
+};
+
+use aya::{
+    maps::{HashMap, MapError, MapRefMut},
+    programs::{
+        tc, xdp::XdpLinkId, SchedClassifier, SchedClassifierLinkId, TcAttachType, Xdp, XdpFlags,
+    },
+    Bpf,
+};
+use dhcp_common::{
+    IfaceConfig, IFACE_ARP_INSPECTION, IFACE_CAPTURE, IFACE_LOG_ONLY, IFACE_RATE_LIMIT_DROP,
+    IFACE_SOURCE_GUARD, IFACE_TRUSTED, IFACE_VERIFY_UDP_CHECKSUM,
+};
+use log::{info, warn};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    config::InterfaceConfig,
+    fleet::Policy,
+    health::Health,
+    iface,
+    offload::{self, VlanOffload},
+};
+
+const XDP_PROGRAM: &str = "dhcp";
+const TC_PROGRAM: &str = "dhcp_tc";
+
+/// How the program gets hooked into the interfaces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// XDP in the driver, falling back to generic XDP if the driver can't do it
+    #[default]
+    Auto,
+    /// XDP in the driver only
+    Native,
+    /// Generic XDP, runs after the skb has been allocated
+    Skb,
+    /// A TC classifier on the ingress hook
+    Tc,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Mode::Auto,
+            "native" => Mode::Native,
+            "skb" => Mode::Skb,
+            "tc" => Mode::Tc,
+            _ => {
+                return Err(format!(
+                    "invalid mode {:?}, expected auto, native, skb or tc",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mode::Auto => "auto",
+            Mode::Native => "native",
+            Mode::Skb => "skb",
+            Mode::Tc => "tc",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// What happens to the traffic the program would drop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Enforcement {
+    #[default]
+    Drop,
+    /// Count and report it, but let it through. For trying out a configuration.
+    LogOnly,
+}
+
+impl FromStr for Enforcement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "drop" => Enforcement::Drop,
+            "log-only" => Enforcement::LogOnly,
+            _ => {
+                return Err(format!(
+                    "invalid enforcement {:?}, expected drop or log-only",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+impl fmt::Display for Enforcement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Enforcement::Drop => "drop",
+            Enforcement::LogOnly => "log-only",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Enforcement {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+enum Link {
+    Xdp(XdpLinkId),
+    Tc(SchedClassifierLinkId),
+}
+
+struct Attachment {
+    config: InterfaceConfig,
+    /// ifindex the program is attached to and the link doing it
+    link: Option<(u32, Link)>,
+    /// What it was last attached in
+    mode: Mode,
+}
+
+/// The program's attachments to the configured interfaces, along with the `IFACES` map
+/// telling the program how to treat each of them
+pub struct Attachments {
+    mode: Mode,
+    enforcement: Enforcement,
+    /// Whether the interfaces are configured in `IFACES`, without entries the program
+    /// treats every interface as trusted
+    enforcing: bool,
+    interfaces: BTreeMap<String, Attachment>,
+    iface_configs: HashMap<MapRefMut, u32, IfaceConfig>,
+    health: Arc<Health>,
+    /// Interfaces whose VLAN offload was turned off, to turn back on on exit
+    offload_disabled: BTreeSet<String>,
+}
+
+/// Load the programs `mode` and the interfaces falling back to TC need into the kernel
+pub fn load(
+    bpf: &mut Bpf,
+    mode: Mode,
+    interfaces: &[InterfaceConfig],
+) -> Result<(), anyhow::Error> {
+    let tc_fallback = interfaces
+        .iter()
+        .any(|interface| interface.vlan_offload == VlanOffload::Tc);
+
+    if mode == Mode::Tc || tc_fallback {
+        let program: &mut SchedClassifier = bpf.program_mut(TC_PROGRAM).unwrap().try_into()?;
+        program.load()?;
+    }
+    if mode != Mode::Tc {
+        let program: &mut Xdp = bpf.program_mut(XDP_PROGRAM).unwrap().try_into()?;
+        program.load()?;
+    }
+
+    Ok(())
+}
+
+impl Attachments {
+    pub fn new(
+        mode: Mode,
+        enforcement: Enforcement,
+        enforcing: bool,
+        interfaces: Vec<InterfaceConfig>,
+        mut iface_configs: HashMap<MapRefMut, u32, IfaceConfig>,
+        health: Arc<Health>,
+    ) -> Attachments {
+        // The map stays pinned when the daemon dies, whatever a previous run configured is
+        // stale by now
+        let stale: Vec<u32> = iface_configs.keys().filter_map(Result::ok).collect();
+        for ifindex in stale {
+            let _ = iface_configs.remove(&ifindex);
+        }
+
+        let interfaces = interfaces
+            .into_iter()
+            .map(|config| {
+                health.set_attached(&config.name, false);
+                let attachment = Attachment {
+                    config,
+                    link: None,
+                    mode,
+                };
+                (attachment.config.name.clone(), attachment)
+            })
+            .collect();
+
+        Attachments {
+            mode,
+            enforcement,
+            enforcing,
+            interfaces,
+            iface_configs,
+            health,
+            offload_disabled: BTreeSet::new(),
+        }
+    }
+
+    /// Attach to every configured interface that exists right now, the rest are picked up
+    /// by `link_added` once they show up
+    pub fn attach_all(&mut self, bpf: &mut Bpf) {
+        let names: Vec<String> = self.interfaces.keys().cloned().collect();
+
+        for name in names {
+            match iface::index(&name) {
+                Some(ifindex) => self.link_added(bpf, &name, ifindex),
+                None => warn!("interface {} doesn't exist, waiting for it to appear", name),
+            }
+        }
+    }
+
+    pub fn link_added(&mut self, bpf: &mut Bpf, name: &str, ifindex: u32) {
+        let _span = tracing::info_span!("attach", iface = name, ifindex).entered();
+        let attachment = match self.interfaces.get_mut(name) {
+            Some(attachment) => attachment,
+            None => return,
+        };
+        // Netlink repeats NEWLINK for every state change of an interface
+        if matches!(attachment.link, Some((index, _)) if index == ifindex) {
+            return;
+        }
+        // Same name, new interface
+        if let Some((old_index, link)) = attachment.link.take() {
+            detach(bpf, link);
+            let _ = self.iface_configs.remove(&old_index);
+        }
+
+        let mode = vlan_mode(
+            self.mode,
+            name,
+            attachment.config.vlan_offload,
+            &mut self.offload_disabled,
+        );
+        attachment.mode = mode;
+        if attachment.config.verify_udp_checksum && mode != Mode::Native {
+            warn!(
+                "not verifying UDP checksums on {} in {} mode, only in native mode",
+                name, mode
+            );
+        }
+
+        if self.enforcing {
+            if let Err(e) = configure(
+                &mut self.iface_configs,
+                &attachment.config,
+                self.enforcement,
+                mode,
+                ifindex,
+            ) {
+                warn!("failed to configure {}: {}", name, e);
+                self.health.set_attached(name, false);
+                return;
+            }
+        }
+
+        match attach(bpf, mode, name) {
+            Ok(link) => {
+                info!(
+                    "attached to {} in {} mode ({})",
+                    name,
+                    mode,
+                    if attachment.config.trusted {
+                        "trusted"
+                    } else {
+                        "untrusted"
+                    }
+                );
+                attachment.link = Some((ifindex, link));
+                self.health.set_attached(name, true);
+            }
+            Err(e) => {
+                warn!("failed to attach to {}: {:#}", name, e);
+                let _ = self.iface_configs.remove(&ifindex);
+                self.health.set_attached(name, false);
+            }
+        }
+    }
+
+    pub fn link_removed(&mut self, bpf: &mut Bpf, name: &str) {
+        let attachment = match self.interfaces.get_mut(name) {
+            Some(attachment) => attachment,
+            None => return,
+        };
+
+        // Comes back with the driver's defaults, if at all
+        self.offload_disabled.remove(name);
+        if let Some((ifindex, link)) = attachment.link.take() {
+            info!("{} went away, detached", name);
+            // The kernel drops the program along with the interface, this only cleans up
+            // our side
+            detach(bpf, link);
+            let _ = self.iface_configs.remove(&ifindex);
+            self.health.set_attached(name, false);
+        }
+    }
+
+    /// Turn VLAN offload back on wherever it was turned off
+    pub fn restore_vlan_offload(&mut self) {
+        for name in std::mem::take(&mut self.offload_disabled) {
+            match offload::set_rx_vlan_stripped(&name, true) {
+                Ok(()) => info!("turned VLAN offload on {} back on", name),
+                Err(e) => warn!("failed to turn VLAN offload on {} back on: {}", name, e),
+            }
+        }
+    }
+
+    /// Start or stop enforcing the trusted interfaces on every interface attached to
+    pub fn set_enforcing(&mut self, enforcing: bool) {
+        self.enforcing = enforcing;
+        self.configure_all();
+    }
+
+    /// Take over the enforcement and rate limits of a fleet policy
+    pub fn apply(&mut self, policy: &Policy) {
+        if let Some(enforcement) = policy.enforcement {
+            self.enforcement = enforcement;
+        }
+        for interface in &policy.interfaces {
+            let attachment = match self.interfaces.get_mut(&interface.name) {
+                Some(attachment) => attachment,
+                None => {
+                    warn!(
+                        "fleet policy configures unknown interface {}",
+                        interface.name
+                    );
+                    continue;
+                }
+            };
+            if let Some(rate_limit) = &interface.rate_limit {
+                attachment.config.rate_limit = Some(rate_limit.clone());
+            }
+        }
+
+        self.configure_all();
+    }
+
+    /// Bring every interface attached to in line with its config
+    fn configure_all(&mut self) {
+        for attachment in self.interfaces.values() {
+            let ifindex = match attachment.link {
+                Some((ifindex, _)) => ifindex,
+                None => continue,
+            };
+
+            let result = if self.enforcing {
+                configure(
+                    &mut self.iface_configs,
+                    &attachment.config,
+                    self.enforcement,
+                    attachment.mode,
+                    ifindex,
+                )
+            } else {
+                self.iface_configs.remove(&ifindex)
+            };
+            if let Err(e) = result {
+                warn!("failed to configure {}: {}", attachment.config.name, e);
+            }
+        }
+    }
+}
+
+fn configure(
+    iface_configs: &mut HashMap<MapRefMut, u32, IfaceConfig>,
+    config: &InterfaceConfig,
+    enforcement: Enforcement,
+    mode: Mode,
+    ifindex: u32,
+) -> Result<(), MapError> {
+    let mut flags = 0;
+    if config.trusted {
+        flags |= IFACE_TRUSTED;
+    }
+    if config.source_guard {
+        flags |= IFACE_SOURCE_GUARD;
+    }
+    if config.arp_inspection {
+        flags |= IFACE_ARP_INSPECTION;
+    }
+    if config.capture {
+        flags |= IFACE_CAPTURE;
+    }
+    if enforcement == Enforcement::LogOnly {
+        flags |= IFACE_LOG_ONLY;
+    }
+    // In TC and generic XDP, traffic sent from the host itself reaches the program with
+    // the UDP checksum left for the NIC to finish
+    if config.verify_udp_checksum && mode == Mode::Native {
+        flags |= IFACE_VERIFY_UDP_CHECKSUM;
+    }
+
+    let mut iface_config = IfaceConfig {
+        flags,
+        ..Default::default()
+    };
+    if let Some(limit) = &config.rate_limit {
+        if limit.drop {
+            iface_config.flags |= IFACE_RATE_LIMIT_DROP;
+        }
+        iface_config.client_rate = limit.client;
+        iface_config.client_burst = limit.client_burst.unwrap_or(limit.client * 2);
+        iface_config.interface_rate = limit.interface;
+        iface_config.interface_burst = limit.interface_burst.unwrap_or(limit.interface * 2);
+    }
+    iface_configs.insert(ifindex, iface_config, 0)
+}
+
+/// The mode to attach to `name` in. When its NIC strips VLAN tags before XDP sees them,
+/// XDP is traded for TC or the offload turned off, as the interface is configured to.
+fn vlan_mode(
+    mode: Mode,
+    name: &str,
+    vlan_offload: VlanOffload,
+    offload_disabled: &mut BTreeSet<String>,
+) -> Mode {
+    // The stack keeps stripped tags with the skb, where TC finds them
+    if mode == Mode::Tc {
+        return mode;
+    }
+    match offload::rx_vlan_stripped(name) {
+        Ok(true) => {}
+        // Most virtual devices don't answer, they don't strip tags either
+        Ok(false) | Err(_) => return mode,
+    }
+
+    match vlan_offload {
+        VlanOffload::Warn => {
+            warn!(
+                "{} strips VLAN tags before XDP sees them, VLANs won't be reported on it. \
+                 Set vlan-offload to tc or disable on the interface to recover them",
+                name
+            );
+            mode
+        }
+        VlanOffload::Tc => {
+            info!(
+                "{} strips VLAN tags before XDP sees them, attaching with TC",
+                name
+            );
+            Mode::Tc
+        }
+        VlanOffload::Disable => {
+            match offload::set_rx_vlan_stripped(name, false) {
+                Ok(()) => {
+                    info!("turned VLAN offload on {} off", name);
+                    offload_disabled.insert(name.to_owned());
+                }
+                Err(e) => warn!(
+                    "failed to turn VLAN offload on {} off, VLANs won't be reported on it: {}",
+                    name, e
+                ),
+            }
+            mode
+        }
+    }
+}
+
+fn attach(bpf: &mut Bpf, mode: Mode, name: &str) -> Result<Link, anyhow::Error> {
+    let flags = match mode {
+        Mode::Auto => XdpFlags::default(),
+        Mode::Native => XdpFlags::DRV_MODE,
+        Mode::Skb => XdpFlags::SKB_MODE,
+        Mode::Tc => {
+            // Fails when the interface already has a clsact qdisc, that's fine
+            let _ = tc::qdisc_add_clsact(name);
+            let program: &mut SchedClassifier = bpf.program_mut(TC_PROGRAM).unwrap().try_into()?;
+            return Ok(Link::Tc(program.attach(name, TcAttachType::Ingress)?));
+        }
+    };
+
+    let program: &mut Xdp = bpf.program_mut(XDP_PROGRAM).unwrap().try_into()?;
+    Ok(Link::Xdp(program.attach(name, flags)?))
+}
+
+fn detach(bpf: &mut Bpf, link: Link) {
+    let result = match link {
+        Link::Xdp(link) => bpf
+            .program_mut(XDP_PROGRAM)
+            .unwrap()
+            .try_into()
+            .and_then(|program: &mut Xdp| program.detach(link)),
+        Link::Tc(link) => bpf
+            .program_mut(TC_PROGRAM)
+            .unwrap()
+            .try_into()
+            .and_then(|program: &mut SchedClassifier| program.detach(link)),
+    };
+
+    if let Err(e) = result {
+        warn!("failed to detach: {}", e);
+    }
+}
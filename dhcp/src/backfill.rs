@@ -0,0 +1,255 @@
+//! Recovers what the first versions of the program logged through aya-log, before there
+//! was a history database, and records it there so that `history` searches it like the
+//! rest.
+//!
+//! Those versions only logged one line per DHCP reply with the source and destination MAC
+//! and port, so that is all there is to recover. The address, hostname, server and
+//! transaction id were never logged, and neither was the message type, the replies are
+//! recorded as BOOTREPLY. Replies broadcast to ff:ff:ff:ff:ff:ff can't be tied to a client
+//! and are skipped.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use rusqlite::params;
+
+use crate::{history, mac::MacAddr, message::MessageType};
+
+/// Port the old program logged replies from
+const SERVER_PORT: u16 = 67;
+/// The eBPF crate, aya-log records carry it as their target
+const TARGET: &str = "dhcp";
+
+/// A reply the old program logged
+#[derive(Debug, PartialEq, Eq)]
+struct Reply {
+    at: SystemTime,
+    client: MacAddr,
+}
+
+#[derive(Default)]
+struct Summary {
+    recorded: usize,
+    duplicate: usize,
+    broadcast: usize,
+}
+
+/// Record the replies in `log`, `-` for stdin, to the history database at `db` as seen on
+/// `interface`, the one the old program was attached to. Replies already in the database
+/// are left alone, so running it on the same log twice is harmless.
+pub fn backfill(log: &Path, interface: &str, db: &Path) -> Result<(), anyhow::Error> {
+    let reader: Box<dyn BufRead> = if log == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(
+            File::open(log).with_context(|| format!("failed to open {:?}", log))?,
+        ))
+    };
+
+    let mut db = history::open(db)?;
+    let tx = db.transaction()?;
+    let mut summary = Summary::default();
+    {
+        // The old program never logged a transaction id, which is recorded as 0
+        let mut insert = tx.prepare(
+            "INSERT INTO messages (at, interface, mac, message_type, xid)
+             SELECT ?1, ?2, ?3, ?4, 0
+             WHERE NOT EXISTS (
+                 SELECT 1 FROM messages
+                 WHERE at = ?1 AND interface = ?2 AND mac = ?3 AND message_type = ?4
+             )",
+        )?;
+        let message_type = MessageType::BootReply.to_string();
+
+        for line in reader.lines() {
+            let line = line.with_context(|| format!("failed to read {:?}", log))?;
+            let reply = match parse_line(&line) {
+                Some(reply) => reply,
+                None => continue,
+            };
+            if reply.client == MacAddr([0xff; 6]) {
+                summary.broadcast += 1;
+                continue;
+            }
+
+            let inserted = insert.execute(params![
+                history::millis(reply.at),
+                interface,
+                reply.client.to_string(),
+                message_type,
+            ])?;
+            if inserted > 0 {
+                summary.recorded += 1;
+            } else {
+                summary.duplicate += 1;
+            }
+        }
+    }
+    tx.commit()?;
+
+    eprintln!(
+        "recorded {} replies, {} were already recorded and {} were broadcast",
+        summary.recorded, summary.duplicate, summary.broadcast
+    );
+
+    Ok(())
+}
+
+/// A reply out of a line env_logger wrote for the old program, e.g.
+/// `[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> 5254001a2b3c 68`, with whatever
+/// journald or a log shipper put in front of it. Every other line it logged, the option
+/// numbers and payload lengths, isn't worth anything on its own.
+fn parse_line(line: &str) -> Option<Reply> {
+    // syslog's `dhcp-snoop[812]:` comes first
+    line.match_indices('[')
+        .find_map(|(start, _)| parse_record(&line[start + 1..]))
+}
+
+/// What follows the `[` of env_logger's header
+fn parse_record(record: &str) -> Option<Reply> {
+    let (header, message) = record.split_once("] ")?;
+
+    let mut header = header.split_whitespace();
+    let at = humantime::parse_rfc3339_weak(header.next()?).ok()?;
+    let _level = header.next()?;
+    if header.next()? != TARGET {
+        return None;
+    }
+
+    let mut fields = message.split_whitespace();
+    let _server = legacy_mac(fields.next()?)?;
+    let source_port: u16 = fields.next()?.parse().ok()?;
+    if fields.next()? != "->" {
+        return None;
+    }
+    let client = legacy_mac(fields.next()?)?;
+    let _destination_port: u16 = fields.next()?.parse().ok()?;
+    if fields.next().is_some() || source_port != SERVER_PORT {
+        return None;
+    }
+
+    Some(Reply { at, client })
+}
+
+/// The old program logged MACs as one hex number, without the leading zeros
+fn legacy_mac(s: &str) -> Option<MacAddr> {
+    if s.is_empty() || s.len() > 12 {
+        return None;
+    }
+    let n = u64::from_str_radix(s, 16).ok()?;
+    let bytes = n.to_be_bytes();
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&bytes[2..]);
+
+    Some(MacAddr(mac))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn reply(client: [u8; 6]) -> Option<Reply> {
+        Some(Reply {
+            at: UNIX_EPOCH + Duration::from_secs(1667384100),
+            client: MacAddr(client),
+        })
+    }
+
+    #[test]
+    fn env_logger_line() {
+        assert_eq!(
+            parse_line("[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> 5254001a2b3c 68"),
+            reply([0x52, 0x54, 0x00, 0x1a, 0x2b, 0x3c])
+        );
+    }
+
+    #[test]
+    fn syslog_and_journald_prefixes() {
+        for line in [
+            "Nov  2 10:15:00 router dhcp-snoop[812]: \
+             [2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> 5254001a2b3c 68",
+            "<30>1 2022-11-02T10:15:00Z router dhcp-snoop 812 - - \
+             [2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> 5254001a2b3c 68",
+        ] {
+            assert_eq!(
+                parse_line(line),
+                reply([0x52, 0x54, 0x00, 0x1a, 0x2b, 0x3c]),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn macs_without_leading_zeros() {
+        assert_eq!(
+            parse_line("[2022-11-02T10:15:00Z INFO  dhcp] a0b1c2 67 -> 1a2b3c 68"),
+            reply([0, 0, 0, 0x1a, 0x2b, 0x3c])
+        );
+        // Skipped by `backfill`, but still a reply
+        assert_eq!(
+            parse_line("[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> ffffffffffff 68"),
+            reply([0xff; 6])
+        );
+    }
+
+    #[test]
+    fn other_lines_are_skipped() {
+        for line in [
+            "",
+            "no brackets at all",
+            // Requests, logged from the client's port
+            "[2022-11-02T10:15:00Z INFO  dhcp] 5254001a2b3c 68 -> ffffffffffff 67",
+            // The other lines the old program logged
+            "[2022-11-02T10:15:00Z INFO  dhcp] option = 53 len = 1",
+            "[2022-11-02T10:15:00Z INFO  dhcp] payload length = 300",
+            // Other crates, and the userspace side
+            "[2022-11-02T10:15:00Z INFO  aya] 1c697a2b3c4d 67 -> 5254001a2b3c 68",
+            "[2022-11-02T10:15:00Z INFO  dhcp::userspace] Waiting for Ctrl-C...",
+            // ISC dhcpd's own logs, nothing the old program wrote
+            "Nov  2 10:15:00 router dhcpd[944]: DHCPACK on 10.0.0.5 to 52:54:00:1a:2b:3c via eth0",
+            "Nov  2 10:15:00 router dhcpd[944]: [undefined] lease 10.0.0.5 expired",
+        ] {
+            assert_eq!(parse_line(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        for line in [
+            "[2022-11-02T10:15:00Z INFO  dhcp]",
+            "[2022-11-02T10:15:00Z INFO  dhcp 1c697a2b3c4d 67 -> 5254001a2b3c 68",
+            "[yesterday INFO  dhcp] 1c697a2b3c4d 67 -> 5254001a2b3c 68",
+            "[2022-11-02T10:15:00Z] 1c697a2b3c4d 67 -> 5254001a2b3c 68",
+            "[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 5254001a2b3c 68",
+            "[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> 5254001a2b3c",
+            "[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> 5254001a2b3c 68 extra",
+            "[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d sixty-seven -> 5254001a2b3c 68",
+            "[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> 52:54:00:1a:2b:3c 68",
+            "[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> 5254001a2b3c4d5e 68",
+            "[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> nothex 68",
+            "[2022-11-02T10:15:00Z INFO  dhcp] 1c697a2b3c4d 67 -> 5254001a2b3c 99999",
+        ] {
+            assert_eq!(parse_line(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn legacy_macs() {
+        assert_eq!(
+            legacy_mac("1c697a2b3c4d"),
+            Some(MacAddr([0x1c, 0x69, 0x7a, 0x2b, 0x3c, 0x4d]))
+        );
+        assert_eq!(legacy_mac("1"), Some(MacAddr([0, 0, 0, 0, 0, 1])));
+        assert_eq!(legacy_mac(""), None);
+        assert_eq!(legacy_mac("1c697a2b3c4d5"), None);
+        assert_eq!(legacy_mac("-1"), None);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashMap as StdHashMap, net::Ipv4Addr, time::Duration};
+
+use aya::{
+    maps::{HashMap, MapError, MapRefMut},
+    Bpf,
+};
+use dhcp_common::Binding;
+use log::warn;
+
+use crate::{
+    clock::{monotonic_ns, BootTime},
+    leases::Lease,
+    mac::MacAddr,
+};
+
+/// Write side of the `BINDINGS` map, which IP Source Guard checks client traffic against
+pub struct Bindings {
+    map: Box<dyn Map>,
+}
+
+/// The program's map, keyed by address, or a plain one standing in for it in tests
+trait Map: Send {
+    fn get(&self, address: u32) -> Option<Binding>;
+    fn insert(&mut self, address: u32, binding: Binding) -> Result<(), MapError>;
+    fn remove(&mut self, address: u32);
+    fn entries(&self) -> Vec<(u32, Binding)>;
+}
+
+impl Map for HashMap<MapRefMut, u32, Binding> {
+    fn get(&self, address: u32) -> Option<Binding> {
+        HashMap::get(self, &address, 0).ok()
+    }
+
+    fn insert(&mut self, address: u32, binding: Binding) -> Result<(), MapError> {
+        HashMap::insert(self, address, binding, 0)
+    }
+
+    fn remove(&mut self, address: u32) {
+        let _ = HashMap::remove(self, &address);
+    }
+
+    fn entries(&self) -> Vec<(u32, Binding)> {
+        self.iter().filter_map(Result::ok).collect()
+    }
+}
+
+#[cfg(test)]
+impl Map for StdHashMap<u32, Binding> {
+    fn get(&self, address: u32) -> Option<Binding> {
+        StdHashMap::get(self, &address).copied()
+    }
+
+    fn insert(&mut self, address: u32, binding: Binding) -> Result<(), MapError> {
+        StdHashMap::insert(self, address, binding);
+        Ok(())
+    }
+
+    fn remove(&mut self, address: u32) {
+        StdHashMap::remove(self, &address);
+    }
+
+    fn entries(&self) -> Vec<(u32, Binding)> {
+        self.iter()
+            .map(|(address, binding)| (*address, *binding))
+            .collect()
+    }
+}
+
+impl Bindings {
+    /// Returns the leases a previous run that didn't exit cleanly left in the map, for the
+    /// lease table to take back. They stay in force meanwhile, the program may already be
+    /// enforcing them, until `retain` drops the ones the lease table didn't keep. Only
+    /// those that ran out are removed right away.
+    pub fn new(bpf: &Bpf) -> Result<(Bindings, Vec<Lease>), anyhow::Error> {
+        let mut map: HashMap<_, u32, Binding> = HashMap::try_from(bpf.map_mut("BINDINGS")?)?;
+        let monotonic_now = monotonic_ns();
+        let (pinned, expired): (Vec<(u32, Binding)>, Vec<(u32, Binding)>) =
+            map.iter().filter_map(Result::ok).partition(|(_, binding)| {
+                binding.expires_at == 0 || binding.expires_at > monotonic_now
+            });
+        for (address, _) in &expired {
+            let _ = map.remove(address);
+        }
+
+        let now = BootTime::now();
+        let leases = pinned
+            .into_iter()
+            .map(|(address, binding)| Lease {
+                mac: MacAddr(binding.mac),
+                address: Ipv4Addr::from(address),
+                hostname: None,
+                ifindex: binding.ifindex,
+                vlan: Some(binding.vlan).filter(|vlan| *vlan != 0),
+                server_id: None,
+                relay: None,
+                class: None,
+                expires_at: match binding.expires_at {
+                    0 => None,
+                    at => Some(now + Duration::from_nanos(at - monotonic_now)),
+                },
+            })
+            .collect();
+
+        Ok((Bindings { map: Box::new(map) }, leases))
+    }
+
+    /// Bindings kept in memory, without a program to enforce them
+    #[cfg(test)]
+    pub fn in_memory() -> Bindings {
+        Bindings {
+            map: Box::new(StdHashMap::new()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, address: Ipv4Addr) -> Option<Binding> {
+        self.map.get(u32::from(address))
+    }
+
+    pub fn insert(&mut self, lease: &Lease) {
+        // The program reads CLOCK_MONOTONIC, which falls behind the lease's clock with every
+        // suspend. Only the time left on the lease carries over, entries are written again
+        // on resume.
+        let expires_at = lease.expires_at.map_or(0, |at| {
+            monotonic_ns() + at.saturating_duration_since(BootTime::now()).as_nanos() as u64
+        });
+        let binding = Binding {
+            mac: lease.mac.0,
+            vlan: lease.vlan.unwrap_or(0),
+            ifindex: lease.ifindex,
+            _reserved: 0,
+            expires_at,
+        };
+        if let Err(e) = self.map.insert(u32::from(lease.address), binding) {
+            // The MAC is left out, the logs may leave the host
+            warn!(
+                "failed to bind {} in the eBPF program: {}",
+                lease.address, e
+            );
+        }
+    }
+
+    /// Drop every binding that isn't one of `leases`'
+    pub fn retain<'a>(&mut self, leases: impl Iterator<Item = &'a Lease>) {
+        let leased: StdHashMap<u32, [u8; 6]> = leases
+            .map(|lease| (u32::from(lease.address), lease.mac.0))
+            .collect();
+        let stale: Vec<u32> = self
+            .map
+            .entries()
+            .into_iter()
+            .filter(|(address, binding)| leased.get(address) != Some(&binding.mac))
+            .map(|(address, _)| address)
+            .collect();
+        for address in stale {
+            self.map.remove(address);
+        }
+    }
+
+    pub fn remove(&mut self, lease: &Lease) {
+        let key = u32::from(lease.address);
+        // The address may have been handed to another client since
+        if matches!(self.map.get(key), Some(binding) if binding.mac == lease.mac.0) {
+            self.map.remove(key);
+        }
+    }
+}
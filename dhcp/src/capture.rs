@@ -0,0 +1,298 @@
+//! Writes the DHCP frames mirrored by the eBPF program to rotating pcapng files, so
+//! suspicious exchanges can be opened in Wireshark without a full tcpdump of the interface
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    mem,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use dhcp_common::{CaptureHeader, CAPTURE_SNAPLEN};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::mpsc::Receiver;
+
+use crate::{
+    clock::{monotonic_ns, wall_clock_ns},
+    iface,
+    mac::MacAddr,
+};
+
+const DEFAULT_MAX_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_FILES: u32 = 10;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_END: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+
+// Offsets for finding chaddr in a frame
+const ETH_HDR_LEN: usize = 14;
+const VLAN_HDR_LEN: usize = 4;
+const UDP_HDR_LEN: usize = 8;
+const CHADDR_OFFSET: usize = 28;
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88a8;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CaptureConfig {
+    /// Rotated files get `.1`, `.2` and so on appended, `.1` being the most recent
+    pub path: PathBuf,
+    /// Size in bytes at which the file is rotated
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    /// Rotated files to keep around besides the current one
+    #[serde(default = "default_files")]
+    pub files: u32,
+    /// Only keep exchanges of these clients, every client when empty
+    #[serde(default)]
+    pub clients: Vec<MacAddr>,
+}
+
+impl CaptureConfig {
+    pub fn new(path: PathBuf) -> CaptureConfig {
+        CaptureConfig {
+            path,
+            max_size: DEFAULT_MAX_SIZE,
+            files: DEFAULT_FILES,
+            clients: Vec::new(),
+        }
+    }
+}
+
+fn default_max_size() -> u64 {
+    DEFAULT_MAX_SIZE
+}
+
+fn default_files() -> u32 {
+    DEFAULT_FILES
+}
+
+/// A frame as read off the perf array
+pub struct Frame {
+    pub header: CaptureHeader,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// The header followed by the frame, `None` when the sample is shorter than that
+    pub fn decode(sample: &[u8]) -> Option<Frame> {
+        let header_len = mem::size_of::<CaptureHeader>();
+        if sample.len() < header_len {
+            return None;
+        }
+        let header = unsafe { (sample.as_ptr() as *const CaptureHeader).read_unaligned() };
+        let data = sample.get(header_len..header_len + header.captured as usize)?;
+
+        Some(Frame {
+            header,
+            data: data.to_vec(),
+        })
+    }
+
+    /// chaddr of the DHCP message in the frame
+    fn client_mac(&self) -> Option<MacAddr> {
+        let data = &self.data;
+        let mut offset = ETH_HDR_LEN;
+        let mut proto = u16::from_be_bytes(data.get(12..14)?.try_into().ok()?);
+        while proto == ETH_P_8021Q || proto == ETH_P_8021AD {
+            proto = u16::from_be_bytes(data.get(offset + 2..offset + 4)?.try_into().ok()?);
+            offset += VLAN_HDR_LEN;
+        }
+
+        let ihl = (*data.get(offset)? & 0x0f) as usize * 4;
+        let chaddr = offset + ihl + UDP_HDR_LEN + CHADDR_OFFSET;
+        Some(MacAddr(data.get(chaddr..chaddr + 6)?.try_into().ok()?))
+    }
+}
+
+struct Writer {
+    config: CaptureConfig,
+    file: File,
+    size: u64,
+    /// Interface ids in the current file by ifindex
+    interfaces: HashMap<u32, u32>,
+    /// Nanoseconds to add to CLOCK_MONOTONIC to get the wall clock
+    clock_offset: u64,
+}
+
+/// Write frames to the capture file until the channel closes
+pub async fn run(config: CaptureConfig, mut frames: Receiver<Frame>) -> Result<(), anyhow::Error> {
+    let mut writer = Writer::open(config)?;
+    info!("capturing DHCP frames to {:?}", writer.config.path);
+
+    while let Some(frame) = frames.recv().await {
+        if !writer.config.clients.is_empty()
+            && !frame
+                .client_mac()
+                .map_or(false, |mac| writer.config.clients.contains(&mac))
+        {
+            continue;
+        }
+
+        if let Err(e) = writer.write(&frame) {
+            warn!(
+                "failed to write capture to {:?}: {:#}",
+                writer.config.path, e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+impl Writer {
+    fn open(config: CaptureConfig) -> Result<Writer, anyhow::Error> {
+        if let Some(dir) = config.path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+        }
+        let file = create(&config.path)?;
+
+        let mut writer = Writer {
+            config,
+            file,
+            size: 0,
+            interfaces: HashMap::new(),
+            clock_offset: wall_clock_ns().saturating_sub(monotonic_ns()),
+        };
+        writer.write_section_header()?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, frame: &Frame) -> Result<(), anyhow::Error> {
+        // A file holds at least one frame, however large the limit
+        if self.size + epb_len(frame) > self.config.max_size && !self.interfaces.is_empty() {
+            self.rotate()?;
+        }
+
+        let ifindex = frame.header.ifindex;
+        let interface = match self.interfaces.get(&ifindex) {
+            Some(id) => *id,
+            None => {
+                let id = self.interfaces.len() as u32;
+                self.write_interface_description(&iface::name(ifindex))?;
+                self.interfaces.insert(ifindex, id);
+                id
+            }
+        };
+
+        let timestamp = frame.header.timestamp + self.clock_offset;
+        let mut body = Vec::with_capacity(20 + frame.data.len());
+        body.extend_from_slice(&interface.to_ne_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_ne_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_ne_bytes());
+        body.extend_from_slice(&(frame.data.len() as u32).to_ne_bytes());
+        body.extend_from_slice(&frame.header.len.to_ne_bytes());
+        body.extend_from_slice(&frame.data);
+        pad(&mut body);
+
+        self.write_block(BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    /// `path.n` becomes `path.n+1`, dropping whatever is past `files`, and `path` starts
+    /// over
+    fn rotate(&mut self) -> Result<(), anyhow::Error> {
+        let path = &self.config.path;
+        if self.config.files == 0 {
+            let _ = fs::remove_file(path);
+        } else {
+            let _ = fs::remove_file(rotated(path, self.config.files));
+            for n in (1..self.config.files).rev() {
+                let _ = fs::rename(rotated(path, n), rotated(path, n + 1));
+            }
+            fs::rename(path, rotated(path, 1))
+                .with_context(|| format!("failed to rotate {:?}", path))?;
+        }
+
+        self.file = create(path)?;
+        self.size = 0;
+        self.interfaces.clear();
+        self.write_section_header()
+    }
+
+    fn write_section_header(&mut self) -> Result<(), anyhow::Error> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_ne_bytes());
+        body.extend_from_slice(&1u16.to_ne_bytes());
+        body.extend_from_slice(&0u16.to_ne_bytes());
+        // Section length isn't known up front
+        body.extend_from_slice(&(-1i64).to_ne_bytes());
+        put_option(
+            &mut body,
+            OPT_SHB_USERAPPL,
+            concat!("dhcp-snoop ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        put_option(&mut body, OPT_END, &[]);
+
+        self.write_block(BLOCK_SECTION_HEADER, &body)
+    }
+
+    fn write_interface_description(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_ETHERNET.to_ne_bytes());
+        body.extend_from_slice(&0u16.to_ne_bytes());
+        body.extend_from_slice(&CAPTURE_SNAPLEN.to_ne_bytes());
+        put_option(&mut body, OPT_IF_NAME, name.as_bytes());
+        // Timestamps are in nanoseconds
+        put_option(&mut body, OPT_IF_TSRESOL, &[9]);
+        put_option(&mut body, OPT_END, &[]);
+
+        self.write_block(BLOCK_INTERFACE_DESCRIPTION, &body)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> Result<(), anyhow::Error> {
+        let len = (body.len() + 12) as u32;
+        let mut block = Vec::with_capacity(len as usize);
+        block.extend_from_slice(&block_type.to_ne_bytes());
+        block.extend_from_slice(&len.to_ne_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&len.to_ne_bytes());
+
+        self.file.write_all(&block)?;
+        self.size += len as u64;
+        Ok(())
+    }
+}
+
+fn create(path: &Path) -> Result<File, anyhow::Error> {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("failed to create {:?}", path))
+}
+
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn epb_len(frame: &Frame) -> u64 {
+    (32 + (frame.data.len() + 3) / 4 * 4) as u64
+}
+
+fn put_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_ne_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_ne_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+/// Blocks and options are padded to 32 bits
+fn pad(buf: &mut Vec<u8>) {
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
@@ -0,0 +1,211 @@
+use std::{fs, io::Write, path::Path, time::SystemTime};
+
+use anyhow::Context;
+
+use dhcp_common::{Stat, STAT_COUNT};
+
+use crate::{
+    control::{self, Request, Response},
+    mac::MacAddr,
+};
+
+/// How long ago `time` was, for humans
+fn ago(time: SystemTime) -> String {
+    let elapsed = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    format!(
+        "{} ago",
+        humantime::format_duration(std::time::Duration::from_secs(elapsed))
+    )
+}
+
+pub async fn locate(socket: &Path, mac: MacAddr) -> Result<(), anyhow::Error> {
+    let device = match control::request(socket, &Request::Locate { mac }).await? {
+        Response::Device(Some(device)) => device,
+        Response::Device(None) => anyhow::bail!("{} hasn't been seen", mac),
+        response => anyhow::bail!("unexpected response {:?}", response),
+    };
+
+    let location = match device.location {
+        Some(location) => location,
+        None => anyhow::bail!(
+            "{} was last seen {} but never behind a relay agent",
+            mac,
+            ago(device.last_seen)
+        ),
+    };
+
+    println!(
+        "{}{}",
+        device.mac,
+        if device.frozen { " (frozen)" } else { "" }
+    );
+    if let Some(note) = &device.note {
+        println!("  note        {}", note);
+    }
+    println!("  relay       {}", location.relay.address);
+    if let Some(circuit_id) = &location.relay.circuit_id {
+        println!("  circuit-id  {}", circuit_id);
+    }
+    if let Some(remote_id) = &location.relay.remote_id {
+        println!("  remote-id   {}", remote_id);
+    }
+    if let Some(address) = device.address {
+        println!("  address     {}", address);
+    }
+    if let Some(hostname) = &device.hostname {
+        println!("  hostname    {}", hostname);
+    }
+    if let Some(class) = &device.class {
+        println!("  class       {}", class);
+    }
+    if let Some(presence) = &device.presence {
+        println!(
+            "  presence    {} ARP {}",
+            if presence.online {
+                "answered"
+            } else {
+                "didn't answer"
+            },
+            ago(presence.probed_at)
+        );
+    }
+    println!("  seen there  {}", ago(location.seen_at));
+
+    Ok(())
+}
+
+pub async fn set_note(
+    socket: &Path,
+    mac: MacAddr,
+    note: Option<String>,
+) -> Result<(), anyhow::Error> {
+    match control::request(socket, &Request::SetNote { mac, note }).await? {
+        Response::Device(Some(_)) => Ok(()),
+        Response::Device(None) => anyhow::bail!("{} hasn't been seen", mac),
+        response => anyhow::bail!("unexpected response {:?}", response),
+    }
+}
+
+pub async fn set_frozen(socket: &Path, mac: MacAddr, frozen: bool) -> Result<(), anyhow::Error> {
+    match control::request(socket, &Request::SetFrozen { mac, frozen }).await? {
+        Response::Device(Some(_)) => Ok(()),
+        Response::Device(None) => anyhow::bail!("{} hasn't been seen", mac),
+        response => anyhow::bail!("unexpected response {:?}", response),
+    }
+}
+
+pub async fn flush(socket: &Path, mac: MacAddr) -> Result<(), anyhow::Error> {
+    match control::request(socket, &Request::Flush { mac }).await? {
+        Response::Flushed(Some(address)) => {
+            println!("flushed the lease of {} on {}", mac, address);
+            Ok(())
+        }
+        Response::Flushed(None) => anyhow::bail!("{} holds no lease", mac),
+        response => anyhow::bail!("unexpected response {:?}", response),
+    }
+}
+
+pub async fn set_blocked(socket: &Path, mac: MacAddr, blocked: bool) -> Result<(), anyhow::Error> {
+    match control::request(socket, &Request::SetBlocked { mac, blocked }).await? {
+        Response::Blocked(_) => Ok(()),
+        response => anyhow::bail!("unexpected response {:?}", response),
+    }
+}
+
+pub async fn blocked(socket: &Path) -> Result<(), anyhow::Error> {
+    match control::request(socket, &Request::Blocked).await? {
+        Response::Blocked(blocked) => {
+            for mac in blocked {
+                println!("{}", mac);
+            }
+            Ok(())
+        }
+        response => anyhow::bail!("unexpected response {:?}", response),
+    }
+}
+
+pub async fn stats(socket: &Path) -> Result<(), anyhow::Error> {
+    let interfaces = match control::request(socket, &Request::Stats).await? {
+        Response::Stats(interfaces) => interfaces,
+        response => anyhow::bail!("unexpected response {:?}", response),
+    };
+
+    let mut total = [0; STAT_COUNT];
+    for interface in &interfaces {
+        for (sum, value) in total.iter_mut().zip(interface.counters) {
+            *sum += value;
+        }
+    }
+
+    print!("{:<16}", "");
+    for interface in &interfaces {
+        print!("{:>12}", interface.interface);
+    }
+    println!("{:>12}", "total");
+
+    for stat in Stat::ALL {
+        print!("{:<16}", stat.name());
+        for interface in &interfaces {
+            print!("{:>12}", interface.get(stat));
+        }
+        println!("{:>12}", total[stat as usize]);
+    }
+
+    Ok(())
+}
+
+pub async fn arp_rejects(socket: &Path) -> Result<(), anyhow::Error> {
+    let rejects = match control::request(socket, &Request::ArpRejects).await? {
+        Response::ArpRejects(rejects) => rejects,
+        response => anyhow::bail!("unexpected response {:?}", response),
+    };
+
+    println!("{:<20}{:>12}", "sender", "dropped");
+    for reject in rejects {
+        println!("{:<20}{:>12}", reject.mac, reject.count);
+    }
+
+    Ok(())
+}
+
+pub async fn export_state(socket: &Path, path: &Path) -> Result<(), anyhow::Error> {
+    let snapshot = match control::request(socket, &Request::ExportState).await? {
+        Response::State(snapshot) => snapshot,
+        response => anyhow::bail!("unexpected response {:?}", response),
+    };
+    let contents = serde_json::to_vec_pretty(&snapshot)?;
+
+    if path == Path::new("-") {
+        std::io::stdout().write_all(&contents)?;
+        return Ok(());
+    }
+
+    fs::write(path, contents).with_context(|| format!("failed to write {:?}", path))?;
+    eprintln!(
+        "exported {} leases and {} devices to {:?}",
+        snapshot.leases.len(),
+        snapshot.devices.len(),
+        path
+    );
+
+    Ok(())
+}
+
+pub async fn import_state(socket: &Path, path: &Path) -> Result<(), anyhow::Error> {
+    let contents = fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+    let snapshot =
+        serde_json::from_slice(&contents).with_context(|| format!("failed to parse {:?}", path))?;
+
+    match control::request(socket, &Request::ImportState { snapshot }).await? {
+        Response::Imported(summary) => eprintln!(
+            "imported {} leases, {} devices and {} trusted servers",
+            summary.leases, summary.devices, summary.trusted_servers
+        ),
+        response => anyhow::bail!("unexpected response {:?}", response),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! The clocks lease expiry is kept on. `Instant` is CLOCK_MONOTONIC, which stops while the
+//! machine is suspended, so a laptop waking up after a night would carry on with leases
+//! the server let go of hours ago. The wall clock keeps counting through suspend but jumps
+//! whenever NTP steps it. CLOCK_BOOTTIME does neither.
+
+use std::{fs, ops::Add, time::Duration};
+
+const BOOT_ID: &str = "/proc/sys/kernel/random/boot_id";
+
+/// A reading of CLOCK_BOOTTIME, time since boot including time spent suspended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BootTime(Duration);
+
+impl BootTime {
+    pub fn now() -> BootTime {
+        BootTime(Duration::from_nanos(clock_ns(libc::CLOCK_BOOTTIME)))
+    }
+
+    pub fn from_nanos(nanos: u64) -> BootTime {
+        BootTime(Duration::from_nanos(nanos))
+    }
+
+    pub fn as_nanos(self) -> u64 {
+        self.0.as_nanos() as u64
+    }
+
+    pub fn saturating_duration_since(self, earlier: BootTime) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
+impl Add<Duration> for BootTime {
+    type Output = BootTime;
+
+    fn add(self, rhs: Duration) -> BootTime {
+        BootTime(self.0 + rhs)
+    }
+}
+
+/// How long the machine has spent suspended since boot, which is how far CLOCK_MONOTONIC
+/// has fallen behind CLOCK_BOOTTIME
+pub fn suspended() -> Duration {
+    let boot = clock_ns(libc::CLOCK_BOOTTIME);
+    let monotonic = clock_ns(libc::CLOCK_MONOTONIC);
+    Duration::from_nanos(boot.saturating_sub(monotonic))
+}
+
+/// Changes on every boot, boot times from another boot mean nothing in this one
+pub fn boot_id() -> Option<String> {
+    fs::read_to_string(BOOT_ID)
+        .ok()
+        .map(|id| id.trim().to_owned())
+}
+
+/// What `bpf_ktime_get_ns()` returns right now
+pub fn monotonic_ns() -> u64 {
+    clock_ns(libc::CLOCK_MONOTONIC)
+}
+
+pub fn wall_clock_ns() -> u64 {
+    clock_ns(libc::CLOCK_REALTIME)
+}
+
+fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
@@ -0,0 +1,158 @@
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    alerts::AlertConfig,
+    attach::{Enforcement, Mode},
+    capture::CaptureConfig,
+    control::ControlConfig,
+    firewall::FirewallSetConfig,
+    fleet::FleetConfig,
+    ha::HaConfig,
+    history::HistoryConfig,
+    hooks::HookConfig,
+    http_sink::HttpSinkConfig,
+    lease_times::LeaseTimeConfig,
+    leases::ConflictPolicy,
+    loki::LokiConfig,
+    offload::VlanOffload,
+    output::OutputFormat,
+    presence::PresenceConfig,
+    redact::RedactionConfig,
+    remote_write::RemoteWriteConfig,
+    reorder::OrderingConfig,
+    sanity::SanityCheckConfig,
+    server_check::ServerCheckConfig,
+    statsd::StatsdConfig,
+    syslog::SyslogConfig,
+};
+
+/// Contents of the file passed with `--config`, command line flags are merged on top
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(rename = "interface")]
+    pub interfaces: Vec<InterfaceConfig>,
+    pub trusted_servers: Vec<Ipv4Addr>,
+    pub metrics_listen: Option<SocketAddr>,
+    /// Push metrics instead of, or as well as, serving them
+    pub remote_write: Option<RemoteWriteConfig>,
+    pub statsd: Option<StatsdConfig>,
+    pub mode: Mode,
+    /// Whether what the program would drop actually is
+    pub enforcement: Enforcement,
+    pub output: OutputFormat,
+    /// Bytes of options the parser doesn't know to hex dump into message records, none
+    /// when zero. The eBPF program passes on at most 64.
+    pub unknown_option_bytes: usize,
+    /// Extra fingerprints for classifying clients, tried before the built-in ones
+    pub fingerprints: Option<PathBuf>,
+    /// Run as one half of an active/standby pair
+    pub ha: Option<HaConfig>,
+    #[serde(rename = "http-sink")]
+    pub http_sinks: Vec<HttpSinkConfig>,
+    /// Scripts and webhooks run when leases come and go
+    #[serde(rename = "hook")]
+    pub hooks: Vec<HookConfig>,
+    pub syslog: Option<SyslogConfig>,
+    pub loki: Option<LokiConfig>,
+    /// Keep an nftables set or ipset of the leased address/MAC pairs
+    pub firewall_set: Option<FirewallSetConfig>,
+    /// Record every message to an SQLite database for `history`
+    pub history: Option<HistoryConfig>,
+    /// ARP-ping devices whose leases are running out
+    pub presence: Option<PresenceConfig>,
+    /// Where interfaces with `capture` set write their DHCP frames
+    pub capture: Option<CaptureConfig>,
+    /// Pull trusted servers and policies from a central place
+    pub fleet: Option<FleetConfig>,
+    /// Save leases and devices here on exit and every minute, and pick them up on start
+    pub state_file: Option<PathBuf>,
+    /// Which binding to keep when two disagree, at startup or across interfaces
+    pub conflict_policy: ConflictPolicy,
+    /// Reporting servers handing out leases far shorter or longer than usual
+    pub lease_times: LeaseTimeConfig,
+    /// Who besides root may use the control socket
+    pub control: ControlConfig,
+    /// Pseudonymizing what the sinks send off the host
+    pub redaction: RedactionConfig,
+    /// Putting messages from different CPUs back in order
+    pub ordering: OrderingConfig,
+    /// Threshold rules on the metrics, raising alert events
+    #[serde(rename = "alert")]
+    pub alerts: Vec<AlertConfig>,
+    /// Compare what the program saw on each interface with the kernel's counters
+    pub sanity_check: Option<SanityCheckConfig>,
+    /// DORA or DHCPINFORM against the servers from a test client
+    pub server_check: Option<ServerCheckConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct InterfaceConfig {
+    pub name: String,
+    /// Whether DHCP servers may answer on this interface
+    #[serde(default = "default_trusted")]
+    pub trusted: bool,
+    /// Drop IPv4 traffic from addresses that aren't leased to the sending MAC, only for
+    /// untrusted interfaces
+    #[serde(default)]
+    pub source_guard: bool,
+    /// Drop ARP replies and gratuitous ARPs whose sender address isn't leased to the
+    /// sender MAC, only for untrusted interfaces
+    #[serde(default)]
+    pub arp_inspection: bool,
+    /// Mirror DHCP frames to the file in `[capture]`
+    #[serde(default)]
+    pub capture: bool,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// What to do when the NIC strips VLAN tags before XDP sees them
+    #[serde(default)]
+    pub vlan_offload: VlanOffload,
+    /// Verify the UDP checksum of DHCP on the interface, only in native XDP mode
+    #[serde(default)]
+    pub verify_udp_checksum: bool,
+}
+
+/// Limits on the DISCOVERs and REQUESTs clients send, zero meaning no limit. Going over a
+/// limit raises an event, the excess is only dropped with `drop`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RateLimit {
+    /// Per second from any one client
+    pub client: u32,
+    /// Allowed above `client` in a burst, twice the rate when not set
+    pub client_burst: Option<u32>,
+    /// Per second from all clients on the interface together
+    pub interface: u32,
+    pub interface_burst: Option<u32>,
+    pub drop: bool,
+}
+
+fn default_trusted() -> bool {
+    true
+}
+
+/// Durations are written the humantime way, e.g. "1m 30s"
+pub fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s).map_err(de::Error::custom)
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, anyhow::Error> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        toml::from_str(&contents).with_context(|| format!("failed to parse {:?}", path))
+    }
+}
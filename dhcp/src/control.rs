@@ -0,0 +1,641 @@
+//! Line delimited JSON over a unix socket, used by the CLI subcommands to query the daemon.
+//!
+//! Who may do what goes by the peer's credentials, which the kernel vouches for. Root and
+//! the daemon's own user may do anything, members of the groups in `[control]` may query
+//! or also change what the daemon holds, everyone else nothing. Observers get devices and
+//! state as `[redaction]` has them, admins could read the state file anyway. How long a
+//! request may be goes by the role too, only admins get to send whole snapshots.
+
+use std::{
+    ffi::CString,
+    fmt, fs,
+    io::{self, ErrorKind},
+    mem,
+    net::Ipv4Addr,
+    os::{fd::AsRawFd, unix::fs::PermissionsExt},
+    path::{Path, PathBuf},
+    ptr,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    devices::Device,
+    events::Event,
+    mac::MacAddr,
+    redact::Redactor,
+    snapshot::{self, ImportSummary, Snapshot},
+    state::Backend,
+    stats::{ArpRejects, InterfaceStats},
+};
+
+pub const DEFAULT_SOCKET: &str = "/run/dhcp-snoop.sock";
+/// Longest request line taken from anyone but admins
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+/// From admins, `import-state` carries the whole state
+const MAX_ADMIN_REQUEST_LEN: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ControlConfig {
+    /// Groups whose members may query the daemon
+    pub observers: Vec<String>,
+    /// Groups whose members may also change its state
+    pub admins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Permission {
+    /// Looking up devices, stats, state and blocked clients
+    Observe,
+    /// Changing what the daemon holds
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Observe => "observer",
+            Permission::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Locate {
+        mac: MacAddr,
+    },
+    FindHostname {
+        hostname: String,
+    },
+    Stats,
+    ArpRejects,
+    ExportState,
+    ImportState {
+        snapshot: Snapshot,
+    },
+    /// Set the note on a device, or clear it with `None`
+    SetNote {
+        mac: MacAddr,
+        note: Option<String>,
+    },
+    SetFrozen {
+        mac: MacAddr,
+        frozen: bool,
+    },
+    /// Drop a device's lease, and with it the binding IP Source Guard lets it through on
+    Flush {
+        mac: MacAddr,
+    },
+    /// Flush a device's lease and ignore the ones it's ACKed until unblocked
+    SetBlocked {
+        mac: MacAddr,
+        blocked: bool,
+    },
+    Blocked,
+}
+
+impl Request {
+    fn permission(&self) -> Permission {
+        match self {
+            Request::Locate { .. }
+            | Request::FindHostname { .. }
+            | Request::Stats
+            | Request::ArpRejects
+            | Request::ExportState
+            | Request::Blocked => Permission::Observe,
+            Request::ImportState { .. }
+            | Request::SetNote { .. }
+            | Request::SetFrozen { .. }
+            | Request::Flush { .. }
+            | Request::SetBlocked { .. } => Permission::Admin,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Response {
+    Device(Option<Device>),
+    Devices(Vec<Device>),
+    Stats(Vec<InterfaceStats>),
+    ArpRejects(Vec<ArpRejects>),
+    State(Snapshot),
+    Imported(ImportSummary),
+    /// The address of the lease flushed, `None` when there was none
+    Flushed(Option<Ipv4Addr>),
+    Blocked(Vec<MacAddr>),
+    Error(String),
+}
+
+/// `ControlConfig` with the group names resolved
+struct Access {
+    observers: Vec<libc::gid_t>,
+    admins: Vec<libc::gid_t>,
+}
+
+impl Access {
+    fn new(config: &ControlConfig) -> Result<Access, anyhow::Error> {
+        let resolve = |names: &[String]| -> Result<Vec<_>, anyhow::Error> {
+            names.iter().map(|name| group_id(name)).collect()
+        };
+
+        Ok(Access {
+            observers: resolve(&config.observers)?,
+            admins: resolve(&config.admins)?,
+        })
+    }
+
+    /// Whether anyone beyond root and the daemon's user gets in
+    fn is_shared(&self) -> bool {
+        !self.observers.is_empty() || !self.admins.is_empty()
+    }
+
+    /// What the peer on `stream` may do, `None` for nothing at all
+    fn permission(&self, stream: &UnixStream) -> io::Result<Option<Permission>> {
+        let cred = stream.peer_cred()?;
+        // Both could take the socket over anyway
+        if cred.uid() == 0 || cred.uid() == unsafe { libc::geteuid() } {
+            return Ok(Some(Permission::Admin));
+        }
+
+        let mut groups = peer_groups(stream)?;
+        groups.push(cred.gid());
+        let member = |allowed: &[libc::gid_t]| groups.iter().any(|gid| allowed.contains(gid));
+
+        Ok(if member(&self.admins) {
+            Some(Permission::Admin)
+        } else if member(&self.observers) {
+            Some(Permission::Observe)
+        } else {
+            None
+        })
+    }
+}
+
+/// The supplementary groups of the peer on `stream`, as they were when it connected
+fn peer_groups(stream: &UnixStream) -> io::Result<Vec<libc::gid_t>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+
+    loop {
+        let mut len = (groups.len() * mem::size_of::<libc::gid_t>()) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERGROUPS,
+                groups.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        let count = len as usize / mem::size_of::<libc::gid_t>();
+        if ret == 0 {
+            groups.truncate(count);
+            return Ok(groups);
+        }
+
+        let e = io::Error::last_os_error();
+        // Too many to fit, `len` is what it takes
+        if e.raw_os_error() != Some(libc::ERANGE) || count <= groups.len() {
+            return Err(e);
+        }
+        groups.resize(count, 0);
+    }
+}
+
+fn group_id(name: &str) -> Result<libc::gid_t, anyhow::Error> {
+    let c_name = CString::new(name).with_context(|| format!("invalid group name {:?}", name))?;
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut result = ptr::null_mut();
+
+    let ret = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret))
+            .with_context(|| format!("failed to look up group {}", name));
+    }
+    if result.is_null() {
+        anyhow::bail!("no group named {}", name);
+    }
+
+    Ok(group.gr_gid)
+}
+
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+    access: Arc<Access>,
+}
+
+impl Server {
+    pub fn bind(path: &Path, config: &ControlConfig) -> Result<Server, anyhow::Error> {
+        let access = Access::new(config)?;
+
+        // Left behind if the previous daemon didn't shut down cleanly
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("failed to remove stale socket {:?}", path))
+            }
+            _ => {}
+        }
+
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to bind control socket {:?}", path))?;
+        // Peers are checked once connected, everyone has to be able to connect for that
+        if access.is_shared() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o666))
+                .with_context(|| format!("failed to open up control socket {:?}", path))?;
+        }
+
+        Ok(Server {
+            listener,
+            path: path.to_owned(),
+            access: Arc::new(access),
+        })
+    }
+
+    pub async fn serve(self, backend: Backend) {
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("failed to accept control connection: {}", e);
+                    continue;
+                }
+            };
+
+            let (backend, access) = (backend.clone(), self.access.clone());
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(stream, &access, backend).await {
+                    warn!("control connection failed: {:#}", e);
+                }
+            });
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+async fn serve_connection(
+    stream: UnixStream,
+    access: &Access,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    let cred = stream.peer_cred()?;
+    let granted = access
+        .permission(&stream)
+        .context("failed to check the peer's credentials")?;
+    let max_len = match granted {
+        Some(Permission::Admin) => MAX_ADMIN_REQUEST_LEN,
+        _ => MAX_REQUEST_LEN,
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+
+    loop {
+        let fits = match read_line(&mut reader, max_len, &mut line).await? {
+            Some(fits) => fits,
+            None => break,
+        };
+        if !fits {
+            let response = Response::Error(format!("request longer than {} bytes", max_len));
+            let mut out = serde_json::to_vec(&response)?;
+            out.push(b'\n');
+            writer.write_all(&out).await?;
+            anyhow::bail!(
+                "uid {} (pid {:?}) sent a request longer than {} bytes",
+                cred.uid(),
+                cred.pid(),
+                max_len
+            );
+        }
+
+        let response = match serde_json::from_slice::<Request>(&line) {
+            Ok(request) => {
+                let needed = request.permission();
+                match granted {
+                    Some(granted) if granted >= needed => {
+                        if needed == Permission::Admin {
+                            info!(
+                                "admin request from uid {} (pid {:?})",
+                                cred.uid(),
+                                cred.pid()
+                            );
+                        }
+                        let redactor = backend
+                            .redactor
+                            .as_deref()
+                            .filter(|_| granted < Permission::Admin);
+                        handle(request, &backend, redactor)
+                    }
+                    _ => {
+                        warn!(
+                            "denied uid {} (pid {:?}) a request for the {} role",
+                            cred.uid(),
+                            cred.pid(),
+                            needed
+                        );
+                        Response::Error(format!(
+                            "permission denied, this needs the {} role",
+                            needed
+                        ))
+                    }
+                }
+            }
+            Err(e) => Response::Error(format!("invalid request: {}", e)),
+        };
+
+        let mut out = serde_json::to_vec(&response)?;
+        out.push(b'\n');
+        writer.write_all(&out).await?;
+    }
+
+    Ok(())
+}
+
+/// The next line of `reader` into `line`, `None` once there are no more. `Some(false)`
+/// for a line longer than `max_len` bytes, newline included, the rest of which is left
+/// unread.
+pub async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: u64,
+    line: &mut Vec<u8>,
+) -> io::Result<Option<bool>> {
+    line.clear();
+    // One past the limit, to tell a line that fits from one that doesn't
+    let read = reader.take(max_len + 1).read_until(b'\n', line).await?;
+
+    Ok((read > 0).then(|| line.len() as u64 <= max_len))
+}
+
+/// Devices and state come out through `redactor` if there is one
+fn handle(request: Request, backend: &Backend, redactor: Option<&Redactor>) -> Response {
+    let device = |device: &Device| match redactor {
+        Some(redactor) => redactor.device(device.clone()),
+        None => device.clone(),
+    };
+
+    match request {
+        Request::Locate { mac } => {
+            let state = backend.state.lock().unwrap();
+            Response::Device(state.devices.get(&mac).map(device))
+        }
+        // Finding a device by its hostname would give away which one has it
+        Request::FindHostname { .. } if redactor.map_or(false, Redactor::drops_hostnames) => {
+            Response::Error("hostnames are redacted".to_owned())
+        }
+        Request::FindHostname { hostname } => {
+            let state = backend.state.lock().unwrap();
+            Response::Devices(state.devices.by_hostname(&hostname).map(device).collect())
+        }
+        Request::Stats => match backend.stats.read() {
+            Ok(stats) => Response::Stats(stats),
+            Err(e) => Response::Error(format!("failed to read stats: {:#}", e)),
+        },
+        Request::ArpRejects => match backend.stats.read_arp_rejects() {
+            Ok(rejects) => Response::ArpRejects(redact_arp_rejects(rejects, redactor)),
+            Err(e) => Response::Error(format!("failed to read ARP rejects: {:#}", e)),
+        },
+        Request::ExportState => match snapshot::export(backend) {
+            Ok(snapshot) => Response::State(match redactor {
+                Some(redactor) => redactor.snapshot(snapshot),
+                None => snapshot,
+            }),
+            Err(e) => Response::Error(format!("failed to export state: {:#}", e)),
+        },
+        Request::ImportState { snapshot } => match snapshot::import(backend, snapshot) {
+            Ok(summary) => Response::Imported(summary),
+            Err(e) => Response::Error(format!("failed to import state: {:#}", e)),
+        },
+        Request::SetNote { mac, note } => {
+            let mut state = backend.state.lock().unwrap();
+            Response::Device(state.devices.set_note(mac, note).cloned())
+        }
+        Request::SetFrozen { mac, frozen } => {
+            let mut state = backend.state.lock().unwrap();
+            Response::Device(state.devices.set_frozen(mac, frozen).cloned())
+        }
+        Request::Flush { mac } => {
+            let mut state = backend.state.lock().unwrap();
+            let flushed = state.leases.flush(&mac);
+            let address = flushed.as_ref().map(|lease| lease.address);
+            if let Some(lease) = flushed {
+                state.emit(Event::Lease(lease));
+            }
+            Response::Flushed(address)
+        }
+        Request::SetBlocked { mac, blocked } => {
+            let mut state = backend.state.lock().unwrap();
+            if let Some(lease) = state.leases.set_blocked(mac, blocked) {
+                state.emit(Event::Lease(lease));
+            }
+            Response::Blocked(state.leases.blocked())
+        }
+        Request::Blocked => {
+            let state = backend.state.lock().unwrap();
+            let mut blocked = state.leases.blocked();
+            if let Some(redactor) = redactor {
+                blocked = blocked.into_iter().map(|mac| redactor.mac(mac)).collect();
+            }
+            Response::Blocked(blocked)
+        }
+    }
+}
+
+fn redact_arp_rejects(rejects: Vec<ArpRejects>, redactor: Option<&Redactor>) -> Vec<ArpRejects> {
+    match redactor {
+        Some(redactor) => rejects
+            .into_iter()
+            .map(|mut reject| {
+                reject.mac = redactor.mac(reject.mac);
+                reject
+            })
+            .collect(),
+        None => rejects,
+    }
+}
+
+/// Send a single request to the daemon listening on `path`
+pub async fn request(path: &Path, request: &Request) -> Result<Response, anyhow::Error> {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("failed to connect to the daemon at {:?}", path))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut out = serde_json::to_vec(request)?;
+    out.push(b'\n');
+    writer.write_all(&out).await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .context("daemon closed the connection without responding")?;
+
+    match serde_json::from_str(&line)? {
+        Response::Error(e) => Err(anyhow::anyhow!(e)),
+        response => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redact::{MacRedaction, RedactionConfig};
+
+    #[tokio::test]
+    async fn request_lines_are_capped() {
+        let mut reader = &b"{\"command\":\"stats\"}\n12345678\n123456789\nrest"[..];
+        let mut line = Vec::new();
+
+        assert_eq!(
+            read_line(&mut reader, 21, &mut line).await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(line, b"{\"command\":\"stats\"}\n");
+        // The newline counts
+        assert_eq!(
+            read_line(&mut reader, 9, &mut line).await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(line, b"12345678\n");
+        assert_eq!(
+            read_line(&mut reader, 9, &mut line).await.unwrap(),
+            Some(false)
+        );
+        assert_eq!(line.len(), 10);
+        // A last line without a newline is still a line
+        assert_eq!(
+            read_line(&mut reader, 4, &mut line).await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(line, b"rest");
+        assert_eq!(read_line(&mut reader, 4, &mut line).await.unwrap(), None);
+    }
+
+    #[test]
+    fn permissions() {
+        let mac = MacAddr([0x02, 0, 0, 0, 0, 1]);
+        for (request, permission) in [
+            (Request::Locate { mac }, Permission::Observe),
+            (
+                Request::FindHostname {
+                    hostname: "printer".to_owned(),
+                },
+                Permission::Observe,
+            ),
+            (Request::Stats, Permission::Observe),
+            (Request::ArpRejects, Permission::Observe),
+            (Request::ExportState, Permission::Observe),
+            (Request::Blocked, Permission::Observe),
+            (Request::SetNote { mac, note: None }, Permission::Admin),
+            (Request::SetFrozen { mac, frozen: true }, Permission::Admin),
+            (Request::Flush { mac }, Permission::Admin),
+            (
+                Request::SetBlocked { mac, blocked: true },
+                Permission::Admin,
+            ),
+            (
+                Request::SetBlocked {
+                    mac,
+                    blocked: false,
+                },
+                Permission::Admin,
+            ),
+        ] {
+            assert_eq!(request.permission(), permission, "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn arp_rejects_are_redacted_for_observers() {
+        let rejects = || {
+            vec![
+                ArpRejects {
+                    mac: MacAddr([0x52, 0x54, 0x00, 0x1a, 0x2b, 0x3c]),
+                    count: 3,
+                },
+                ArpRejects {
+                    mac: MacAddr([0x1c, 0x69, 0x7a, 0x00, 0x00, 0x01]),
+                    count: 1,
+                },
+            ]
+        };
+        let redactor = Redactor::new(&RedactionConfig {
+            mac: MacRedaction::Truncate,
+            ..RedactionConfig::default()
+        })
+        .unwrap()
+        .unwrap();
+
+        let redacted: Vec<_> = redact_arp_rejects(rejects(), Some(&redactor))
+            .into_iter()
+            .map(|reject| (reject.mac, reject.count))
+            .collect();
+        assert_eq!(
+            redacted,
+            [
+                (MacAddr([0x52, 0x54, 0x00, 0, 0, 0]), 3),
+                (MacAddr([0x1c, 0x69, 0x7a, 0, 0, 0]), 1),
+            ]
+        );
+
+        let admin: Vec<_> = redact_arp_rejects(rejects(), None)
+            .into_iter()
+            .map(|reject| reject.mac)
+            .collect();
+        assert_eq!(
+            admin,
+            [
+                MacAddr([0x52, 0x54, 0x00, 0x1a, 0x2b, 0x3c]),
+                MacAddr([0x1c, 0x69, 0x7a, 0x00, 0x00, 0x01]),
+            ]
+        );
+    }
+
+    #[test]
+    fn admin_requests_on_the_wire() {
+        for (line, request) in [
+            (
+                r#"{"command":"flush","mac":"02:00:00:00:00:01"}"#,
+                Request::Flush {
+                    mac: MacAddr([0x02, 0, 0, 0, 0, 1]),
+                },
+            ),
+            (
+                r#"{"command":"set-blocked","mac":"02:00:00:00:00:01","blocked":true}"#,
+                Request::SetBlocked {
+                    mac: MacAddr([0x02, 0, 0, 0, 0, 1]),
+                    blocked: true,
+                },
+            ),
+            (r#"{"command":"blocked"}"#, Request::Blocked),
+        ] {
+            assert_eq!(serde_json::to_string(&request).unwrap(), line);
+            let parsed: Request = serde_json::from_str(line).unwrap();
+            assert_eq!(parsed.permission(), request.permission());
+        }
+    }
+}
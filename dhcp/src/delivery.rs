@@ -0,0 +1,538 @@
+//! Getting events to sinks that can fail: retries with exponential backoff, a circuit
+//! breaker so an unreachable sink isn't hammered, and a spool on disk holding whatever
+//! couldn't be delivered until the sink comes back.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::time::sleep;
+
+use crate::{config::deserialize_duration, health::Health};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RetryPolicy {
+    /// Attempts per event before it's spooled, including the first one
+    pub attempts: u32,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub initial_backoff: Duration,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after the `attempt`th failed attempt, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// Call `send` until it succeeds, fails for good or runs out of attempts
+    pub async fn run<F, Fut>(&self, mut send: F) -> Result<(), Failure>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), Failure>>,
+    {
+        let mut attempt = 1;
+
+        loop {
+            match send().await {
+                Err(Failure::Transient(_)) if attempt < self.attempts => {
+                    sleep(self.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BreakerConfig {
+    /// Consecutive failed deliveries after which the sink is left alone
+    pub failure_threshold: u32,
+    /// How long to leave it alone before trying again
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub reset_timeout: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failure_threshold: 5,
+            reset_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// Trying again after `reset_timeout`, the next delivery decides which way it goes
+    HalfOpen,
+}
+
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: BreakerState,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            state: BreakerState::Closed { failures: 0 },
+        }
+    }
+
+    /// Whether a delivery should be attempted now
+    pub fn allow(&mut self) -> bool {
+        match self.state {
+            BreakerState::Closed { .. } | BreakerState::HalfOpen => true,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                self.state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open { .. } => false,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self.state, BreakerState::Open { .. })
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = BreakerState::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&mut self) {
+        let failures = match self.state {
+            BreakerState::Closed { failures } => failures + 1,
+            // The one trial delivery failed, straight back to open
+            BreakerState::HalfOpen | BreakerState::Open { .. } => self.config.failure_threshold,
+        };
+
+        self.state = if failures >= self.config.failure_threshold {
+            BreakerState::Open {
+                until: Instant::now() + self.config.reset_timeout,
+            }
+        } else {
+            BreakerState::Closed { failures }
+        };
+    }
+}
+
+/// Why a delivery failed
+pub enum Failure {
+    /// Worth trying again later, e.g. a timeout or a 503
+    Transient(anyhow::Error),
+    /// The sink refused the event and will keep doing so, e.g. a 400
+    Permanent(anyhow::Error),
+}
+
+/// Undelivered events, one JSON document per line. `<name>.ndjson` holds events to retry
+/// once the sink is back, `<name>.rejected.ndjson` the ones it refused for good.
+/// Replays work from `<name>.replaying.ndjson`, so events spooled meanwhile aren't lost and
+/// a replay cut short by a crash picks up where it left off.
+pub struct Spool {
+    path: PathBuf,
+    replaying: PathBuf,
+    rejected: PathBuf,
+    max_bytes: u64,
+}
+
+impl Spool {
+    pub fn new(dir: &Path, name: &str, max_bytes: u64) -> Result<Spool, anyhow::Error> {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+
+        Ok(Spool {
+            path: dir.join(format!("{}.ndjson", name)),
+            replaying: dir.join(format!("{}.replaying.ndjson", name)),
+            rejected: dir.join(format!("{}.rejected.ndjson", name)),
+            max_bytes,
+        })
+    }
+
+    /// Keep `line` around for a later retry
+    pub fn push(&self, line: &str) {
+        let size = fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+        if size + line.len() as u64 > self.max_bytes {
+            warn!("spool {:?} is full, dropping event", self.path);
+            return;
+        }
+
+        if let Err(e) = append(&self.path, line) {
+            warn!("failed to spool event to {:?}: {}", self.path, e);
+        }
+    }
+
+    pub fn reject(&self, line: &str) {
+        if let Err(e) = append(&self.rejected, line) {
+            warn!(
+                "failed to write rejected event to {:?}: {}",
+                self.rejected, e
+            );
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        [&self.replaying, &self.path]
+            .iter()
+            .all(|path| fs::metadata(path).map_or(true, |metadata| metadata.len() == 0))
+    }
+
+    /// Events to replay, the unfinished replay if there is one and otherwise everything
+    /// spooled so far. The caller hands back what it couldn't deliver to [`Spool::replayed`].
+    pub fn take(&self) -> Vec<String> {
+        if !self.replaying.exists() {
+            match fs::rename(&self.path, &self.replaying) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => return Vec::new(),
+                Err(e) => {
+                    warn!("failed to move spool {:?} aside: {}", self.path, e);
+                    return Vec::new();
+                }
+            }
+        }
+
+        let lines = match read_lines(&self.replaying) {
+            Ok(lines) => lines,
+            Err(e) => {
+                warn!("failed to read spool {:?}: {}", self.replaying, e);
+                return Vec::new();
+            }
+        };
+        if !lines.is_empty() {
+            info!(
+                "replaying {} spooled events from {:?}",
+                lines.len(),
+                self.replaying
+            );
+        }
+
+        lines
+    }
+
+    /// Done with the events from [`Spool::take`], `rest` are the ones still undelivered and
+    /// go first on the next replay
+    pub fn replayed(&self, rest: &[String]) {
+        let result = if rest.is_empty() {
+            fs::remove_file(&self.replaying)
+        } else {
+            let partial = self.replaying.with_extension("partial");
+            write_lines(&partial, rest).and_then(|()| fs::rename(&partial, &self.replaying))
+        };
+        if let Err(e) = result {
+            warn!("failed to update spool {:?}: {}", self.replaying, e);
+        }
+    }
+}
+
+/// Retries, the breaker and the spool of one sink, wrapped around the `send` that does the
+/// actual delivery
+pub struct Delivery {
+    /// Shows up in logs and health checks
+    name: String,
+    retry: RetryPolicy,
+    breaker: CircuitBreaker,
+    spool: Arc<Spool>,
+    health: Arc<Health>,
+}
+
+impl Delivery {
+    pub fn new(
+        name: &str,
+        retry: RetryPolicy,
+        breaker: BreakerConfig,
+        spool: Arc<Spool>,
+        health: Arc<Health>,
+    ) -> Delivery {
+        Delivery {
+            name: name.to_owned(),
+            retry,
+            breaker: CircuitBreaker::new(breaker),
+            spool,
+            health,
+        }
+    }
+
+    /// Deliver `line`, spooling it when the sink is down
+    pub async fn push<F, Fut>(&mut self, line: String, send: F)
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<(), Failure>>,
+    {
+        if !self.breaker.allow() {
+            self.spool.push(&line);
+            return;
+        }
+
+        match self.retry.run(|| send(line.clone())).await {
+            Ok(()) => {
+                self.record_success();
+                // Back from an outage, catch up on what piled up in the meantime
+                self.replay(send).await;
+            }
+            Err(Failure::Transient(e)) => {
+                self.record_failure(&e);
+                self.spool.push(&line);
+            }
+            Err(Failure::Permanent(e)) => {
+                warn!("{} rejected delivery: {:#}", self.name, e);
+                self.spool.reject(&line);
+            }
+        }
+    }
+
+    /// Deliver what's spooled, if the sink is up again
+    pub async fn replay<F, Fut>(&mut self, send: F)
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<(), Failure>>,
+    {
+        if self.spool.is_empty() || !self.breaker.allow() {
+            return;
+        }
+
+        let lines = self.spool.take();
+        for (i, line) in lines.iter().enumerate() {
+            match self.retry.run(|| send(line.clone())).await {
+                Ok(()) => self.record_success(),
+                Err(Failure::Transient(e)) => {
+                    self.record_failure(&e);
+                    // Keep the rest for the next replay, in order
+                    self.spool.replayed(&lines[i..]);
+                    return;
+                }
+                Err(Failure::Permanent(e)) => {
+                    warn!("{} rejected delivery: {:#}", self.name, e);
+                    self.spool.reject(line);
+                }
+            }
+        }
+        self.spool.replayed(&[]);
+    }
+
+    fn record_success(&mut self) {
+        self.breaker.record_success();
+        self.health.set_sink(&self.name, Ok(()));
+    }
+
+    fn record_failure(&mut self, e: &anyhow::Error) {
+        warn!("failed to deliver to {}: {:#}", self.name, e);
+        self.breaker.record_failure();
+        if self.breaker.is_open() {
+            warn!(
+                "{} keeps failing, pausing deliveries for {}",
+                self.name,
+                humantime::format_duration(self.breaker.config.reset_timeout)
+            );
+        }
+        self.health.set_sink(&self.name, Err(format!("{:#}", e)));
+    }
+}
+
+fn read_lines(path: &Path) -> Result<Vec<String>, std::io::Error> {
+    let lines = BufReader::new(File::open(path)?)
+        .lines()
+        .filter_map(|line| line.ok())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    Ok(lines)
+}
+
+fn write_lines(path: &Path, lines: &[String]) -> Result<(), std::io::Error> {
+    let mut file = File::create(path)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.sync_all()
+}
+
+fn append(path: &Path, line: &str) -> Result<(), std::io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(reset_timeout: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 3,
+            reset_timeout,
+        })
+    }
+
+    /// An empty spool directory of its own for each test
+    fn spool_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dhcp-snoop-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn breaker_opens_after_the_threshold() {
+        let mut breaker = breaker(Duration::from_secs(60));
+        for _ in 0..2 {
+            breaker.record_failure();
+            assert!(breaker.allow());
+        }
+
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn a_success_resets_the_count() {
+        let mut breaker = breaker(Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn breaker_half_opens_after_the_timeout() {
+        let mut breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        assert!(breaker.is_open());
+
+        assert!(breaker.allow());
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        breaker.record_success();
+        assert_eq!(breaker.state, BreakerState::Closed { failures: 0 });
+    }
+
+    #[test]
+    fn a_failed_trial_opens_it_again() {
+        let mut breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        assert!(breaker.allow());
+
+        // One failure is enough once half open
+        breaker.record_failure();
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy {
+            attempts: 10,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(3),
+        };
+
+        let backoffs: Vec<_> = (1..=5).map(|attempt| policy.backoff(attempt)).collect();
+        assert_eq!(
+            backoffs,
+            [
+                Duration::from_millis(500),
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(3),
+                Duration::from_secs(3),
+            ]
+        );
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn spool_round_trip() {
+        let dir = spool_dir("round-trip");
+        let spool = Spool::new(&dir, "sink", 1024).unwrap();
+        assert!(spool.is_empty());
+        assert!(spool.take().is_empty());
+
+        spool.push("one");
+        spool.push("two");
+        spool.reject("bad");
+        assert!(!spool.is_empty());
+        assert_eq!(spool.take(), ["one", "two"]);
+
+        // Pushed during the replay, kept for the next one
+        spool.push("three");
+        spool.replayed(&[]);
+        assert_eq!(spool.take(), ["three"]);
+        spool.replayed(&[]);
+        assert!(spool.is_empty());
+
+        assert_eq!(
+            fs::read_to_string(dir.join("sink.rejected.ndjson")).unwrap(),
+            "bad\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn an_unfinished_replay_goes_first() {
+        let dir = spool_dir("unfinished");
+        let spool = Spool::new(&dir, "sink", 1024).unwrap();
+        spool.push("one");
+        spool.push("two");
+        spool.push("three");
+
+        let lines = spool.take();
+        spool.push("four");
+        spool.replayed(&lines[1..]);
+        assert_eq!(spool.take(), ["two", "three"]);
+
+        // Crashed before finishing, a new spool picks the replay back up
+        let spool = Spool::new(&dir, "sink", 1024).unwrap();
+        assert_eq!(spool.take(), ["two", "three"]);
+        spool.replayed(&[]);
+        assert_eq!(spool.take(), ["four"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_full_spool_drops_events() {
+        let dir = spool_dir("full");
+        let spool = Spool::new(&dir, "sink", 8).unwrap();
+        spool.push("one");
+        // 4 bytes spooled and 5 more would go over
+        spool.push("three");
+        spool.push("two");
+
+        assert_eq!(spool.take(), ["one", "two"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,435 @@
+use std::{collections::HashMap, net::Ipv4Addr, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events::{Change, ChangeEvent},
+    mac::MacAddr,
+    message::{DhcpMessage, MessageType, RelayInfo},
+};
+
+/// Everything known about a client, kept around after its lease is gone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub mac: MacAddr,
+    pub address: Option<Ipv4Addr>,
+    pub hostname: Option<String>,
+    /// Interface its DHCP traffic last went through
+    #[serde(default)]
+    pub ifindex: Option<u32>,
+    pub vlan: Option<u16>,
+    /// From the fingerprint of its last DISCOVER or REQUEST that matched one
+    #[serde(default)]
+    pub class: Option<String>,
+    /// Relay port the device was last seen behind
+    pub location: Option<Location>,
+    /// What ARP-pinging it last found, only devices whose leases ran out or were about to
+    /// are pinged
+    #[serde(default)]
+    pub presence: Option<Presence>,
+    /// Whatever the operators want to remember about it
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Whether it's meant to stay where it is, changes to its address, VLAN or fingerprint
+    /// are alerted on
+    #[serde(default)]
+    pub frozen: bool,
+    /// What its last ACK gave it, which the next one is held against. The other fields
+    /// follow every message, OFFERs and the client's own included.
+    #[serde(default)]
+    pub leased: Option<Leased>,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Leased {
+    pub address: Ipv4Addr,
+    /// The ACK's, or else the one the client last sent
+    pub hostname: Option<String>,
+    pub vlan: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+    /// Whether it answered
+    pub online: bool,
+    pub probed_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Location {
+    pub relay: RelayInfo,
+    pub seen_at: SystemTime,
+}
+
+/// Inventory of every client MAC seen in DHCP traffic
+#[derive(Default)]
+pub struct DeviceStore {
+    devices: HashMap<MacAddr, Device>,
+}
+
+impl DeviceStore {
+    /// Record `msg`, and the `class` its fingerprint gave, against the client it's for. For
+    /// an ACK that hands the device attributes other than its previous ACK did, or a
+    /// fingerprint giving it another class, the differences are returned.
+    pub fn observe(&mut self, msg: &DhcpMessage, class: Option<&str>) -> Vec<ChangeEvent> {
+        let now = SystemTime::now();
+        let device = self
+            .devices
+            .entry(msg.client_mac)
+            .or_insert_with(|| Device {
+                mac: msg.client_mac,
+                address: None,
+                hostname: None,
+                ifindex: None,
+                vlan: None,
+                class: None,
+                location: None,
+                presence: None,
+                note: None,
+                frozen: false,
+                leased: None,
+                first_seen: now,
+                last_seen: now,
+            });
+
+        let mut changes = Vec::new();
+        if let (Some(old), Some(new)) = (&device.class, class) {
+            if old != new {
+                changes.push(Change::Class {
+                    old: old.clone(),
+                    new: new.to_owned(),
+                });
+            }
+        }
+        // Clients send their hostname, ACKs seldom repeat it
+        if msg.hostname.is_some() {
+            device.hostname = msg.hostname.clone();
+        }
+        if msg.message_type == MessageType::Ack && !msg.your_address.is_unspecified() {
+            let leased = Leased {
+                address: msg.your_address,
+                hostname: device.hostname.clone(),
+                vlan: msg.vlan,
+            };
+            if let Some(old) = &device.leased {
+                changes.extend(diff(old, &leased));
+            }
+            device.leased = Some(leased);
+        }
+        let changes = changes
+            .into_iter()
+            .map(|change| ChangeEvent {
+                mac: msg.client_mac,
+                at: now,
+                frozen: device.frozen && !matches!(change, Change::Hostname { .. }),
+                change,
+            })
+            .collect();
+
+        device.last_seen = now;
+        device.ifindex = Some(msg.ifindex);
+        device.vlan = msg.vlan;
+        if !msg.your_address.is_unspecified() {
+            device.address = Some(msg.your_address);
+        }
+        if let Some(class) = class {
+            device.class = Some(class.to_owned());
+        }
+        // Only relayed messages say anything about the port, a direct one doesn't mean
+        // the device moved
+        if let Some(relay) = &msg.relay {
+            device.location = Some(Location {
+                relay: relay.clone(),
+                seen_at: now,
+            });
+        }
+
+        changes
+    }
+
+    /// Record whether `mac` answered a probe
+    pub fn set_presence(&mut self, mac: MacAddr, online: bool) {
+        if let Some(device) = self.devices.get_mut(&mac) {
+            device.presence = Some(Presence {
+                online,
+                probed_at: SystemTime::now(),
+            });
+        }
+    }
+
+    /// Set or clear the note on `mac`, `None` when it hasn't been seen
+    pub fn set_note(&mut self, mac: MacAddr, note: Option<String>) -> Option<&Device> {
+        let device = self.devices.get_mut(&mac)?;
+        device.note = note;
+        Some(device)
+    }
+
+    pub fn set_frozen(&mut self, mac: MacAddr, frozen: bool) -> Option<&Device> {
+        let device = self.devices.get_mut(&mac)?;
+        device.frozen = frozen;
+        Some(device)
+    }
+
+    pub fn get(&self, mac: &MacAddr) -> Option<&Device> {
+        self.devices.get(mac)
+    }
+
+    /// Devices last going by `hostname`, whatever its case
+    pub fn by_hostname<'a>(&'a self, hostname: &'a str) -> impl Iterator<Item = &'a Device> {
+        self.devices.values().filter(move |device| {
+            device
+                .hostname
+                .as_deref()
+                .map_or(false, |name| name.eq_ignore_ascii_case(hostname))
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Device> {
+        self.devices.values()
+    }
+
+    /// Take over a device record from elsewhere unless the one here was seen more
+    /// recently. Returns whether the record was taken.
+    pub fn restore(&mut self, device: Device) -> bool {
+        match self.devices.get(&device.mac) {
+            Some(existing) if existing.last_seen >= device.last_seen => false,
+            _ => {
+                self.devices.insert(device.mac, device);
+                true
+            }
+        }
+    }
+}
+
+/// What `new` changed about a lease, hostnames left out of either aren't considered
+/// changed
+fn diff(old: &Leased, new: &Leased) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    if old.address != new.address {
+        changes.push(Change::Address {
+            old: old.address,
+            new: new.address,
+        });
+    }
+
+    if let (Some(old), Some(new)) = (&old.hostname, &new.hostname) {
+        if !old.eq_ignore_ascii_case(new) {
+            changes.push(Change::Hostname {
+                old: old.clone(),
+                new: new.clone(),
+            });
+        }
+    }
+
+    if old.vlan != new.vlan {
+        changes.push(Change::Vlan {
+            old: old.vlan,
+            new: new.vlan,
+        });
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+    use crate::{events::Event, output};
+
+    const MAC: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 1]);
+
+    /// DISCOVER, OFFER, REQUEST and ACK of `address` to a client calling itself
+    /// `hostname` on `vlan`, with the changes each of them came up with
+    fn dora(
+        store: &mut DeviceStore,
+        address: Ipv4Addr,
+        hostname: &str,
+        vlan: Option<u16>,
+    ) -> Vec<ChangeEvent> {
+        let mut changes = Vec::new();
+        for message_type in [
+            MessageType::Discover,
+            MessageType::Offer,
+            MessageType::Request,
+            MessageType::Ack,
+        ] {
+            let mut msg = DhcpMessage::test(message_type, MAC);
+            msg.vlan = vlan;
+            match message_type {
+                MessageType::Offer | MessageType::Ack => msg.your_address = address,
+                _ => msg.hostname = Some(hostname.to_owned()),
+            }
+            changes.extend(store.observe(&msg, None));
+        }
+        changes
+    }
+
+    fn changes(events: Vec<ChangeEvent>) -> Vec<(Change, bool)> {
+        events
+            .into_iter()
+            .map(|event| (event.change, event.frozen))
+            .collect()
+    }
+
+    #[test]
+    fn first_lease_changes_nothing() {
+        let mut store = DeviceStore::default();
+        assert!(dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "laptop", None).is_empty());
+    }
+
+    #[test]
+    fn same_lease_again_changes_nothing() {
+        let mut store = DeviceStore::default();
+        dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "laptop", Some(10));
+        assert!(dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "LAPTOP", Some(10)).is_empty());
+    }
+
+    #[test]
+    fn address_and_hostname_changes_are_held_against_the_last_ack() {
+        let mut store = DeviceStore::default();
+        dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "laptop", None);
+        let events = dora(&mut store, Ipv4Addr::new(10, 0, 0, 9), "desktop", None);
+
+        assert_eq!(
+            changes(events),
+            vec![
+                (
+                    Change::Address {
+                        old: Ipv4Addr::new(10, 0, 0, 5),
+                        new: Ipv4Addr::new(10, 0, 0, 9),
+                    },
+                    false
+                ),
+                (
+                    Change::Hostname {
+                        old: "laptop".to_owned(),
+                        new: "desktop".to_owned(),
+                    },
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn vlan_change_is_held_against_the_last_ack() {
+        let mut store = DeviceStore::default();
+        dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "laptop", Some(10));
+        let events = dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "laptop", Some(20));
+
+        assert_eq!(
+            changes(events),
+            vec![(
+                Change::Vlan {
+                    old: Some(10),
+                    new: Some(20),
+                },
+                false
+            )]
+        );
+    }
+
+    #[test]
+    fn frozen_device_moving_is_flagged() {
+        let mut store = DeviceStore::default();
+        dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "printer", Some(10));
+        assert!(store.set_frozen(MAC, true).is_some());
+        let events = dora(&mut store, Ipv4Addr::new(10, 0, 1, 5), "printer", Some(20));
+
+        assert_eq!(
+            changes(events),
+            vec![
+                (
+                    Change::Address {
+                        old: Ipv4Addr::new(10, 0, 0, 5),
+                        new: Ipv4Addr::new(10, 0, 1, 5),
+                    },
+                    true
+                ),
+                (
+                    Change::Vlan {
+                        old: Some(10),
+                        new: Some(20),
+                    },
+                    true
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn frozen_device_moving_is_logged_as_a_warning() {
+        let mut store = DeviceStore::default();
+        dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "printer", None);
+        let moved = dora(&mut store, Ipv4Addr::new(10, 0, 1, 5), "printer", None);
+        store.set_frozen(MAC, true);
+        let frozen = dora(&mut store, Ipv4Addr::new(10, 0, 2, 5), "printer", None);
+
+        let levels = |events: Vec<ChangeEvent>| -> Vec<Level> {
+            events
+                .into_iter()
+                .map(|event| output::level(&Event::Changed(event)))
+                .collect()
+        };
+        assert_eq!(levels(moved), [Level::Info]);
+        assert_eq!(levels(frozen), [Level::Warn]);
+    }
+
+    #[test]
+    fn frozen_device_renaming_itself_is_not_flagged() {
+        let mut store = DeviceStore::default();
+        dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "printer", None);
+        store.set_frozen(MAC, true);
+        let events = dora(&mut store, Ipv4Addr::new(10, 0, 0, 5), "printer-2", None);
+
+        assert_eq!(
+            changes(events),
+            vec![(
+                Change::Hostname {
+                    old: "printer".to_owned(),
+                    new: "printer-2".to_owned(),
+                },
+                false
+            )]
+        );
+    }
+
+    #[test]
+    fn class_change_is_reported_for_every_device() {
+        let mut store = DeviceStore::default();
+        let discover = DhcpMessage::test(MessageType::Discover, MAC);
+        assert!(store.observe(&discover, Some("Windows")).is_empty());
+        assert!(store.observe(&discover, Some("Windows")).is_empty());
+        assert!(store.observe(&discover, None).is_empty());
+
+        let events = store.observe(&discover, Some("Linux"));
+        assert_eq!(
+            changes(events),
+            vec![(
+                Change::Class {
+                    old: "Windows".to_owned(),
+                    new: "Linux".to_owned(),
+                },
+                false
+            )]
+        );
+
+        store.set_frozen(MAC, true);
+        let events = store.observe(&discover, Some("Android"));
+        assert_eq!(
+            changes(events),
+            vec![(
+                Change::Class {
+                    old: "Linux".to_owned(),
+                    new: "Android".to_owned(),
+                },
+                true
+            )]
+        );
+    }
+}
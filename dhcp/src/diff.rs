@@ -0,0 +1,288 @@
+//! What changed in the lease table between two points in time, for reviewing what a
+//! maintenance window did to the network
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+
+use crate::{
+    control::{self, Request, Response},
+    history,
+    mac::MacAddr,
+    snapshot::{self, Snapshot},
+};
+
+/// Where one side of the comparison comes from
+pub enum Source {
+    /// A file written by `export-state`
+    Snapshot(PathBuf),
+    /// The leases the history database had as active at the time
+    History { db: PathBuf, at: SystemTime },
+    /// The running daemon's lease table
+    Daemon,
+}
+
+struct Binding {
+    address: Ipv4Addr,
+    hostname: Option<String>,
+}
+
+impl Binding {
+    fn new(address: Ipv4Addr, hostname: Option<String>) -> Binding {
+        Binding { address, hostname }
+    }
+}
+
+/// The lease table at one point in time
+struct Table {
+    at: SystemTime,
+    bindings: BTreeMap<MacAddr, Binding>,
+}
+
+impl Table {
+    fn from_snapshot(snapshot: Snapshot) -> Table {
+        Table {
+            at: snapshot.created_at,
+            bindings: snapshot
+                .leases
+                .into_iter()
+                .map(|lease| (lease.mac, Binding::new(lease.address, lease.hostname)))
+                .collect(),
+        }
+    }
+
+    async fn load(source: Source, socket: &Path) -> Result<Table, anyhow::Error> {
+        match source {
+            Source::Snapshot(path) => {
+                let contents =
+                    fs::read(&path).with_context(|| format!("failed to read {:?}", path))?;
+                let snapshot = serde_json::from_slice(&contents)
+                    .with_context(|| format!("failed to parse {:?}", path))?;
+                snapshot::check_version(&snapshot)?;
+                Ok(Table::from_snapshot(snapshot))
+            }
+            Source::History { db, at } => Ok(Table {
+                at,
+                bindings: history::leases_at(&db, at)?
+                    .into_iter()
+                    .map(|lease| (lease.mac, Binding::new(lease.address, lease.hostname)))
+                    .collect(),
+            }),
+            Source::Daemon => match control::request(socket, &Request::ExportState).await? {
+                Response::State(snapshot) => Ok(Table::from_snapshot(snapshot)),
+                response => anyhow::bail!("unexpected response {:?}", response),
+            },
+        }
+    }
+}
+
+/// What happened to one device
+#[derive(Debug, PartialEq, Eq)]
+enum Change {
+    Appeared(Ipv4Addr),
+    Disappeared(Ipv4Addr),
+    Moved { from: Ipv4Addr, to: Ipv4Addr },
+}
+
+impl Change {
+    fn name(&self) -> &'static str {
+        match self {
+            Change::Appeared(_) => "appeared",
+            Change::Disappeared(_) => "disappeared",
+            Change::Moved { .. } => "moved",
+        }
+    }
+
+    fn address(&self) -> String {
+        match self {
+            Change::Appeared(address) | Change::Disappeared(address) => address.to_string(),
+            Change::Moved { from, to } => format!("{} -> {}", from, to),
+        }
+    }
+}
+
+/// The devices that changed, with their latest hostname. Those that were there before come
+/// first, each side in MAC order.
+fn changes<'a>(before: &'a Table, after: &'a Table) -> Vec<(MacAddr, Change, Option<&'a str>)> {
+    let mut changes = Vec::new();
+    for (mac, binding) in &before.bindings {
+        let change = match after.bindings.get(mac) {
+            None => Change::Disappeared(binding.address),
+            Some(now) if now.address != binding.address => Change::Moved {
+                from: binding.address,
+                to: now.address,
+            },
+            Some(_) => continue,
+        };
+        let hostname = after
+            .bindings
+            .get(mac)
+            .unwrap_or(binding)
+            .hostname
+            .as_deref();
+        changes.push((*mac, change, hostname));
+    }
+    for (mac, binding) in &after.bindings {
+        if !before.bindings.contains_key(mac) {
+            changes.push((
+                *mac,
+                Change::Appeared(binding.address),
+                binding.hostname.as_deref(),
+            ));
+        }
+    }
+    changes
+}
+
+/// Print the devices that appeared, disappeared or changed address between `from` and `to`
+pub async fn diff(socket: &Path, from: Source, to: Source) -> Result<(), anyhow::Error> {
+    let before = Table::load(from, socket).await?;
+    let after = Table::load(to, socket).await?;
+
+    println!(
+        "leases at {} against {}",
+        humantime::format_rfc3339_seconds(before.at),
+        humantime::format_rfc3339_seconds(after.at)
+    );
+    println!("{:<12} {:<17} {:<33} hostname", "change", "mac", "address");
+
+    let (mut appeared, mut disappeared, mut moved) = (0, 0, 0);
+    for (mac, change, hostname) in changes(&before, &after) {
+        match change {
+            Change::Appeared(_) => appeared += 1,
+            Change::Disappeared(_) => disappeared += 1,
+            Change::Moved { .. } => moved += 1,
+        }
+        println!(
+            "{:<12} {:<17} {:<33} {}",
+            change.name(),
+            mac,
+            change.address(),
+            hostname.unwrap_or("-")
+        );
+    }
+
+    println!(
+        "{} appeared, {} disappeared, {} moved",
+        appeared, disappeared, moved
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(last: u8) -> MacAddr {
+        MacAddr([0x02, 0, 0, 0, 0, last])
+    }
+
+    fn table(bindings: &[(u8, [u8; 4], Option<&str>)]) -> Table {
+        Table {
+            at: SystemTime::UNIX_EPOCH,
+            bindings: bindings
+                .iter()
+                .map(|&(last, address, hostname)| {
+                    (
+                        mac(last),
+                        Binding::new(address.into(), hostname.map(str::to_owned)),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn nothing_changed() {
+        let leases = [
+            (1, [10, 0, 0, 1], Some("printer")),
+            (2, [10, 0, 0, 2], None),
+        ];
+        assert!(changes(&table(&leases), &table(&leases)).is_empty());
+        assert!(changes(&table(&[]), &table(&[])).is_empty());
+    }
+
+    #[test]
+    fn appeared_disappeared_and_moved() {
+        let before = table(&[
+            (1, [10, 0, 0, 1], Some("printer")),
+            (2, [10, 0, 0, 2], Some("laptop")),
+            (4, [10, 0, 0, 4], None),
+        ]);
+        let after = table(&[
+            (1, [10, 0, 0, 1], Some("printer")),
+            (3, [10, 0, 0, 3], Some("phone")),
+            (4, [10, 0, 0, 40], Some("camera")),
+        ]);
+
+        assert_eq!(
+            changes(&before, &after),
+            [
+                (
+                    mac(2),
+                    Change::Disappeared([10, 0, 0, 2].into()),
+                    Some("laptop")
+                ),
+                (
+                    mac(4),
+                    Change::Moved {
+                        from: [10, 0, 0, 4].into(),
+                        to: [10, 0, 0, 40].into(),
+                    },
+                    // The hostname it has now
+                    Some("camera")
+                ),
+                (
+                    mac(3),
+                    Change::Appeared([10, 0, 0, 3].into()),
+                    Some("phone")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_new_hostname_alone_isnt_a_change() {
+        let before = table(&[(1, [10, 0, 0, 1], Some("old"))]);
+        let after = table(&[(1, [10, 0, 0, 1], Some("new"))]);
+        assert!(changes(&before, &after).is_empty());
+    }
+
+    #[test]
+    fn a_device_on_an_address_another_had() {
+        // One device's lease went to another, each side is reported for its own MAC
+        let before = table(&[(1, [10, 0, 0, 1], None)]);
+        let after = table(&[(2, [10, 0, 0, 1], None)]);
+        assert_eq!(
+            changes(&before, &after),
+            [
+                (mac(1), Change::Disappeared([10, 0, 0, 1].into()), None),
+                (mac(2), Change::Appeared([10, 0, 0, 1].into()), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn rows() {
+        let moved = Change::Moved {
+            from: [10, 0, 0, 4].into(),
+            to: [10, 0, 0, 40].into(),
+        };
+        assert_eq!(
+            (moved.name(), moved.address().as_str()),
+            ("moved", "10.0.0.4 -> 10.0.0.40")
+        );
+        let gone = Change::Disappeared([10, 0, 0, 2].into());
+        assert_eq!(
+            (gone.name(), gone.address().as_str()),
+            ("disappeared", "10.0.0.2")
+        );
+    }
+}
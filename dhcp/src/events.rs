@@ -0,0 +1,696 @@
+use std::{fmt, net::Ipv4Addr, time::SystemTime};
+
+use dhcp_common::{ArpEvent, RateLimitEvent, ARP_REPLY, RATE_LIMIT_CLIENT};
+use serde::{Serialize, Serializer};
+
+use crate::{
+    iface,
+    leases::ConflictPolicy,
+    mac::MacAddr,
+    message::{LeaseTime, MessageType},
+};
+
+/// Things the daemon works out from the DHCP traffic, as opposed to the raw messages
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    Changed(ChangeEvent),
+    RogueOffer(RogueOffer),
+    ArpRejected(ArpRejected),
+    RateLimited(RateLimited),
+    LeaseConflict(LeaseConflict),
+    Reconciled(Reconciliation),
+    ProtocolViolation(ProtocolViolation),
+    LeaseTimeAnomaly(LeaseTimeAnomaly),
+    Lease(LeaseEvent),
+    Presence(PresenceEvent),
+    Alert(Alert),
+    ServerCheck(ServerCheckEvent),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Changed(event) => event.fmt(f),
+            Event::RogueOffer(event) => event.fmt(f),
+            Event::ArpRejected(event) => event.fmt(f),
+            Event::RateLimited(event) => event.fmt(f),
+            Event::LeaseConflict(event) => event.fmt(f),
+            Event::Reconciled(event) => event.fmt(f),
+            Event::ProtocolViolation(event) => event.fmt(f),
+            Event::LeaseTimeAnomaly(event) => event.fmt(f),
+            Event::Lease(event) => event.fmt(f),
+            Event::Presence(event) => event.fmt(f),
+            Event::Alert(event) => event.fmt(f),
+            Event::ServerCheck(event) => event.fmt(f),
+        }
+    }
+}
+
+/// A device got a lease with attributes different from its previous one
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeEvent {
+    pub mac: MacAddr,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+    #[serde(flatten)]
+    pub change: Change,
+    /// Whether the address, VLAN or fingerprint of a device frozen in place changed
+    pub frozen: bool,
+}
+
+impl fmt::Display for ChangeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.frozen {
+            write!(f, "frozen device ")?;
+        }
+        write!(f, "{} ", self.mac)?;
+        match &self.change {
+            Change::Address { old, new } => write!(f, "address changed {} -> {}", old, new),
+            Change::Hostname { old, new } => write!(f, "hostname changed {:?} -> {:?}", old, new),
+            Change::Vlan { old, new } => {
+                write!(f, "vlan changed {} -> {}", fmt_vlan(*old), fmt_vlan(*new))
+            }
+            Change::Class { old, new } => write!(f, "class changed {:?} -> {:?}", old, new),
+        }
+    }
+}
+
+/// RFC 3339, which is what log pipelines expect
+pub fn serialize_time<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_rfc3339_millis(*time))
+}
+
+pub fn serialize_interface<S: Serializer>(ifindex: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&iface::name(*ifindex))
+}
+
+fn fmt_vlan(vlan: Option<u16>) -> String {
+    vlan.map_or_else(|| "untagged".to_owned(), |vlan| vlan.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "kebab-case")]
+pub enum Change {
+    Address {
+        old: Ipv4Addr,
+        new: Ipv4Addr,
+    },
+    Hostname {
+        old: String,
+        new: String,
+    },
+    Vlan {
+        old: Option<u16>,
+        new: Option<u16>,
+    },
+    /// A fingerprint matched another class than the last one that matched
+    Class {
+        old: String,
+        new: String,
+    },
+}
+
+/// An offer from a server that isn't trusted
+#[derive(Debug, Clone, Serialize)]
+pub struct RogueOffer {
+    pub server: Ipv4Addr,
+    pub client_mac: MacAddr,
+    pub offered: Ipv4Addr,
+    #[serde(rename = "interface", serialize_with = "serialize_interface")]
+    pub ifindex: u32,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl fmt::Display for RogueOffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rogue server {} offered {} to {} on {}",
+            self.server,
+            self.offered,
+            self.client_mac,
+            iface::name(self.ifindex)
+        )
+    }
+}
+
+/// An ARP reply or gratuitous ARP dropped for claiming an address that isn't leased to its
+/// sender
+#[derive(Debug, Clone, Serialize)]
+pub struct ArpRejected {
+    #[serde(rename = "interface", serialize_with = "serialize_interface")]
+    pub ifindex: u32,
+    pub vlan: Option<u16>,
+    pub reply: bool,
+    pub sender_mac: MacAddr,
+    pub sender_address: Ipv4Addr,
+    pub target_address: Ipv4Addr,
+    /// Who the lease table says holds `sender_address`
+    pub leased_to: Option<MacAddr>,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl From<&ArpEvent> for ArpRejected {
+    fn from(event: &ArpEvent) -> Self {
+        ArpRejected {
+            ifindex: event.ifindex,
+            vlan: (event.vlan != 0).then_some(event.vlan),
+            reply: event.operation == ARP_REPLY,
+            sender_mac: MacAddr(event.sender_mac),
+            sender_address: Ipv4Addr::from(event.sender_address),
+            target_address: Ipv4Addr::from(event.target_address),
+            leased_to: None,
+            at: SystemTime::now(),
+        }
+    }
+}
+
+impl fmt::Display for ArpRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dropped {} from {} claiming {} on {}",
+            if self.reply {
+                "ARP reply"
+            } else {
+                "gratuitous ARP"
+            },
+            self.sender_mac,
+            self.sender_address,
+            iface::name(self.ifindex)
+        )?;
+        if let Some(vlan) = self.vlan {
+            write!(f, " vlan {}", vlan)?;
+        }
+        match self.leased_to {
+            Some(mac) => write!(f, ", leased to {}", mac),
+            None => write!(f, ", not leased"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitScope {
+    /// A single client sent too many
+    Client,
+    /// All clients on the interface together did, e.g. a starvation attack cycling
+    /// through random MACs
+    Interface,
+}
+
+/// A client or an interface went over its DISCOVER/REQUEST rate limit. Raised once when
+/// the limit is first exceeded, not for every message over it.
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimited {
+    #[serde(rename = "interface", serialize_with = "serialize_interface")]
+    pub ifindex: u32,
+    pub vlan: Option<u16>,
+    pub scope: RateLimitScope,
+    /// Messages per second allowed
+    pub rate: u32,
+    /// The client whose message went over the limit
+    pub client_mac: MacAddr,
+    pub message_type: MessageType,
+    /// Whether messages over the limit are dropped or only counted
+    pub dropping: bool,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl From<&RateLimitEvent> for RateLimited {
+    fn from(event: &RateLimitEvent) -> Self {
+        RateLimited {
+            ifindex: event.ifindex,
+            vlan: (event.vlan != 0).then_some(event.vlan),
+            scope: if event.scope == RATE_LIMIT_CLIENT {
+                RateLimitScope::Client
+            } else {
+                RateLimitScope::Interface
+            },
+            rate: event.rate,
+            client_mac: MacAddr(event.client_mac),
+            message_type: MessageType::from(event.message_type),
+            dropping: event.dropping != 0,
+            at: SystemTime::now(),
+        }
+    }
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scope {
+            RateLimitScope::Client => write!(
+                f,
+                "{} is sending more than {} DISCOVER/REQUEST per second on {}",
+                self.client_mac,
+                self.rate,
+                iface::name(self.ifindex)
+            )?,
+            RateLimitScope::Interface => write!(
+                f,
+                "possible DHCP starvation on {}, more than {} DISCOVER/REQUEST per second, \
+                 last from {}",
+                iface::name(self.ifindex),
+                self.rate,
+                self.client_mac
+            )?,
+        }
+        if let Some(vlan) = self.vlan {
+            write!(f, " vlan {}", vlan)?;
+        }
+        if self.dropping {
+            write!(f, ", dropping the excess")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictKind {
+    /// An address was ACKed to a client while another client's lease on it was active
+    DuplicateAddress,
+    /// Two servers ACKed the same transaction with different addresses
+    Transaction,
+    /// A client was ACKed while leasing under a hostname another client's active lease has
+    DuplicateHostname,
+}
+
+/// One side of a lease conflict
+#[derive(Debug, Clone, Serialize)]
+pub struct ConflictingBinding {
+    pub client_mac: MacAddr,
+    pub address: Ipv4Addr,
+    pub server_id: Option<Ipv4Addr>,
+    #[serde(rename = "interface", serialize_with = "serialize_interface")]
+    pub ifindex: u32,
+    pub vlan: Option<u16>,
+}
+
+impl ConflictingBinding {
+    fn fmt_server(&self) -> String {
+        self.server_id.map_or_else(
+            || "a server without option 54".to_owned(),
+            |id| id.to_string(),
+        )
+    }
+}
+
+/// Two bindings that can't both be right
+#[derive(Debug, Clone, Serialize)]
+pub struct LeaseConflict {
+    pub conflict: ConflictKind,
+    /// Of the ACK that raised the conflict
+    pub xid: u32,
+    /// The one claimed twice, for `duplicate-hostname`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// What the daemon knew before
+    pub existing: ConflictingBinding,
+    /// What the ACK handed out
+    pub new: ConflictingBinding,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl fmt::Display for LeaseConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.conflict {
+            ConflictKind::DuplicateAddress => write!(
+                f,
+                "{} ACKed to {} by {} is still leased to {} by {}",
+                self.new.address,
+                self.new.client_mac,
+                self.new.fmt_server(),
+                self.existing.client_mac,
+                self.existing.fmt_server()
+            ),
+            ConflictKind::Transaction => write!(
+                f,
+                "xid {:#010x} of {} ACKed twice, {} by {} and {} by {}",
+                self.xid,
+                self.new.client_mac,
+                self.existing.address,
+                self.existing.fmt_server(),
+                self.new.address,
+                self.new.fmt_server()
+            ),
+            ConflictKind::DuplicateHostname => write!(
+                f,
+                "hostname {:?} of {} on {} is already leased to {} on {}",
+                self.hostname.as_deref().unwrap_or_default(),
+                self.new.client_mac,
+                self.new.address,
+                self.existing.client_mac,
+                self.existing.address
+            ),
+        }
+    }
+}
+
+/// Where two bindings that couldn't both be kept came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReconcileSource {
+    /// The map a previous run left pinned and the state file disagreed at startup
+    Startup,
+    /// An address was ACKed on one interface while leased on another
+    Interfaces,
+}
+
+/// A binding `conflict-policy` dropped in favour of another for the same address or client
+#[derive(Debug, Clone, Serialize)]
+pub struct Reconciliation {
+    pub source: ReconcileSource,
+    pub policy: ConflictPolicy,
+    pub kept: ConflictingBinding,
+    pub dropped: ConflictingBinding,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kept {} for {} on {} over {} for {} on {} ({}, {})",
+            self.kept.address,
+            self.kept.client_mac,
+            iface::name(self.kept.ifindex),
+            self.dropped.address,
+            self.dropped.client_mac,
+            iface::name(self.dropped.ifindex),
+            self.policy,
+            match self.source {
+                ReconcileSource::Startup => "pinned map and state file disagreed",
+                ReconcileSource::Interfaces => "leased on two interfaces",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Violation {
+    /// An OFFER, or an ACK to anything but a DHCPINFORM, without yiaddr
+    MissingAddress,
+    /// yiaddr is broadcast, multicast, loopback, link-local or reserved
+    UnassignableAddress,
+    /// ciaddr set in a message that must leave it 0
+    UnexpectedClientAddress,
+    /// ciaddr left 0 in a RELEASE or INFORM
+    MissingClientAddress,
+}
+
+/// A message whose addresses RFC 2131 doesn't allow for its type
+#[derive(Debug, Clone, Serialize)]
+pub struct ProtocolViolation {
+    pub violation: Violation,
+    pub message_type: MessageType,
+    pub client_mac: MacAddr,
+    /// The server that sent it, `None` for clients' messages and servers leaving out
+    /// option 54
+    pub server_id: Option<Ipv4Addr>,
+    pub client_address: Ipv4Addr,
+    pub your_address: Ipv4Addr,
+    #[serde(rename = "interface", serialize_with = "serialize_interface")]
+    pub ifindex: u32,
+    pub vlan: Option<u16>,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.message_type)?;
+        match self.message_type {
+            MessageType::Offer | MessageType::Ack | MessageType::Nak => write!(
+                f,
+                "from {} to {}",
+                self.server_id.map_or_else(
+                    || "a server without option 54".to_owned(),
+                    |id| id.to_string()
+                ),
+                self.client_mac
+            )?,
+            _ => write!(f, "from {}", self.client_mac)?,
+        }
+        write!(f, " on {}", iface::name(self.ifindex))?;
+        if let Some(vlan) = self.vlan {
+            write!(f, " vlan {}", vlan)?;
+        }
+        match self.violation {
+            Violation::MissingAddress => write!(f, " carries no yiaddr"),
+            Violation::UnassignableAddress => {
+                write!(f, " hands out unassignable yiaddr {}", self.your_address)
+            }
+            Violation::UnexpectedClientAddress => {
+                write!(f, " sets ciaddr {}, must be 0", self.client_address)
+            }
+            Violation::MissingClientAddress => write!(f, " leaves ciaddr 0"),
+        }
+    }
+}
+
+/// A server ACKed a lease far shorter or longer than the ones it usually hands out
+#[derive(Debug, Clone, Serialize)]
+pub struct LeaseTimeAnomaly {
+    pub server_id: Ipv4Addr,
+    pub client_mac: MacAddr,
+    pub address: Ipv4Addr,
+    pub lease_time: LeaseTime,
+    /// The median of the server's recent leases
+    pub usual: LeaseTime,
+    #[serde(rename = "interface", serialize_with = "serialize_interface")]
+    pub ifindex: u32,
+    pub vlan: Option<u16>,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl fmt::Display for LeaseTimeAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ACKed {} to {} on {} for {}, its leases usually run {}",
+            self.server_id,
+            self.address,
+            self.client_mac,
+            iface::name(self.ifindex),
+            fmt_lease_time(self.lease_time),
+            fmt_lease_time(self.usual)
+        )
+    }
+}
+
+fn fmt_lease_time(lease_time: LeaseTime) -> String {
+    match lease_time {
+        LeaseTime::Seconds(secs) => {
+            humantime::format_duration(std::time::Duration::from_secs(secs as u64)).to_string()
+        }
+        LeaseTime::Infinite => "forever".to_owned(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LeaseAction {
+    /// A client got an address it didn't hold before
+    Bound,
+    /// A client's lease on its address was extended
+    Renewed,
+    /// The client released the address, the server NAKed it or an admin flushed it
+    Released,
+    /// The lease ran out without being renewed
+    Expired,
+}
+
+impl LeaseAction {
+    pub fn name(self) -> &'static str {
+        match self {
+            LeaseAction::Bound => "bound",
+            LeaseAction::Renewed => "renewed",
+            LeaseAction::Released => "released",
+            LeaseAction::Expired => "expired",
+        }
+    }
+}
+
+/// A lease came or went
+#[derive(Debug, Clone, Serialize)]
+pub struct LeaseEvent {
+    pub action: LeaseAction,
+    pub mac: MacAddr,
+    pub address: Ipv4Addr,
+    pub hostname: Option<String>,
+    /// What the client is according to its fingerprint
+    pub class: Option<String>,
+    #[serde(rename = "interface", serialize_with = "serialize_interface")]
+    pub ifindex: u32,
+    pub vlan: Option<u16>,
+    pub server_id: Option<Ipv4Addr>,
+    /// Seconds the lease is good for from `at`, `None` for infinite leases and once the
+    /// lease is gone
+    pub lease_time: Option<u64>,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl fmt::Display for LeaseEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "lease of {} for {}", self.address, self.mac)?;
+        if let Some(hostname) = &self.hostname {
+            write!(f, " ({})", hostname)?;
+        }
+        write!(
+            f,
+            " {} on {}",
+            self.action.name(),
+            iface::name(self.ifindex)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PresenceState {
+    /// The lease ran out and the device doesn't answer ARP any more
+    Left,
+    /// The lease ran out but the device still answers ARP at the address, e.g. because its
+    /// DHCP client died or the address was set statically
+    StillOnline,
+}
+
+/// What ARP-pinging a device whose lease ran out found
+#[derive(Debug, Clone, Serialize)]
+pub struct PresenceEvent {
+    pub state: PresenceState,
+    pub mac: MacAddr,
+    /// Of the lease that ran out
+    pub address: Ipv4Addr,
+    pub hostname: Option<String>,
+    #[serde(rename = "interface", serialize_with = "serialize_interface")]
+    pub ifindex: u32,
+    pub vlan: Option<u16>,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl fmt::Display for PresenceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mac)?;
+        if let Some(hostname) = &self.hostname {
+            write!(f, " ({})", hostname)?;
+        }
+        match self.state {
+            PresenceState::Left => write!(f, " left {}", iface::name(self.ifindex)),
+            PresenceState::StillOnline => write!(
+                f,
+                " stopped renewing {} but still answers ARP on {}",
+                self.address,
+                iface::name(self.ifindex)
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertState {
+    /// The rule has held for as long as it has to
+    Firing,
+    /// It stopped holding
+    Resolved,
+}
+
+impl AlertState {
+    pub fn name(self) -> &'static str {
+        match self {
+            AlertState::Firing => "firing",
+            AlertState::Resolved => "resolved",
+        }
+    }
+}
+
+/// An `[[alert]]` rule started or stopped holding
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub name: String,
+    pub rule: String,
+    pub state: AlertState,
+    /// What the rule compared, in the units of its threshold
+    pub value: f64,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "alert {} {}, {} at {:.2}",
+            self.name,
+            self.state.name(),
+            self.rule,
+            self.value
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerState {
+    /// It failed as many checks in a row as it may
+    Down,
+    /// It answers again
+    Up,
+}
+
+impl ServerState {
+    pub fn name(self) -> &'static str {
+        match self {
+            ServerState::Down => "down",
+            ServerState::Up => "up",
+        }
+    }
+}
+
+/// A server stopped answering the `[server-check]` exchanges, or started again
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerCheckEvent {
+    pub server: Ipv4Addr,
+    pub state: ServerState,
+    /// Checks failed in a row, up to now when it's up again
+    pub failures: u32,
+    /// Why the last check failed, when it's down
+    pub reason: Option<String>,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl fmt::Display for ServerCheckEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.state, &self.reason) {
+            (ServerState::Down, Some(reason)) => write!(
+                f,
+                "server {} is down, {} checks failed in a row, the last because it {}",
+                self.server, self.failures, reason
+            ),
+            (ServerState::Down, None) => write!(
+                f,
+                "server {} is down, {} checks failed in a row",
+                self.server, self.failures
+            ),
+            (ServerState::Up, _) => write!(
+                f,
+                "server {} is up again after {} failed checks",
+                self.server, self.failures
+            ),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Telling what kind of device a client is from the options it asks for (option 55) and the
+//! vendor class it sends (option 60). Clients ask in an order particular to their DHCP
+//! client, which is often enough to name the OS.
+
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::message::{DhcpMessage, Direction};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Fingerprint {
+    /// What matching clients are classified as, e.g. "Android"
+    pub class: String,
+    /// Option 55 exactly, in the order the client asks
+    pub parameter_list: Option<Vec<u8>>,
+    /// Option 60 starting with this
+    pub vendor_class: Option<String>,
+}
+
+impl Fingerprint {
+    fn matches(&self, parameter_list: &[u8], vendor_class: Option<&str>) -> bool {
+        // One that doesn't say anything would match every client
+        if self.parameter_list.is_none() && self.vendor_class.is_none() {
+            return false;
+        }
+
+        self.parameter_list
+            .as_deref()
+            .map_or(true, |list| list == parameter_list)
+            && self.vendor_class.as_deref().map_or(true, |prefix| {
+                vendor_class.map_or(false, |vendor_class| vendor_class.starts_with(prefix))
+            })
+    }
+}
+
+/// Contents of the file given with `fingerprints`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FingerprintFile {
+    fingerprint: Vec<Fingerprint>,
+}
+
+/// (class, parameter list, vendor class prefix), the first match wins
+const BUILT_IN: &[(&str, &[u8], &str)] = &[
+    (
+        "Windows 10/11",
+        &[1, 3, 6, 15, 31, 33, 43, 44, 46, 47, 119, 121, 249, 252],
+        "MSFT 5.0",
+    ),
+    (
+        "Windows 7",
+        &[1, 15, 3, 6, 44, 46, 47, 31, 33, 121, 249, 43, 252],
+        "MSFT 5.0",
+    ),
+    ("Windows", &[], "MSFT 5.0"),
+    ("Windows 9x", &[], "MSFT 98"),
+    (
+        "macOS",
+        &[1, 121, 3, 6, 15, 108, 114, 119, 162, 252, 95, 44, 46],
+        "",
+    ),
+    ("macOS", &[1, 121, 3, 6, 15, 119, 252, 95, 44, 46], ""),
+    ("iOS", &[1, 121, 3, 6, 15, 108, 114, 119, 252], ""),
+    ("iOS", &[1, 121, 3, 6, 15, 119, 252], ""),
+    ("Android", &[], "android-dhcp-"),
+    (
+        "Android",
+        &[1, 3, 6, 15, 26, 28, 51, 58, 59, 43, 114, 108],
+        "",
+    ),
+    ("Android", &[1, 3, 6, 15, 26, 28, 51, 58, 59, 43, 114], ""),
+    ("Android", &[1, 3, 6, 15, 26, 28, 51, 58, 59, 43], ""),
+    (
+        "Linux (dhclient)",
+        &[1, 28, 2, 3, 15, 6, 119, 12, 44, 47, 26, 121, 42],
+        "",
+    ),
+    ("Linux (dhcpcd)", &[], "dhcpcd-"),
+    ("Embedded Linux (udhcpc)", &[], "udhcp"),
+    ("IP phone", &[], "Cisco Systems, Inc. IP Phone"),
+    ("IP phone", &[], "Polycom-"),
+    ("IP phone", &[], "yealink"),
+    ("IP camera", &[], "AXIS,Network Camera"),
+    ("Printer", &[], "Hewlett-Packard JetDirect"),
+    ("PXE boot", &[], "PXEClient"),
+];
+
+/// The fingerprints from the file in `fingerprints`, ahead of the built-in ones
+pub struct FingerprintDb {
+    fingerprints: Vec<Fingerprint>,
+}
+
+impl FingerprintDb {
+    pub fn load(path: Option<&Path>) -> Result<FingerprintDb, anyhow::Error> {
+        let mut fingerprints = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {:?}", path))?;
+                toml::from_str::<FingerprintFile>(&contents)
+                    .with_context(|| format!("failed to parse {:?}", path))?
+                    .fingerprint
+            }
+            None => Vec::new(),
+        };
+
+        fingerprints.extend(
+            BUILT_IN
+                .iter()
+                .map(|(class, parameter_list, vendor_class)| Fingerprint {
+                    class: class.to_string(),
+                    parameter_list: (!parameter_list.is_empty()).then(|| parameter_list.to_vec()),
+                    vendor_class: (!vendor_class.is_empty()).then(|| vendor_class.to_string()),
+                }),
+        );
+
+        Ok(FingerprintDb { fingerprints })
+    }
+
+    /// What the client sending `msg` is, `None` for server messages, messages without
+    /// option 55 or 60 and clients no fingerprint matches
+    pub fn classify(&self, msg: &DhcpMessage) -> Option<&str> {
+        // PXE servers answer with option 60 too
+        if msg.direction != Direction::ClientToServer
+            || (msg.parameter_list.is_empty() && msg.vendor_class.is_none())
+        {
+            return None;
+        }
+
+        self.fingerprints
+            .iter()
+            .find(|fingerprint| {
+                fingerprint.matches(&msg.parameter_list, msg.vendor_class.as_deref())
+            })
+            .map(|fingerprint| fingerprint.class.as_str())
+    }
+}
@@ -0,0 +1,204 @@
+//! Keeps an nftables set, or an ipset, of the leased address/MAC pairs so firewall rules
+//! can match on devices the DHCP server knows about
+
+use std::{fmt::Write, process::Stdio, time::Duration};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    sync::broadcast,
+    time::{interval, sleep},
+};
+
+use crate::{
+    clock::BootTime,
+    config::deserialize_duration,
+    events::Event,
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const HEALTH_NAME: &str = "firewall-set";
+/// Lease changes arriving within this of each other are applied together
+const DEBOUNCE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Tool {
+    /// A set of `ipv4_addr . ether_addr`
+    Nftables,
+    /// A `hash:ip,mac` set
+    Ipset,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FirewallSetConfig {
+    #[serde(default = "default_backend")]
+    pub backend: Tool,
+    /// nftables only
+    #[serde(default = "default_family")]
+    pub family: String,
+    /// nftables only
+    #[serde(default = "default_table")]
+    pub table: String,
+    #[serde(default = "default_set")]
+    pub set: String,
+    /// The set is rewritten this often even without lease changes, which also catches
+    /// leases taken over with import-state
+    #[serde(default = "default_resync", deserialize_with = "deserialize_duration")]
+    pub resync: Duration,
+}
+
+impl sinks::Sink for FirewallSetConfig {
+    fn name(&self) -> String {
+        "firewall set".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(*self, ctx.unredacted_events(), ctx.backend.clone()).boxed()
+    }
+}
+
+fn default_backend() -> Tool {
+    Tool::Nftables
+}
+
+fn default_family() -> String {
+    "inet".to_owned()
+}
+
+fn default_table() -> String {
+    "filter".to_owned()
+}
+
+fn default_set() -> String {
+    "dhcp_leases".to_owned()
+}
+
+fn default_resync() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Rewrite the set whenever leases change until the events channel closes. The set is
+/// created if it's missing and replaced as a whole, so that it ends up matching the lease
+/// table however it looked before.
+async fn run(
+    config: FirewallSetConfig,
+    mut events: broadcast::Receiver<Event>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    info!(
+        "keeping {} set {} in step with the leases",
+        name(config.backend),
+        config.set
+    );
+
+    let mut resync = interval(config.resync);
+    loop {
+        let changed = tokio::select! {
+            _ = resync.tick() => false,
+            event = events.recv() => match event {
+                Ok(Event::Lease(_)) | Err(broadcast::error::RecvError::Lagged(_)) => true,
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        if changed {
+            sleep(DEBOUNCE).await;
+            // Whatever else changed meanwhile is in the lease table already
+            events = events.resubscribe();
+        }
+
+        let status = sync(&config, &backend).await.map_err(|e| {
+            warn!(
+                "failed to update {} set {}: {:#}",
+                name(config.backend),
+                config.set,
+                e
+            );
+            format!("{:#}", e)
+        });
+        backend.health.set_sink(HEALTH_NAME, status);
+    }
+}
+
+fn name(backend: Tool) -> &'static str {
+    match backend {
+        Tool::Nftables => "nftables",
+        Tool::Ipset => "ipset",
+    }
+}
+
+async fn sync(config: &FirewallSetConfig, backend: &Backend) -> Result<(), anyhow::Error> {
+    let pairs: Vec<String> = {
+        let state = backend.state.lock().unwrap();
+        let now = BootTime::now();
+        let separator = match config.backend {
+            Tool::Nftables => " . ",
+            Tool::Ipset => ",",
+        };
+        state
+            .leases
+            .iter()
+            .filter(|lease| lease.is_active(now))
+            .map(|lease| format!("{}{}{}", lease.address, separator, lease.mac))
+            .collect()
+    };
+
+    let mut script = String::new();
+    let (program, args): (&str, &[&str]) = match config.backend {
+        Tool::Nftables => {
+            let set = format!("{} {} {}", config.family, config.table, config.set);
+            let _ = writeln!(script, "add table {} {}", config.family, config.table);
+            let _ = writeln!(script, "add set {} {{ type ipv4_addr . ether_addr; }}", set);
+            let _ = writeln!(script, "flush set {}", set);
+            if !pairs.is_empty() {
+                let _ = writeln!(script, "add element {} {{ {} }}", set, pairs.join(", "));
+            }
+            ("nft", &["-f", "-"])
+        }
+        Tool::Ipset => {
+            let _ = writeln!(script, "create {} hash:ip,mac", config.set);
+            let _ = writeln!(script, "flush {}", config.set);
+            for pair in &pairs {
+                let _ = writeln!(script, "add {} {}", config.set, pair);
+            }
+            ("ipset", &["-exist", "restore"])
+        }
+    };
+
+    run_script(program, args, &script).await
+}
+
+/// Run `program` with `script` on stdin, nft and ipset apply a script in one go
+async fn run_script(program: &str, args: &[&str], script: &str) -> Result<(), anyhow::Error> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to run {}", program))?;
+
+    let mut stdin = child.stdin.take().context("no stdin")?;
+    stdin.write_all(script.as_bytes()).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,253 @@
+//! Pulls trusted servers and interface policies from a URL, so a fleet of snoopers can be
+//! managed from one place. A document is only applied when it carries a valid ed25519
+//! signature from the configured key.
+
+use std::{net::Ipv4Addr, time::Duration};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use ed25519_dalek::{Signature, VerifyingKey};
+use hyper::{
+    body,
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_LENGTH, ETAG, IF_NONE_MATCH},
+    Body, Client, Method, Request, Response, StatusCode,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::{
+    sync::mpsc,
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+use crate::{
+    attach::Enforcement,
+    config::{deserialize_duration, RateLimit},
+    secret::{Secret, SecretSource},
+    state::Backend,
+};
+
+const HEALTH_SINK: &str = "fleet";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Documents larger than this are refused
+const MAX_DOCUMENT_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FleetConfig {
+    /// e.g. https://config.example.com/snoopers/site-a.toml
+    pub url: String,
+    /// Where the detached signature of the document is, `<url>.sig` when not set
+    pub signature_url: Option<String>,
+    /// Base64 of the raw 32 byte ed25519 key documents are signed with
+    pub public_key: String,
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// Sent as a bearer token with both requests
+    pub token: Option<SecretSource>,
+}
+
+fn default_interval() -> Duration {
+    DEFAULT_INTERVAL
+}
+
+/// What a fleet document can set, on top of the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Policy {
+    /// Replaces the trusted servers when given, an empty list trusts every server
+    pub trusted_servers: Option<Vec<Ipv4Addr>>,
+    pub enforcement: Option<Enforcement>,
+    #[serde(rename = "interface")]
+    pub interfaces: Vec<InterfacePolicy>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct InterfacePolicy {
+    /// Interfaces that aren't in the config file are ignored, a policy can't add any
+    pub name: String,
+    /// Replaces the interface's rate limit when given
+    pub rate_limit: Option<RateLimit>,
+}
+
+struct Fetcher {
+    config: FleetConfig,
+    signature_url: String,
+    key: VerifyingKey,
+    authorization: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+/// Poll the document every `interval`, applying the trusted servers right away and
+/// passing the rest on to whoever manages the attachments
+pub async fn run(
+    config: FleetConfig,
+    token: Option<Secret>,
+    policies: mpsc::Sender<Policy>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    let key = STANDARD
+        .decode(config.public_key.trim())
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .context("the fleet public key isn't 32 bytes of base64")?;
+    let fetcher = Fetcher {
+        signature_url: config
+            .signature_url
+            .clone()
+            .unwrap_or_else(|| format!("{}.sig", config.url)),
+        key: VerifyingKey::from_bytes(&key).context("invalid fleet public key")?,
+        authorization: token.map(|token| format!("Bearer {}", token.expose())),
+        client: Client::builder().build(
+            HttpsConnectorBuilder::new()
+                .with_native_roots()
+                .https_or_http()
+                .enable_http1()
+                .build(),
+        ),
+        config,
+    };
+    info!(
+        "pulling policies from {} every {}",
+        fetcher.config.url,
+        humantime::format_duration(fetcher.config.interval)
+    );
+
+    let mut ticks = interval(fetcher.config.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut etag = None;
+    // Servers that don't do ETags send the same document every time
+    let mut applied: Option<Bytes> = None;
+
+    loop {
+        ticks.tick().await;
+
+        let (document, new_etag) = match fetcher.fetch(etag.as_deref()).await {
+            Ok(Some(fetched)) => fetched,
+            Ok(None) => {
+                backend.health.set_sink(HEALTH_SINK, Ok(()));
+                continue;
+            }
+            Err(e) => {
+                warn!("failed to pull the fleet policy: {:#}", e);
+                backend
+                    .health
+                    .set_sink(HEALTH_SINK, Err(format!("{:#}", e)));
+                continue;
+            }
+        };
+        backend.health.set_sink(HEALTH_SINK, Ok(()));
+        // The signature checked out, a document that doesn't parse won't get any better
+        // until it changes
+        etag = new_etag;
+        if applied.as_ref() == Some(&document) {
+            continue;
+        }
+
+        let policy: Policy = match std::str::from_utf8(&document)
+            .context("not UTF-8")
+            .and_then(|document| toml::from_str(document).context("invalid policy"))
+        {
+            Ok(policy) => policy,
+            Err(e) => {
+                warn!("ignoring fleet policy: {:#}", e);
+                continue;
+            }
+        };
+
+        if let Some(servers) = &policy.trusted_servers {
+            let mut state = backend.state.lock().unwrap();
+            if let Err(e) = state.trusted_servers.replace(servers) {
+                warn!("failed to update the trusted servers: {}", e);
+            }
+        }
+        info!("applying fleet policy from {}", fetcher.config.url);
+        if policies.send(policy).await.is_err() {
+            return Ok(());
+        }
+        applied = Some(document);
+    }
+}
+
+impl Fetcher {
+    /// The document and its ETag once it's been verified, `None` when it hasn't changed
+    /// since `etag`
+    async fn fetch(
+        &self,
+        etag: Option<&str>,
+    ) -> Result<Option<(Bytes, Option<String>)>, anyhow::Error> {
+        let response = self.get(&self.config.url, etag).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_owned);
+        let document = read_body(response).await?;
+
+        let signature = read_body(self.get(&self.signature_url, None).await?)
+            .await
+            .context("failed to fetch the signature")?;
+        let signature = std::str::from_utf8(&signature)
+            .ok()
+            .and_then(|signature| STANDARD.decode(signature.trim()).ok())
+            .context("the signature isn't base64")?;
+        let signature = Signature::from_slice(&signature).context("invalid signature")?;
+        self.key
+            .verify_strict(&document, &signature)
+            .context("the signature doesn't match the document")?;
+
+        Ok(Some((document, etag)))
+    }
+
+    async fn get(&self, url: &str, etag: Option<&str>) -> Result<Response<Body>, anyhow::Error> {
+        let mut request = Request::builder().method(Method::GET).uri(url);
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let request = request.body(Body::empty()).context("invalid request")?;
+
+        let response = timeout(REQUEST_TIMEOUT, self.client.request(request))
+            .await
+            .with_context(|| format!("{} timed out", url))?
+            .with_context(|| format!("request to {} failed", url))?;
+
+        let status = response.status();
+        if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+            anyhow::bail!("{} returned {}", url, status);
+        }
+        Ok(response)
+    }
+}
+
+async fn read_body(response: Response<Body>) -> Result<Bytes, anyhow::Error> {
+    let len = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    if len.map_or(false, |len| len > MAX_DOCUMENT_LEN) {
+        anyhow::bail!("larger than {} bytes", MAX_DOCUMENT_LEN);
+    }
+
+    let body = timeout(REQUEST_TIMEOUT, body::to_bytes(response.into_body()))
+        .await
+        .context("timed out reading the body")?
+        .context("failed to read the body")?;
+    if body.len() > MAX_DOCUMENT_LEN {
+        anyhow::bail!("larger than {} bytes", MAX_DOCUMENT_LEN);
+    }
+    Ok(body)
+}
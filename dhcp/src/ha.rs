@@ -0,0 +1,408 @@
+//! Active/standby pair of daemons. Both snoop, but only the active one enforces the
+//! trusted interfaces and exports events. The standby keeps its state in sync with the
+//! snapshots the active one sends and takes over once the active one's heartbeats stop.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time::{interval, timeout},
+};
+
+use crate::{
+    control,
+    secret::{Secret, SecretSource},
+    snapshot::{self, Snapshot},
+    state::Backend,
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// The peer is considered dead after this long without a heartbeat
+const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+/// Longest hello taken, before the peer has shown it has the key
+const MAX_HELLO_LEN: u64 = 4 * 1024;
+/// Longest message after it, a sync carries the whole state
+const MAX_MESSAGE_LEN: u64 = 256 * 1024 * 1024;
+const HEALTH_SINK: &str = "ha";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HaConfig {
+    /// Address the peer connects to
+    pub listen: SocketAddr,
+    /// The peer's `listen` address
+    pub peer: SocketAddr,
+    /// When both nodes compete for the active role the one with the higher priority wins
+    #[serde(default)]
+    pub priority: u8,
+    /// Shared between both nodes, connections from a peer that doesn't present it are
+    /// refused
+    pub key: Option<SecretSource>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Active,
+    Standby,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Active => "active",
+            Role::Standby => "standby",
+        })
+    }
+}
+
+// No Debug, a hello carries the key
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Message {
+    /// First message on every connection
+    Hello {
+        key: String,
+    },
+    Heartbeat {
+        /// The sender's `listen` address, breaks ties between equal priorities
+        id: SocketAddr,
+        priority: u8,
+        role: Role,
+    },
+    Sync {
+        snapshot: Snapshot,
+    },
+}
+
+struct Peer {
+    last_seen: Instant,
+    id: SocketAddr,
+    priority: u8,
+    role: Role,
+}
+
+/// Exchange heartbeats and snapshots with the peer and switch `role` over as the
+/// election dictates
+pub async fn run(
+    config: HaConfig,
+    key: Option<Secret>,
+    backend: Backend,
+    role: watch::Sender<Role>,
+) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(config.listen)
+        .await
+        .with_context(|| format!("failed to bind HA listener {}", config.listen))?;
+    info!("HA listening on {}, peer is {}", config.listen, config.peer);
+
+    let peer = Arc::new(Mutex::new(None));
+    tokio::spawn(accept(listener, key.clone(), backend.clone(), peer.clone()));
+
+    let started = Instant::now();
+    let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+    let mut connection: Option<TcpStream> = None;
+    let mut last_sync: Option<Instant> = None;
+
+    loop {
+        heartbeat.tick().await;
+
+        let current = *role.borrow();
+        let next = elect(&config, current, peer.lock().unwrap().as_ref(), started);
+        if next != current {
+            info!("HA role changed from {} to {}", current, next);
+            role.send_replace(next);
+        }
+
+        if connection.is_none() {
+            match timeout(HEARTBEAT_INTERVAL, TcpStream::connect(config.peer)).await {
+                Ok(Ok(mut stream)) => {
+                    let hello = Message::Hello {
+                        key: key
+                            .as_ref()
+                            .map(Secret::expose)
+                            .unwrap_or_default()
+                            .to_owned(),
+                    };
+                    if let Err(e) = send(&mut stream, &[hello]).await {
+                        warn!("failed to greet HA peer {}: {:#}", config.peer, e);
+                        continue;
+                    }
+                    info!("connected to HA peer {}", config.peer);
+                    backend.health.set_sink(HEALTH_SINK, Ok(()));
+                    connection = Some(stream);
+                    // A fresh connection may well be a restarted peer, catch it up right away
+                    last_sync = None;
+                }
+                Ok(Err(e)) => debug!("failed to connect to HA peer {}: {}", config.peer, e),
+                Err(_) => debug!("timed out connecting to HA peer {}", config.peer),
+            }
+        }
+        let stream = match &mut connection {
+            Some(stream) => stream,
+            None => {
+                let status = Err(format!("not connected to peer {}", config.peer));
+                backend.health.set_sink(HEALTH_SINK, status);
+                continue;
+            }
+        };
+
+        let mut messages = vec![Message::Heartbeat {
+            id: config.listen,
+            priority: config.priority,
+            role: next,
+        }];
+        if next == Role::Active && last_sync.map_or(true, |at| at.elapsed() >= SYNC_INTERVAL) {
+            match snapshot::export(&backend) {
+                Ok(snapshot) => {
+                    messages.push(Message::Sync { snapshot });
+                    last_sync = Some(Instant::now());
+                }
+                Err(e) => warn!("failed to take snapshot for HA peer: {:#}", e),
+            }
+        }
+
+        if let Err(e) = send(stream, &messages).await {
+            warn!("lost connection to HA peer {}: {:#}", config.peer, e);
+            backend
+                .health
+                .set_sink(HEALTH_SINK, Err(format!("lost connection: {:#}", e)));
+            connection = None;
+        }
+    }
+}
+
+/// The role this node should be in given what it last heard from the peer
+fn elect(config: &HaConfig, current: Role, peer: Option<&Peer>, started: Instant) -> Role {
+    let peer = match peer.filter(|peer| peer.last_seen.elapsed() < PEER_TIMEOUT) {
+        Some(peer) => peer,
+        // Give a running peer the chance to speak up before taking over at startup
+        None if started.elapsed() < PEER_TIMEOUT => return current,
+        None => return Role::Active,
+    };
+    let outranks = (config.priority, config.listen) > (peer.priority, peer.id);
+
+    match (current, peer.role) {
+        // A node coming back doesn't take over from a working one
+        (Role::Standby, Role::Active) => Role::Standby,
+        (Role::Active, Role::Standby) => Role::Active,
+        // Both active happens after a partition heals, both standby at startup
+        _ if outranks => Role::Active,
+        _ => Role::Standby,
+    }
+}
+
+async fn send(stream: &mut TcpStream, messages: &[Message]) -> Result<(), anyhow::Error> {
+    let mut out = Vec::new();
+    for message in messages {
+        serde_json::to_writer(&mut out, message)?;
+        out.push(b'\n');
+    }
+
+    timeout(HEARTBEAT_INTERVAL, stream.write_all(&out))
+        .await
+        .context("timed out")??;
+
+    Ok(())
+}
+
+async fn accept(
+    listener: TcpListener,
+    key: Option<Secret>,
+    backend: Backend,
+    peer: Arc<Mutex<Option<Peer>>>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("failed to accept HA connection: {}", e);
+                continue;
+            }
+        };
+
+        let (key, backend, peer) = (key.clone(), backend.clone(), peer.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, key, backend, peer).await {
+                warn!("HA connection from {} failed: {:#}", addr, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    key: Option<Secret>,
+    backend: Backend,
+    peer: Arc<Mutex<Option<Peer>>>,
+) -> Result<(), anyhow::Error> {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+
+    let hello = timeout(
+        PEER_TIMEOUT,
+        control::read_line(&mut reader, MAX_HELLO_LEN, &mut line),
+    )
+    .await
+    .context("peer didn't say hello in time")??;
+    match hello {
+        Some(true) => {}
+        Some(false) => anyhow::bail!("peer's hello is longer than {} bytes", MAX_HELLO_LEN),
+        None => anyhow::bail!("peer closed the connection without saying hello"),
+    }
+    match serde_json::from_slice::<Message>(&line).context("invalid message")? {
+        Message::Hello { key: presented } => {
+            if !key.as_ref().map_or(true, |key| key.matches(&presented)) {
+                anyhow::bail!("peer presented the wrong key");
+            }
+        }
+        _ => anyhow::bail!("peer didn't say hello"),
+    }
+
+    while let Some(fits) = control::read_line(&mut reader, MAX_MESSAGE_LEN, &mut line).await? {
+        if !fits {
+            anyhow::bail!("peer sent a message longer than {} bytes", MAX_MESSAGE_LEN);
+        }
+        match serde_json::from_slice::<Message>(&line).context("invalid message")? {
+            Message::Hello { .. } => anyhow::bail!("peer said hello twice"),
+            Message::Heartbeat { id, priority, role } => {
+                *peer.lock().unwrap() = Some(Peer {
+                    last_seen: Instant::now(),
+                    id,
+                    priority,
+                    role,
+                });
+            }
+            // Only the standby follows the peer, the active node's own view wins
+            Message::Sync { snapshot } if *backend.role.borrow() == Role::Standby => {
+                let summary = snapshot::sync(&backend, snapshot)?;
+                debug!(
+                    "synced {} leases and {} devices from HA peer",
+                    summary.leases, summary.devices
+                );
+            }
+            Message::Sync { .. } => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(priority: u8) -> HaConfig {
+        HaConfig {
+            listen: "10.0.0.2:9377".parse().unwrap(),
+            peer: "10.0.0.3:9377".parse().unwrap(),
+            priority,
+            key: None,
+        }
+    }
+
+    fn ago(secs: u64) -> Instant {
+        Instant::now() - Duration::from_secs(secs)
+    }
+
+    fn peer(priority: u8, role: Role) -> Peer {
+        Peer {
+            last_seen: Instant::now(),
+            id: "10.0.0.3:9377".parse().unwrap(),
+            priority,
+            role,
+        }
+    }
+
+    #[test]
+    fn waits_for_the_peer_at_startup() {
+        for current in [Role::Standby, Role::Active] {
+            assert_eq!(elect(&config(100), current, None, Instant::now()), current);
+        }
+        assert_eq!(
+            elect(&config(100), Role::Standby, None, ago(60)),
+            Role::Active
+        );
+    }
+
+    #[test]
+    fn takes_over_from_a_silent_peer() {
+        let mut silent = peer(200, Role::Active);
+        silent.last_seen = ago(10);
+        assert_eq!(
+            elect(&config(100), Role::Standby, Some(&silent), ago(60)),
+            Role::Active
+        );
+        // Still in the startup grace
+        assert_eq!(
+            elect(&config(100), Role::Standby, Some(&silent), Instant::now()),
+            Role::Standby
+        );
+
+        let heard = peer(200, Role::Active);
+        assert_eq!(
+            elect(&config(100), Role::Standby, Some(&heard), ago(60)),
+            Role::Standby
+        );
+    }
+
+    #[test]
+    fn a_working_pair_stays_as_it_is() {
+        // Outranking the active peer isn't enough to take over
+        assert_eq!(
+            elect(
+                &config(200),
+                Role::Standby,
+                Some(&peer(100, Role::Active)),
+                ago(60)
+            ),
+            Role::Standby
+        );
+        assert_eq!(
+            elect(
+                &config(100),
+                Role::Active,
+                Some(&peer(200, Role::Standby)),
+                ago(60)
+            ),
+            Role::Active
+        );
+    }
+
+    #[test]
+    fn ties_go_by_priority_then_address() {
+        for both in [Role::Active, Role::Standby] {
+            let elected = |priority, peer_priority| {
+                elect(
+                    &config(priority),
+                    both,
+                    Some(&peer(peer_priority, both)),
+                    ago(60),
+                )
+            };
+            assert_eq!(elected(200, 100), Role::Active, "both {}", both);
+            assert_eq!(elected(100, 200), Role::Standby, "both {}", both);
+            // 10.0.0.2 loses to the peer's 10.0.0.3
+            assert_eq!(elected(100, 100), Role::Standby, "both {}", both);
+
+            let mut lower = peer(100, both);
+            lower.id = "10.0.0.1:9377".parse().unwrap();
+            assert_eq!(
+                elect(&config(100), both, Some(&lower), ago(60)),
+                Role::Active,
+                "both {}",
+                both
+            );
+        }
+    }
+}
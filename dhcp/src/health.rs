@@ -0,0 +1,124 @@
+//! What `/healthz` and `/readyz` report on. The parts of the daemon that can fail on their
+//! own record their status here as they go.
+
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::state::Backend;
+
+/// The event loop ticks at least every 10 seconds, it's considered stuck after missing a
+/// few of those
+const EVENT_LOOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+pub struct Health {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Configured interfaces and whether the program is attached to them
+    interfaces: BTreeMap<String, bool>,
+    event_loop: Option<Instant>,
+    sinks: BTreeMap<String, Result<(), String>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub healthy: bool,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: Result<(), String>) -> Check {
+        Check {
+            name: name.into(),
+            healthy: result.is_ok(),
+            detail: result.err(),
+        }
+    }
+}
+
+impl Health {
+    pub fn set_attached(&self, interface: &str, attached: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.interfaces.insert(interface.to_owned(), attached);
+    }
+
+    /// Called by the event loop every time it gets around to doing something
+    pub fn event_loop_alive(&self) {
+        self.inner.lock().unwrap().event_loop = Some(Instant::now());
+    }
+
+    pub fn set_sink(&self, sink: &str, status: Result<(), String>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sinks.insert(sink.to_owned(), status);
+    }
+}
+
+/// Whether the daemon is working at all: the event loop is running and the eBPF maps can
+/// be read
+pub fn liveness(backend: &Backend) -> Report {
+    let mut checks = Vec::new();
+
+    let event_loop = match backend.health.inner.lock().unwrap().event_loop {
+        Some(at) if at.elapsed() < EVENT_LOOP_TIMEOUT => Ok(()),
+        Some(at) => Err(format!(
+            "last ran {} ago",
+            humantime::format_duration(Duration::from_secs(at.elapsed().as_secs()))
+        )),
+        None => Err("hasn't started".to_owned()),
+    };
+    checks.push(Check::new("event-loop", event_loop));
+    checks.push(Check::new(
+        "maps",
+        backend
+            .stats
+            .read()
+            .map(|_| ())
+            .map_err(|e| format!("{:#}", e)),
+    ));
+
+    report(checks)
+}
+
+/// Liveness, plus the program attached to every configured interface and every sink
+/// working
+pub fn readiness(backend: &Backend) -> Report {
+    let mut checks = liveness(backend).checks;
+    let inner = backend.health.inner.lock().unwrap();
+
+    for (interface, attached) in &inner.interfaces {
+        let status = if *attached {
+            Ok(())
+        } else {
+            Err("not attached".to_owned())
+        };
+        checks.push(Check::new(format!("interface:{}", interface), status));
+    }
+    for (sink, status) in &inner.sinks {
+        checks.push(Check::new(format!("sink:{}", sink), status.clone()));
+    }
+
+    report(checks)
+}
+
+fn report(checks: Vec<Check>) -> Report {
+    Report {
+        healthy: checks.iter().all(|check| check.healthy),
+        checks,
+    }
+}
@@ -0,0 +1,485 @@
+//! Keeps every DHCP message in an SQLite database for auditing who had which address when,
+//! long after the lease is gone from the live table
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use log::{info, warn};
+use rusqlite::{params, Connection, OpenFlags};
+use serde::Deserialize;
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+use crate::{
+    config::deserialize_duration,
+    iface,
+    mac::MacAddr,
+    message::{DhcpMessage, LeaseTime, INFINITE_LEASE},
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+pub const DEFAULT_PATH: &str = "/var/lib/dhcp-snoop/history.db";
+const DEFAULT_RETENTION: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Messages written in one transaction at most
+const MAX_BATCH: usize = 256;
+const HEALTH_NAME: &str = "history";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        -- Milliseconds since the Unix epoch
+        at INTEGER NOT NULL,
+        interface TEXT NOT NULL,
+        vlan INTEGER,
+        mac TEXT NOT NULL,
+        address TEXT,
+        hostname TEXT,
+        server TEXT,
+        message_type TEXT NOT NULL,
+        xid INTEGER NOT NULL,
+        -- Seconds, as option 51 has it
+        lease_time INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS messages_at ON messages (at);
+    CREATE INDEX IF NOT EXISTS messages_mac ON messages (mac, at);
+    CREATE INDEX IF NOT EXISTS messages_address ON messages (address, at);
+";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HistoryConfig {
+    #[serde(default = "default_path")]
+    pub path: PathBuf,
+    /// Messages older than this are deleted
+    #[serde(
+        default = "default_retention",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub retention: Duration,
+}
+
+impl HistoryConfig {
+    pub fn new(path: PathBuf) -> HistoryConfig {
+        HistoryConfig {
+            path,
+            retention: DEFAULT_RETENTION,
+        }
+    }
+}
+
+impl sinks::Sink for HistoryConfig {
+    fn name(&self) -> String {
+        "history".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(*self, ctx.unredacted_messages(), ctx.backend.clone()).boxed()
+    }
+}
+
+fn default_path() -> PathBuf {
+    PathBuf::from(DEFAULT_PATH)
+}
+
+fn default_retention() -> Duration {
+    DEFAULT_RETENTION
+}
+
+/// Record messages until the channel closes. SQLite blocks, so this runs on a thread of
+/// its own.
+async fn run(
+    config: HistoryConfig,
+    messages: broadcast::Receiver<DhcpMessage>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    tokio::task::spawn_blocking(move || record(config, messages, backend)).await?
+}
+
+fn record(
+    config: HistoryConfig,
+    mut messages: broadcast::Receiver<DhcpMessage>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    let mut db = open(&config.path)?;
+    info!(
+        "recording DHCP messages to {:?}, keeping them for {}",
+        config.path,
+        humantime::format_duration(config.retention)
+    );
+
+    prune(&db, config.retention)?;
+    let mut pruned_at = Instant::now();
+
+    loop {
+        let mut batch = match messages.blocking_recv() {
+            Ok(msg) => vec![msg],
+            Err(RecvError::Lagged(n)) => {
+                warn!("history fell behind, lost {} messages", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        while batch.len() < MAX_BATCH {
+            match messages.try_recv() {
+                Ok(msg) => batch.push(msg),
+                Err(TryRecvError::Lagged(n)) => {
+                    warn!("history fell behind, lost {} messages", n)
+                }
+                Err(_) => break,
+            }
+        }
+
+        let status = insert(&mut db, &batch).map_err(|e| {
+            warn!("failed to record messages to {:?}: {:#}", config.path, e);
+            format!("{:#}", e)
+        });
+        backend.health.set_sink(HEALTH_NAME, status);
+
+        if pruned_at.elapsed() >= PRUNE_INTERVAL {
+            if let Err(e) = prune(&db, config.retention) {
+                warn!("failed to prune {:?}: {:#}", config.path, e);
+            }
+            pruned_at = Instant::now();
+        }
+    }
+}
+
+pub fn open(path: &Path) -> Result<Connection, anyhow::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+    }
+    let db = Connection::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    // Lets `history` read while the daemon writes
+    db.pragma_update(None, "journal_mode", "WAL")?;
+    db.execute_batch(SCHEMA)
+        .with_context(|| format!("failed to create the tables in {:?}", path))?;
+    migrate(&db).with_context(|| format!("failed to update the tables in {:?}", path))?;
+
+    Ok(db)
+}
+
+/// Add the columns databases created by older versions lack
+fn migrate(db: &Connection) -> Result<(), anyhow::Error> {
+    let has_lease_time: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('messages') WHERE name = 'lease_time'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_lease_time {
+        db.execute_batch("ALTER TABLE messages ADD COLUMN lease_time INTEGER")?;
+    }
+
+    Ok(())
+}
+
+fn insert(db: &mut Connection, batch: &[DhcpMessage]) -> Result<(), anyhow::Error> {
+    let tx = db.transaction()?;
+    {
+        let mut insert = tx.prepare_cached(
+            "INSERT INTO messages
+                (at, interface, vlan, mac, address, hostname, server, message_type, xid,
+                 lease_time)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        for msg in batch {
+            insert.execute(params![
+                millis(msg.seen_at),
+                iface::name(msg.ifindex),
+                msg.vlan,
+                msg.client_mac.to_string(),
+                address(msg).map(|address| address.to_string()),
+                msg.hostname,
+                msg.server_id.map(|server| server.to_string()),
+                msg.message_type.to_string(),
+                msg.xid,
+                msg.lease_time.map(|lease_time| match lease_time {
+                    LeaseTime::Seconds(secs) => secs,
+                    LeaseTime::Infinite => INFINITE_LEASE,
+                }),
+            ])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// The address the message is about, what the server hands out or what the client
+/// already has
+fn address(msg: &DhcpMessage) -> Option<Ipv4Addr> {
+    [msg.your_address, msg.client_address]
+        .into_iter()
+        .find(|address| !address.is_unspecified())
+}
+
+fn prune(db: &Connection, retention: Duration) -> Result<(), anyhow::Error> {
+    let cutoff = SystemTime::now()
+        .checked_sub(retention)
+        .unwrap_or(UNIX_EPOCH);
+    let deleted = db
+        .execute("DELETE FROM messages WHERE at < ?", [millis(cutoff)])
+        .context("failed to delete old messages")?;
+    if deleted > 0 {
+        info!("deleted {} messages past retention from history", deleted);
+    }
+
+    Ok(())
+}
+
+pub fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// A time given either as how long ago, e.g. `7d`, or as an RFC 3339 timestamp
+pub fn parse_time(s: &str) -> Result<SystemTime, String> {
+    if let Ok(ago) = humantime::parse_duration(s) {
+        return SystemTime::now()
+            .checked_sub(ago)
+            .ok_or_else(|| format!("{} is too long ago", s));
+    }
+    humantime::parse_rfc3339_weak(s)
+        .map_err(|_| format!("{} is neither a duration nor a timestamp", s))
+}
+
+/// What `history` looks for, everything when nothing is set
+pub struct Filter {
+    pub mac: Option<MacAddr>,
+    pub address: Option<Ipv4Addr>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    /// Of the most recent messages
+    pub limit: u32,
+}
+
+/// Print the recorded messages matching `filter`, oldest first
+pub fn query(path: &Path, filter: &Filter) -> Result<(), anyhow::Error> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("failed to open {:?}", path))?;
+
+    let mut statement = db.prepare(
+        "SELECT at, interface, vlan, mac, address, hostname, server, message_type, xid
+         FROM messages
+         WHERE (?1 IS NULL OR mac = ?1)
+           AND (?2 IS NULL OR address = ?2)
+           AND (?3 IS NULL OR at >= ?3)
+           AND (?4 IS NULL OR at <= ?4)
+         ORDER BY at DESC, id DESC
+         LIMIT ?5",
+    )?;
+    let rows = statement.query_map(
+        params![
+            filter.mac.map(|mac| mac.to_string()),
+            filter.address.map(|address| address.to_string()),
+            filter.since.map(millis),
+            filter.until.map(millis),
+            filter.limit,
+        ],
+        |row| {
+            Ok(Row {
+                at: row.get(0)?,
+                interface: row.get(1)?,
+                vlan: row.get(2)?,
+                mac: row.get(3)?,
+                address: row.get(4)?,
+                hostname: row.get(5)?,
+                server: row.get(6)?,
+                message_type: row.get(7)?,
+                xid: row.get(8)?,
+            })
+        },
+    )?;
+    let mut rows = rows
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to read history")?;
+    rows.reverse();
+
+    println!(
+        "{:<20} {:<12} {:>5} {:<17} {:<15} {:<13} {:<10} {:<15} hostname",
+        "time", "interface", "vlan", "mac", "address", "type", "xid", "server"
+    );
+    for row in rows {
+        let at = UNIX_EPOCH + Duration::from_millis(row.at.max(0) as u64);
+        println!(
+            "{:<20} {:<12} {:>5} {:<17} {:<15} {:<13} {:#010x} {:<15} {}",
+            humantime::format_rfc3339_seconds(at),
+            row.interface,
+            row.vlan
+                .map_or_else(|| "-".to_owned(), |vlan| vlan.to_string()),
+            row.mac,
+            row.address.as_deref().unwrap_or("-"),
+            row.message_type,
+            row.xid,
+            row.server.as_deref().unwrap_or("-"),
+            row.hostname.as_deref().unwrap_or("-")
+        );
+    }
+
+    Ok(())
+}
+
+struct Row {
+    at: i64,
+    interface: String,
+    vlan: Option<u16>,
+    mac: String,
+    address: Option<String>,
+    hostname: Option<String>,
+    server: Option<String>,
+    message_type: String,
+    xid: u32,
+}
+
+/// A lease as the recorded messages have it
+pub struct RecordedLease {
+    pub mac: MacAddr,
+    pub address: Ipv4Addr,
+    pub hostname: Option<String>,
+}
+
+/// The leases active at `at`, going by the ACKs recorded up to then and the RELEASEs,
+/// DECLINEs and NAKs ending them early. ACKs recorded without a lease time, by versions
+/// that didn't keep it or by `backfill`, don't count.
+pub fn leases_at(path: &Path, at: SystemTime) -> Result<Vec<RecordedLease>, anyhow::Error> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("failed to open {:?}", path))?;
+
+    leases_in(&db, at)
+}
+
+fn leases_in(db: &Connection, at: SystemTime) -> Result<Vec<RecordedLease>, anyhow::Error> {
+    let mut statement = db.prepare(
+        "SELECT mac, address, hostname, message_type, at, lease_time
+         FROM messages
+         WHERE at <= ?1
+           AND (message_type IN ('DHCPRELEASE', 'DHCPDECLINE', 'DHCPNAK')
+                OR (message_type = 'DHCPACK' AND address IS NOT NULL
+                    AND lease_time IS NOT NULL))
+         ORDER BY at, id",
+    )?;
+    let at = millis(at);
+    let mut rows = statement.query([at])?;
+
+    // With the time each runs out, `None` for never
+    let mut leases = BTreeMap::new();
+    while let Some(row) = rows.next().context("failed to read history")? {
+        let mac: String = row.get(0)?;
+        let mac: MacAddr = mac.parse().map_err(anyhow::Error::msg)?;
+        let message_type: String = row.get(3)?;
+        if message_type != "DHCPACK" {
+            leases.remove(&mac);
+            continue;
+        }
+
+        let address: String = row.get(1)?;
+        let acked_at: i64 = row.get(4)?;
+        let lease_time: u32 = row.get(5)?;
+        let lease = RecordedLease {
+            mac,
+            address: address
+                .parse()
+                .with_context(|| format!("invalid address {:?} in history", address))?,
+            hostname: row.get(2)?,
+        };
+        let expires_at =
+            (lease_time != INFINITE_LEASE).then(|| acked_at + lease_time as i64 * 1000);
+        leases.insert(mac, (lease, expires_at));
+    }
+
+    Ok(leases
+        .into_values()
+        .filter(|(_, expires_at)| expires_at.map_or(true, |expires_at| expires_at > at))
+        .map(|(lease, _)| lease)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageType;
+
+    const MAC: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 1]);
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 5);
+
+    fn history(messages: &[DhcpMessage]) -> Connection {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.execute_batch(SCHEMA).unwrap();
+        insert(&mut db, messages).unwrap();
+        db
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn ack(secs: u64, lease_time: Option<LeaseTime>) -> DhcpMessage {
+        let mut msg = DhcpMessage::test(MessageType::Ack, MAC);
+        msg.seen_at = at(secs);
+        msg.your_address = ADDRESS;
+        msg.hostname = Some("laptop".to_owned());
+        msg.lease_time = lease_time;
+        msg
+    }
+
+    fn leases(db: &Connection, secs: u64) -> Vec<(MacAddr, Ipv4Addr, Option<String>)> {
+        leases_in(db, at(secs))
+            .unwrap()
+            .into_iter()
+            .map(|lease| (lease.mac, lease.address, lease.hostname))
+            .collect()
+    }
+
+    #[test]
+    fn an_ack_is_a_lease_until_it_runs_out() {
+        let db = history(&[ack(1000, Some(LeaseTime::Seconds(3600)))]);
+
+        assert!(leases(&db, 999).is_empty());
+        assert_eq!(
+            leases(&db, 1000),
+            [(MAC, ADDRESS, Some("laptop".to_owned()))]
+        );
+        assert_eq!(leases(&db, 4599).len(), 1);
+        assert!(leases(&db, 4600).is_empty());
+    }
+
+    #[test]
+    fn release_decline_and_nak_end_the_lease() {
+        for message_type in [MessageType::Release, MessageType::Decline, MessageType::Nak] {
+            let mut end = DhcpMessage::test(message_type, MAC);
+            end.seen_at = at(2000);
+            let db = history(&[ack(1000, Some(LeaseTime::Seconds(3600))), end]);
+
+            assert_eq!(leases(&db, 1999).len(), 1, "{}", message_type);
+            assert!(leases(&db, 2000).is_empty(), "{}", message_type);
+        }
+    }
+
+    #[test]
+    fn infinite_leases_never_run_out() {
+        let db = history(&[ack(1000, Some(LeaseTime::Infinite))]);
+
+        assert_eq!(leases(&db, u32::MAX as u64).len(), 1);
+    }
+
+    #[test]
+    fn acks_without_a_lease_time_are_skipped() {
+        let db = history(&[ack(1000, None)]);
+        assert!(leases(&db, 1000).is_empty());
+
+        // Nor do they stretch the lease of an earlier ACK
+        let db = history(&[ack(1000, Some(LeaseTime::Seconds(3600))), ack(2000, None)]);
+        assert!(leases(&db, 4600).is_empty());
+    }
+}
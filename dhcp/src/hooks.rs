@@ -0,0 +1,413 @@
+//! Runs a script and/or POSTs to a webhook when a lease comes or goes or a rogue server
+//! shows up, e.g. to keep DNS records or firewall allowlists in step with the leases
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use hyper::{
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request, StatusCode,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::{
+    process::Command,
+    sync::{broadcast, mpsc},
+    time::timeout,
+};
+
+use crate::{
+    config::deserialize_duration,
+    delivery::{Failure, RetryPolicy},
+    events::{Change, Event, LeaseAction},
+    ha::Role,
+    iface,
+    secret::{Secret, SecretSource},
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+/// Events waiting for the hook, more than this and they're dropped
+const QUEUE_LEN: usize = 256;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HookConfig {
+    /// Shows up in logs and health checks
+    pub name: String,
+    /// What the hook runs for, everything when not set
+    #[serde(default = "default_triggers")]
+    pub on: Vec<Trigger>,
+    /// POSTed the event as JSON
+    pub url: Option<String>,
+    /// Sent to `url` as a bearer token
+    pub token: Option<SecretSource>,
+    /// Run with the event in `DHCP_*` environment variables
+    pub command: Option<PathBuf>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// For each request and each run of the command, which is killed past it
+    #[serde(default = "default_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+    /// For the webhook, commands aren't run again
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+fn default_triggers() -> Vec<Trigger> {
+    Trigger::ALL.to_vec()
+}
+
+fn default_timeout() -> Duration {
+    DEFAULT_TIMEOUT
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    Bound,
+    Renewed,
+    Released,
+    Expired,
+    RogueOffer,
+    Alert,
+    /// The address, VLAN or fingerprint of a frozen device changed
+    FrozenChanged,
+    /// A server went down or came back up in the server checks
+    ServerCheck,
+}
+
+impl Trigger {
+    const ALL: [Trigger; 8] = [
+        Trigger::Bound,
+        Trigger::Renewed,
+        Trigger::Released,
+        Trigger::Expired,
+        Trigger::RogueOffer,
+        Trigger::Alert,
+        Trigger::FrozenChanged,
+        Trigger::ServerCheck,
+    ];
+
+    /// What `event` triggers, if anything
+    fn of(event: &Event) -> Option<Trigger> {
+        match event {
+            Event::Lease(lease) => Some(match lease.action {
+                LeaseAction::Bound => Trigger::Bound,
+                LeaseAction::Renewed => Trigger::Renewed,
+                LeaseAction::Released => Trigger::Released,
+                LeaseAction::Expired => Trigger::Expired,
+            }),
+            Event::RogueOffer(_) => Some(Trigger::RogueOffer),
+            Event::Alert(_) => Some(Trigger::Alert),
+            Event::Changed(change) if change.frozen => Some(Trigger::FrozenChanged),
+            Event::ServerCheck(_) => Some(Trigger::ServerCheck),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Trigger::Bound => "bound",
+            Trigger::Renewed => "renewed",
+            Trigger::Released => "released",
+            Trigger::Expired => "expired",
+            Trigger::RogueOffer => "rogue-offer",
+            Trigger::Alert => "alert",
+            Trigger::FrozenChanged => "frozen-changed",
+            Trigger::ServerCheck => "server-check",
+        }
+    }
+}
+
+/// A hook with its token resolved
+pub struct HookSink {
+    config: HookConfig,
+    token: Option<Secret>,
+}
+
+impl HookSink {
+    pub fn new(config: HookConfig) -> Result<HookSink, anyhow::Error> {
+        let token = match &config.token {
+            Some(token) => Some(
+                token
+                    .resolve()
+                    .with_context(|| format!("failed to resolve the token of {}", config.name))?,
+            ),
+            None => None,
+        };
+
+        Ok(HookSink { config, token })
+    }
+}
+
+impl sinks::Sink for HookSink {
+    fn name(&self) -> String {
+        format!("hook {}", self.config.name)
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(self.config, self.token, ctx.events(), ctx.backend.clone()).boxed()
+    }
+}
+
+struct Hook {
+    config: HookConfig,
+    authorization: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+    health_name: String,
+    backend: Backend,
+}
+
+/// Run the hook for every event it's configured for. Events are handled one at a time in
+/// the order they happened, the events channel is drained without waiting on the hook.
+async fn run(
+    config: HookConfig,
+    token: Option<Secret>,
+    mut events: broadcast::Receiver<Event>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    if config.url.is_none() && config.command.is_none() {
+        anyhow::bail!("neither a url nor a command is set");
+    }
+    info!(
+        "running hook {} on {}",
+        config.name,
+        config
+            .on
+            .iter()
+            .map(|trigger| trigger.name())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    let hook = Hook {
+        health_name: format!("hook-{}", config.name),
+        authorization: token.map(|token| format!("Bearer {}", token.expose())),
+        client: Client::builder().build(
+            HttpsConnectorBuilder::new()
+                .with_native_roots()
+                .https_or_http()
+                .enable_http1()
+                .build(),
+        ),
+        config,
+        backend: backend.clone(),
+    };
+    let name = hook.config.name.clone();
+    let triggers = hook.config.on.clone();
+    tokio::spawn(hook.process(rx));
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("hook {} fell behind, lost {} events", name, n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        let trigger = match Trigger::of(&event) {
+            Some(trigger) if triggers.contains(&trigger) => trigger,
+            _ => continue,
+        };
+        // The active node of an HA pair reports for both
+        if *backend.role.borrow() != Role::Active {
+            continue;
+        }
+
+        if tx.try_send((trigger, event)).is_err() {
+            warn!("hook {} is too slow, dropping event", name);
+        }
+    }
+}
+
+impl Hook {
+    async fn process(self, mut queue: mpsc::Receiver<(Trigger, Event)>) {
+        while let Some((trigger, event)) = queue.recv().await {
+            let json = match serde_json::to_string(&event) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!(
+                        "failed to serialize event for hook {}: {}",
+                        self.config.name, e
+                    );
+                    continue;
+                }
+            };
+
+            let mut status = Ok(());
+            if let Some(url) = &self.config.url {
+                if let Err(e) = self.config.retry.run(|| self.post(url, &json)).await {
+                    let e = match e {
+                        Failure::Transient(e) | Failure::Permanent(e) => e,
+                    };
+                    warn!("webhook of hook {} failed: {:#}", self.config.name, e);
+                    status = Err(format!("{:#}", e));
+                }
+            }
+            if let Some(command) = &self.config.command {
+                if let Err(e) = self.exec(command, trigger, &event, &json).await {
+                    warn!("command of hook {} failed: {:#}", self.config.name, e);
+                    status = Err(format!("{:#}", e));
+                }
+            }
+            self.backend.health.set_sink(&self.health_name, status);
+        }
+    }
+
+    async fn post(&self, url: &str, json: &str) -> Result<(), Failure> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let request = request
+            .body(Body::from(json.to_owned()))
+            .context("invalid request")
+            .map_err(Failure::Permanent)?;
+
+        let response = timeout(self.config.timeout, self.client.request(request))
+            .await
+            .context("timed out")
+            .map_err(Failure::Transient)?
+            .context("request failed")
+            .map_err(Failure::Transient)?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Transient(anyhow::anyhow!("{}", status)))
+        } else {
+            Err(Failure::Permanent(anyhow::anyhow!("{}", status)))
+        }
+    }
+
+    async fn exec(
+        &self,
+        command: &Path,
+        trigger: Trigger,
+        event: &Event,
+        json: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut child = Command::new(command);
+        child
+            .args(&self.config.args)
+            .envs(environment(trigger, event))
+            .env("DHCP_JSON", json)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let output = timeout(self.config.timeout, child.output())
+            .await
+            .context("timed out, killed it")?
+            .with_context(|| format!("failed to run {:?}", command))?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "{:?} exited with {}: {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The `DHCP_*` variables a command gets, the ones without a value are left unset
+fn environment(trigger: Trigger, event: &Event) -> Vec<(&'static str, String)> {
+    let mut env = vec![("DHCP_EVENT", trigger.name().to_owned())];
+
+    match event {
+        Event::Lease(lease) => {
+            env.push(("DHCP_MAC", lease.mac.to_string()));
+            env.push(("DHCP_ADDRESS", lease.address.to_string()));
+            env.push(("DHCP_INTERFACE", iface::name(lease.ifindex)));
+            env.push((
+                "DHCP_AT",
+                humantime::format_rfc3339_millis(lease.at).to_string(),
+            ));
+            if let Some(hostname) = &lease.hostname {
+                env.push(("DHCP_HOSTNAME", hostname.clone()));
+            }
+            if let Some(vlan) = lease.vlan {
+                env.push(("DHCP_VLAN", vlan.to_string()));
+            }
+            if let Some(server_id) = lease.server_id {
+                env.push(("DHCP_SERVER_ID", server_id.to_string()));
+            }
+            if let Some(lease_time) = lease.lease_time {
+                env.push(("DHCP_LEASE_TIME", lease_time.to_string()));
+            }
+        }
+        Event::RogueOffer(offer) => {
+            env.push(("DHCP_MAC", offer.client_mac.to_string()));
+            env.push(("DHCP_ADDRESS", offer.offered.to_string()));
+            env.push(("DHCP_INTERFACE", iface::name(offer.ifindex)));
+            env.push((
+                "DHCP_AT",
+                humantime::format_rfc3339_millis(offer.at).to_string(),
+            ));
+            env.push(("DHCP_SERVER_ID", offer.server.to_string()));
+        }
+        Event::Changed(change) => {
+            let (what, old, new) = match &change.change {
+                Change::Address { old, new } => ("address", old.to_string(), new.to_string()),
+                Change::Hostname { old, new } => ("hostname", old.clone(), new.clone()),
+                Change::Vlan { old, new } => {
+                    let vlan =
+                        |vlan: &Option<u16>| vlan.map_or_else(String::new, |v| v.to_string());
+                    ("vlan", vlan(old), vlan(new))
+                }
+                Change::Class { old, new } => ("class", old.clone(), new.clone()),
+            };
+            env.push(("DHCP_MAC", change.mac.to_string()));
+            env.push(("DHCP_CHANGE", what.to_owned()));
+            env.push(("DHCP_OLD", old));
+            env.push(("DHCP_NEW", new));
+            env.push((
+                "DHCP_AT",
+                humantime::format_rfc3339_millis(change.at).to_string(),
+            ));
+        }
+        Event::Alert(alert) => {
+            env.push(("DHCP_ALERT", alert.name.clone()));
+            env.push(("DHCP_ALERT_STATE", alert.state.name().to_owned()));
+            env.push(("DHCP_ALERT_RULE", alert.rule.clone()));
+            env.push(("DHCP_ALERT_VALUE", alert.value.to_string()));
+            env.push((
+                "DHCP_AT",
+                humantime::format_rfc3339_millis(alert.at).to_string(),
+            ));
+        }
+        Event::ServerCheck(check) => {
+            env.push(("DHCP_SERVER_ID", check.server.to_string()));
+            env.push(("DHCP_SERVER_STATE", check.state.name().to_owned()));
+            if let Some(reason) = &check.reason {
+                env.push(("DHCP_REASON", reason.clone()));
+            }
+            env.push((
+                "DHCP_AT",
+                humantime::format_rfc3339_millis(check.at).to_string(),
+            ));
+        }
+        _ => {}
+    }
+
+    env
+}
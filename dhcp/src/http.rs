@@ -0,0 +1,90 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use futures::{future::BoxFuture, FutureExt};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{info, warn};
+
+use crate::{
+    health::{self, Report},
+    metrics,
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// `/metrics` and `/health` on `addr`
+pub struct MetricsEndpoint {
+    pub addr: SocketAddr,
+}
+
+impl sinks::Sink for MetricsEndpoint {
+    fn name(&self) -> String {
+        "metrics endpoint".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        serve(self.addr, ctx.backend.clone()).boxed()
+    }
+}
+
+async fn serve(addr: SocketAddr, backend: Backend) -> Result<(), anyhow::Error> {
+    let make_service = make_service_fn(move |_| {
+        let backend = backend.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let backend = backend.clone();
+                async move { Ok::<_, Infallible>(handle(request, &backend)) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!("serving metrics and health checks on http://{}", addr);
+    server.await?;
+
+    Ok(())
+}
+
+fn handle(request: Request<Body>, backend: &Backend) -> Response<Body> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => match metrics::collect(backend) {
+            Ok(families) => Response::builder()
+                .header(CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)
+                .body(Body::from(metrics::render(&families)))
+                .unwrap(),
+            Err(e) => {
+                warn!("failed to collect metrics: {:#}", e);
+                status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        },
+        (&Method::GET, "/healthz") => report(&health::liveness(backend)),
+        (&Method::GET, "/readyz") => report(&health::readiness(backend)),
+        _ => status(StatusCode::NOT_FOUND),
+    }
+}
+
+fn report(report: &Report) -> Response<Body> {
+    let code = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(report).unwrap()))
+        .unwrap()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::from(code.canonical_reason().unwrap_or_default()))
+        .unwrap()
+}
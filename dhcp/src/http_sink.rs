@@ -0,0 +1,207 @@
+//! POSTs every event as JSON to an HTTP endpoint, e.g. a SIEM or an alerting webhook
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use hyper::{
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request, StatusCode,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{interval, timeout},
+};
+
+use crate::{
+    delivery::{BreakerConfig, Delivery, Failure, RetryPolicy, Spool},
+    events::Event,
+    ha::Role,
+    secret::{Secret, SecretSource},
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+/// Events waiting for delivery in memory, more than this and they go to the spool
+const QUEUE_LEN: usize = 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SPOOL_DIR: &str = "/var/lib/dhcp-snoop/spool";
+const DEFAULT_SPOOL_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HttpSinkConfig {
+    /// Shows up in logs and health checks, and names the spool files
+    pub name: String,
+    pub url: String,
+    /// Sent as a bearer token
+    pub token: Option<SecretSource>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub breaker: BreakerConfig,
+    #[serde(default = "default_spool_dir")]
+    pub spool_dir: PathBuf,
+    #[serde(default = "default_spool_bytes")]
+    pub max_spool_bytes: u64,
+}
+
+fn default_spool_dir() -> PathBuf {
+    PathBuf::from(DEFAULT_SPOOL_DIR)
+}
+
+fn default_spool_bytes() -> u64 {
+    DEFAULT_SPOOL_BYTES
+}
+
+/// An HTTP sink with its token resolved
+pub struct HttpSink {
+    config: HttpSinkConfig,
+    token: Option<Secret>,
+}
+
+impl HttpSink {
+    pub fn new(config: HttpSinkConfig) -> Result<HttpSink, anyhow::Error> {
+        let token = match &config.token {
+            Some(token) => Some(
+                token
+                    .resolve()
+                    .with_context(|| format!("failed to resolve the token of {}", config.name))?,
+            ),
+            None => None,
+        };
+
+        Ok(HttpSink { config, token })
+    }
+}
+
+impl sinks::Sink for HttpSink {
+    fn name(&self) -> String {
+        format!("http sink {}", self.config.name)
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(self.config, self.token, ctx.events(), ctx.backend.clone()).boxed()
+    }
+}
+
+struct Sink {
+    config: HttpSinkConfig,
+    token: Option<Secret>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+/// Forward events to the sink, the events channel is drained without waiting on the sink
+async fn run(
+    config: HttpSinkConfig,
+    token: Option<Secret>,
+    mut events: broadcast::Receiver<Event>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    let spool = Arc::new(Spool::new(
+        &config.spool_dir,
+        &config.name,
+        config.max_spool_bytes,
+    )?);
+    let client = Client::builder().build(
+        HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build(),
+    );
+    info!("sending events to {} at {}", config.name, config.url);
+    let name = config.name.clone();
+
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    let delivery = Delivery::new(
+        &config.name,
+        config.retry.clone(),
+        config.breaker.clone(),
+        spool.clone(),
+        backend.health.clone(),
+    );
+    let sink = Sink {
+        config,
+        token,
+        client,
+    };
+    tokio::spawn(sink.deliver(delivery, rx));
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("http sink fell behind, lost {} events", n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        // The active node of an HA pair reports for both
+        if *backend.role.borrow() != Role::Active {
+            continue;
+        }
+
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("failed to serialize event for {}: {}", name, e);
+                continue;
+            }
+        };
+        if let Err(mpsc::error::TrySendError::Full(line)) = tx.try_send(line) {
+            spool.push(&line);
+        }
+    }
+}
+
+impl Sink {
+    async fn deliver(self, mut delivery: Delivery, mut queue: mpsc::Receiver<String>) {
+        let mut replay = interval(self.config.breaker.reset_timeout);
+        let send = |line: String| self.send(line);
+
+        loop {
+            tokio::select! {
+                line = queue.recv() => match line {
+                    Some(line) => delivery.push(line, send).await,
+                    None => return,
+                },
+                _ = replay.tick() => delivery.replay(send).await,
+            }
+        }
+    }
+
+    async fn send(&self, line: String) -> Result<(), Failure> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.config.url)
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token.expose()));
+        }
+        let request = request
+            .body(Body::from(line))
+            .context("invalid request")
+            .map_err(Failure::Permanent)?;
+
+        let response = timeout(REQUEST_TIMEOUT, self.client.request(request))
+            .await
+            .context("timed out")
+            .map_err(Failure::Transient)?
+            .context("request failed")
+            .map_err(Failure::Transient)?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Transient(anyhow::anyhow!("{}", status)))
+        } else {
+            Err(Failure::Permanent(anyhow::anyhow!("{}", status)))
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::ffi::{CStr, CString};
+
+/// Name of the interface with index `ifindex`, falls back to the index itself for
+/// interfaces that have gone away
+pub fn name(ifindex: u32) -> String {
+    let mut buf = [0; libc::IF_NAMESIZE];
+
+    let ret = unsafe { libc::if_indextoname(ifindex, buf.as_mut_ptr()) };
+    if ret.is_null() {
+        return format!("if{}", ifindex);
+    }
+
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        ifindex => Some(ifindex),
+    }
+}
@@ -0,0 +1,302 @@
+//! A baseline of the lease times each server hands out, learned from its ACKs. A server
+//! that suddenly hands out leases of a minute where it used to hand out days, or the other
+//! way round, has been misconfigured or taken over.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::Ipv4Addr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    events::LeaseTimeAnomaly,
+    message::{DhcpMessage, LeaseTime, MessageType},
+};
+
+/// ACKs kept per server
+const SAMPLES: usize = 512;
+/// Share of a server's recent leases one has to be close to for it to be usual, low
+/// enough for a pool handing out a minority of the server's leases to count
+const USUAL_SHARE: f64 = 0.05;
+/// A server is reported once in this long at most
+const REPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LeaseTimeConfig {
+    /// How many times shorter or longer than usual a lease has to be to be reported, 1 or
+    /// less turns the check off
+    pub factor: f64,
+    /// ACKs to learn from before a server's leases are checked
+    pub learn: usize,
+}
+
+impl Default for LeaseTimeConfig {
+    fn default() -> Self {
+        LeaseTimeConfig {
+            factor: 4.0,
+            learn: 50,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Baseline {
+    recent: VecDeque<LeaseTime>,
+    reported_at: Option<Instant>,
+}
+
+impl Baseline {
+    fn usual(&self, lease_time: LeaseTime, factor: f64) -> bool {
+        let secs = seconds(lease_time);
+        let close = self
+            .recent
+            .iter()
+            .filter(|&&recent| {
+                let recent = seconds(recent);
+                recent / factor <= secs && secs <= recent * factor
+            })
+            .count();
+
+        close as f64 >= (self.recent.len() as f64 * USUAL_SHARE).max(1.0)
+    }
+
+    fn median(&self) -> LeaseTime {
+        let mut recent: Vec<_> = self.recent.iter().copied().collect();
+        recent.sort_by(|a, b| seconds(*a).total_cmp(&seconds(*b)));
+        recent[recent.len() / 2]
+    }
+}
+
+fn seconds(lease_time: LeaseTime) -> f64 {
+    match lease_time {
+        LeaseTime::Seconds(secs) => secs as f64,
+        LeaseTime::Infinite => f64::INFINITY,
+    }
+}
+
+pub struct LeaseTimes {
+    config: LeaseTimeConfig,
+    servers: HashMap<Ipv4Addr, Baseline>,
+}
+
+impl LeaseTimes {
+    pub fn new(config: LeaseTimeConfig) -> LeaseTimes {
+        LeaseTimes {
+            config,
+            servers: HashMap::new(),
+        }
+    }
+
+    /// Learn from an ACK, `Some` when its lease time is far off what its server usually
+    /// hands out
+    pub fn observe(&mut self, msg: &DhcpMessage) -> Option<LeaseTimeAnomaly> {
+        if self.config.factor <= 1.0 || msg.message_type != MessageType::Ack {
+            return None;
+        }
+        // ACKs to a DHCPINFORM come without a lease time
+        let (server_id, lease_time) = match (msg.server_id, msg.lease_time) {
+            (Some(server_id), Some(lease_time)) => (server_id, lease_time),
+            _ => return None,
+        };
+
+        let baseline = self.servers.entry(server_id).or_default();
+        let unusual = baseline.recent.len() >= self.config.learn.max(1)
+            && !baseline.usual(lease_time, self.config.factor)
+            && baseline
+                .reported_at
+                .map_or(true, |at| at.elapsed() >= REPORT_INTERVAL);
+        let anomaly = unusual.then(|| {
+            baseline.reported_at = Some(Instant::now());
+            LeaseTimeAnomaly {
+                server_id,
+                client_mac: msg.client_mac,
+                address: msg.your_address,
+                lease_time,
+                usual: baseline.median(),
+                ifindex: msg.ifindex,
+                vlan: msg.vlan,
+                at: SystemTime::now(),
+            }
+        });
+
+        // What's unusual now becomes the baseline if the server keeps at it
+        if baseline.recent.len() == SAMPLES {
+            baseline.recent.pop_front();
+        }
+        baseline.recent.push_back(lease_time);
+
+        anomaly
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mac::MacAddr;
+
+    const DAY: LeaseTime = LeaseTime::Seconds(24 * 60 * 60);
+
+    fn ack(server: u8, lease_time: LeaseTime) -> DhcpMessage {
+        let mut msg = DhcpMessage::test(MessageType::Ack, MacAddr([0x02, 0, 0, 0, 0, 1]));
+        msg.server_id = Some(Ipv4Addr::new(10, 0, 0, server));
+        msg.your_address = Ipv4Addr::new(10, 0, 0, 100);
+        msg.lease_time = Some(lease_time);
+        msg
+    }
+
+    /// Lease times with `config`, having learnt `acks` ACKs of a day from server 1
+    fn learnt(config: LeaseTimeConfig, acks: usize) -> LeaseTimes {
+        let mut lease_times = LeaseTimes::new(config);
+        for _ in 0..acks {
+            assert!(lease_times.observe(&ack(1, DAY)).is_none());
+        }
+        lease_times
+    }
+
+    #[test]
+    fn nothing_is_reported_while_learning() {
+        let config = LeaseTimeConfig::default();
+        let mut lease_times = LeaseTimes::new(config.clone());
+        for i in 0..config.learn {
+            // All over the place
+            let lease_time = LeaseTime::Seconds(60 << (i % 16));
+            assert!(lease_times.observe(&ack(1, lease_time)).is_none(), "{}", i);
+        }
+        assert!(lease_times.observe(&ack(1, LeaseTime::Infinite)).is_some());
+    }
+
+    #[test]
+    fn far_off_acks_are_reported() {
+        let config = LeaseTimeConfig::default();
+        for lease_time in [
+            LeaseTime::Seconds(60),
+            LeaseTime::Seconds(0),
+            LeaseTime::Seconds(30 * 24 * 60 * 60),
+            LeaseTime::Infinite,
+        ] {
+            let mut lease_times = learnt(config.clone(), config.learn);
+            let anomaly = lease_times
+                .observe(&ack(1, lease_time))
+                .unwrap_or_else(|| panic!("{:?} wasn't reported", lease_time));
+            assert_eq!(anomaly.server_id, Ipv4Addr::new(10, 0, 0, 1));
+            assert_eq!(anomaly.address, Ipv4Addr::new(10, 0, 0, 100));
+            assert_eq!(anomaly.lease_time, lease_time);
+            assert_eq!(anomaly.usual, DAY);
+        }
+    }
+
+    #[test]
+    fn acks_within_the_factor_are_usual() {
+        let config = LeaseTimeConfig::default();
+        let mut lease_times = learnt(config.clone(), config.learn);
+        for secs in [
+            6 * 60 * 60,
+            12 * 60 * 60,
+            3 * 24 * 60 * 60,
+            4 * 24 * 60 * 60,
+        ] {
+            assert!(
+                lease_times
+                    .observe(&ack(1, LeaseTime::Seconds(secs)))
+                    .is_none(),
+                "{}",
+                secs
+            );
+        }
+    }
+
+    #[test]
+    fn a_pool_with_a_share_of_the_leases_is_usual() {
+        let config = LeaseTimeConfig::default();
+        let mut lease_times = learnt(config.clone(), 56);
+        for _ in 0..4 {
+            lease_times.observe(&ack(1, LeaseTime::Seconds(60 * 60)));
+        }
+        // 4 of 60 are an hour, more than 5%
+        assert!(lease_times
+            .observe(&ack(1, LeaseTime::Seconds(60 * 60)))
+            .is_none());
+    }
+
+    #[test]
+    fn servers_are_reported_once_an_interval() {
+        let config = LeaseTimeConfig::default();
+        let mut lease_times = learnt(config.clone(), config.learn);
+        assert!(lease_times
+            .observe(&ack(1, LeaseTime::Seconds(60)))
+            .is_some());
+        assert!(lease_times
+            .observe(&ack(1, LeaseTime::Seconds(0)))
+            .is_none());
+
+        // Another server is learnt and reported on its own
+        for _ in 0..config.learn {
+            assert!(lease_times.observe(&ack(2, DAY)).is_none());
+        }
+        assert!(lease_times
+            .observe(&ack(2, LeaseTime::Seconds(60)))
+            .is_some());
+    }
+
+    #[test]
+    fn a_new_lease_time_becomes_the_baseline() {
+        let config = LeaseTimeConfig::default();
+        let mut lease_times = learnt(config.clone(), config.learn);
+        let minute = LeaseTime::Seconds(60);
+        assert!(lease_times.observe(&ack(1, minute)).is_some());
+
+        for _ in 0..SAMPLES {
+            lease_times.observe(&ack(1, minute));
+        }
+        // Let the report interval run out
+        let server = Ipv4Addr::new(10, 0, 0, 1);
+        lease_times.servers.get_mut(&server).unwrap().reported_at = None;
+
+        assert!(lease_times.observe(&ack(1, minute)).is_none());
+        let anomaly = lease_times.observe(&ack(1, DAY)).unwrap();
+        assert_eq!(anomaly.usual, minute);
+    }
+
+    #[test]
+    fn only_acks_with_a_lease_time_are_checked() {
+        let config = LeaseTimeConfig::default();
+        let mut lease_times = learnt(config.clone(), config.learn);
+
+        let mut offer = ack(1, LeaseTime::Seconds(60));
+        offer.message_type = MessageType::Offer;
+        assert!(lease_times.observe(&offer).is_none());
+
+        // An ACK to a DHCPINFORM
+        let mut inform = ack(1, DAY);
+        inform.lease_time = None;
+        assert!(lease_times.observe(&inform).is_none());
+
+        let mut anonymous = ack(1, LeaseTime::Seconds(60));
+        anonymous.server_id = None;
+        assert!(lease_times.observe(&anonymous).is_none());
+
+        // None of them were learnt from either
+        assert_eq!(
+            lease_times.servers[&Ipv4Addr::new(10, 0, 0, 1)]
+                .recent
+                .len(),
+            config.learn
+        );
+    }
+
+    #[test]
+    fn a_factor_of_one_turns_the_check_off() {
+        let config = LeaseTimeConfig {
+            factor: 1.0,
+            learn: 1,
+        };
+        let mut lease_times = learnt(config, 10);
+        assert!(lease_times
+            .observe(&ack(1, LeaseTime::Seconds(60)))
+            .is_none());
+        assert!(lease_times.servers.is_empty());
+    }
+}
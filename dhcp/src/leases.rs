@@ -0,0 +1,1115 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bindings::Bindings,
+    clock::{self, BootTime},
+    events::{
+        ConflictKind, ConflictingBinding, LeaseAction, LeaseConflict, LeaseEvent, ReconcileSource,
+        Reconciliation,
+    },
+    iface,
+    mac::MacAddr,
+    message::{DhcpMessage, LeaseTime, MessageType, RelayInfo},
+    redact::Redactor,
+    validate,
+};
+
+/// How long an ACK is remembered by its xid, a second server answering the same
+/// transaction does so within seconds
+const TRANSACTION_TTL: Duration = Duration::from_secs(60);
+/// Past this many remembered ACKs the expired ones are dropped, then the oldest
+const MAX_TRANSACTIONS: usize = 4096;
+/// How long leases that ran out while the machine was suspended are kept after it resumes.
+/// Renewals sent meanwhile were missed, clients that are still around get this long to be
+/// seen again.
+const RESUME_GRACE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub mac: MacAddr,
+    pub address: Ipv4Addr,
+    pub hostname: Option<String>,
+    pub ifindex: u32,
+    pub vlan: Option<u16>,
+    pub server_id: Option<Ipv4Addr>,
+    /// Where the client sits according to option 82, if its traffic was relayed
+    pub relay: Option<RelayInfo>,
+    /// What the client is according to its fingerprint
+    pub class: Option<String>,
+    /// `None` for infinite leases
+    pub expires_at: Option<BootTime>,
+}
+
+impl Lease {
+    pub fn is_active(&self, now: BootTime) -> bool {
+        self.expires_at.map_or(true, |at| at > now)
+    }
+
+    fn event(&self, action: LeaseAction, now: BootTime) -> LeaseEvent {
+        let lease_time = match action {
+            LeaseAction::Bound | LeaseAction::Renewed => self
+                .expires_at
+                .map(|at| at.saturating_duration_since(now).as_secs()),
+            LeaseAction::Released | LeaseAction::Expired => None,
+        };
+
+        LeaseEvent {
+            action,
+            mac: self.mac,
+            address: self.address,
+            hostname: self.hostname.clone(),
+            class: self.class.clone(),
+            ifindex: self.ifindex,
+            vlan: self.vlan,
+            server_id: self.server_id,
+            lease_time,
+            at: SystemTime::now(),
+        }
+    }
+}
+
+/// Which of two bindings for the same address, or the same client, is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// The one running out last, normally the one ACKed most recently
+    #[default]
+    NewestWins,
+    /// The one on a trusted interface, the newest when both or neither are
+    PreferTrustedPort,
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConflictPolicy::NewestWins => "newest-wins",
+            ConflictPolicy::PreferTrustedPort => "prefer-trusted-port",
+        })
+    }
+}
+
+/// What a message did to the lease table
+#[derive(Debug, Default)]
+pub struct Update {
+    pub lease: Option<LeaseEvent>,
+    pub conflicts: Vec<LeaseConflict>,
+    /// Leases on other interfaces dropped for the ACK, or the ACK dropped for them
+    pub reconciliations: Vec<Reconciliation>,
+}
+
+impl From<&Lease> for ConflictingBinding {
+    fn from(lease: &Lease) -> Self {
+        ConflictingBinding {
+            client_mac: lease.mac,
+            address: lease.address,
+            server_id: lease.server_id,
+            ifindex: lease.ifindex,
+            vlan: lease.vlan,
+        }
+    }
+}
+
+impl From<&DhcpMessage> for ConflictingBinding {
+    fn from(msg: &DhcpMessage) -> Self {
+        ConflictingBinding {
+            client_mac: msg.client_mac,
+            address: msg.your_address,
+            server_id: msg.server_id,
+            ifindex: msg.ifindex,
+            vlan: msg.vlan,
+        }
+    }
+}
+
+/// Active bindings learned from the DHCP traffic seen by the eBPF program, mirrored into
+/// the program's `BINDINGS` map for IP Source Guard
+pub struct LeaseTable {
+    leases: HashMap<MacAddr, Lease>,
+    bindings: Bindings,
+    /// For what's logged
+    redactor: Option<Arc<Redactor>>,
+    /// Hostname conflicts that have been reported already, keyed by lowercased hostname.
+    /// A conflict is reported again only when the set of clients claiming it changes.
+    reported_conflicts: HashMap<String, BTreeSet<MacAddr>>,
+    /// Same for addresses ACKed to more than one client
+    reported_duplicates: HashMap<Ipv4Addr, BTreeSet<MacAddr>>,
+    /// Recent ACKs by xid, with when they were seen
+    transactions: HashMap<u32, (ConflictingBinding, Instant)>,
+    /// Time spent suspended as of the last expiry pass
+    suspended: Duration,
+    policy: ConflictPolicy,
+    /// Names of the interfaces DHCP servers may answer on
+    trusted_interfaces: Vec<String>,
+    /// Clients whose ACKs are ignored, leaving IP Source Guard no binding to let them
+    /// through on. Until the daemon restarts.
+    blocked: HashSet<MacAddr>,
+}
+
+impl LeaseTable {
+    pub fn new(
+        bindings: Bindings,
+        redactor: Option<Arc<Redactor>>,
+        policy: ConflictPolicy,
+        trusted_interfaces: Vec<String>,
+    ) -> LeaseTable {
+        LeaseTable {
+            leases: HashMap::new(),
+            bindings,
+            redactor,
+            policy,
+            trusted_interfaces,
+            reported_conflicts: HashMap::new(),
+            reported_duplicates: HashMap::new(),
+            transactions: HashMap::new(),
+            suspended: clock::suspended(),
+            blocked: HashSet::new(),
+        }
+    }
+
+    /// Update the leases from `msg`, returning what changed and the conflicts it revealed.
+    /// `class` is what the client is known to be, ACKs don't carry a fingerprint.
+    pub fn handle(&mut self, msg: &DhcpMessage, class: Option<&str>) -> Update {
+        match msg.message_type {
+            MessageType::Ack => self.bind(msg, class),
+            MessageType::Nak | MessageType::Release => Update {
+                lease: self.unbind(&msg.client_mac),
+                ..Update::default()
+            },
+            _ => Update::default(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Lease> {
+        self.leases.values()
+    }
+
+    pub fn by_address(&self, address: Ipv4Addr) -> Option<&Lease> {
+        self.leases.values().find(|lease| lease.address == address)
+    }
+
+    /// Take over a lease learned elsewhere unless a newer one is known for the client.
+    /// Returns whether the lease was taken.
+    pub fn restore(&mut self, lease: Lease) -> bool {
+        if self.blocked.contains(&lease.mac) {
+            return false;
+        }
+        if let Some(existing) = self.leases.get(&lease.mac) {
+            let newer = match (existing.expires_at, lease.expires_at) {
+                (_, None) => false,
+                (None, Some(_)) => true,
+                (Some(existing), Some(restored)) => existing >= restored,
+            };
+            if newer {
+                return false;
+            }
+        }
+
+        let hostname = lease.hostname.clone();
+        self.insert(lease);
+        if let Some(hostname) = hostname {
+            self.check_hostname(&hostname, None);
+        }
+        true
+    }
+
+    fn bind(&mut self, msg: &DhcpMessage, class: Option<&str>) -> Update {
+        // An ACK to a DHCPINFORM doesn't hand out an address, and no client can hold an
+        // unassignable one if a server tries
+        if msg.your_address.is_unspecified() || !validate::assignable(msg.your_address) {
+            return Update::default();
+        }
+        if self.blocked.contains(&msg.client_mac) {
+            debug!(
+                "ignored the ACK of {} to blocked {}",
+                msg.your_address,
+                self.logged_mac(msg.client_mac)
+            );
+            return Update::default();
+        }
+        let mut conflicts: Vec<LeaseConflict> =
+            [self.check_transaction(msg), self.check_address(msg)]
+                .into_iter()
+                .flatten()
+                .collect();
+
+        let now = BootTime::now();
+        let expires_at = match msg.lease_time {
+            Some(LeaseTime::Seconds(secs)) => Some(now + Duration::from_secs(secs as u64)),
+            Some(LeaseTime::Infinite) => None,
+            // Keep the previous expiry if the server didn't repeat option 51
+            None => self
+                .leases
+                .get(&msg.client_mac)
+                .and_then(|lease| lease.expires_at),
+        };
+
+        let lease = Lease {
+            mac: msg.client_mac,
+            address: msg.your_address,
+            hostname: msg.hostname.clone(),
+            ifindex: msg.ifindex,
+            vlan: msg.vlan,
+            server_id: msg.server_id,
+            relay: msg.relay.clone(),
+            class: class.map(str::to_owned),
+            expires_at,
+        };
+
+        let reconciliations = match self.settle_interfaces(&lease, now) {
+            Ok(reconciliations) => reconciliations,
+            Err(reconciliation) => {
+                return Update {
+                    lease: None,
+                    conflicts,
+                    reconciliations: vec![reconciliation],
+                }
+            }
+        };
+
+        info!(
+            "{} bound to {} hostname = {}{}",
+            self.logged_mac(lease.mac),
+            lease.address,
+            self.logged_hostname(lease.hostname.as_deref()),
+            lease.relay.as_ref().map(describe_relay).unwrap_or_default()
+        );
+
+        let renewed = self.leases.get(&lease.mac).map_or(false, |previous| {
+            previous.address == lease.address && previous.is_active(now)
+        });
+        let action = if renewed {
+            LeaseAction::Renewed
+        } else {
+            LeaseAction::Bound
+        };
+        let event = lease.event(action, now);
+        let previous = self.insert(lease);
+
+        if let Some(hostname) = previous.and_then(|lease| lease.hostname) {
+            self.check_hostname(&hostname, None);
+        }
+        if let Some(hostname) = msg.hostname.as_deref() {
+            conflicts.extend(self.check_hostname(hostname, Some((msg.client_mac, msg.xid))));
+        }
+
+        Update {
+            lease: Some(event),
+            conflicts,
+            reconciliations,
+        }
+    }
+
+    /// Apply the policy to the active leases on `lease`'s address held on other
+    /// interfaces. Those dropped for it are reported, or `Err` when `lease` itself loses.
+    fn settle_interfaces(
+        &mut self,
+        lease: &Lease,
+        now: BootTime,
+    ) -> Result<Vec<Reconciliation>, Reconciliation> {
+        let rivals: Vec<Lease> = self
+            .leases
+            .values()
+            .filter(|rival| {
+                rival.address == lease.address
+                    && rival.mac != lease.mac
+                    && rival.ifindex != lease.ifindex
+                    && rival.is_active(now)
+            })
+            .cloned()
+            .collect();
+        if let Some(rival) = rivals.iter().find(|rival| !self.prefers(lease, rival)) {
+            return Err(self.reconciliation(ReconcileSource::Interfaces, rival, lease));
+        }
+
+        let mut reconciliations = Vec::new();
+        for rival in rivals {
+            if let Some(hostname) = self.remove(&rival.mac).and_then(|rival| rival.hostname) {
+                self.check_hostname(&hostname, None);
+            }
+            reconciliations.push(self.reconciliation(ReconcileSource::Interfaces, lease, &rival));
+        }
+        Ok(reconciliations)
+    }
+
+    /// Restore what a previous run left behind, the leases still in the program's map and
+    /// those in the state file, keeping the one the policy prefers where they disagree
+    pub fn reconcile(&mut self, pinned: Vec<Lease>, stored: Vec<Lease>) -> Vec<Reconciliation> {
+        let mut leases: HashMap<MacAddr, Lease> =
+            stored.into_iter().map(|lease| (lease.mac, lease)).collect();
+        let mut reconciliations = Vec::new();
+
+        'pinned: for lease in pinned {
+            if let Some(stored) = leases.get_mut(&lease.mac) {
+                // The same binding, the state file knows more about it but the map may
+                // have seen a renewal since it was written
+                if stored.address == lease.address {
+                    if self.prefers(&lease, stored) {
+                        stored.expires_at = lease.expires_at;
+                    }
+                    continue;
+                }
+            }
+
+            let rivals: Vec<MacAddr> = leases
+                .values()
+                .filter(|stored| stored.mac == lease.mac || stored.address == lease.address)
+                .map(|stored| stored.mac)
+                .collect();
+            for mac in &rivals {
+                if !self.prefers(&lease, &leases[mac]) {
+                    reconciliations.push(self.reconciliation(
+                        ReconcileSource::Startup,
+                        &leases[mac],
+                        &lease,
+                    ));
+                    continue 'pinned;
+                }
+            }
+            for mac in rivals {
+                if let Some(stored) = leases.remove(&mac) {
+                    reconciliations.push(self.reconciliation(
+                        ReconcileSource::Startup,
+                        &lease,
+                        &stored,
+                    ));
+                }
+            }
+            leases.insert(lease.mac, lease);
+        }
+
+        for lease in leases.into_values() {
+            self.restore(lease);
+        }
+        // The map was left as it was until now, for the program to go on enforcing it
+        self.bindings.retain(self.leases.values());
+        reconciliations
+    }
+
+    /// Whether the policy keeps `a` over `b`
+    fn prefers(&self, a: &Lease, b: &Lease) -> bool {
+        if self.policy == ConflictPolicy::PreferTrustedPort {
+            match (self.is_trusted(a.ifindex), self.is_trusted(b.ifindex)) {
+                (true, false) => return true,
+                (false, true) => return false,
+                _ => {}
+            }
+        }
+        match (a.expires_at, b.expires_at) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(a), Some(b)) => a >= b,
+        }
+    }
+
+    fn is_trusted(&self, ifindex: u32) -> bool {
+        let name = iface::name(ifindex);
+        self.trusted_interfaces
+            .iter()
+            .any(|trusted| *trusted == name)
+    }
+
+    fn reconciliation(
+        &self,
+        source: ReconcileSource,
+        kept: &Lease,
+        dropped: &Lease,
+    ) -> Reconciliation {
+        Reconciliation {
+            source,
+            policy: self.policy,
+            kept: ConflictingBinding::from(kept),
+            dropped: ConflictingBinding::from(dropped),
+            at: SystemTime::now(),
+        }
+    }
+
+    /// Remember the ACK by its xid, and whether another server ACKed the same transaction
+    /// with a different address
+    fn check_transaction(&mut self, msg: &DhcpMessage) -> Option<LeaseConflict> {
+        let now = Instant::now();
+        if self.transactions.len() >= MAX_TRANSACTIONS {
+            self.transactions
+                .retain(|_, (_, seen_at)| now.duration_since(*seen_at) < TRANSACTION_TTL);
+        }
+        if self.transactions.len() >= MAX_TRANSACTIONS {
+            let oldest = self
+                .transactions
+                .iter()
+                .min_by_key(|(_, (_, seen_at))| *seen_at)
+                .map(|(xid, _)| *xid);
+            if let Some(xid) = oldest {
+                self.transactions.remove(&xid);
+            }
+        }
+
+        let new = ConflictingBinding::from(msg);
+        let (existing, seen_at) = self.transactions.insert(msg.xid, (new.clone(), now))?;
+        // Retransmissions, and other clients that happened to pick the same xid, are fine
+        if now.duration_since(seen_at) >= TRANSACTION_TTL
+            || existing.client_mac != new.client_mac
+            || existing.server_id == new.server_id
+            || existing.address == new.address
+        {
+            return None;
+        }
+
+        Some(LeaseConflict {
+            conflict: ConflictKind::Transaction,
+            xid: msg.xid,
+            hostname: None,
+            existing,
+            new,
+            at: SystemTime::now(),
+        })
+    }
+
+    /// Whether another client holds an active lease on the address ACKed. Reported once
+    /// for each set of clients holding it.
+    fn check_address(&mut self, msg: &DhcpMessage) -> Option<LeaseConflict> {
+        let now = BootTime::now();
+        let holders: Vec<&Lease> = self
+            .leases
+            .values()
+            .filter(|lease| {
+                lease.address == msg.your_address
+                    && lease.mac != msg.client_mac
+                    && lease.is_active(now)
+            })
+            .collect();
+        let existing = match holders.first() {
+            Some(lease) => ConflictingBinding::from(*lease),
+            None => {
+                self.reported_duplicates.remove(&msg.your_address);
+                return None;
+            }
+        };
+
+        let mut macs: BTreeSet<MacAddr> = holders.iter().map(|lease| lease.mac).collect();
+        macs.insert(msg.client_mac);
+        if self.reported_duplicates.get(&msg.your_address) == Some(&macs) {
+            return None;
+        }
+        self.reported_duplicates.insert(msg.your_address, macs);
+
+        Some(LeaseConflict {
+            conflict: ConflictKind::DuplicateAddress,
+            xid: msg.xid,
+            hostname: None,
+            existing,
+            new: ConflictingBinding::from(msg),
+            at: SystemTime::now(),
+        })
+    }
+
+    fn unbind(&mut self, mac: &MacAddr) -> Option<LeaseEvent> {
+        let lease = self.remove(mac)?;
+        info!("{} released {}", self.logged_mac(lease.mac), lease.address);
+        Some(self.released(lease))
+    }
+
+    /// Drop `mac`'s lease as if the client had released it
+    pub fn flush(&mut self, mac: &MacAddr) -> Option<LeaseEvent> {
+        let lease = self.remove(mac)?;
+        info!(
+            "flushed the lease of {} on {}",
+            self.logged_mac(lease.mac),
+            lease.address
+        );
+        Some(self.released(lease))
+    }
+
+    /// Flush `mac`'s lease and ignore the ones it's ACKed from now on, or go back to
+    /// taking them
+    pub fn set_blocked(&mut self, mac: MacAddr, blocked: bool) -> Option<LeaseEvent> {
+        if !blocked {
+            self.blocked.remove(&mac);
+            return None;
+        }
+        self.blocked.insert(mac);
+        self.flush(&mac)
+    }
+
+    pub fn blocked(&self) -> Vec<MacAddr> {
+        let mut blocked: Vec<MacAddr> = self.blocked.iter().copied().collect();
+        blocked.sort_unstable();
+        blocked
+    }
+
+    fn released(&mut self, lease: Lease) -> LeaseEvent {
+        let event = lease.event(LeaseAction::Released, BootTime::now());
+        if let Some(hostname) = lease.hostname {
+            self.check_hostname(&hostname, None);
+        }
+        event
+    }
+
+    /// Drop leases that ran out without being renewed
+    pub fn expire(&mut self, now: BootTime) -> Vec<LeaseEvent> {
+        self.check_resume(now);
+
+        let expired: Vec<MacAddr> = self
+            .leases
+            .values()
+            .filter(|lease| !lease.is_active(now))
+            .map(|lease| lease.mac)
+            .collect();
+
+        let mut events = Vec::new();
+        for mac in expired {
+            if let Some(lease) = self.remove(&mac) {
+                info!(
+                    "lease for {} on {} expired",
+                    self.logged_mac(lease.mac),
+                    lease.address
+                );
+                events.push(lease.event(LeaseAction::Expired, now));
+                if let Some(hostname) = lease.hostname {
+                    self.check_hostname(&hostname, None);
+                }
+            }
+        }
+        events
+    }
+
+    /// Whether the machine was suspended since the last pass
+    fn check_resume(&mut self, now: BootTime) {
+        let suspended = clock::suspended();
+        let slept = suspended.saturating_sub(self.suspended);
+        self.suspended = suspended;
+        self.resumed(slept, now);
+    }
+
+    /// After `slept` suspended, the leases that ran out in the meantime are held for
+    /// `RESUME_GRACE` instead of all expiring at once, and the program's bindings, whose
+    /// clock stopped, are written again
+    fn resumed(&mut self, slept: Duration, now: BootTime) {
+        // Both clocks are read one after the other, they never quite agree
+        if slept < Duration::from_secs(1) {
+            return;
+        }
+
+        let held_until = now + RESUME_GRACE;
+        let mut held = 0;
+        for lease in self.leases.values_mut() {
+            if matches!(lease.expires_at, Some(at) if at < held_until) {
+                lease.expires_at = Some(held_until);
+                held += 1;
+            }
+        }
+        for lease in self.leases.values() {
+            self.bindings.insert(lease);
+        }
+        info!(
+            "resumed after {} suspended, holding {} leases running out for another {}",
+            humantime::format_duration(Duration::from_secs(slept.as_secs())),
+            held,
+            humantime::format_duration(RESUME_GRACE)
+        );
+    }
+
+    fn insert(&mut self, lease: Lease) -> Option<Lease> {
+        self.bindings.insert(&lease);
+        let address = lease.address;
+        let previous = self.leases.insert(lease.mac, lease)?;
+
+        if previous.address != address {
+            self.bindings.remove(&previous);
+        }
+        Some(previous)
+    }
+
+    fn remove(&mut self, mac: &MacAddr) -> Option<Lease> {
+        let lease = self.leases.remove(mac)?;
+        self.bindings.remove(&lease);
+        Some(lease)
+    }
+
+    /// Whether more than one client holds an active lease under `hostname`. The set of
+    /// clients claiming it is remembered, when the ACK with `xid` adds `mac` to it there's
+    /// a conflict with each of the others.
+    fn check_hostname(
+        &mut self,
+        hostname: &str,
+        claim: Option<(MacAddr, u32)>,
+    ) -> Vec<LeaseConflict> {
+        let key = hostname.to_ascii_lowercase();
+        let now = BootTime::now();
+
+        let claimants: Vec<&Lease> = self
+            .leases
+            .values()
+            .filter(|lease| lease.is_active(now))
+            .filter(|lease| {
+                lease
+                    .hostname
+                    .as_deref()
+                    .map_or(false, |h| h.eq_ignore_ascii_case(hostname))
+            })
+            .collect();
+
+        let macs: BTreeSet<MacAddr> = claimants.iter().map(|lease| lease.mac).collect();
+        if macs.len() < 2 {
+            self.reported_conflicts.remove(&key);
+            return Vec::new();
+        }
+        let reported = self
+            .reported_conflicts
+            .insert(key, macs)
+            .unwrap_or_default();
+
+        let (mac, xid) = match claim {
+            Some((mac, xid)) if !reported.contains(&mac) => (mac, xid),
+            _ => return Vec::new(),
+        };
+        let new = match claimants.iter().find(|lease| lease.mac == mac) {
+            Some(lease) => ConflictingBinding::from(*lease),
+            None => return Vec::new(),
+        };
+        claimants
+            .iter()
+            .filter(|lease| lease.mac != mac)
+            .map(|lease| LeaseConflict {
+                conflict: ConflictKind::DuplicateHostname,
+                xid,
+                hostname: Some(hostname.to_owned()),
+                existing: ConflictingBinding::from(*lease),
+                new: new.clone(),
+                at: SystemTime::now(),
+            })
+            .collect()
+    }
+
+    /// The logs are often shipped off the host, like the exported events
+    fn logged_mac(&self, mac: MacAddr) -> MacAddr {
+        self.redactor
+            .as_ref()
+            .map_or(mac, |redactor| redactor.mac(mac))
+    }
+
+    fn logged_hostname<'a>(&self, hostname: Option<&'a str>) -> &'a str {
+        match hostname {
+            Some(_)
+                if self
+                    .redactor
+                    .as_ref()
+                    .map_or(false, |r| r.drops_hostnames()) =>
+            {
+                "<redacted>"
+            }
+            Some(hostname) => hostname,
+            None => "-",
+        }
+    }
+}
+
+fn describe_relay(relay: &RelayInfo) -> String {
+    let mut description = format!(" via {}", relay.address);
+    if let Some(circuit_id) = &relay.circuit_id {
+        description += &format!(" circuit-id = {}", circuit_id);
+    }
+    if let Some(remote_id) = &relay.remote_id {
+        description += &format!(" remote-id = {}", remote_id);
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 1]);
+    const B: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 2]);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn table(policy: ConflictPolicy, trusted_interfaces: Vec<String>) -> LeaseTable {
+        LeaseTable::new(Bindings::in_memory(), None, policy, trusted_interfaces)
+    }
+
+    /// An hour long lease of `address` for `mac`
+    fn ack(mac: MacAddr, address: Ipv4Addr, hostname: Option<&str>) -> DhcpMessage {
+        let mut msg = DhcpMessage::test(MessageType::Ack, mac);
+        msg.your_address = address;
+        msg.server_id = Some(SERVER);
+        msg.lease_time = Some(LeaseTime::Seconds(3600));
+        msg.hostname = hostname.map(str::to_owned);
+        msg
+    }
+
+    /// A lease running out in `secs`, `None` for an infinite one
+    fn lease(mac: MacAddr, address: Ipv4Addr, ifindex: u32, secs: Option<u64>) -> Lease {
+        Lease {
+            mac,
+            address,
+            hostname: None,
+            ifindex,
+            vlan: None,
+            server_id: Some(SERVER),
+            relay: None,
+            class: None,
+            expires_at: secs.map(|secs| BootTime::now() + Duration::from_secs(secs)),
+        }
+    }
+
+    fn kinds(conflicts: &[LeaseConflict]) -> Vec<ConflictKind> {
+        conflicts.iter().map(|conflict| conflict.conflict).collect()
+    }
+
+    #[test]
+    fn two_clients_with_one_hostname_conflict_once() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let first = leases.handle(&ack(A, Ipv4Addr::new(10, 0, 0, 5), Some("laptop")), None);
+        assert!(first.conflicts.is_empty());
+
+        let second = leases.handle(&ack(B, Ipv4Addr::new(10, 0, 0, 6), Some("LAPTOP")), None);
+        assert_eq!(kinds(&second.conflicts), [ConflictKind::DuplicateHostname]);
+        let conflict = &second.conflicts[0];
+        assert_eq!(conflict.hostname.as_deref(), Some("LAPTOP"));
+        assert_eq!(conflict.existing.client_mac, A);
+        assert_eq!(conflict.new.client_mac, B);
+
+        // Both renewing is the same conflict
+        for mac in [A, B] {
+            let address = leases.leases[&mac].address;
+            let renewal = leases.handle(&ack(mac, address, Some("laptop")), None);
+            assert!(renewal.conflicts.is_empty());
+        }
+    }
+
+    #[test]
+    fn a_release_ends_the_conflict() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        leases.handle(&ack(A, Ipv4Addr::new(10, 0, 0, 5), Some("laptop")), None);
+        leases.handle(&ack(B, Ipv4Addr::new(10, 0, 0, 6), Some("laptop")), None);
+
+        leases.handle(&DhcpMessage::test(MessageType::Release, B), None);
+        assert!(leases.reported_conflicts.is_empty());
+
+        // Claiming it again is a new conflict
+        let again = leases.handle(&ack(B, Ipv4Addr::new(10, 0, 0, 6), Some("laptop")), None);
+        assert_eq!(kinds(&again.conflicts), [ConflictKind::DuplicateHostname]);
+    }
+
+    #[test]
+    fn an_expiry_ends_the_conflict() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let mut short = ack(A, Ipv4Addr::new(10, 0, 0, 5), Some("laptop"));
+        short.lease_time = Some(LeaseTime::Seconds(60));
+        leases.handle(&short, None);
+        leases.handle(&ack(B, Ipv4Addr::new(10, 0, 0, 6), Some("laptop")), None);
+        assert!(!leases.reported_conflicts.is_empty());
+
+        let expired = leases.expire(BootTime::now() + Duration::from_secs(120));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].mac, A);
+        assert!(leases.reported_conflicts.is_empty());
+    }
+
+    #[test]
+    fn a_renewal_is_not_a_conflict() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let msg = ack(A, Ipv4Addr::new(10, 0, 0, 5), Some("laptop"));
+        leases.handle(&msg, None);
+
+        let renewal = leases.handle(&msg, None);
+        assert!(renewal.conflicts.is_empty());
+        assert_eq!(renewal.lease.unwrap().action, LeaseAction::Renewed);
+        assert!(leases.reported_conflicts.is_empty());
+    }
+
+    #[test]
+    fn an_address_acked_to_two_clients_conflicts_once() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let address = Ipv4Addr::new(10, 0, 0, 5);
+        leases.handle(&ack(A, address, None), None);
+
+        let second = leases.handle(&ack(B, address, None), None);
+        assert_eq!(kinds(&second.conflicts), [ConflictKind::DuplicateAddress]);
+        assert_eq!(second.conflicts[0].existing.client_mac, A);
+        assert_eq!(second.conflicts[0].new.client_mac, B);
+
+        let again = leases.handle(&ack(B, address, None), None);
+        assert!(again.conflicts.is_empty());
+    }
+
+    #[test]
+    fn two_servers_acking_one_transaction_conflict() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let first = ack(A, Ipv4Addr::new(10, 0, 0, 5), None);
+        let mut second = ack(A, Ipv4Addr::new(10, 0, 0, 6), None);
+        second.server_id = Some(Ipv4Addr::new(10, 0, 0, 2));
+        leases.handle(&first, None);
+
+        let update = leases.handle(&second, None);
+        assert_eq!(kinds(&update.conflicts), [ConflictKind::Transaction]);
+        assert_eq!(update.conflicts[0].xid, second.xid);
+        assert_eq!(update.conflicts[0].existing.address, first.your_address);
+        assert_eq!(update.conflicts[0].new.address, second.your_address);
+    }
+
+    #[test]
+    fn a_server_repeating_its_ack_is_not_a_conflict() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let msg = ack(A, Ipv4Addr::new(10, 0, 0, 5), None);
+        leases.handle(&msg, None);
+
+        assert!(leases.handle(&msg, None).conflicts.is_empty());
+    }
+
+    #[test]
+    fn transactions_are_forgotten_after_a_while() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let first = ack(A, Ipv4Addr::new(10, 0, 0, 5), None);
+        let mut second = ack(A, Ipv4Addr::new(10, 0, 0, 6), None);
+        second.server_id = Some(Ipv4Addr::new(10, 0, 0, 2));
+        let long_ago = Instant::now() - TRANSACTION_TTL * 2;
+        leases
+            .transactions
+            .insert(first.xid, (ConflictingBinding::from(&first), long_ago));
+
+        assert!(leases.check_transaction(&second).is_none());
+    }
+
+    #[test]
+    fn a_full_transaction_cache_drops_expired_acks_first() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let long_ago = Instant::now() - TRANSACTION_TTL * 2;
+        let binding = ConflictingBinding::from(&ack(B, Ipv4Addr::new(10, 0, 0, 6), None));
+        for xid in 0..MAX_TRANSACTIONS as u32 {
+            let seen_at = if xid % 2 == 0 {
+                long_ago
+            } else {
+                Instant::now()
+            };
+            leases.transactions.insert(xid, (binding.clone(), seen_at));
+        }
+
+        let mut msg = ack(A, Ipv4Addr::new(10, 0, 0, 5), None);
+        msg.xid = u32::MAX;
+        leases.check_transaction(&msg);
+        assert_eq!(leases.transactions.len(), MAX_TRANSACTIONS / 2 + 1);
+        assert!(leases
+            .transactions
+            .keys()
+            .all(|xid| xid % 2 == 1 || *xid == u32::MAX));
+    }
+
+    #[test]
+    fn a_full_transaction_cache_drops_the_oldest_ack() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let earlier = Instant::now() - Duration::from_secs(30);
+        let binding = ConflictingBinding::from(&ack(B, Ipv4Addr::new(10, 0, 0, 6), None));
+        for xid in 0..MAX_TRANSACTIONS as u32 {
+            let seen_at = if xid == 0 { earlier } else { Instant::now() };
+            leases.transactions.insert(xid, (binding.clone(), seen_at));
+        }
+
+        let mut msg = ack(A, Ipv4Addr::new(10, 0, 0, 5), None);
+        msg.xid = u32::MAX;
+        leases.check_transaction(&msg);
+        assert_eq!(leases.transactions.len(), MAX_TRANSACTIONS);
+        assert!(!leases.transactions.contains_key(&0));
+        assert!(leases.transactions.contains_key(&u32::MAX));
+    }
+
+    #[test]
+    fn reconcile_keeps_what_the_policy_prefers() {
+        let (pinned, stored) = (Ipv4Addr::new(10, 0, 0, 5), Ipv4Addr::new(10, 0, 0, 6));
+        // The policy, the pinned and the stored lease's interface and time left, and
+        // which one is kept
+        let cases = [
+            (
+                ConflictPolicy::NewestWins,
+                (1, Some(600)),
+                (2, Some(300)),
+                pinned,
+            ),
+            (
+                ConflictPolicy::NewestWins,
+                (1, Some(300)),
+                (2, Some(600)),
+                stored,
+            ),
+            (
+                ConflictPolicy::NewestWins,
+                (1, None),
+                (2, Some(600)),
+                pinned,
+            ),
+            (
+                ConflictPolicy::NewestWins,
+                (1, Some(600)),
+                (2, None),
+                stored,
+            ),
+            (
+                ConflictPolicy::PreferTrustedPort,
+                (1, Some(300)),
+                (2, Some(600)),
+                pinned,
+            ),
+            (
+                ConflictPolicy::PreferTrustedPort,
+                (2, None),
+                (1, Some(300)),
+                stored,
+            ),
+            (
+                ConflictPolicy::PreferTrustedPort,
+                (2, Some(600)),
+                (2, Some(300)),
+                pinned,
+            ),
+            (
+                ConflictPolicy::PreferTrustedPort,
+                (2, Some(300)),
+                (2, Some(600)),
+                stored,
+            ),
+        ];
+
+        for (policy, (pinned_ifindex, pinned_secs), (stored_ifindex, stored_secs), kept) in cases {
+            let mut leases = table(policy, vec![iface::name(1)]);
+            let reconciliations = leases.reconcile(
+                vec![lease(A, pinned, pinned_ifindex, pinned_secs)],
+                vec![lease(A, stored, stored_ifindex, stored_secs)],
+            );
+            let dropped = if kept == pinned { stored } else { pinned };
+
+            assert_eq!(reconciliations.len(), 1, "{:?}", policy);
+            let reconciliation = &reconciliations[0];
+            assert_eq!(reconciliation.source, ReconcileSource::Startup);
+            assert_eq!(reconciliation.policy, policy);
+            assert_eq!(reconciliation.kept.address, kept, "{:?}", policy);
+            assert_eq!(reconciliation.dropped.address, dropped, "{:?}", policy);
+            assert_eq!(leases.leases[&A].address, kept, "{:?}", policy);
+            assert_eq!(
+                leases.bindings.get(kept).map(|binding| binding.mac),
+                Some(A.0)
+            );
+        }
+    }
+
+    #[test]
+    fn reconcile_takes_a_renewal_from_the_pinned_binding() {
+        let address = Ipv4Addr::new(10, 0, 0, 5);
+        for (pinned_secs, stored_secs) in [(600, 300), (300, 600)] {
+            let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+            let pinned = lease(A, address, 1, Some(pinned_secs));
+            let mut stored = lease(A, address, 1, Some(stored_secs));
+            stored.hostname = Some("laptop".to_owned());
+            let latest = pinned.expires_at.max(stored.expires_at);
+
+            assert!(leases.reconcile(vec![pinned], vec![stored]).is_empty());
+            let lease = &leases.leases[&A];
+            assert_eq!(lease.hostname.as_deref(), Some("laptop"));
+            assert_eq!(lease.expires_at, latest);
+        }
+    }
+
+    #[test]
+    fn reconcile_drops_a_stored_lease_on_a_pinned_address() {
+        let address = Ipv4Addr::new(10, 0, 0, 5);
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let reconciliations = leases.reconcile(
+            vec![lease(A, address, 1, Some(600))],
+            vec![lease(B, address, 1, Some(300))],
+        );
+
+        assert_eq!(reconciliations.len(), 1);
+        assert_eq!(reconciliations[0].kept.client_mac, A);
+        assert_eq!(reconciliations[0].dropped.client_mac, B);
+        assert!(!leases.leases.contains_key(&B));
+    }
+
+    #[test]
+    fn an_address_on_two_interfaces_goes_by_the_policy() {
+        let address = Ipv4Addr::new(10, 0, 0, 5);
+        // The policy and which client keeps the address, A on the trusted interface or B,
+        // ACKed last, on another one
+        for (policy, winner, loser) in [
+            (ConflictPolicy::NewestWins, B, A),
+            (ConflictPolicy::PreferTrustedPort, A, B),
+        ] {
+            let mut leases = table(policy, vec![iface::name(1)]);
+            leases.handle(&ack(A, address, None), None);
+            let mut msg = ack(B, address, None);
+            msg.ifindex = 2;
+            let update = leases.handle(&msg, None);
+
+            assert_eq!(update.lease.is_some(), winner == B, "{:?}", policy);
+            assert_eq!(update.reconciliations.len(), 1, "{:?}", policy);
+            let reconciliation = &update.reconciliations[0];
+            assert_eq!(reconciliation.source, ReconcileSource::Interfaces);
+            assert_eq!(reconciliation.kept.client_mac, winner, "{:?}", policy);
+            assert_eq!(reconciliation.dropped.client_mac, loser, "{:?}", policy);
+            assert!(leases.leases.contains_key(&winner));
+            assert!(!leases.leases.contains_key(&loser));
+            assert_eq!(
+                leases.bindings.get(address).map(|binding| binding.mac),
+                Some(winner.0)
+            );
+        }
+    }
+
+    #[test]
+    fn leases_running_out_while_suspended_are_held() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let (short, long, infinite) = (
+            lease(A, Ipv4Addr::new(10, 0, 0, 5), 1, Some(60)),
+            lease(B, Ipv4Addr::new(10, 0, 0, 6), 1, Some(3 * 3600)),
+            lease(
+                MacAddr([0x02, 0, 0, 0, 0, 3]),
+                Ipv4Addr::new(10, 0, 0, 7),
+                1,
+                None,
+            ),
+        );
+        let long_expiry = long.expires_at;
+        for lease in [short, long, infinite] {
+            leases.insert(lease);
+        }
+
+        // Woke up an hour later, the short lease ran out meanwhile
+        let now = BootTime::now() + Duration::from_secs(3600);
+        leases.resumed(Duration::from_secs(3600), now);
+
+        let held_until = now + RESUME_GRACE;
+        assert_eq!(leases.leases[&A].expires_at, Some(held_until));
+        assert_eq!(leases.leases[&B].expires_at, long_expiry);
+        assert_eq!(
+            leases.leases[&MacAddr([0x02, 0, 0, 0, 0, 3])].expires_at,
+            None
+        );
+        // Written again with the time the binding has left now, not the minute it had
+        let binding = leases.bindings.get(Ipv4Addr::new(10, 0, 0, 5)).unwrap();
+        assert!(binding.expires_at > clock::monotonic_ns() + 3600 * 1_000_000_000);
+
+        assert!(leases.expire(now).is_empty());
+        let expired = leases.expire(held_until + Duration::from_secs(1));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].mac, A);
+    }
+
+    #[test]
+    fn a_moment_suspended_holds_nothing() {
+        let mut leases = table(ConflictPolicy::NewestWins, Vec::new());
+        let short = lease(A, Ipv4Addr::new(10, 0, 0, 5), 1, Some(60));
+        let expires_at = short.expires_at;
+        leases.insert(short);
+
+        leases.resumed(
+            Duration::from_millis(500),
+            BootTime::now() + Duration::from_secs(120),
+        );
+        assert_eq!(leases.leases[&A].expires_at, expires_at);
+    }
+}
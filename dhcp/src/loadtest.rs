@@ -0,0 +1,288 @@
+//! Synthetic DORA exchanges for finding out how much traffic the snooper keeps up with
+//! before it sits in front of real clients. The frames go out one end of a veth pair with
+//! the daemon attached to the other end as a trusted interface, so it takes them for the
+//! clients and the server of a real network. What the eBPF program counted and the leases
+//! the daemon ended up with are then held against what was sent.
+
+use std::{
+    collections::HashSet,
+    net::Ipv4Addr,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use dhcp_common::{Stat, STAT_COUNT};
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    control::{self, Request, Response},
+    iface,
+    mac::MacAddr,
+    packet::{bootp, frame, PacketSocket, ETH_P_IP},
+};
+
+const LEASE_TIME: u32 = 3600;
+/// Locally administered, the rest of the MAC is the client's number
+const MAC_PREFIX: [u8; 3] = [0x02, 0x4c, 0x54];
+const MAX_CLIENTS: u32 = 1 << 24;
+const TICK: Duration = Duration::from_millis(10);
+/// For the daemon to get through its perf buffers before the counters are read
+const SETTLE: Duration = Duration::from_secs(2);
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPRELEASE: u8 = 7;
+
+const SENT: [Stat; 4] = [Stat::Discover, Stat::Offer, Stat::Request, Stat::Ack];
+
+pub struct Options {
+    /// Where the frames are sent, the peer of the interface the daemon is attached to
+    pub iface: String,
+    /// Exchanges per second
+    pub rate: u32,
+    pub duration: Duration,
+    /// Exchanges go round this many clients, the ones after the first round are
+    /// renewals
+    pub clients: u32,
+    /// Address of the first client, the others follow it
+    pub pool: Ipv4Addr,
+    pub server: Ipv4Addr,
+}
+
+struct Client {
+    mac: MacAddr,
+    address: Ipv4Addr,
+    hostname: String,
+}
+
+impl Client {
+    fn new(n: u32, pool: Ipv4Addr) -> Client {
+        let [_, a, b, c] = n.to_be_bytes();
+        Client {
+            mac: MacAddr([MAC_PREFIX[0], MAC_PREFIX[1], MAC_PREFIX[2], a, b, c]),
+            address: Ipv4Addr::from(u32::from(pool).wrapping_add(n)),
+            hostname: format!("loadtest-{}", n),
+        }
+    }
+}
+
+/// The server side of the exchanges
+struct Server {
+    mac: MacAddr,
+    address: Ipv4Addr,
+}
+
+pub async fn run(socket: &Path, options: Options) -> Result<(), anyhow::Error> {
+    if options.rate == 0 || options.clients == 0 || options.clients > MAX_CLIENTS {
+        anyhow::bail!(
+            "--rate has to be at least 1 and --clients between 1 and {}",
+            MAX_CLIENTS
+        );
+    }
+    let ifindex =
+        iface::index(&options.iface).with_context(|| format!("no interface {}", options.iface))?;
+    let packet = PacketSocket::open(ifindex, ETH_P_IP)?;
+    let server = Server {
+        mac: packet.mac,
+        address: options.server,
+    };
+
+    let before = counters(socket).await?;
+    // New xids on every run, the daemon remembers recent ones
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let total = (options.rate as f64 * options.duration.as_secs_f64()) as u64;
+    let started = Instant::now();
+    let mut ticker = tokio::time::interval(TICK);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut sent = 0;
+
+    while sent < total {
+        ticker.tick().await;
+        let due = ((started.elapsed().as_secs_f64() * options.rate as f64) as u64).min(total);
+        while sent < due {
+            let client = Client::new((sent % options.clients as u64) as u32, options.pool);
+            let xid = seed.wrapping_add((sent as u32).wrapping_mul(0x9e37_79b9));
+            for frame in exchange(&client, &server, xid) {
+                packet
+                    .send(&frame)
+                    .await
+                    .with_context(|| format!("failed to send on {}", options.iface))?;
+            }
+            sent += 1;
+        }
+    }
+    let elapsed = started.elapsed();
+
+    tokio::time::sleep(SETTLE).await;
+    let after = counters(socket).await?;
+    let clients: Vec<Client> = (0..sent.min(options.clients as u64) as u32)
+        .map(|n| Client::new(n, options.pool))
+        .collect();
+    let bound = bound(socket, &clients).await?;
+
+    println!(
+        "sent {} exchanges for {} clients in {:.1}s, {:.0}/s",
+        sent,
+        clients.len(),
+        elapsed.as_secs_f64(),
+        sent as f64 / elapsed.as_secs_f64()
+    );
+    println!("{:<16}{:>12}{:>12}", "", "sent", "counted");
+    for stat in SENT {
+        println!(
+            "{:<16}{:>12}{:>12}",
+            stat.name(),
+            sent,
+            after[stat as usize].saturating_sub(before[stat as usize])
+        );
+    }
+    for stat in Stat::ALL.into_iter().filter(|stat| !SENT.contains(stat)) {
+        let counted = after[stat as usize].saturating_sub(before[stat as usize]);
+        if counted > 0 {
+            println!("{:<16}{:>12}{:>12}", stat.name(), "", counted);
+        }
+    }
+    println!(
+        "{} of {} clients hold their lease in the daemon",
+        bound,
+        clients.len()
+    );
+
+    // Leave the lease table the way it was
+    for client in &clients {
+        packet
+            .send(&release(client, &server, seed))
+            .await
+            .with_context(|| format!("failed to send on {}", options.iface))?;
+    }
+
+    Ok(())
+}
+
+/// The daemon's counters summed over its interfaces
+async fn counters(socket: &Path) -> Result<[u64; STAT_COUNT], anyhow::Error> {
+    let interfaces = match control::request(socket, &Request::Stats).await? {
+        Response::Stats(interfaces) => interfaces,
+        response => anyhow::bail!("unexpected response {:?}", response),
+    };
+
+    let mut total = [0; STAT_COUNT];
+    for interface in &interfaces {
+        for (sum, value) in total.iter_mut().zip(interface.counters) {
+            *sum += value;
+        }
+    }
+    Ok(total)
+}
+
+/// How many of `clients` the daemon has a lease for on the address they were given
+async fn bound(socket: &Path, clients: &[Client]) -> Result<usize, anyhow::Error> {
+    let snapshot = match control::request(socket, &Request::ExportState).await? {
+        Response::State(snapshot) => snapshot,
+        response => anyhow::bail!("unexpected response {:?}", response),
+    };
+
+    let expected: HashSet<(MacAddr, Ipv4Addr)> = clients
+        .iter()
+        .map(|client| (client.mac, client.address))
+        .collect();
+    Ok(snapshot
+        .leases
+        .iter()
+        .filter(|lease| expected.contains(&(lease.mac, lease.address)))
+        .count())
+}
+
+/// DISCOVER, OFFER, REQUEST and ACK, all broadcast
+fn exchange(client: &Client, server: &Server, xid: u32) -> [Vec<u8>; 4] {
+    let broadcast = MacAddr([0xff; 6]);
+    let (any, all) = (Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST);
+    let hostname = client.hostname.as_bytes();
+    let server_id = server.address.octets();
+    let lease_time = LEASE_TIME.to_be_bytes();
+    let mask = [255, 255, 0, 0];
+    let requested = client.address.octets();
+
+    let discover = bootp(
+        1,
+        xid,
+        client.mac,
+        any,
+        any,
+        &[(53, &[DHCPDISCOVER]), (12, hostname), (55, &[1, 3, 6, 15])],
+    );
+    let offer = bootp(
+        2,
+        xid,
+        client.mac,
+        any,
+        client.address,
+        &[
+            (53, &[DHCPOFFER]),
+            (54, &server_id),
+            (51, &lease_time),
+            (1, &mask),
+        ],
+    );
+    let request = bootp(
+        1,
+        xid,
+        client.mac,
+        any,
+        any,
+        &[
+            (53, &[DHCPREQUEST]),
+            (50, &requested),
+            (54, &server_id),
+            (12, hostname),
+            (55, &[1, 3, 6, 15]),
+        ],
+    );
+    let ack = bootp(
+        2,
+        xid,
+        client.mac,
+        any,
+        client.address,
+        &[
+            (53, &[DHCPACK]),
+            (54, &server_id),
+            (51, &lease_time),
+            (1, &mask),
+        ],
+    );
+
+    [
+        frame(client.mac, broadcast, any, all, 68, 67, &discover),
+        frame(server.mac, broadcast, server.address, all, 67, 68, &offer),
+        frame(client.mac, broadcast, any, all, 68, 67, &request),
+        frame(server.mac, broadcast, server.address, all, 67, 68, &ack),
+    ]
+}
+
+/// A RELEASE unicast to the server
+fn release(client: &Client, server: &Server, xid: u32) -> Vec<u8> {
+    let message = bootp(
+        1,
+        xid,
+        client.mac,
+        client.address,
+        Ipv4Addr::UNSPECIFIED,
+        &[(53, &[DHCPRELEASE]), (54, &server.address.octets())],
+    );
+    frame(
+        client.mac,
+        server.mac,
+        client.address,
+        server.address,
+        68,
+        67,
+        &message,
+    )
+}
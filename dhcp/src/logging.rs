@@ -0,0 +1,51 @@
+//! The daemon's own logs, filtered with `RUST_LOG` and written to stderr so they stay out of
+//! the way of `--output json` on stdout
+
+use std::{fmt, io, str::FromStr};
+
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the spans the line was logged in
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => return Err(format!("invalid log format {:?}, expected text or json", s)),
+        })
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+/// Install the subscriber, `log` records from the rest of the daemon and its dependencies
+/// go through it too. Spans log how long they took when they close, so
+/// `RUST_LOG=dhcp=trace` shows where time goes between a sample leaving the kernel and the
+/// sinks
+pub fn init(format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(io::stderr);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
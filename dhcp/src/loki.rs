@@ -0,0 +1,380 @@
+//! Pushes events to Loki, so they can be queried in Grafana next to everything else's logs
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{future::BoxFuture, FutureExt};
+use hyper::{
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request, StatusCode,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+use crate::{
+    delivery::{BreakerConfig, Delivery, Failure, RetryPolicy, Spool},
+    events::Event,
+    ha::Role,
+    message::DhcpMessage,
+    output::MessageRecord,
+    secret::{Secret, SecretSource},
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const HEALTH_SINK: &str = "loki";
+/// Entries waiting for the next push, more than this and they're dropped
+const QUEUE_LEN: usize = 4096;
+/// A push is sent once this many entries are waiting, or every `FLUSH_INTERVAL`
+const BATCH_LEN: usize = 500;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SPOOL_DIR: &str = "/var/lib/dhcp-snoop/spool";
+const DEFAULT_SPOOL_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LokiConfig {
+    /// e.g. http://loki:3100/loki/api/v1/push
+    pub url: String,
+    /// Labels on every stream, e.g. `site = "office"`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Labels taken from each entry
+    #[serde(default = "default_stream_labels")]
+    pub stream_labels: Vec<StreamLabel>,
+    /// Push every DHCP message too, not only events
+    #[serde(default)]
+    pub messages: bool,
+    /// Sent as `X-Scope-OrgID`
+    pub tenant: Option<String>,
+    /// With `password`, for basic auth as Grafana Cloud wants it
+    pub username: Option<String>,
+    /// A bearer token without `username`
+    pub password: Option<SecretSource>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub breaker: BreakerConfig,
+    #[serde(default = "default_spool_dir")]
+    pub spool_dir: PathBuf,
+    #[serde(default = "default_spool_bytes")]
+    pub max_spool_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StreamLabel {
+    /// The interface the entry is about, where there is one
+    Interface,
+    /// The kind of event, `message` for DHCP messages
+    Event,
+    /// DHCPACK and so on, only for DHCP messages
+    Msgtype,
+}
+
+impl StreamLabel {
+    fn name(self) -> &'static str {
+        match self {
+            StreamLabel::Interface => "interface",
+            StreamLabel::Event => "event",
+            StreamLabel::Msgtype => "msgtype",
+        }
+    }
+
+    /// The field of the JSON entry the label's value comes from
+    fn field(self) -> &'static str {
+        match self {
+            StreamLabel::Interface => "interface",
+            StreamLabel::Event => "event",
+            StreamLabel::Msgtype => "message_type",
+        }
+    }
+}
+
+fn default_stream_labels() -> Vec<StreamLabel> {
+    vec![
+        StreamLabel::Interface,
+        StreamLabel::Event,
+        StreamLabel::Msgtype,
+    ]
+}
+
+fn default_spool_dir() -> PathBuf {
+    PathBuf::from(DEFAULT_SPOOL_DIR)
+}
+
+fn default_spool_bytes() -> u64 {
+    DEFAULT_SPOOL_BYTES
+}
+
+struct Entry {
+    labels: BTreeMap<String, String>,
+    /// Nanoseconds since the epoch, as a string the way Loki wants it
+    timestamp: String,
+    line: String,
+}
+
+#[derive(Serialize)]
+struct Push<'a> {
+    streams: Vec<Stream<'a>>,
+}
+
+#[derive(Serialize)]
+struct Stream<'a> {
+    stream: &'a BTreeMap<String, String>,
+    values: Vec<[&'a str; 2]>,
+}
+
+/// The Loki sink with its password resolved
+pub struct LokiSink {
+    config: LokiConfig,
+    password: Option<Secret>,
+}
+
+impl LokiSink {
+    pub fn new(config: LokiConfig) -> Result<LokiSink, anyhow::Error> {
+        let password = match &config.password {
+            Some(password) => Some(
+                password
+                    .resolve()
+                    .context("failed to resolve the Loki password")?,
+            ),
+            None => None,
+        };
+
+        Ok(LokiSink { config, password })
+    }
+}
+
+impl sinks::Sink for LokiSink {
+    fn name(&self) -> String {
+        "Loki sink".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        let (messages, events) = (ctx.messages(), ctx.events());
+        run(
+            self.config,
+            self.password,
+            messages,
+            events,
+            ctx.backend.clone(),
+        )
+        .boxed()
+    }
+}
+
+struct Sink {
+    config: LokiConfig,
+    authorization: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+/// Forward events, and messages if configured, to Loki in batches
+async fn run(
+    config: LokiConfig,
+    password: Option<Secret>,
+    mut messages: broadcast::Receiver<DhcpMessage>,
+    mut events: broadcast::Receiver<Event>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    let spool = Spool::new(&config.spool_dir, "loki", config.max_spool_bytes)?;
+    let client = Client::builder().build(
+        HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build(),
+    );
+    let authorization = match (&config.username, &password) {
+        (Some(username), Some(password)) => Some(format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", username, password.expose()))
+        )),
+        (None, Some(token)) => Some(format!("Bearer {}", token.expose())),
+        (Some(_), None) => anyhow::bail!("loki username is set without a password"),
+        (None, None) => None,
+    };
+    info!("pushing events to Loki at {}", config.url);
+
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    let delivery = Delivery::new(
+        HEALTH_SINK,
+        config.retry.clone(),
+        config.breaker.clone(),
+        Arc::new(spool),
+        backend.health.clone(),
+    );
+    let sink = Sink {
+        config: config.clone(),
+        authorization,
+        client,
+    };
+    tokio::spawn(sink.deliver(delivery, rx));
+
+    loop {
+        let entry = tokio::select! {
+            msg = messages.recv() => match msg {
+                Ok(msg) if config.messages => {
+                    match serde_json::to_value(MessageRecord::from(&msg)) {
+                        Ok(entry) => entry,
+                        Err(e) => {
+                            warn!("failed to serialize message for Loki: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("loki sink fell behind, lost {} messages", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            event = events.recv() => match event {
+                Ok(event) => match serde_json::to_value(&event) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("failed to serialize event for Loki: {}", e);
+                        continue;
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("loki sink fell behind, lost {} events", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        // The active node of an HA pair reports for both
+        if *backend.role.borrow() != Role::Active {
+            continue;
+        }
+
+        if tx.try_send(entry_for(&config, entry)).is_err() {
+            warn!("loki sink is backed up, dropping event");
+        }
+    }
+}
+
+fn entry_for(config: &LokiConfig, entry: Value) -> Entry {
+    let mut labels = config.labels.clone();
+    for label in &config.stream_labels {
+        if let Some(value) = entry.get(label.field()).and_then(Value::as_str) {
+            labels.insert(label.name().to_owned(), value.to_owned());
+        }
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    Entry {
+        labels,
+        timestamp: timestamp.to_string(),
+        line: entry.to_string(),
+    }
+}
+
+/// A push request body carrying `entries`, grouped into streams by label set
+fn encode(entries: &[Entry]) -> Result<String, serde_json::Error> {
+    let mut streams: BTreeMap<&BTreeMap<String, String>, Vec<[&str; 2]>> = BTreeMap::new();
+    for entry in entries {
+        streams
+            .entry(&entry.labels)
+            .or_default()
+            .push([entry.timestamp.as_str(), entry.line.as_str()]);
+    }
+
+    serde_json::to_string(&Push {
+        streams: streams
+            .into_iter()
+            .map(|(stream, values)| Stream { stream, values })
+            .collect(),
+    })
+}
+
+impl Sink {
+    async fn deliver(self, mut delivery: Delivery, mut queue: mpsc::Receiver<Entry>) {
+        let send = |body: String| self.send(body);
+        let mut flush = interval(FLUSH_INTERVAL);
+        flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut replay = interval(self.config.breaker.reset_timeout);
+        let mut batch = Vec::new();
+
+        loop {
+            tokio::select! {
+                entry = queue.recv() => match entry {
+                    Some(entry) => {
+                        batch.push(entry);
+                        if batch.len() < BATCH_LEN {
+                            continue;
+                        }
+                    }
+                    None => return,
+                },
+                _ = flush.tick() => {}
+                _ = replay.tick() => {
+                    delivery.replay(send).await;
+                    continue;
+                }
+            }
+            if batch.is_empty() {
+                continue;
+            }
+
+            match encode(&batch) {
+                Ok(body) => delivery.push(body, send).await,
+                Err(e) => warn!("failed to encode Loki push: {}", e),
+            }
+            batch.clear();
+        }
+    }
+
+    async fn send(&self, body: String) -> Result<(), Failure> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.config.url)
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        if let Some(tenant) = &self.config.tenant {
+            request = request.header("X-Scope-OrgID", tenant);
+        }
+        let request = request
+            .body(Body::from(body))
+            .context("invalid request")
+            .map_err(Failure::Permanent)?;
+
+        let response = timeout(REQUEST_TIMEOUT, self.client.request(request))
+            .await
+            .context("timed out")
+            .map_err(Failure::Transient)?
+            .context("request failed")
+            .map_err(Failure::Transient)?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Transient(anyhow::anyhow!("{}", status)))
+        } else {
+            Err(Failure::Permanent(anyhow::anyhow!("{}", status)))
+        }
+    }
+}
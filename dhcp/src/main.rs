@@ -1,47 +1,800 @@
+mod alerts;
+mod attach;
+mod backfill;
+mod bindings;
+mod capture;
+mod cli;
+mod clock;
+mod config;
+mod control;
+mod delivery;
+mod devices;
+mod diff;
+mod events;
+mod fingerprint;
+mod firewall;
+mod fleet;
+mod ha;
+mod health;
+mod history;
+mod hooks;
+mod http;
+mod http_sink;
+mod iface;
+mod lease_times;
+mod leases;
+mod loadtest;
+mod logging;
+mod loki;
+mod mac;
+mod message;
+mod metrics;
+mod netlink;
+mod offload;
+mod options;
+mod output;
+mod packet;
+mod pinned;
+mod presence;
+mod redact;
+mod remote_write;
+mod reorder;
+mod sanity;
+mod secret;
+mod server_check;
+mod settings;
+mod sinks;
+mod snapshot;
+mod state;
+mod stats;
+mod statsd;
+mod store;
+mod syslog;
+mod topology;
+mod trusted;
+mod validate;
+mod wake;
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
 use anyhow::Context;
-use aya::programs::{Xdp, XdpFlags};
+use aya::maps::{AsyncPerfEventArray, HashMap};
+use aya::util::online_cpus;
 use aya::{include_bytes_aligned, Bpf};
 use aya_log::BpfLogger;
-use clap::Parser;
+use bytes::BytesMut;
+use clap::{Parser, Subcommand};
+use dhcp_common::{ArpEvent, CaptureHeader, DhcpEvent, RateLimitEvent, CAPTURE_SNAPLEN};
+use futures::StreamExt;
 use log::{info, warn};
-use tokio::signal;
+use tokio::{
+    signal,
+    sync::{broadcast, mpsc, watch},
+};
+use tracing::Instrument;
+
+use crate::{
+    attach::{self, Attachments, Enforcement, Mode},
+    bindings::Bindings,
+    capture::{CaptureConfig, Frame},
+    config::{Config, InterfaceConfig},
+    events::{ArpRejected, RateLimited},
+    fingerprint::FingerprintDb,
+    ha::Role,
+    health::Health,
+    history::{Filter, HistoryConfig},
+    logging::LogFormat,
+    mac::MacAddr,
+    message::DhcpMessage,
+    netlink::LinkEvent,
+    offload::VlanOffload,
+    output::OutputFormat,
+    redact::Redactor,
+    remote_write::RemoteWriteConfig,
+    settings::Setting,
+    sinks::SinkContext,
+    state::{Backend, SharedState, State},
+    stats::Stats,
+    statsd::StatsdConfig,
+    syslog::SyslogConfig,
+    topology::GraphFormat,
+    trusted::TrustedServers,
+};
 
 #[derive(Debug, Parser)]
 struct Opt {
-    #[clap(short, long, default_value = "enp7s0")]
-    iface: String,
+    /// Control socket of the daemon
+    #[clap(long, global = true, default_value = control::DEFAULT_SOCKET)]
+    control_socket: PathBuf,
+    /// Format of the daemon's own logs on stderr, text or json
+    #[clap(long, global = true, default_value_t)]
+    log_format: LogFormat,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Attach to an interface and snoop DHCP traffic
+    Run(RunOpt),
+    /// Show the relay agent port a device was last seen behind
+    Locate {
+        mac: MacAddr,
+    },
+    /// Send a Wake-on-LAN magic packet to a device by MAC or hostname, on the interface and
+    /// VLAN it was last seen on
+    Wake {
+        device: String,
+    },
+    /// Leave a note on a device, or clear it when none is given
+    Note {
+        mac: MacAddr,
+        note: Option<String>,
+    },
+    /// Alert on any change to a device's address, VLAN or fingerprint, for servers and
+    /// printers that should never move
+    Freeze {
+        mac: MacAddr,
+    },
+    Unfreeze {
+        mac: MacAddr,
+    },
+    /// Drop a device's lease, and the binding IP Source Guard lets it through on, as if it
+    /// had released it
+    Flush {
+        mac: MacAddr,
+    },
+    /// Flush a device's lease and ignore the ones it's handed until it's unblocked, keeping
+    /// it off untrusted interfaces with IP Source Guard. Lasts until the daemon restarts.
+    Block {
+        mac: MacAddr,
+    },
+    Unblock {
+        mac: MacAddr,
+    },
+    /// List the blocked devices
+    Blocked,
+    /// Print the eBPF program's per interface counters
+    Stats,
+    /// Print how many ARPs Dynamic ARP Inspection dropped from each sender MAC
+    ArpRejects,
+    /// Write the daemon's leases, devices, allowlists and counters to a file, `-` for stdout
+    ExportState {
+        path: PathBuf,
+    },
+    /// Merge a file written by export-state into the running daemon
+    ImportState {
+        path: PathBuf,
+    },
+    /// Show the devices that appeared, disappeared or changed address between two
+    /// export-state snapshots, or between one and the running daemon
+    Diff {
+        from: Option<PathBuf>,
+        /// The running daemon when left out
+        to: Option<PathBuf>,
+        /// Instead of a snapshot, compare the running daemon with the leases `[history]`
+        /// had as active this long ago, e.g. 1h, or at an RFC 3339 timestamp
+        #[clap(long, value_parser = history::parse_time, conflicts_with = "from")]
+        since: Option<SystemTime>,
+        /// The database the daemon records to
+        #[clap(long, default_value = history::DEFAULT_PATH)]
+        db: PathBuf,
+    },
+    /// Print the relay ports, relays, interfaces, VLANs and servers the active leases went
+    /// through, with client counts, as a Graphviz or JSON graph
+    Topology {
+        /// An export-state snapshot instead of the running daemon
+        snapshot: Option<PathBuf>,
+        /// dot or json
+        #[clap(long, default_value = "dot")]
+        format: GraphFormat,
+    },
+    /// Search the messages recorded with `[history]`, e.g. who had an address last
+    /// Tuesday with `--ip 10.0.0.5 --since 7d --until 6d`
+    History {
+        #[clap(long)]
+        mac: Option<MacAddr>,
+        #[clap(long)]
+        ip: Option<Ipv4Addr>,
+        /// How long ago, e.g. 7d, or an RFC 3339 timestamp
+        #[clap(long, value_parser = history::parse_time)]
+        since: Option<SystemTime>,
+        #[clap(long, value_parser = history::parse_time)]
+        until: Option<SystemTime>,
+        /// Show at most this many of the most recent messages
+        #[clap(long, default_value_t = 100)]
+        limit: u32,
+        /// The database the daemon records to
+        #[clap(long, default_value = history::DEFAULT_PATH)]
+        db: PathBuf,
+    },
+    /// Record the DHCP replies versions before `[history]` logged into the history
+    /// database, from a file of their log output or `-` for stdin
+    Backfill {
+        log: PathBuf,
+        /// The interface the old version was attached to
+        #[clap(long)]
+        iface: String,
+        #[clap(long, default_value = history::DEFAULT_PATH)]
+        db: PathBuf,
+    },
+    /// Send synthetic DHCP exchanges out of a test interface and compare what the daemon
+    /// saw with what was sent. The daemon has to be attached, as trusted, to the other end
+    /// of a veth pair.
+    Loadtest {
+        /// The end of the veth pair the daemon isn't attached to
+        #[clap(long)]
+        iface: String,
+        /// DISCOVER/OFFER/REQUEST/ACK exchanges per second
+        #[clap(long, default_value_t = 100)]
+        rate: u32,
+        #[clap(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        duration: Duration,
+        /// Exchanges go round this many simulated clients
+        #[clap(long, default_value_t = 1000)]
+        clients: u32,
+        /// Address handed to the first client, the others get the ones after it
+        #[clap(long, default_value = "10.99.0.10")]
+        pool: Ipv4Addr,
+        /// Address the simulated server answers from
+        #[clap(long, default_value = "10.99.0.1")]
+        server: Ipv4Addr,
+    },
+    /// Read or change the running program's settings through its pinned maps, without
+    /// restarting it
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Print the trusted servers and the settings of every interface
+    Get {
+        #[clap(long)]
+        iface: Option<String>,
+    },
+    /// Change trusted-servers, enforcement, client-rate, client-burst, interface-rate,
+    /// interface-burst or rate-limit-drop. Lasts until the daemon restarts or re-attaches
+    /// to the interface.
+    Set {
+        setting: Setting,
+        value: String,
+        /// Only change the interface given, rather than every interface
+        #[clap(long)]
+        iface: Option<String>,
+    },
+}
+
+#[derive(Debug, Parser)]
+struct RunOpt {
+    /// Configuration file, flags given on the command line are added to it
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+    /// Trusted interface to attach to, repeat for more interfaces
+    #[clap(short, long = "iface")]
+    ifaces: Vec<String>,
+    /// Untrusted interface to attach to, DHCP server messages arriving on it are dropped
+    #[clap(long = "untrusted-iface")]
+    untrusted_ifaces: Vec<String>,
+    /// Enable IP Source Guard on the untrusted interfaces
+    #[clap(long)]
+    source_guard: bool,
+    /// Enable Dynamic ARP Inspection on the untrusted interfaces
+    #[clap(long)]
+    arp_inspection: bool,
+    /// Count and report what would be dropped, but let everything through
+    #[clap(long)]
+    log_only: bool,
+    /// DHCP server allowed to hand out leases, repeat for every server. Offers from any
+    /// other server are reported as rogue. Every server is trusted when none are given.
+    #[clap(long = "trusted-server")]
+    trusted_servers: Vec<Ipv4Addr>,
+    /// Serve Prometheus metrics and health checks on this address, e.g. 0.0.0.0:9376
+    #[clap(long)]
+    metrics_listen: Option<SocketAddr>,
+    /// Push metrics to this Prometheus remote write endpoint, e.g.
+    /// http://mimir:9009/api/v1/push
+    #[clap(long)]
+    remote_write: Option<String>,
+    /// Send metrics to this DogStatsD server, e.g. 127.0.0.1:8125
+    #[clap(long)]
+    statsd: Option<SocketAddr>,
+    /// How to hook into the interfaces: auto, native, skb or tc. Use skb or tc when the
+    /// driver doesn't support XDP
+    #[clap(long)]
+    mode: Option<Mode>,
+    /// When a NIC strips VLAN tags before XDP sees them: warn, attach with tc instead or
+    /// disable the offload while attached
+    #[clap(long, default_value_t)]
+    vlan_offload: VlanOffload,
+    /// text logs events, json writes every DHCP message and event to stdout as one JSON
+    /// object per line
+    #[clap(long)]
+    output: Option<OutputFormat>,
+    /// Hex dump up to this many bytes of DHCP options the parser doesn't know into JSON
+    /// message records, at most 64
+    #[clap(long)]
+    unknown_option_bytes: Option<usize>,
+    /// Write every DHCP frame to this pcapng file, rotated at 16MiB
+    #[clap(long)]
+    capture: Option<PathBuf>,
+    /// Forward events to this syslog collector over UDP, e.g. 127.0.0.1:514
+    #[clap(long)]
+    syslog: Option<SocketAddr>,
+    /// Record every DHCP message to this SQLite database, kept for 90 days
+    #[clap(long)]
+    history: Option<PathBuf>,
+    /// Save leases and devices to this file on exit and every minute, and pick them up
+    /// again on start
+    #[clap(long)]
+    state_file: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let opt = Opt::parse();
 
-    env_logger::init();
+    logging::init(opt.log_format);
 
-    // This will include your eBPF object file as raw bytes at compile-time and load it at
-    // runtime. This approach is recommended for most real-world use cases. If you would
-    // like to specify the eBPF program at runtime rather than at compile-time, you can
-    // reach for `Bpf::load_file` instead.
+    match opt.command {
+        Command::Run(run_opt) => run(run_opt, &opt.control_socket).await,
+        Command::Locate { mac } => cli::locate(&opt.control_socket, mac).await,
+        Command::Wake { device } => wake::wake(&opt.control_socket, &device).await,
+        Command::Note { mac, note } => cli::set_note(&opt.control_socket, mac, note).await,
+        Command::Freeze { mac } => cli::set_frozen(&opt.control_socket, mac, true).await,
+        Command::Unfreeze { mac } => cli::set_frozen(&opt.control_socket, mac, false).await,
+        Command::Flush { mac } => cli::flush(&opt.control_socket, mac).await,
+        Command::Block { mac } => cli::set_blocked(&opt.control_socket, mac, true).await,
+        Command::Unblock { mac } => cli::set_blocked(&opt.control_socket, mac, false).await,
+        Command::Blocked => cli::blocked(&opt.control_socket).await,
+        Command::Stats => cli::stats(&opt.control_socket).await,
+        Command::ArpRejects => cli::arp_rejects(&opt.control_socket).await,
+        Command::ExportState { path } => cli::export_state(&opt.control_socket, &path).await,
+        Command::ImportState { path } => cli::import_state(&opt.control_socket, &path).await,
+        Command::Diff {
+            from,
+            to,
+            since,
+            db,
+        } => {
+            let from = match (from, since) {
+                (Some(path), _) => diff::Source::Snapshot(path),
+                (None, Some(at)) => diff::Source::History { db, at },
+                (None, None) => anyhow::bail!("pass a snapshot to compare with, or --since"),
+            };
+            let to = to.map_or(diff::Source::Daemon, diff::Source::Snapshot);
+            diff::diff(&opt.control_socket, from, to).await
+        }
+        Command::Topology { snapshot, format } => {
+            topology::topology(&opt.control_socket, snapshot.as_deref(), format).await
+        }
+        Command::History {
+            mac,
+            ip,
+            since,
+            until,
+            limit,
+            db,
+        } => history::query(
+            &db,
+            &Filter {
+                mac,
+                address: ip,
+                since,
+                until,
+                limit,
+            },
+        ),
+        Command::Backfill { log, iface, db } => backfill::backfill(&log, &iface, &db),
+        Command::Loadtest {
+            iface,
+            rate,
+            duration,
+            clients,
+            pool,
+            server,
+        } => {
+            let options = loadtest::Options {
+                iface,
+                rate,
+                duration,
+                clients,
+                pool,
+                server,
+            };
+            loadtest::run(&opt.control_socket, options).await
+        }
+        Command::Config { command } => {
+            let bpf = pinned::open(object()?)?;
+            match command {
+                ConfigCommand::Get { iface } => settings::get(&bpf, iface.as_deref()),
+                ConfigCommand::Set {
+                    setting,
+                    value,
+                    iface,
+                } => settings::set(&bpf, setting, &value, iface.as_deref()),
+            }
+        }
+    }
+}
+
+/// The eBPF object for the host's byte order. The program shares its structs with
+/// userspace as they're laid out in memory, so it has to be built for the same byte order
+/// as the host. Both objects are built into the binary, unless one of the `bpfel` and
+/// `bpfeb` features is turned off.
+fn object() -> Result<&'static [u8], anyhow::Error> {
+    let (object, feature) = if cfg!(target_endian = "big") {
+        (big_endian_object(), "bpfeb")
+    } else {
+        (little_endian_object(), "bpfel")
+    };
+
+    object.with_context(|| {
+        format!(
+            "built without the eBPF object for this host's byte order, enable the {} feature",
+            feature
+        )
+    })
+}
+
+// This will include your eBPF object file as raw bytes at compile-time and load it at
+// runtime. This approach is recommended for most real-world use cases. If you would like
+// to specify the eBPF program at runtime rather than at compile-time, you can reach for
+// `BpfLoader::load_file` instead.
+#[cfg(feature = "bpfel")]
+fn little_endian_object() -> Option<&'static [u8]> {
     #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/dhcp"
-    ))?;
+    let object = include_bytes_aligned!("../../target/bpfel-unknown-none/debug/dhcp");
     #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/dhcp"
-    ))?;
+    let object = include_bytes_aligned!("../../target/bpfel-unknown-none/release/dhcp");
+
+    Some(object)
+}
+
+#[cfg(not(feature = "bpfel"))]
+fn little_endian_object() -> Option<&'static [u8]> {
+    None
+}
+
+#[cfg(feature = "bpfeb")]
+fn big_endian_object() -> Option<&'static [u8]> {
+    #[cfg(debug_assertions)]
+    let object = include_bytes_aligned!("../../target/bpfeb-unknown-none/debug/dhcp");
+    #[cfg(not(debug_assertions))]
+    let object = include_bytes_aligned!("../../target/bpfeb-unknown-none/release/dhcp");
+
+    Some(object)
+}
+
+#[cfg(not(feature = "bpfeb"))]
+fn big_endian_object() -> Option<&'static [u8]> {
+    None
+}
+
+async fn run(opt: RunOpt, control_socket: &Path) -> Result<(), anyhow::Error> {
+    let mut config = match &opt.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    for (names, trusted) in [(opt.ifaces, true), (opt.untrusted_ifaces, false)] {
+        config
+            .interfaces
+            .extend(names.into_iter().map(|name| InterfaceConfig {
+                name,
+                trusted,
+                source_guard: opt.source_guard && !trusted,
+                arp_inspection: opt.arp_inspection && !trusted,
+                capture: false,
+                rate_limit: None,
+                vlan_offload: opt.vlan_offload,
+                verify_udp_checksum: false,
+            }));
+    }
+    config.trusted_servers.extend(opt.trusted_servers);
+    if opt.metrics_listen.is_some() {
+        config.metrics_listen = opt.metrics_listen;
+    }
+    if let Some(url) = opt.remote_write {
+        config.remote_write = Some(RemoteWriteConfig::new(url));
+    }
+    if let Some(addr) = opt.statsd {
+        config.statsd = Some(StatsdConfig::new(addr));
+    }
+    if let Some(mode) = opt.mode {
+        config.mode = mode;
+    }
+    if opt.log_only {
+        config.enforcement = Enforcement::LogOnly;
+    }
+    if let Some(output) = opt.output {
+        config.output = output;
+    }
+    if let Some(bytes) = opt.unknown_option_bytes {
+        config.unknown_option_bytes = bytes;
+    }
+    if let Some(path) = opt.capture {
+        config.capture = Some(CaptureConfig::new(path));
+        for interface in &mut config.interfaces {
+            interface.capture = true;
+        }
+    }
+    if let Some(addr) = opt.syslog {
+        config.syslog = Some(SyslogConfig::new(addr));
+    }
+    if let Some(path) = opt.history {
+        config.history = Some(HistoryConfig::new(path));
+    }
+    if opt.state_file.is_some() {
+        config.state_file = opt.state_file;
+    }
+    if config.interfaces.is_empty() {
+        anyhow::bail!("no interfaces to attach to, pass --iface or list them in the config file");
+    }
+    if let Some(interface) = config
+        .interfaces
+        .iter()
+        .find(|interface| interface.trusted && (interface.source_guard || interface.arp_inspection))
+    {
+        anyhow::bail!(
+            "source guard and ARP inspection can't be enabled on trusted interface {}",
+            interface.name
+        );
+    }
+    if config.capture.is_none() && config.interfaces.iter().any(|interface| interface.capture) {
+        anyhow::bail!("interfaces are set to capture but there's no [capture] section");
+    }
+
+    let sinks = sinks::from_config(&config)?;
+    let stored = match &config.state_file {
+        Some(path) => store::load(path)?,
+        None => None,
+    };
+
+    let mut bpf = pinned::load(object()?)?;
     if let Err(e) = BpfLogger::init(&mut bpf) {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let program: &mut Xdp = bpf.program_mut("dhcp").unwrap().try_into()?;
-    program.load()?;
-    program.attach(&opt.iface, XdpFlags::default())
-        .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::SKB_MODE")?;
+    let iface_configs = HashMap::try_from(bpf.map_mut("IFACES")?)?;
+    attach::load(&mut bpf, config.mode, &config.interfaces)?;
+
+    // A member of an HA pair starts out as standby until it has heard from its peer
+    let (role_tx, mut role) = watch::channel(match config.ha {
+        Some(_) => Role::Standby,
+        None => Role::Active,
+    });
+    let health = Arc::new(Health::default());
+    if config.enforcement == Enforcement::LogOnly {
+        info!("log-only, nothing will be dropped");
+    }
+    let mut attachments = Attachments::new(
+        config.mode,
+        config.enforcement,
+        *role.borrow() == Role::Active,
+        config.interfaces.clone(),
+        iface_configs,
+        health.clone(),
+    );
+    attachments.attach_all(&mut bpf);
+    let mut links = Box::pin(netlink::monitor().context("failed to monitor interfaces")?);
+
+    let mut trusted_servers = TrustedServers::new(&bpf)?;
+    trusted_servers
+        .replace(&config.trusted_servers)
+        .context("failed to set the trusted servers")?;
+
+    // Sinks subscribe as they start, sending into channels nobody listens on yet is fine
+    let (events_tx, _) = broadcast::channel(1024);
+    let (messages_tx, _) = broadcast::channel(1024);
+
+    let fingerprints = FingerprintDb::load(config.fingerprints.as_deref())?;
+    let (bindings, pinned_leases) = Bindings::new(&bpf)?;
+    let redactor = Redactor::new(&config.redaction)?.map(Arc::new);
+    let state: SharedState = Arc::new(Mutex::new(State::new(
+        events_tx.clone(),
+        messages_tx.clone(),
+        &config,
+        bindings,
+        trusted_servers,
+        fingerprints,
+        redactor.clone(),
+    )));
+    let backend = Backend {
+        state: state.clone(),
+        stats: Arc::new(Stats::new(&bpf)?),
+        role: role.clone(),
+        health: health.clone(),
+        redactor,
+    };
+    let control = control::Server::bind(control_socket, &config.control)?;
+    tokio::spawn(control.serve(backend.clone()));
+
+    if let Some(ha) = config.ha.clone() {
+        let key = match &ha.key {
+            Some(key) => Some(key.resolve().context("failed to resolve the HA key")?),
+            None => None,
+        };
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            if let Err(e) = ha::run(ha, key, backend, role_tx).await {
+                warn!("HA failed: {:#}", e);
+            }
+        });
+    }
+
+    sinks::spawn_all(
+        sinks,
+        &SinkContext::new(events_tx.clone(), messages_tx.clone(), backend.clone()),
+    );
+
+    // After the sinks have subscribed, for them to hear about the bindings dropped
+    if stored.is_some() || !pinned_leases.is_empty() {
+        let summary = snapshot::restore(&mut state.lock().unwrap(), stored, pinned_leases);
+        info!(
+            "restored {} leases and {} devices",
+            summary.leases, summary.devices
+        );
+    }
+    let (policies_tx, mut policies) = mpsc::channel(4);
+    if let Some(fleet) = config.fleet.clone() {
+        let token = match &fleet.token {
+            Some(token) => Some(
+                token
+                    .resolve()
+                    .context("failed to resolve the fleet token")?,
+            ),
+            None => None,
+        };
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fleet::run(fleet, token, policies_tx, backend).await {
+                warn!("fleet policy pull failed: {:#}", e);
+            }
+        });
+    }
+
+    let (tx, mut rx) = mpsc::channel(1024);
+    if !config.ordering.window.is_zero() {
+        let (ordered_tx, ordered_rx) = mpsc::channel(1024);
+        tokio::spawn(reorder::run(config.ordering.clone(), rx, ordered_tx));
+        rx = ordered_rx;
+    }
+    let (arp_tx, arp_rx) = mpsc::channel(1024);
+    let (rate_tx, rate_rx) = mpsc::channel(1024);
+    tokio::spawn(state::run(state, health, rx, arp_rx, rate_rx));
+
+    if let Some(capture) = config.capture.clone() {
+        let (frames_tx, frames_rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            if let Err(e) = capture::run(capture, frames_rx).await {
+                warn!("capture failed: {:#}", e);
+            }
+        });
+        let size = std::mem::size_of::<CaptureHeader>() + CAPTURE_SNAPLEN as usize;
+        read_perf_samples(&bpf, "CAPTURES", size, frames_tx, Frame::decode)?;
+    }
+
+    read_perf_array::<DhcpEvent, _>(&bpf, "EVENTS", tx, |event| DhcpMessage::from(&event))?;
+    read_perf_array::<ArpEvent, _>(&bpf, "ARP_EVENTS", arp_tx, |event| {
+        ArpRejected::from(&event)
+    })?;
+    read_perf_array::<RateLimitEvent, _>(&bpf, "RATE_EVENTS", rate_tx, |event| {
+        RateLimited::from(&event)
+    })?;
 
     info!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
+    loop {
+        tokio::select! {
+            _ = signal::ctrl_c() => break,
+            Some(link) = links.next() => match link {
+                LinkEvent::Added { name, ifindex } => {
+                    attachments.link_added(&mut bpf, &name, ifindex)
+                }
+                LinkEvent::Removed { name } => attachments.link_removed(&mut bpf, &name),
+            },
+            Some(policy) = policies.recv() => attachments.apply(&policy),
+            Ok(()) = role.changed() => {
+                let active = *role.borrow() == Role::Active;
+                attachments.set_enforcing(active);
+            }
+        }
+    }
     info!("Exiting...");
+    if let Some(path) = &config.state_file {
+        if let Err(e) = store::save(path, &backend) {
+            warn!("failed to save state: {:#}", e);
+        }
+    }
+    attachments.restore_vlan_offload();
+    pinned::unpin();
+
+    Ok(())
+}
+
+/// Read `T`s written by the eBPF program to the perf array `name` on every CPU, decode them
+/// and pass them on
+fn read_perf_array<T, M>(
+    bpf: &Bpf,
+    name: &str,
+    tx: mpsc::Sender<M>,
+    decode: fn(T) -> M,
+) -> Result<(), anyhow::Error>
+where
+    T: Copy + 'static,
+    M: Send + 'static,
+{
+    read_perf_samples(bpf, name, std::mem::size_of::<T>(), tx, move |sample| {
+        if sample.len() < std::mem::size_of::<T>() {
+            return None;
+        }
+        let event = unsafe { (sample.as_ptr() as *const T).read_unaligned() };
+        Some(decode(event))
+    })
+}
+
+/// Like `read_perf_array` for samples of up to `size` bytes that aren't a fixed size
+/// struct. Samples `decode` can't make sense of are skipped.
+fn read_perf_samples<M, F>(
+    bpf: &Bpf,
+    name: &str,
+    size: usize,
+    tx: mpsc::Sender<M>,
+    decode: F,
+) -> Result<(), anyhow::Error>
+where
+    M: Send + 'static,
+    F: Fn(&[u8]) -> Option<M> + Copy + Send + 'static,
+{
+    let mut array = AsyncPerfEventArray::try_from(bpf.map_mut(name)?)?;
+
+    for cpu_id in online_cpus()? {
+        let mut buf = array.open(cpu_id, None)?;
+        let tx = tx.clone();
+        let name = name.to_owned();
+
+        let span = tracing::info_span!("poll", map = %name, cpu = cpu_id);
+        tokio::spawn(
+            async move {
+                let mut buffers = (0..10)
+                    .map(|_| BytesMut::with_capacity(size))
+                    .collect::<Vec<_>>();
+
+                loop {
+                    let events = match buf.read_events(&mut buffers).await {
+                        Ok(events) => events,
+                        Err(e) => {
+                            warn!("failed to read {} on cpu {}: {}", name, cpu_id, e);
+                            return;
+                        }
+                    };
+                    if events.lost > 0 {
+                        warn!("lost {} {} on cpu {}", events.lost, name, cpu_id);
+                    }
+                    tracing::trace!(read = events.read, "read samples");
+
+                    for buf in buffers.iter().take(events.read) {
+                        let decoded = tracing::trace_span!("decode", len = buf.len())
+                            .in_scope(|| decode(buf));
+                        let message = match decoded {
+                            Some(message) => message,
+                            None => continue,
+                        };
+                        if tx.send(message).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            .instrument(span),
+        );
+    }
 
     Ok(())
 }
@@ -0,0 +1,390 @@
+use std::{
+    fmt,
+    net::Ipv4Addr,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use dhcp_common::{
+    DhcpEvent, BOOTREPLY, DHCP_ACK, DHCP_DECLINE, DHCP_DISCOVER, DHCP_INFORM, DHCP_NAK, DHCP_OFFER,
+    DHCP_RELEASE, DHCP_REQUEST,
+};
+
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{mac::MacAddr, options};
+
+/// Lease time value meaning the lease never expires
+pub const INFINITE_LEASE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+    /// Plain BOOTP, without option 53
+    BootRequest,
+    BootReply,
+    Unknown(u8),
+}
+
+impl MessageType {
+    pub fn is_bootp(self) -> bool {
+        matches!(self, MessageType::BootRequest | MessageType::BootReply)
+    }
+}
+
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            DHCP_DISCOVER => MessageType::Discover,
+            DHCP_OFFER => MessageType::Offer,
+            DHCP_REQUEST => MessageType::Request,
+            DHCP_DECLINE => MessageType::Decline,
+            DHCP_ACK => MessageType::Ack,
+            DHCP_NAK => MessageType::Nak,
+            DHCP_RELEASE => MessageType::Release,
+            DHCP_INFORM => MessageType::Inform,
+            v => MessageType::Unknown(v),
+        }
+    }
+}
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageType::Discover => f.write_str("DHCPDISCOVER"),
+            MessageType::Offer => f.write_str("DHCPOFFER"),
+            MessageType::Request => f.write_str("DHCPREQUEST"),
+            MessageType::Decline => f.write_str("DHCPDECLINE"),
+            MessageType::Ack => f.write_str("DHCPACK"),
+            MessageType::Nak => f.write_str("DHCPNAK"),
+            MessageType::Release => f.write_str("DHCPRELEASE"),
+            MessageType::Inform => f.write_str("DHCPINFORM"),
+            MessageType::BootRequest => f.write_str("BOOTREQUEST"),
+            MessageType::BootReply => f.write_str("BOOTREPLY"),
+            MessageType::Unknown(v) => write!(f, "DHCP({})", v),
+        }
+    }
+}
+
+impl Serialize for MessageType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    /// A client's request, to port 67
+    ClientToServer,
+    /// A server's reply, from port 67
+    ServerToClient,
+}
+
+/// The request a server's reply answers, when the eBPF program saw it go by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Answered {
+    pub request: MessageType,
+    /// From the request to the reply, as seen by the program
+    pub response_time: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseTime {
+    Seconds(u32),
+    Infinite,
+}
+
+/// Seconds, or "infinite"
+impl Serialize for LeaseTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            LeaseTime::Seconds(secs) => serializer.serialize_u32(*secs),
+            LeaseTime::Infinite => serializer.serialize_str("infinite"),
+        }
+    }
+}
+
+/// Circuit-id or remote-id from option 82.
+///
+/// These are opaque to DHCP, relays fill them with anything from an interface name to a
+/// binary encoded slot/port/vlan.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AgentId(pub Vec<u8>);
+
+impl AgentId {
+    fn from_event(bytes: &[u8], len: u8) -> Option<AgentId> {
+        let len = (len as usize).min(bytes.len());
+        (len > 0).then(|| AgentId(bytes[..len].to_vec()))
+    }
+}
+
+impl fmt::Display for AgentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.iter().all(|c| c.is_ascii_graphic() || *c == b' ') {
+            return f.write_str(&String::from_utf8_lossy(&self.0));
+        }
+
+        f.write_str("0x")?;
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AgentId {
+    type Err = String;
+
+    /// Inverse of `Display`, ids that aren't printable are written out as 0x prefixed hex
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = match s.strip_prefix("0x") {
+            Some(hex) if hex.len() % 2 == 0 && hex.bytes().all(|c| c.is_ascii_hexdigit()) => hex,
+            _ => return Ok(AgentId(s.as_bytes().to_vec())),
+        };
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map(AgentId)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl Serialize for AgentId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AgentId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Relay agent information attached by the relay that forwarded a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayInfo {
+    /// giaddr
+    pub address: Ipv4Addr,
+    pub circuit_id: Option<AgentId>,
+    pub remote_id: Option<AgentId>,
+}
+
+/// An option the eBPF program doesn't parse, passed on as is for investigating vendor
+/// options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownOption {
+    pub code: u8,
+    /// Of the value in the packet, `data` is shorter when it was cut off
+    pub length: u8,
+    pub data: Vec<u8>,
+}
+
+/// With the option's name and its decoded value next to the hex dump, where they're known
+impl Serialize for UnknownOption {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("UnknownOption", 5)?;
+        s.serialize_field("code", &self.code)?;
+        match options::name(self.code) {
+            Some(name) => s.serialize_field("name", name)?,
+            None => s.skip_field("name")?,
+        }
+        s.serialize_field("length", &self.length)?;
+        let hex: String = self
+            .data
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        s.serialize_field("data", &hex)?;
+        // A value cut short would decode to something it isn't
+        match options::decode(self.code, &self.data) {
+            Some(value) if self.data.len() == self.length as usize => {
+                s.serialize_field("value", &value)?
+            }
+            _ => s.skip_field("value")?,
+        }
+        s.end()
+    }
+}
+
+/// The options in `raw`, as the eBPF program packed them into `unknown_options`
+fn unknown_options(mut raw: &[u8]) -> Vec<UnknownOption> {
+    let mut options = Vec::new();
+
+    while let [code, length, rest @ ..] = raw {
+        let len = (*length as usize).min(rest.len());
+        options.push(UnknownOption {
+            code: *code,
+            length: *length,
+            data: rest[..len].to_vec(),
+        });
+        raw = &rest[len..];
+    }
+
+    options
+}
+
+/// Userspace view of a `DhcpEvent`
+#[derive(Debug, Clone)]
+pub struct DhcpMessage {
+    pub ifindex: u32,
+    /// `None` for untagged frames
+    pub vlan: Option<u16>,
+    pub xid: u32,
+    pub direction: Direction,
+    pub message_type: MessageType,
+    pub client_mac: MacAddr,
+    /// ciaddr
+    pub client_address: Ipv4Addr,
+    pub your_address: Ipv4Addr,
+    pub server_id: Option<Ipv4Addr>,
+    pub lease_time: Option<LeaseTime>,
+    pub hostname: Option<String>,
+    /// `None` when the message wasn't relayed
+    pub relay: Option<RelayInfo>,
+    /// Option 55, in the order the client asked
+    pub parameter_list: Vec<u8>,
+    /// Option 60
+    pub vendor_class: Option<String>,
+    /// What the client is according to its fingerprint, filled in by the state
+    pub class: Option<String>,
+    pub unknown_options: Vec<UnknownOption>,
+    /// `None` for requests, and for replies to requests that didn't go through here
+    pub answered: Option<Answered>,
+    /// When userspace received it
+    pub seen_at: SystemTime,
+    /// CLOCK_MONOTONIC nanoseconds when the eBPF program saw it
+    pub timestamp_ns: u64,
+}
+
+impl DhcpMessage {
+    /// Cut the unknown options down to `max_bytes` of values in total
+    pub fn truncate_unknown_options(&mut self, max_bytes: usize) {
+        let mut left = max_bytes;
+
+        self.unknown_options.retain_mut(|option| {
+            if left == 0 {
+                return false;
+            }
+            option.data.truncate(left);
+            left -= option.data.len();
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+impl DhcpMessage {
+    /// A message of `message_type` for `client_mac` with nothing else in it, for tests to
+    /// fill in what they need
+    pub fn test(message_type: MessageType, client_mac: MacAddr) -> DhcpMessage {
+        let direction = match message_type {
+            MessageType::Offer | MessageType::Ack | MessageType::Nak | MessageType::BootReply => {
+                Direction::ServerToClient
+            }
+            _ => Direction::ClientToServer,
+        };
+
+        DhcpMessage {
+            ifindex: 1,
+            vlan: None,
+            xid: 1,
+            direction,
+            message_type,
+            client_mac,
+            client_address: Ipv4Addr::UNSPECIFIED,
+            your_address: Ipv4Addr::UNSPECIFIED,
+            server_id: None,
+            lease_time: None,
+            hostname: None,
+            relay: None,
+            parameter_list: Vec::new(),
+            vendor_class: None,
+            class: None,
+            unknown_options: Vec::new(),
+            answered: None,
+            seen_at: SystemTime::now(),
+            timestamp_ns: 0,
+        }
+    }
+}
+
+impl From<&DhcpEvent> for DhcpMessage {
+    fn from(event: &DhcpEvent) -> Self {
+        let hostname_len = (event.hostname_len as usize).min(event.hostname.len());
+        let hostname = String::from_utf8_lossy(&event.hostname[..hostname_len])
+            .trim_end_matches('\0')
+            .to_owned();
+
+        let relay = RelayInfo {
+            address: Ipv4Addr::from(event.relay_address),
+            circuit_id: AgentId::from_event(&event.circuit_id, event.circuit_id_len),
+            remote_id: AgentId::from_event(&event.remote_id, event.remote_id_len),
+        };
+        let parameter_list_len =
+            (event.parameter_list_len as usize).min(event.parameter_list.len());
+        let vendor_class_len = (event.vendor_class_len as usize).min(event.vendor_class.len());
+        let vendor_class = String::from_utf8_lossy(&event.vendor_class[..vendor_class_len])
+            .trim_end_matches('\0')
+            .to_owned();
+        let unknown_len = (event.unknown_options_len as usize).min(event.unknown_options.len());
+
+        let relayed = !relay.address.is_unspecified()
+            || relay.circuit_id.is_some()
+            || relay.remote_id.is_some();
+
+        let direction = if event.op == BOOTREPLY {
+            Direction::ServerToClient
+        } else {
+            Direction::ClientToServer
+        };
+        let message_type = match (event.bootp != 0, direction) {
+            (false, _) => event.message_type.into(),
+            (true, Direction::ClientToServer) => MessageType::BootRequest,
+            (true, Direction::ServerToClient) => MessageType::BootReply,
+        };
+        let answered = (event.answered != 0).then(|| Answered {
+            request: match event.request_type {
+                0 => MessageType::BootRequest,
+                request => request.into(),
+            },
+            response_time: Duration::from_micros(event.response_time_us as u64),
+        });
+
+        DhcpMessage {
+            ifindex: event.ifindex,
+            vlan: (event.vlan != 0).then_some(event.vlan),
+            xid: event.xid,
+            direction,
+            message_type,
+            client_mac: MacAddr(event.client_mac),
+            client_address: Ipv4Addr::from(event.client_address),
+            your_address: Ipv4Addr::from(event.your_address),
+            server_id: (event.server_id != 0).then(|| Ipv4Addr::from(event.server_id)),
+            lease_time: (event.has_lease_time != 0).then(|| match event.lease_time {
+                INFINITE_LEASE => LeaseTime::Infinite,
+                secs => LeaseTime::Seconds(secs),
+            }),
+            hostname: (!hostname.is_empty()).then_some(hostname),
+            relay: relayed.then_some(relay),
+            parameter_list: event.parameter_list[..parameter_list_len].to_vec(),
+            vendor_class: (!vendor_class.is_empty()).then_some(vendor_class),
+            class: None,
+            unknown_options: unknown_options(&event.unknown_options[..unknown_len]),
+            answered,
+            seen_at: SystemTime::now(),
+            timestamp_ns: event.timestamp_ns,
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+use dhcp_common::Stat;
+
+use crate::{clock::BootTime, ha::Role, state::Backend};
+
+pub const NAMESPACE: &str = "dhcp_snoop";
+
+/// The families `collect` returns, without `NAMESPACE`. There may be no samples of some.
+pub const FAMILIES: [&str; 17] = [
+    "messages_total",
+    "malformed_packets_total",
+    "rogue_dropped_total",
+    "arp_rejected_total",
+    "rate_limited_total",
+    "source_guard_dropped_total",
+    "active_leases",
+    "leases_expiring_soon",
+    "active_leases_by_class",
+    "rogue_offers_total",
+    "lease_conflicts_total",
+    "protocol_violations_total",
+    "lease_time_anomalies_total",
+    "server_check_up",
+    "server_check_latency_seconds",
+    "server_checks_total",
+    "ha_active",
+];
+
+/// Leases running out within this window count as expiring soon
+const EXPIRING_SOON: Duration = Duration::from_secs(300);
+
+pub const MESSAGE_STATS: [Stat; 10] = [
+    Stat::Discover,
+    Stat::Offer,
+    Stat::Request,
+    Stat::Decline,
+    Stat::Ack,
+    Stat::Nak,
+    Stat::Release,
+    Stat::Inform,
+    Stat::UnknownType,
+    Stat::Bootp,
+];
+
+pub const ERROR_STATS: [Stat; 7] = [
+    Stat::Truncated,
+    Stat::BadCookie,
+    Stat::OptionOverrun,
+    Stat::BadIpHeader,
+    Stat::BadUdpLength,
+    Stat::BadChecksum,
+    Stat::Fragmented,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+/// All samples of one metric
+#[derive(Debug, Clone)]
+pub struct Family {
+    pub name: String,
+    pub help: &'static str,
+    pub kind: Kind,
+    pub samples: Vec<Sample>,
+}
+
+impl Family {
+    fn new(name: &str, help: &'static str, kind: Kind) -> Family {
+        debug_assert!(
+            FAMILIES.contains(&name),
+            "{} is missing from FAMILIES",
+            name
+        );
+        Family {
+            name: format!("{}_{}", NAMESPACE, name),
+            help,
+            kind,
+            samples: Vec::new(),
+        }
+    }
+
+    fn single(name: &str, help: &'static str, kind: Kind, value: f64) -> Family {
+        let mut family = Family::new(name, help, kind);
+        family.samples.push(Sample {
+            labels: Vec::new(),
+            value,
+        });
+        family
+    }
+}
+
+/// Take a snapshot of the eBPF counters and of the lease table
+pub fn collect(backend: &Backend) -> Result<Vec<Family>, anyhow::Error> {
+    let mut messages = Family::new(
+        "messages_total",
+        "DHCP messages seen by the eBPF program",
+        Kind::Counter,
+    );
+    let mut errors = Family::new(
+        "malformed_packets_total",
+        "DHCP packets the eBPF program couldn't parse",
+        Kind::Counter,
+    );
+    let mut dropped = Family::new(
+        "rogue_dropped_total",
+        "Server messages dropped on untrusted interfaces",
+        Kind::Counter,
+    );
+    let mut arp_rejected = Family::new(
+        "arp_rejected_total",
+        "ARPs dropped by Dynamic ARP Inspection",
+        Kind::Counter,
+    );
+    let mut rate_limited = Family::new(
+        "rate_limited_total",
+        "DISCOVERs and REQUESTs over a rate limit",
+        Kind::Counter,
+    );
+    let mut guarded = Family::new(
+        "source_guard_dropped_total",
+        "Client traffic dropped for not matching a lease",
+        Kind::Counter,
+    );
+
+    for interface in backend.stats.read()? {
+        for stat in MESSAGE_STATS {
+            messages.samples.push(Sample {
+                labels: vec![
+                    ("interface", interface.interface.clone()),
+                    ("type", stat.name().to_owned()),
+                ],
+                value: interface.get(stat) as f64,
+            });
+        }
+        for stat in ERROR_STATS {
+            errors.samples.push(Sample {
+                labels: vec![
+                    ("interface", interface.interface.clone()),
+                    ("reason", stat.name().to_owned()),
+                ],
+                value: interface.get(stat) as f64,
+            });
+        }
+        dropped.samples.push(Sample {
+            labels: vec![("interface", interface.interface.clone())],
+            value: interface.get(Stat::RogueDropped) as f64,
+        });
+        guarded.samples.push(Sample {
+            labels: vec![("interface", interface.interface.clone())],
+            value: interface.get(Stat::SourceGuardDropped) as f64,
+        });
+        arp_rejected.samples.push(Sample {
+            labels: vec![("interface", interface.interface.clone())],
+            value: interface.get(Stat::ArpRejected) as f64,
+        });
+        rate_limited.samples.push(Sample {
+            labels: vec![("interface", interface.interface.clone())],
+            value: interface.get(Stat::RateLimited) as f64,
+        });
+    }
+
+    let state = backend.state.lock().unwrap();
+    let now = BootTime::now();
+    let active = state.leases.iter().filter(|lease| lease.is_active(now));
+    let (mut active_count, mut expiring) = (0, 0);
+    let mut classes: BTreeMap<&str, u64> = BTreeMap::new();
+    for lease in active {
+        active_count += 1;
+        *classes
+            .entry(lease.class.as_deref().unwrap_or("unknown"))
+            .or_default() += 1;
+        if lease
+            .expires_at
+            .map_or(false, |at| at <= now + EXPIRING_SOON)
+        {
+            expiring += 1;
+        }
+    }
+
+    let mut by_class = Family::new(
+        "active_leases_by_class",
+        "Leases that haven't expired by what the client's fingerprint says it is",
+        Kind::Gauge,
+    );
+    for (class, count) in classes {
+        by_class.samples.push(Sample {
+            labels: vec![("class", class.to_owned())],
+            value: count as f64,
+        });
+    }
+
+    let mut server_up = Family::new(
+        "server_check_up",
+        "Whether the server answered its last server check",
+        Kind::Gauge,
+    );
+    let mut server_latency = Family::new(
+        "server_check_latency_seconds",
+        "How long the last server check the server answered took",
+        Kind::Gauge,
+    );
+    let mut server_checks = Family::new(
+        "server_checks_total",
+        "Server checks by how they went",
+        Kind::Counter,
+    );
+    for (server, status) in &state.server_checks {
+        let server = server.to_string();
+        server_up.samples.push(Sample {
+            labels: vec![("server", server.clone())],
+            value: status.answering as u8 as f64,
+        });
+        if let Some(latency) = status.latency {
+            server_latency.samples.push(Sample {
+                labels: vec![("server", server.clone())],
+                value: latency.as_secs_f64(),
+            });
+        }
+        for (result, count) in [("success", status.successes), ("failure", status.failures)] {
+            server_checks.samples.push(Sample {
+                labels: vec![("server", server.clone()), ("result", result.to_owned())],
+                value: count as f64,
+            });
+        }
+    }
+
+    Ok(vec![
+        messages,
+        errors,
+        dropped,
+        guarded,
+        arp_rejected,
+        rate_limited,
+        Family::single(
+            "active_leases",
+            "Leases that haven't expired",
+            Kind::Gauge,
+            active_count as f64,
+        ),
+        Family::single(
+            "leases_expiring_soon",
+            "Active leases expiring within the next 5 minutes",
+            Kind::Gauge,
+            expiring as f64,
+        ),
+        by_class,
+        Family::single(
+            "rogue_offers_total",
+            "Offers seen from servers that aren't trusted",
+            Kind::Counter,
+            state.rogue_offers as f64,
+        ),
+        Family::single(
+            "lease_conflicts_total",
+            "Addresses or hostnames leased to two clients at once and transactions ACKed twice",
+            Kind::Counter,
+            state.lease_conflicts as f64,
+        ),
+        Family::single(
+            "protocol_violations_total",
+            "Messages with a yiaddr or ciaddr RFC 2131 doesn't allow for their type",
+            Kind::Counter,
+            state.protocol_violations as f64,
+        ),
+        Family::single(
+            "lease_time_anomalies_total",
+            "ACKs with lease times far off what their server usually hands out",
+            Kind::Counter,
+            state.lease_time_anomalies as f64,
+        ),
+        server_up,
+        server_latency,
+        server_checks,
+        Family::single(
+            "ha_active",
+            "Whether this daemon is enforcing and exporting events",
+            Kind::Gauge,
+            (*backend.role.borrow() == Role::Active) as u8 as f64,
+        ),
+    ])
+}
+
+/// Prometheus text exposition format
+pub fn render(families: &[Family]) -> String {
+    let mut out = String::new();
+
+    for family in families {
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind.as_str());
+
+        for sample in &family.samples {
+            out.push_str(&family.name);
+            if !sample.labels.is_empty() {
+                let labels = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let _ = write!(out, "{{{}}}", labels);
+            }
+            let _ = writeln!(out, " {}", sample.value);
+        }
+    }
+
+    out
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,43 @@
+use futures::{Stream, StreamExt};
+use netlink_packet_core::NetlinkPayload;
+use netlink_sys::{AsyncSocket, SocketAddr};
+use rtnetlink::{
+    constants::RTMGRP_LINK,
+    packet::{link::nlas::Nla, LinkMessage, RtnlMessage},
+};
+
+#[derive(Debug)]
+pub enum LinkEvent {
+    Added { name: String, ifindex: u32 },
+    Removed { name: String },
+}
+
+/// Subscribe to link notifications, yielding interfaces as they come and go
+pub fn monitor() -> Result<impl Stream<Item = LinkEvent>, anyhow::Error> {
+    let (mut connection, _, messages) = rtnetlink::new_connection()?;
+    connection
+        .socket_mut()
+        .socket_mut()
+        .bind(&SocketAddr::new(0, RTMGRP_LINK))?;
+    tokio::spawn(connection);
+
+    Ok(messages.filter_map(|(message, _)| async move {
+        match message.payload {
+            NetlinkPayload::InnerMessage(RtnlMessage::NewLink(link)) => Some(LinkEvent::Added {
+                ifindex: link.header.index,
+                name: link_name(&link)?,
+            }),
+            NetlinkPayload::InnerMessage(RtnlMessage::DelLink(link)) => Some(LinkEvent::Removed {
+                name: link_name(&link)?,
+            }),
+            _ => None,
+        }
+    }))
+}
+
+fn link_name(link: &LinkMessage) -> Option<String> {
+    link.nlas.iter().find_map(|nla| match nla {
+        Nla::IfName(name) => Some(name.clone()),
+        _ => None,
+    })
+}
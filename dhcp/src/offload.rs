@@ -0,0 +1,116 @@
+//! NICs stripping VLAN tags in hardware (rx-vlan-offload) take the tag off before XDP runs
+//! and keep it with the skb, which XDP never gets to see. The VLAN id every per-VLAN
+//! feature relies on is then always missing on that interface.
+
+use std::{ffi::CString, fmt, io, mem, os::fd::AsRawFd, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_GFLAGS: u32 = 0x25;
+const ETHTOOL_SFLAGS: u32 = 0x26;
+const ETH_FLAG_RXVLAN: u32 = 1 << 8;
+
+/// What to do about an interface that strips VLAN tags before XDP sees them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VlanOffload {
+    /// Attach as configured and warn that VLANs won't be seen
+    #[default]
+    Warn,
+    /// Attach with TC instead, which gets the tag back from the skb
+    Tc,
+    /// Turn the offload off while attached, costing the CPU the tag stripping
+    Disable,
+}
+
+impl FromStr for VlanOffload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "warn" => VlanOffload::Warn,
+            "tc" => VlanOffload::Tc,
+            "disable" => VlanOffload::Disable,
+            _ => {
+                return Err(format!(
+                    "invalid VLAN offload handling {:?}, expected warn, tc or disable",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+impl fmt::Display for VlanOffload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            VlanOffload::Warn => "warn",
+            VlanOffload::Tc => "tc",
+            VlanOffload::Disable => "disable",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for VlanOffload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for VlanOffload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[repr(C)]
+struct EthtoolValue {
+    cmd: u32,
+    data: u32,
+}
+
+/// Whether `name` strips VLAN tags on receive
+pub fn rx_vlan_stripped(name: &str) -> io::Result<bool> {
+    Ok(ethtool(name, ETHTOOL_GFLAGS, 0)? & ETH_FLAG_RXVLAN != 0)
+}
+
+/// Turn VLAN tag stripping on `name` on or off, as `ethtool -K NAME rxvlan on|off` would
+pub fn set_rx_vlan_stripped(name: &str, stripped: bool) -> io::Result<()> {
+    let flags = ethtool(name, ETHTOOL_GFLAGS, 0)?;
+    let flags = if stripped {
+        flags | ETH_FLAG_RXVLAN
+    } else {
+        flags & !ETH_FLAG_RXVLAN
+    };
+    ethtool(name, ETHTOOL_SFLAGS, flags)?;
+
+    Ok(())
+}
+
+/// The ethtool ioctl, which the kernel still maps onto the same features ethtool's netlink
+/// interface changes
+fn ethtool(name: &str, cmd: u32, data: u32) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if name.as_bytes_with_nul().len() > libc::IF_NAMESIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "interface name too long",
+        ));
+    }
+
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    let mut value = EthtoolValue { cmd, data };
+    let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.as_bytes()) {
+        *dst = *src as libc::c_char;
+    }
+    ifr.ifr_ifru.ifru_data = &mut value as *mut EthtoolValue as *mut libc::c_char;
+
+    let ret = unsafe { libc::ioctl(socket.as_raw_fd(), SIOCETHTOOL as _, &mut ifr) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(value.data)
+}
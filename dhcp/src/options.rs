@@ -0,0 +1,356 @@
+//! Names and value types of the DHCP options assigned by IANA, those of RFC 2132 and the
+//! ones added since, for rendering the options the eBPF program passes on as is
+
+use std::{fmt, net::Ipv4Addr};
+
+use serde::Serialize;
+
+/// How an option's value is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Address,
+    Addresses,
+    U8,
+    U16,
+    U32,
+    I32,
+    Flag,
+    Text,
+    U16s,
+    /// Option codes, as in the parameter request list
+    Codes,
+    /// Anything else, left to the hex dump
+    Opaque,
+}
+
+use Kind::*;
+
+/// Sorted by code
+const OPTIONS: &[(u8, &str, Kind)] = &[
+    (0, "pad", Opaque),
+    (1, "subnet-mask", Address),
+    (2, "time-offset", I32),
+    (3, "router", Addresses),
+    (4, "time-server", Addresses),
+    (5, "name-server", Addresses),
+    (6, "domain-name-server", Addresses),
+    (7, "log-server", Addresses),
+    (8, "cookie-server", Addresses),
+    (9, "lpr-server", Addresses),
+    (10, "impress-server", Addresses),
+    (11, "resource-location-server", Addresses),
+    (12, "host-name", Text),
+    (13, "boot-file-size", U16),
+    (14, "merit-dump-file", Text),
+    (15, "domain-name", Text),
+    (16, "swap-server", Address),
+    (17, "root-path", Text),
+    (18, "extensions-path", Text),
+    (19, "ip-forwarding", Flag),
+    (20, "non-local-source-routing", Flag),
+    (21, "policy-filter", Addresses),
+    (22, "max-datagram-reassembly-size", U16),
+    (23, "default-ip-ttl", U8),
+    (24, "path-mtu-aging-timeout", U32),
+    (25, "path-mtu-plateau-table", U16s),
+    (26, "interface-mtu", U16),
+    (27, "all-subnets-are-local", Flag),
+    (28, "broadcast-address", Address),
+    (29, "perform-mask-discovery", Flag),
+    (30, "mask-supplier", Flag),
+    (31, "perform-router-discovery", Flag),
+    (32, "router-solicitation-address", Address),
+    (33, "static-route", Addresses),
+    (34, "trailer-encapsulation", Flag),
+    (35, "arp-cache-timeout", U32),
+    (36, "ethernet-encapsulation", Flag),
+    (37, "tcp-default-ttl", U8),
+    (38, "tcp-keepalive-interval", U32),
+    (39, "tcp-keepalive-garbage", Flag),
+    (40, "nis-domain", Text),
+    (41, "nis-servers", Addresses),
+    (42, "ntp-servers", Addresses),
+    (43, "vendor-specific", Opaque),
+    (44, "netbios-name-servers", Addresses),
+    (45, "netbios-dd-servers", Addresses),
+    (46, "netbios-node-type", U8),
+    (47, "netbios-scope", Text),
+    (48, "x-window-font-servers", Addresses),
+    (49, "x-window-display-managers", Addresses),
+    (50, "requested-address", Address),
+    (51, "lease-time", U32),
+    (52, "option-overload", U8),
+    (53, "message-type", U8),
+    (54, "server-identifier", Address),
+    (55, "parameter-request-list", Codes),
+    (56, "message", Text),
+    (57, "max-message-size", U16),
+    (58, "renewal-time", U32),
+    (59, "rebinding-time", U32),
+    (60, "vendor-class-identifier", Text),
+    (61, "client-identifier", Opaque),
+    (62, "netware-ip-domain", Text),
+    (63, "netware-ip-option", Opaque),
+    (64, "nis-plus-domain", Text),
+    (65, "nis-plus-servers", Addresses),
+    (66, "tftp-server-name", Text),
+    (67, "bootfile-name", Text),
+    (68, "mobile-ip-home-agents", Addresses),
+    (69, "smtp-servers", Addresses),
+    (70, "pop3-servers", Addresses),
+    (71, "nntp-servers", Addresses),
+    (72, "www-servers", Addresses),
+    (73, "finger-servers", Addresses),
+    (74, "irc-servers", Addresses),
+    (75, "streettalk-servers", Addresses),
+    (76, "stda-servers", Addresses),
+    (77, "user-class", Opaque),
+    (78, "slp-directory-agent", Opaque),
+    (79, "slp-service-scope", Opaque),
+    (80, "rapid-commit", Opaque),
+    (81, "client-fqdn", Opaque),
+    (82, "relay-agent-information", Opaque),
+    (83, "isns", Opaque),
+    (85, "nds-servers", Addresses),
+    (86, "nds-tree-name", Text),
+    (87, "nds-context", Text),
+    (88, "bcmcs-controller-domains", Opaque),
+    (89, "bcmcs-controller-addresses", Addresses),
+    (90, "authentication", Opaque),
+    (91, "client-last-transaction-time", U32),
+    (92, "associated-ip", Addresses),
+    (93, "client-system-architecture", U16s),
+    (94, "client-network-interface-id", Opaque),
+    (95, "ldap", Opaque),
+    (97, "client-machine-id", Opaque),
+    (98, "user-authentication", Text),
+    (99, "geoconf-civic", Opaque),
+    (100, "posix-timezone", Text),
+    (101, "tzdb-timezone", Text),
+    (108, "ipv6-only-preferred", U32),
+    (109, "dhcp4o6-s46-saddr", Opaque),
+    (112, "netinfo-address", Opaque),
+    (113, "netinfo-tag", Opaque),
+    (114, "captive-portal", Text),
+    (116, "auto-configure", U8),
+    (117, "name-service-search", U16s),
+    (118, "subnet-selection", Address),
+    (119, "domain-search", Opaque),
+    (120, "sip-servers", Opaque),
+    (121, "classless-static-route", Opaque),
+    (122, "cablelabs-client-configuration", Opaque),
+    (123, "geoconf", Opaque),
+    (124, "vendor-identifying-vendor-class", Opaque),
+    (125, "vendor-identifying-vendor-specific", Opaque),
+    (128, "pxe-vendor-specific-128", Opaque),
+    (129, "pxe-vendor-specific-129", Opaque),
+    (130, "pxe-vendor-specific-130", Opaque),
+    (131, "pxe-vendor-specific-131", Opaque),
+    (132, "pxe-vendor-specific-132", Opaque),
+    (133, "pxe-vendor-specific-133", Opaque),
+    (134, "pxe-vendor-specific-134", Opaque),
+    (135, "pxe-vendor-specific-135", Opaque),
+    (136, "pana-agent", Addresses),
+    (137, "v4-lost", Opaque),
+    (138, "capwap-ac-v4", Addresses),
+    (139, "ipv4-address-mos", Opaque),
+    (140, "ipv4-fqdn-mos", Opaque),
+    (141, "sip-ua-configuration-domains", Opaque),
+    (142, "ipv4-address-andsf", Addresses),
+    (143, "sztp-redirect", Opaque),
+    (144, "geoloc", Opaque),
+    (145, "forcerenew-nonce-capable", Opaque),
+    (146, "rdnss-selection", Opaque),
+    (147, "dots-ri", Opaque),
+    (148, "dots-address", Addresses),
+    (150, "tftp-server-address", Addresses),
+    (151, "status-code", Opaque),
+    (152, "base-time", U32),
+    (153, "start-time-of-state", U32),
+    (154, "query-start-time", U32),
+    (155, "query-end-time", U32),
+    (156, "dhcp-state", U8),
+    (157, "data-source", U8),
+    (158, "v4-pcp-server", Opaque),
+    (159, "v4-portparams", Opaque),
+    (161, "mud-url-v4", Text),
+    (162, "v4-dnr", Opaque),
+    (175, "etherboot", Opaque),
+    (176, "ip-telephone", Opaque),
+    (177, "packetcable-cablehome", Opaque),
+    (208, "pxelinux-magic", Opaque),
+    (209, "configuration-file", Text),
+    (210, "path-prefix", Text),
+    (211, "reboot-time", U32),
+    (212, "6rd", Opaque),
+    (213, "v4-access-domain", Text),
+    (220, "subnet-allocation", Opaque),
+    (221, "virtual-subnet-selection", Opaque),
+    (255, "end", Opaque),
+];
+
+/// Codes IANA leaves to each site, RFC 3942
+const SITE_SPECIFIC: std::ops::RangeInclusive<u8> = 224..=254;
+
+fn lookup(code: u8) -> Option<(&'static str, Kind)> {
+    OPTIONS
+        .binary_search_by_key(&code, |(code, _, _)| *code)
+        .ok()
+        .map(|i| (OPTIONS[i].1, OPTIONS[i].2))
+}
+
+/// The option's name, `site-specific` for the private range
+pub fn name(code: u8) -> Option<&'static str> {
+    match lookup(code) {
+        Some((name, _)) => Some(name),
+        None if SITE_SPECIFIC.contains(&code) => Some("site-specific"),
+        None => None,
+    }
+}
+
+/// Shows as `option 119 (domain-search)`, or `option 84` when it isn't assigned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionCode(pub u8);
+
+impl fmt::Display for OptionCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match name(self.0) {
+            Some(name) => write!(f, "option {} ({})", self.0, name),
+            None => write!(f, "option {}", self.0),
+        }
+    }
+}
+
+impl Serialize for OptionCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A decoded option value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    Address(Ipv4Addr),
+    Addresses(Vec<Ipv4Addr>),
+    Unsigned(u32),
+    Signed(i32),
+    Flag(bool),
+    Text(String),
+    Numbers(Vec<u16>),
+    Codes(Vec<OptionCode>),
+}
+
+/// `data` decoded the way option `code` is laid out. `None` for options without a known
+/// layout, and when `data` doesn't fit the layout, e.g. because it was cut short.
+pub fn decode(code: u8, data: &[u8]) -> Option<Value> {
+    let (_, kind) = lookup(code)?;
+
+    Some(match kind {
+        Address => Value::Address(<[u8; 4]>::try_from(data).ok()?.into()),
+        Addresses if !data.is_empty() && data.len() % 4 == 0 => Value::Addresses(
+            data.chunks_exact(4)
+                .map(|chunk| Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+                .collect(),
+        ),
+        U8 => Value::Unsigned(<[u8; 1]>::try_from(data).ok()?[0] as u32),
+        U16 => Value::Unsigned(u16::from_be_bytes(data.try_into().ok()?) as u32),
+        U32 => Value::Unsigned(u32::from_be_bytes(data.try_into().ok()?)),
+        I32 => Value::Signed(i32::from_be_bytes(data.try_into().ok()?)),
+        Flag => Value::Flag(<[u8; 1]>::try_from(data).ok()?[0] != 0),
+        Text if !data.is_empty() => Value::Text(
+            String::from_utf8_lossy(data)
+                .trim_end_matches('\0')
+                .to_owned(),
+        ),
+        U16s if !data.is_empty() && data.len() % 2 == 0 => Value::Numbers(
+            data.chunks_exact(2)
+                .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+                .collect(),
+        ),
+        Codes if !data.is_empty() => Value::Codes(data.iter().copied().map(OptionCode).collect()),
+        Addresses | Text | U16s | Codes | Opaque => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_sorted() {
+        for pair in OPTIONS.windows(2) {
+            assert!(pair[0].0 < pair[1].0, "{} before {}", pair[0].0, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn decode_each_kind() {
+        let cases: &[(u8, &[u8], Option<Value>)] = &[
+            (
+                1,
+                &[255, 255, 255, 0],
+                Some(Value::Address(Ipv4Addr::new(255, 255, 255, 0))),
+            ),
+            (1, &[255, 255, 255], None),
+            (
+                3,
+                &[10, 0, 0, 1, 10, 0, 0, 2],
+                Some(Value::Addresses(vec![
+                    Ipv4Addr::new(10, 0, 0, 1),
+                    Ipv4Addr::new(10, 0, 0, 2),
+                ])),
+            ),
+            (3, &[10, 0, 0, 1, 10], None),
+            (3, &[], None),
+            (23, &[64], Some(Value::Unsigned(64))),
+            (23, &[64, 0], None),
+            (26, &[0x05, 0xdc], Some(Value::Unsigned(1500))),
+            (26, &[0x05], None),
+            (51, &[0, 0, 0x0e, 0x10], Some(Value::Unsigned(3600))),
+            (51, &[0, 0x0e, 0x10], None),
+            (2, &[0xff, 0xff, 0xf1, 0xf0], Some(Value::Signed(-3600))),
+            (2, &[0xff, 0xff, 0xf1, 0xf0, 0], None),
+            (19, &[1], Some(Value::Flag(true))),
+            (19, &[0], Some(Value::Flag(false))),
+            (19, &[], None),
+            (12, b"laptop\0", Some(Value::Text("laptop".to_owned()))),
+            (12, &[], None),
+            (93, &[0, 7, 0, 9], Some(Value::Numbers(vec![7, 9]))),
+            (93, &[0, 7, 0], None),
+            (
+                55,
+                &[1, 3, 6],
+                Some(Value::Codes(vec![
+                    OptionCode(1),
+                    OptionCode(3),
+                    OptionCode(6),
+                ])),
+            ),
+            (55, &[], None),
+            // Opaque, unassigned and site-specific options are left to the hex dump
+            (119, &[7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0], None),
+            (84, &[1, 2, 3, 4], None),
+            (250, &[1, 2, 3, 4], None),
+        ];
+
+        for (code, data, value) in cases {
+            assert_eq!(decode(*code, data), *value, "option {} {:?}", code, data);
+        }
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(name(119), Some("domain-search"));
+        assert_eq!(name(224), Some("site-specific"));
+        assert_eq!(name(254), Some("site-specific"));
+        assert_eq!(name(84), None);
+    }
+
+    #[test]
+    fn codes_show_their_name() {
+        assert_eq!(OptionCode(119).to_string(), "option 119 (domain-search)");
+        assert_eq!(OptionCode(250).to_string(), "option 250 (site-specific)");
+        assert_eq!(OptionCode(84).to_string(), "option 84");
+    }
+}
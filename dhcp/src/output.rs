@@ -0,0 +1,218 @@
+//! Machine readable records of what the daemon sees, one JSON document per DHCP message or
+//! event, for `--output json` and the syslog forwarder
+
+use std::{
+    fmt,
+    io::{self, Write},
+    net::Ipv4Addr,
+    str::FromStr,
+    time::SystemTime,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use log::{log, warn, Level};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    events::{serialize_interface, serialize_time, Event},
+    ha::Role,
+    mac::MacAddr,
+    message::{DhcpMessage, Direction, LeaseTime, MessageType, RelayInfo, UnknownOption},
+    sinks::{Sink, SinkContext},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Events as log lines
+    #[default]
+    Text,
+    /// Every DHCP message and event as a JSON object on its own line on stdout
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "text" => OutputFormat::Text,
+            "json" => OutputFormat::Json,
+            _ => {
+                return Err(format!(
+                    "invalid output format {:?}, expected text or json",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for OutputFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageRecord<'a> {
+    pub event: &'static str,
+    #[serde(serialize_with = "serialize_time")]
+    pub timestamp: SystemTime,
+    #[serde(serialize_with = "serialize_interface")]
+    pub interface: u32,
+    pub vlan: Option<u16>,
+    pub direction: Direction,
+    pub message_type: MessageType,
+    pub xid: u32,
+    pub client_mac: MacAddr,
+    /// What the client is according to its fingerprint
+    pub class: Option<&'a str>,
+    pub client_address: Ipv4Addr,
+    pub your_address: Ipv4Addr,
+    /// Only for replies to a request that went by
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<InReplyTo>,
+    pub options: Options<'a>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InReplyTo {
+    pub message_type: MessageType,
+    pub response_time_ms: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Options<'a> {
+    pub server_id: Option<Ipv4Addr>,
+    pub lease_time: Option<LeaseTime>,
+    pub hostname: Option<&'a str>,
+    pub relay_agent: Option<&'a RelayInfo>,
+    /// Only in client messages that carry option 55
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub parameter_request_list: &'a [u8],
+    pub vendor_class: Option<&'a str>,
+    /// Only with `unknown-option-bytes` set
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub unknown: &'a [UnknownOption],
+}
+
+impl<'a> From<&'a DhcpMessage> for MessageRecord<'a> {
+    fn from(msg: &'a DhcpMessage) -> Self {
+        MessageRecord {
+            event: if msg.message_type.is_bootp() {
+                "bootp"
+            } else {
+                "message"
+            },
+            timestamp: msg.seen_at,
+            interface: msg.ifindex,
+            vlan: msg.vlan,
+            direction: msg.direction,
+            message_type: msg.message_type,
+            xid: msg.xid,
+            client_mac: msg.client_mac,
+            class: msg.class.as_deref(),
+            client_address: msg.client_address,
+            your_address: msg.your_address,
+            in_reply_to: msg.answered.map(|answered| InReplyTo {
+                message_type: answered.request,
+                response_time_ms: answered.response_time.as_secs_f64() * 1000.0,
+            }),
+            options: Options {
+                server_id: msg.server_id,
+                lease_time: msg.lease_time,
+                hostname: msg.hostname.as_deref(),
+                relay_agent: msg.relay.as_ref(),
+                parameter_request_list: &msg.parameter_list,
+                vendor_class: msg.vendor_class.as_deref(),
+                unknown: &msg.unknown_options,
+            },
+        }
+    }
+}
+
+impl Sink for OutputFormat {
+    fn name(&self) -> String {
+        "output".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        let (messages, events, role) = (ctx.messages(), ctx.events(), ctx.backend.role.clone());
+        async move {
+            run(*self, messages, events, role).await;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Report events, and with JSON output every message too, until the channels close
+async fn run(
+    format: OutputFormat,
+    mut messages: broadcast::Receiver<DhcpMessage>,
+    mut events: broadcast::Receiver<Event>,
+    role: watch::Receiver<Role>,
+) {
+    loop {
+        let line = tokio::select! {
+            msg = messages.recv() => match msg {
+                Ok(msg) if format == OutputFormat::Json => {
+                    serde_json::to_string(&MessageRecord::from(&msg))
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("output fell behind, dropped {} messages", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            event = events.recv() => match event {
+                // The lease table logs these itself
+                Ok(Event::Lease(_)) if format == OutputFormat::Text => continue,
+                Ok(event) if format == OutputFormat::Text => {
+                    // The active node of an HA pair reports for both
+                    if *role.borrow() == Role::Active {
+                        log!(level(&event), "{}", event);
+                    }
+                    continue;
+                }
+                Ok(event) => serde_json::to_string(&event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("output fell behind, dropped {} events", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+        };
+
+        if *role.borrow() != Role::Active {
+            continue;
+        }
+        match line {
+            Ok(line) => {
+                let mut stdout = io::stdout().lock();
+                let _ = writeln!(stdout, "{}", line);
+            }
+            Err(e) => warn!("failed to serialize record: {}", e),
+        }
+    }
+}
+
+/// The level an event is logged at with text output, a frozen device changing needs a look
+pub fn level(event: &Event) -> Level {
+    match event {
+        Event::Changed(event) if event.frozen => Level::Warn,
+        _ => Level::Info,
+    }
+}
@@ -0,0 +1,236 @@
+//! Raw Ethernet frames on a single interface, for the probes and packets the daemon and
+//! the CLI send themselves
+
+use std::{
+    fs, io, mem,
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use anyhow::Context;
+use tokio::io::unix::AsyncFd;
+
+use crate::{iface, mac::MacAddr};
+
+pub const ETH_P_IP: u16 = 0x0800;
+pub const ETH_P_8021Q: u16 = 0x8100;
+const ETH_HDR_LEN: usize = 14;
+const IP_HDR_LEN: usize = 20;
+const UDP_HDR_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+pub const MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+
+/// A packet socket on one interface that sees one ethertype
+pub struct PacketSocket {
+    fd: AsyncFd<OwnedFd>,
+    /// Of the interface
+    pub mac: MacAddr,
+}
+
+impl PacketSocket {
+    pub fn open(ifindex: u32, protocol: u16) -> Result<PacketSocket, anyhow::Error> {
+        let name = iface::name(ifindex);
+        let path = format!("/sys/class/net/{}/address", name);
+        let mac = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path))?
+            .trim()
+            .parse::<MacAddr>()
+            .map_err(anyhow::Error::msg)?;
+
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                protocol.to_be() as i32,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("failed to open packet socket");
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = protocol.to_be();
+        addr.sll_ifindex = ifindex as i32;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("failed to bind packet socket to {}", name));
+        }
+
+        Ok(PacketSocket {
+            fd: AsyncFd::new(fd)?,
+            mac,
+        })
+    }
+
+    pub async fn send(&self, frame: &[u8]) -> io::Result<()> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            let result = guard.try_io(|fd| {
+                let ret = unsafe {
+                    libc::send(
+                        fd.as_raw_fd(),
+                        frame.as_ptr() as *const libc::c_void,
+                        frame.len(),
+                        0,
+                    )
+                };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+            if let Ok(result) = result {
+                return result;
+            }
+        }
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                let ret = unsafe {
+                    libc::recv(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(ret as usize)
+            });
+            if let Ok(result) = result {
+                return result;
+            }
+        }
+    }
+
+    /// Like `recv`, skipping the frames the host sent itself
+    pub async fn recv_incoming(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+                let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+                let ret = unsafe {
+                    libc::recvfrom(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                        &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                        &mut addr_len,
+                    )
+                };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok((ret as usize, addr.sll_pkttype))
+            });
+            match result {
+                Ok(Ok((_, pkttype))) if pkttype == libc::PACKET_OUTGOING => continue,
+                Ok(result) => return result.map(|(len, _)| len),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// A BOOTP message with `options`, a client's when `op` is 1 and a server's when it's 2
+pub fn bootp(
+    op: u8,
+    xid: u32,
+    chaddr: MacAddr,
+    ciaddr: Ipv4Addr,
+    yiaddr: Ipv4Addr,
+    options: &[(u8, &[u8])],
+) -> Vec<u8> {
+    let mut message = vec![op, 1, 6, 0];
+    message.extend_from_slice(&xid.to_be_bytes());
+    // secs, then flags with the broadcast bit set unless the client has an address
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(if ciaddr.is_unspecified() {
+        &[0x80, 0]
+    } else {
+        &[0, 0]
+    });
+    message.extend_from_slice(&ciaddr.octets());
+    message.extend_from_slice(&yiaddr.octets());
+    // siaddr and giaddr
+    message.extend_from_slice(&[0; 8]);
+    message.extend_from_slice(&chaddr.0);
+    // chaddr padding, sname and file
+    message.extend_from_slice(&[0; 10 + 64 + 128]);
+    message.extend_from_slice(&MAGIC_COOKIE);
+    for (code, value) in options {
+        message.push(*code);
+        message.push(value.len() as u8);
+        message.extend_from_slice(value);
+    }
+    message.push(255);
+
+    message
+}
+
+/// An IPv4 UDP datagram in an Ethernet frame
+pub fn frame(
+    src_mac: MacAddr,
+    dst_mac: MacAddr,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = (UDP_HDR_LEN + payload.len()) as u16;
+    let ip_len = IP_HDR_LEN as u16 + udp_len;
+
+    let mut frame = Vec::with_capacity(ETH_HDR_LEN + ip_len as usize);
+    frame.extend_from_slice(&dst_mac.0);
+    frame.extend_from_slice(&src_mac.0);
+    frame.extend_from_slice(&ETH_P_IP.to_be_bytes());
+
+    let mut ip = [0; IP_HDR_LEN];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&ip_len.to_be_bytes());
+    ip[8] = 64;
+    ip[9] = IPPROTO_UDP;
+    ip[12..16].copy_from_slice(&src.octets());
+    ip[16..20].copy_from_slice(&dst.octets());
+    let checksum = checksum(&ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    frame.extend_from_slice(&ip);
+
+    frame.extend_from_slice(&src_port.to_be_bytes());
+    frame.extend_from_slice(&dst_port.to_be_bytes());
+    frame.extend_from_slice(&udp_len.to_be_bytes());
+    // No UDP checksum, which IPv4 allows
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+/// The Internet checksum of an IPv4 header
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,52 @@
+//! The maps pinned under `/sys/fs/bpf/dhcp_snoop`, which `dhcp config` changes while the
+//! daemon runs
+
+use std::{fs, io::ErrorKind, path::Path};
+
+use anyhow::Context;
+use aya::{Bpf, BpfLoader};
+use log::warn;
+
+pub const PIN_PATH: &str = "/sys/fs/bpf/dhcp_snoop";
+/// The maps declared pinned in the eBPF program
+const PINNED_MAPS: [&str; 3] = ["IFACES", "BINDINGS", "TRUSTED_SERVERS"];
+
+/// Load the eBPF object for the daemon, pinning its maps or picking up the ones a previous
+/// run left pinned
+pub fn load(object: &[u8]) -> Result<Bpf, anyhow::Error> {
+    fs::create_dir_all(PIN_PATH)
+        .with_context(|| format!("failed to create {}, is bpffs mounted?", PIN_PATH))?;
+
+    Ok(BpfLoader::new().map_pin_path(PIN_PATH).load(object)?)
+}
+
+/// Load the eBPF object against the maps pinned by the running daemon. Nothing gets
+/// attached, this is only for getting at the maps.
+pub fn open(object: &[u8]) -> Result<Bpf, anyhow::Error> {
+    if let Some(name) = PINNED_MAPS
+        .iter()
+        .find(|name| !Path::new(PIN_PATH).join(name).exists())
+    {
+        anyhow::bail!(
+            "{} isn't pinned under {}, is the daemon running?",
+            name,
+            PIN_PATH
+        );
+    }
+
+    Ok(BpfLoader::new().map_pin_path(PIN_PATH).load(object)?)
+}
+
+/// Remove the pins so `dhcp config` can tell the daemon is gone, the maps go away with the
+/// last reference to them
+pub fn unpin() {
+    for name in PINNED_MAPS {
+        let path = Path::new(PIN_PATH).join(name);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!("failed to unpin {:?}: {}", path, e)
+            }
+            _ => {}
+        }
+    }
+}
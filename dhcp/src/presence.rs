@@ -0,0 +1,329 @@
+//! Telling a device that left from one that stopped renewing its lease but is still on the
+//! network. Devices whose leases are about to run out are ARP-pinged, and once a lease has
+//! run out its device is pinged until it stops answering.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::{
+    sync::broadcast,
+    time::{interval, timeout_at},
+};
+
+use crate::{
+    clock::BootTime,
+    config::deserialize_duration,
+    events::{Event, LeaseAction, LeaseEvent, PresenceEvent, PresenceState},
+    ha::Role,
+    iface,
+    mac::MacAddr,
+    packet::{PacketSocket, ETH_P_8021Q},
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const ETH_P_ARP: u16 = 0x0806;
+const HEALTH_NAME: &str = "presence";
+/// Devices watched after their lease ran out, past this new ones aren't
+const MAX_WATCHED: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PresenceConfig {
+    /// How often devices are probed
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// Leases running out within this of now get their device probed
+    #[serde(
+        default = "default_before_expiry",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub before_expiry: Duration,
+    /// How long to wait for replies to a round of probes
+    #[serde(default = "default_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_before_expiry() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// A device to probe at the address it was leased
+#[derive(Debug, Clone)]
+struct Target {
+    mac: MacAddr,
+    address: Ipv4Addr,
+    hostname: Option<String>,
+    ifindex: u32,
+    vlan: Option<u16>,
+    /// Whether its lease has run out
+    expired: bool,
+    /// Whether it has been reported still online since
+    still_online: bool,
+}
+
+impl Target {
+    fn from_lease(lease: &LeaseEvent) -> Target {
+        Target {
+            mac: lease.mac,
+            address: lease.address,
+            hostname: lease.hostname.clone(),
+            ifindex: lease.ifindex,
+            vlan: lease.vlan,
+            expired: true,
+            still_online: false,
+        }
+    }
+
+    fn event(&self, state: PresenceState) -> Event {
+        Event::Presence(PresenceEvent {
+            state,
+            mac: self.mac,
+            address: self.address,
+            hostname: self.hostname.clone(),
+            ifindex: self.ifindex,
+            vlan: self.vlan,
+            at: SystemTime::now(),
+        })
+    }
+}
+
+impl sinks::Sink for PresenceConfig {
+    fn name(&self) -> String {
+        "presence prober".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(*self, ctx.unredacted_events(), ctx.backend.clone()).boxed()
+    }
+}
+
+/// Probe devices every `interval` until the events channel closes
+async fn run(
+    config: PresenceConfig,
+    mut events: broadcast::Receiver<Event>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    info!(
+        "probing devices with leases expiring within {} every {}",
+        humantime::format_duration(config.before_expiry),
+        humantime::format_duration(config.interval)
+    );
+
+    let mut ticks = interval(config.interval);
+    let mut expired: HashMap<MacAddr, Target> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            event = events.recv() => {
+                match event {
+                    Ok(Event::Lease(lease)) => watch(&mut expired, &lease),
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("presence prober fell behind, lost {} events", n)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                }
+                continue;
+            }
+        }
+        // The active node of an HA pair probes for both
+        if *backend.role.borrow() != Role::Active {
+            continue;
+        }
+
+        let mut targets = expiring(&backend, config.before_expiry);
+        targets.extend(expired.values().cloned());
+        if targets.is_empty() {
+            continue;
+        }
+
+        let mut status = Ok(());
+        let answered = probe_all(&targets, config.timeout, &mut status).await;
+        backend.health.set_sink(HEALTH_NAME, status);
+
+        let mut state = backend.state.lock().unwrap();
+        for target in targets {
+            // Nothing is known about devices on interfaces that couldn't be probed
+            let online = match answered.get(&target.ifindex) {
+                Some(macs) => macs.contains(&target.mac),
+                None => continue,
+            };
+            state.devices.set_presence(target.mac, online);
+
+            let watched = match expired.get_mut(&target.mac) {
+                Some(watched) if target.expired => watched,
+                _ => continue,
+            };
+            if !online {
+                state.emit(target.event(PresenceState::Left));
+                expired.remove(&target.mac);
+            } else if !watched.still_online {
+                watched.still_online = true;
+                state.emit(target.event(PresenceState::StillOnline));
+            }
+        }
+    }
+}
+
+/// Start watching a device when its lease runs out, stop when it has a lease again or
+/// released it
+fn watch(expired: &mut HashMap<MacAddr, Target>, lease: &LeaseEvent) {
+    if lease.action != LeaseAction::Expired {
+        expired.remove(&lease.mac);
+    } else if expired.len() < MAX_WATCHED || expired.contains_key(&lease.mac) {
+        expired.insert(lease.mac, Target::from_lease(lease));
+    }
+}
+
+/// Devices whose lease runs out within `before_expiry`
+fn expiring(backend: &Backend, before_expiry: Duration) -> Vec<Target> {
+    let state = backend.state.lock().unwrap();
+    let now = BootTime::now();
+
+    state
+        .leases
+        .iter()
+        .filter(|lease| {
+            lease.is_active(now)
+                && lease
+                    .expires_at
+                    .map_or(false, |at| at <= now + before_expiry)
+        })
+        .map(|lease| Target {
+            mac: lease.mac,
+            address: lease.address,
+            hostname: lease.hostname.clone(),
+            ifindex: lease.ifindex,
+            vlan: lease.vlan,
+            expired: false,
+            still_online: false,
+        })
+        .collect()
+}
+
+/// Probe every interface at once, returning the MACs that answered on each interface that
+/// could be probed
+async fn probe_all(
+    targets: &[Target],
+    wait: Duration,
+    status: &mut Result<(), String>,
+) -> HashMap<u32, HashSet<MacAddr>> {
+    let mut by_interface: HashMap<u32, Vec<&Target>> = HashMap::new();
+    for target in targets {
+        by_interface.entry(target.ifindex).or_default().push(target);
+    }
+
+    let probes = by_interface
+        .into_iter()
+        .map(|(ifindex, targets)| async move { (ifindex, probe(ifindex, &targets, wait).await) });
+
+    let mut answered = HashMap::new();
+    for (ifindex, result) in futures::future::join_all(probes).await {
+        match result {
+            Ok(macs) => {
+                answered.insert(ifindex, macs);
+            }
+            Err(e) => {
+                warn!("failed to probe on {}: {:#}", iface::name(ifindex), e);
+                *status = Err(format!("{}: {:#}", iface::name(ifindex), e));
+            }
+        }
+    }
+
+    answered
+}
+
+/// ARP-ping `targets` on `ifindex`, returning the ones that answered within `wait`
+async fn probe(
+    ifindex: u32,
+    targets: &[&Target],
+    wait: Duration,
+) -> Result<HashSet<MacAddr>, anyhow::Error> {
+    let socket = PacketSocket::open(ifindex, ETH_P_ARP)?;
+    for target in targets {
+        socket
+            .send(&request(socket.mac, target))
+            .await
+            .with_context(|| format!("failed to send ARP request for {}", target.address))?;
+    }
+
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut answered = HashSet::new();
+    let mut buf = [0; 128];
+    while answered.len() < targets.len() {
+        let len = match timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(len) => len.context("failed to receive ARP")?,
+            Err(_) => break,
+        };
+        if let Some((mac, address)) = parse_reply(&buf[..len]) {
+            if targets
+                .iter()
+                .any(|target| target.mac == mac && target.address == address)
+            {
+                answered.insert(mac);
+            }
+        }
+    }
+
+    Ok(answered)
+}
+
+/// An ARP probe as RFC 5227 has it, sent from 0.0.0.0 so that it doesn't touch anybody's
+/// ARP cache. It goes straight to the device's MAC rather than to everyone.
+fn request(source: MacAddr, target: &Target) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(46);
+    frame.extend_from_slice(&target.mac.0);
+    frame.extend_from_slice(&source.0);
+    if let Some(vlan) = target.vlan {
+        frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+        frame.extend_from_slice(&vlan.to_be_bytes());
+    }
+    frame.extend_from_slice(&ETH_P_ARP.to_be_bytes());
+
+    // Ethernet, IPv4, 6 byte hardware and 4 byte protocol addresses, a request
+    frame.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+    frame.extend_from_slice(&source.0);
+    frame.extend_from_slice(&[0; 4]);
+    frame.extend_from_slice(&[0; 6]);
+    frame.extend_from_slice(&target.address.octets());
+
+    frame
+}
+
+/// Sender MAC and address of an ARP reply
+fn parse_reply(frame: &[u8]) -> Option<(MacAddr, Ipv4Addr)> {
+    let mut ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?);
+    let mut arp = frame.get(14..)?;
+    // NICs usually strip the tag before the socket sees the frame, not all of them do
+    if ethertype == ETH_P_8021Q {
+        ethertype = u16::from_be_bytes(frame.get(16..18)?.try_into().ok()?);
+        arp = frame.get(18..)?;
+    }
+    if ethertype != ETH_P_ARP || arp.get(0..8)? != [0, 1, 0x08, 0x00, 6, 4, 0, 2] {
+        return None;
+    }
+
+    let mac = MacAddr(arp.get(8..14)?.try_into().ok()?);
+    let address: [u8; 4] = arp.get(14..18)?.try_into().ok()?;
+    Some((mac, address.into()))
+}
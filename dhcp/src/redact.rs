@@ -0,0 +1,404 @@
+//! Pseudonymizes what the exporting sinks send off the host. MACs are replaced by keyed
+//! hashes, or cut down to the vendor's OUI, and hostnames dropped, the same way for every
+//! sink. The daemon's own log lines about clients and what the control socket answers
+//! observers with go through it too. The lease table, the firewall set and the history
+//! database keep the real values, none of them leave the host and all of them need the
+//! real MAC.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::Context;
+use serde::{de, Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    devices::Device,
+    events::{Change, ConflictKind, Event},
+    mac::MacAddr,
+    message::DhcpMessage,
+    secret::{Secret, SecretSource},
+    snapshot::Snapshot,
+};
+
+/// Option 81, which carries the client's FQDN
+const OPTION_CLIENT_FQDN: u8 = 81;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MacRedaction {
+    #[default]
+    Keep,
+    /// A keyed hash, the same MAC always maps to the same pseudonym for a given key
+    Hash,
+    /// Only the OUI, which says who made the NIC
+    Truncate,
+}
+
+impl FromStr for MacRedaction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "keep" => MacRedaction::Keep,
+            "hash" => MacRedaction::Hash,
+            "truncate" => MacRedaction::Truncate,
+            _ => {
+                return Err(format!(
+                    "invalid MAC redaction {:?}, expected keep, hash or truncate",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+impl fmt::Display for MacRedaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MacRedaction::Keep => "keep",
+            MacRedaction::Hash => "hash",
+            MacRedaction::Truncate => "truncate",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for MacRedaction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Applies to the sinks, the daemon's logs and the control socket's observers. Not to the
+/// snapshots an HA pair syncs: the standby enforces the bindings in them once it takes
+/// over, which takes the real MACs, and it sits on the same site as the active node.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RedactionConfig {
+    pub mac: MacRedaction,
+    /// Leave out hostnames, and the FQDNs in option 81
+    pub drop_hostnames: bool,
+    /// What MACs are hashed with, without it anyone could hash the few million MACs of a
+    /// vendor and look the pseudonyms up
+    pub key: Option<SecretSource>,
+}
+
+/// A `RedactionConfig` with its key resolved
+pub struct Redactor {
+    mac: MacRedaction,
+    drop_hostnames: bool,
+    key: Option<Secret>,
+}
+
+impl Redactor {
+    /// `None` when the config leaves everything as it is
+    pub fn new(config: &RedactionConfig) -> Result<Option<Redactor>, anyhow::Error> {
+        if config.mac == MacRedaction::Keep && !config.drop_hostnames {
+            return Ok(None);
+        }
+
+        let key = match (&config.key, config.mac) {
+            (Some(key), _) => Some(
+                key.resolve()
+                    .context("failed to resolve the redaction key")?,
+            ),
+            (None, MacRedaction::Hash) => anyhow::bail!("hashing MACs needs a redaction key"),
+            (None, _) => None,
+        };
+
+        Ok(Some(Redactor {
+            mac: config.mac,
+            drop_hostnames: config.drop_hostnames,
+            key,
+        }))
+    }
+
+    pub fn mac(&self, mac: MacAddr) -> MacAddr {
+        match self.mac {
+            MacRedaction::Keep => mac,
+            MacRedaction::Hash => {
+                let key = self.key.as_ref().map_or("", |key| key.expose());
+                let digest = Sha256::new()
+                    .chain_update(key.as_bytes())
+                    .chain_update(mac.0)
+                    .finalize();
+                let mut pseudonym = [0; 6];
+                pseudonym.copy_from_slice(&digest[..6]);
+                // Locally administered unicast, so a pseudonym never passes for a real MAC
+                pseudonym[0] = (pseudonym[0] & 0xfc) | 0x02;
+                MacAddr(pseudonym)
+            }
+            MacRedaction::Truncate => MacAddr([mac.0[0], mac.0[1], mac.0[2], 0, 0, 0]),
+        }
+    }
+
+    fn hostname(&self, hostname: Option<String>) -> Option<String> {
+        hostname.filter(|_| !self.drop_hostnames)
+    }
+
+    pub fn drops_hostnames(&self) -> bool {
+        self.drop_hostnames
+    }
+
+    /// `None` for events that are about nothing but what's redacted
+    pub fn event(&self, event: Event) -> Option<Event> {
+        Some(match event {
+            Event::Changed(mut event) => {
+                if self.drop_hostnames && matches!(event.change, Change::Hostname { .. }) {
+                    return None;
+                }
+                event.mac = self.mac(event.mac);
+                Event::Changed(event)
+            }
+            Event::RogueOffer(mut event) => {
+                event.client_mac = self.mac(event.client_mac);
+                Event::RogueOffer(event)
+            }
+            Event::ArpRejected(mut event) => {
+                event.sender_mac = self.mac(event.sender_mac);
+                event.leased_to = event.leased_to.map(|mac| self.mac(mac));
+                Event::ArpRejected(event)
+            }
+            Event::RateLimited(mut event) => {
+                event.client_mac = self.mac(event.client_mac);
+                Event::RateLimited(event)
+            }
+            Event::LeaseConflict(mut event) => {
+                if self.drop_hostnames && event.conflict == ConflictKind::DuplicateHostname {
+                    return None;
+                }
+                event.existing.client_mac = self.mac(event.existing.client_mac);
+                event.new.client_mac = self.mac(event.new.client_mac);
+                Event::LeaseConflict(event)
+            }
+            Event::Reconciled(mut event) => {
+                event.kept.client_mac = self.mac(event.kept.client_mac);
+                event.dropped.client_mac = self.mac(event.dropped.client_mac);
+                Event::Reconciled(event)
+            }
+            Event::ProtocolViolation(mut event) => {
+                event.client_mac = self.mac(event.client_mac);
+                Event::ProtocolViolation(event)
+            }
+            Event::LeaseTimeAnomaly(mut event) => {
+                event.client_mac = self.mac(event.client_mac);
+                Event::LeaseTimeAnomaly(event)
+            }
+            Event::Lease(mut event) => {
+                event.mac = self.mac(event.mac);
+                event.hostname = self.hostname(event.hostname);
+                Event::Lease(event)
+            }
+            Event::Presence(mut event) => {
+                event.mac = self.mac(event.mac);
+                event.hostname = self.hostname(event.hostname);
+                Event::Presence(event)
+            }
+            // About metrics and servers, not devices
+            event @ (Event::Alert(_) | Event::ServerCheck(_)) => event,
+        })
+    }
+
+    pub fn device(&self, mut device: Device) -> Device {
+        device.mac = self.mac(device.mac);
+        device.hostname = self.hostname(device.hostname);
+        if let Some(leased) = &mut device.leased {
+            leased.hostname = self.hostname(leased.hostname.take());
+        }
+        device
+    }
+
+    pub fn snapshot(&self, mut snapshot: Snapshot) -> Snapshot {
+        for lease in &mut snapshot.leases {
+            lease.mac = self.mac(lease.mac);
+            lease.hostname = self.hostname(lease.hostname.take());
+        }
+        snapshot.devices = snapshot
+            .devices
+            .into_iter()
+            .map(|device| self.device(device))
+            .collect();
+        snapshot
+    }
+
+    pub fn message(&self, mut msg: DhcpMessage) -> DhcpMessage {
+        msg.client_mac = self.mac(msg.client_mac);
+        msg.hostname = self.hostname(msg.hostname);
+        if self.drop_hostnames {
+            msg.unknown_options
+                .retain(|option| option.code != OPTION_CLIENT_FQDN);
+        }
+        msg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::SystemTime};
+
+    use super::*;
+    use crate::{
+        devices::DeviceStore,
+        events::{ChangeEvent, LeaseAction, LeaseEvent},
+        message::{MessageType, UnknownOption},
+    };
+
+    const MAC: MacAddr = MacAddr([0x52, 0x54, 0x00, 0x1a, 0x2b, 0x3c]);
+
+    fn redactor(mac: MacRedaction, drop_hostnames: bool, key: Option<&str>) -> Redactor {
+        Redactor::new(&RedactionConfig {
+            mac,
+            drop_hostnames,
+            key: key.map(|key| SecretSource::Plain(key.to_owned())),
+        })
+        .unwrap()
+        .expect("nothing to redact")
+    }
+
+    fn lease_event(hostname: Option<&str>) -> Event {
+        Event::Lease(LeaseEvent {
+            action: LeaseAction::Bound,
+            mac: MAC,
+            address: Ipv4Addr::new(10, 0, 0, 5),
+            hostname: hostname.map(str::to_owned),
+            class: None,
+            ifindex: 1,
+            vlan: None,
+            server_id: None,
+            lease_time: Some(3600),
+            at: SystemTime::now(),
+        })
+    }
+
+    fn changed(change: Change) -> Event {
+        Event::Changed(ChangeEvent {
+            mac: MAC,
+            at: SystemTime::now(),
+            change,
+            frozen: false,
+        })
+    }
+
+    #[test]
+    fn nothing_to_redact() {
+        assert!(Redactor::new(&RedactionConfig::default())
+            .unwrap()
+            .is_none());
+        assert!(Redactor::new(&RedactionConfig {
+            mac: MacRedaction::Hash,
+            ..RedactionConfig::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn hash_pseudonyms() {
+        let hash = redactor(MacRedaction::Hash, false, Some("site-1"));
+        let pseudonym = hash.mac(MAC);
+
+        assert_ne!(pseudonym, MAC);
+        assert_eq!(hash.mac(MAC), pseudonym);
+        assert_eq!(
+            redactor(MacRedaction::Hash, true, Some("site-1")).mac(MAC),
+            pseudonym
+        );
+        assert_ne!(
+            redactor(MacRedaction::Hash, false, Some("site-2")).mac(MAC),
+            pseudonym
+        );
+        assert_ne!(
+            hash.mac(MacAddr([0x52, 0x54, 0x00, 0x1a, 0x2b, 0x3d])),
+            pseudonym
+        );
+
+        for mac in [MAC, MacAddr([0xff; 6]), MacAddr([0; 6])] {
+            // Locally administered, unicast
+            assert_eq!(hash.mac(mac).0[0] & 0x03, 0x02, "{}", mac);
+        }
+    }
+
+    #[test]
+    fn truncate_keeps_the_oui() {
+        let truncate = redactor(MacRedaction::Truncate, false, None);
+        assert_eq!(truncate.mac(MAC), MacAddr([0x52, 0x54, 0x00, 0, 0, 0]));
+
+        let keep = redactor(MacRedaction::Keep, true, None);
+        assert_eq!(keep.mac(MAC), MAC);
+    }
+
+    #[test]
+    fn hostnames_are_dropped_from_messages() {
+        let mut msg = DhcpMessage::test(MessageType::Request, MAC);
+        msg.hostname = Some("laptop".to_owned());
+        msg.unknown_options = vec![
+            UnknownOption {
+                code: OPTION_CLIENT_FQDN,
+                length: 9,
+                data: b"\x01\x00\x00laptop".to_vec(),
+            },
+            UnknownOption {
+                code: 61,
+                length: 7,
+                data: vec![1, 0x52, 0x54, 0x00, 0x1a, 0x2b, 0x3c],
+            },
+        ];
+
+        let redacted = redactor(MacRedaction::Truncate, true, None).message(msg.clone());
+        assert_eq!(redacted.client_mac, MacAddr([0x52, 0x54, 0x00, 0, 0, 0]));
+        assert_eq!(redacted.hostname, None);
+        assert_eq!(redacted.unknown_options, msg.unknown_options[1..]);
+
+        let kept = redactor(MacRedaction::Truncate, false, None).message(msg.clone());
+        assert_eq!(kept.hostname.as_deref(), Some("laptop"));
+        assert_eq!(kept.unknown_options, msg.unknown_options);
+    }
+
+    #[test]
+    fn hostnames_are_dropped_from_events() {
+        let redactor = redactor(MacRedaction::Hash, true, Some("site-1"));
+
+        match redactor.event(lease_event(Some("laptop"))) {
+            Some(Event::Lease(lease)) => {
+                assert_eq!(lease.mac, redactor.mac(MAC));
+                assert_eq!(lease.hostname, None);
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+
+        // About nothing but the hostname
+        let renamed = changed(Change::Hostname {
+            old: "laptop".to_owned(),
+            new: "laptop-2".to_owned(),
+        });
+        assert!(redactor.event(renamed).is_none());
+
+        let moved = changed(Change::Address {
+            old: Ipv4Addr::new(10, 0, 0, 5),
+            new: Ipv4Addr::new(10, 0, 0, 6),
+        });
+        match redactor.event(moved) {
+            Some(Event::Changed(change)) => assert_eq!(change.mac, redactor.mac(MAC)),
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    #[test]
+    fn hostnames_are_dropped_from_devices() {
+        let mut store = DeviceStore::default();
+        let mut ack = DhcpMessage::test(MessageType::Ack, MAC);
+        ack.your_address = Ipv4Addr::new(10, 0, 0, 5);
+        ack.hostname = Some("laptop".to_owned());
+        store.observe(&ack, None);
+        let device = store.get(&MAC).unwrap().clone();
+        assert_eq!(
+            device.leased.as_ref().unwrap().hostname.as_deref(),
+            Some("laptop")
+        );
+
+        let redactor = redactor(MacRedaction::Hash, true, Some("site-1"));
+        let redacted = redactor.device(device);
+        assert_eq!(redacted.mac, redactor.mac(MAC));
+        assert_eq!(redacted.hostname, None);
+        assert_eq!(redacted.leased.unwrap().hostname, None);
+        assert_eq!(redacted.address, Some(Ipv4Addr::new(10, 0, 0, 5)));
+    }
+}
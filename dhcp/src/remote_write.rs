@@ -0,0 +1,517 @@
+//! Pushes the metrics served on `/metrics` to a Prometheus remote write endpoint, e.g. Mimir
+//! or VictoriaMetrics, for hosts that can't be scraped
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use hyper::{
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, USER_AGENT},
+    Body, Client, Method, Request, StatusCode,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::time::{interval, timeout, MissedTickBehavior};
+
+use crate::{
+    config::deserialize_duration,
+    delivery::{Failure, RetryPolicy},
+    metrics::{self, Family},
+    secret::{Secret, SecretSource},
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const HEALTH_SINK: &str = "remote-write";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RemoteWriteConfig {
+    pub url: String,
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// Sent as a bearer token
+    pub token: Option<SecretSource>,
+    /// Sent as `X-Scope-OrgID`, for multi tenant Mimir and Cortex
+    pub tenant: Option<String>,
+    /// Added to every series, e.g. `instance = "router-1"`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+impl RemoteWriteConfig {
+    pub fn new(url: String) -> RemoteWriteConfig {
+        RemoteWriteConfig {
+            url,
+            interval: DEFAULT_INTERVAL,
+            token: None,
+            tenant: None,
+            labels: BTreeMap::new(),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+fn default_interval() -> Duration {
+    DEFAULT_INTERVAL
+}
+
+struct Writer {
+    config: RemoteWriteConfig,
+    token: Option<Secret>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+/// Remote write with its token resolved
+pub struct RemoteWriteSink {
+    config: RemoteWriteConfig,
+    token: Option<Secret>,
+}
+
+impl RemoteWriteSink {
+    pub fn new(config: RemoteWriteConfig) -> Result<RemoteWriteSink, anyhow::Error> {
+        let token = match &config.token {
+            Some(token) => Some(
+                token
+                    .resolve()
+                    .context("failed to resolve the remote write token")?,
+            ),
+            None => None,
+        };
+
+        Ok(RemoteWriteSink { config, token })
+    }
+}
+
+impl sinks::Sink for RemoteWriteSink {
+    fn name(&self) -> String {
+        "remote write".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(self.config, self.token, ctx.backend.clone()).boxed()
+    }
+}
+
+/// Push the current metrics every `interval`. A push that fails is dropped, the next one
+/// carries the same counters anyway.
+async fn run(
+    config: RemoteWriteConfig,
+    token: Option<Secret>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    for name in config.labels.keys() {
+        anyhow::ensure!(
+            is_valid_label_name(name),
+            "invalid remote write label name {:?}",
+            name
+        );
+    }
+
+    let client = Client::builder().build(
+        HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build(),
+    );
+    info!(
+        "pushing metrics to {} every {}",
+        config.url,
+        humantime::format_duration(config.interval)
+    );
+    let mut ticks = interval(config.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let writer = Writer {
+        config,
+        token,
+        client,
+    };
+
+    loop {
+        ticks.tick().await;
+
+        let families = match metrics::collect(&backend) {
+            Ok(families) => families,
+            Err(e) => {
+                warn!("failed to collect metrics: {:#}", e);
+                continue;
+            }
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let body = body(&families, &writer.config.labels, timestamp)?;
+
+        let status = match writer.config.retry.run(|| writer.send(&body)).await {
+            Ok(()) => Ok(()),
+            Err(Failure::Transient(e) | Failure::Permanent(e)) => {
+                warn!("failed to push metrics to {}: {:#}", writer.config.url, e);
+                Err(format!("{:#}", e))
+            }
+        };
+        backend.health.set_sink(HEALTH_SINK, status);
+    }
+}
+
+impl Writer {
+    async fn send(&self, body: &[u8]) -> Result<(), Failure> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.config.url)
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header(CONTENT_ENCODING, "snappy")
+            .header(
+                USER_AGENT,
+                concat!("dhcp-snoop/", env!("CARGO_PKG_VERSION")),
+            )
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token.expose()));
+        }
+        if let Some(tenant) = &self.config.tenant {
+            request = request.header("X-Scope-OrgID", tenant);
+        }
+        let request = request
+            .body(Body::from(body.to_vec()))
+            .context("invalid request")
+            .map_err(Failure::Permanent)?;
+
+        let response = timeout(REQUEST_TIMEOUT, self.client.request(request))
+            .await
+            .context("timed out")
+            .map_err(Failure::Transient)?
+            .context("request failed")
+            .map_err(Failure::Transient)?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Transient(anyhow::anyhow!("{}", status)))
+        } else {
+            Err(Failure::Permanent(anyhow::anyhow!("{}", status)))
+        }
+    }
+}
+
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+}
+
+/// What's POSTed, the `WriteRequest` compressed with raw (not framed) snappy
+fn body(
+    families: &[Family],
+    extra: &BTreeMap<String, String>,
+    timestamp: i64,
+) -> Result<Vec<u8>, anyhow::Error> {
+    snap::raw::Encoder::new()
+        .compress_vec(&encode(families, extra, timestamp))
+        .context("failed to compress metrics")
+}
+
+/// A `prometheus.WriteRequest` protobuf, one series per sample with a single sample each
+fn encode(families: &[Family], extra: &BTreeMap<String, String>, timestamp: i64) -> Vec<u8> {
+    let mut request = Vec::new();
+
+    for family in families {
+        for sample in &family.samples {
+            let mut labels: Vec<(&str, &str)> = sample
+                .labels
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect();
+            // The metric's own labels win over the configured ones
+            for (name, value) in extra {
+                if !labels.iter().any(|(n, _)| n == name) {
+                    labels.push((name.as_str(), value.as_str()));
+                }
+            }
+            labels.push(("__name__", family.name.as_str()));
+            // Receivers expect labels sorted by name
+            labels.sort_unstable();
+
+            let mut series = Vec::new();
+            for (name, value) in labels {
+                let mut label = Vec::new();
+                put_bytes(&mut label, 1, name.as_bytes());
+                put_bytes(&mut label, 2, value.as_bytes());
+                put_bytes(&mut series, 1, &label);
+            }
+            let mut point = Vec::new();
+            put_key(&mut point, 1, WIRE_FIXED64);
+            point.extend_from_slice(&sample.value.to_le_bytes());
+            put_key(&mut point, 2, WIRE_VARINT);
+            put_varint(&mut point, timestamp as u64);
+            put_bytes(&mut series, 2, &point);
+
+            put_bytes(&mut request, 1, &series);
+        }
+    }
+
+    request
+}
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+
+fn put_key(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(buf, u64::from(field << 3 | u32::from(wire_type)));
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(buf, field, WIRE_LEN);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Kind, Sample};
+
+    /// The labels, value and timestamp of every series in a `WriteRequest`
+    type Series = (Vec<(String, String)>, f64, i64);
+
+    fn family(name: &str, samples: Vec<Sample>) -> Family {
+        Family {
+            name: name.to_owned(),
+            help: "",
+            kind: Kind::Counter,
+            samples,
+        }
+    }
+
+    fn take_varint(buf: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = buf.split_first().expect("truncated varint");
+            *buf = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+        panic!("varint longer than 10 bytes")
+    }
+
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> &'a [u8] {
+        let (taken, rest) = buf.split_at(len);
+        *buf = rest;
+        taken
+    }
+
+    /// The fields of a message as (number, wire type, contents)
+    fn fields(mut buf: &[u8]) -> Vec<(u64, u8, &[u8])> {
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = take_varint(&mut buf);
+            let wire_type = (key & 7) as u8;
+            let contents = match wire_type {
+                WIRE_VARINT => {
+                    let start = buf;
+                    take_varint(&mut buf);
+                    &start[..start.len() - buf.len()]
+                }
+                WIRE_FIXED64 => take(&mut buf, 8),
+                WIRE_LEN => {
+                    let len = take_varint(&mut buf) as usize;
+                    take(&mut buf, len)
+                }
+                _ => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.push((key >> 3, wire_type, contents));
+        }
+        fields
+    }
+
+    fn string(bytes: &[u8]) -> String {
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn decode(request: &[u8]) -> Vec<Series> {
+        fields(request)
+            .into_iter()
+            .map(|(number, wire_type, series)| {
+                assert_eq!((number, wire_type), (1, WIRE_LEN));
+                let mut labels = Vec::new();
+                let mut point = None;
+                for (number, _, contents) in fields(series) {
+                    match number {
+                        1 => {
+                            let label = fields(contents);
+                            assert_eq!(label.len(), 2);
+                            labels.push((string(label[0].2), string(label[1].2)));
+                        }
+                        2 => {
+                            assert!(point.is_none(), "more than one sample");
+                            let sample = fields(contents);
+                            assert_eq!((sample[0].0, sample[0].1), (1, WIRE_FIXED64));
+                            assert_eq!((sample[1].0, sample[1].1), (2, WIRE_VARINT));
+                            let value = f64::from_le_bytes(sample[0].2.try_into().unwrap());
+                            let mut timestamp = sample[1].2;
+                            point = Some((value, take_varint(&mut timestamp) as i64));
+                        }
+                        _ => panic!("unexpected field {} in a series", number),
+                    }
+                }
+                let (value, timestamp) = point.expect("no sample");
+                (labels, value, timestamp)
+            })
+            .collect()
+    }
+
+    fn labels(labels: &[(&str, &str)]) -> Vec<(String, String)> {
+        labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn varints() {
+        for (value, bytes) in [
+            (0, vec![0x00]),
+            (1, vec![0x01]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (300, vec![0xac, 0x02]),
+            (
+                u64::MAX,
+                vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+            ),
+        ] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, value);
+            assert_eq!(buf, bytes, "{}", value);
+        }
+    }
+
+    #[test]
+    fn known_write_request() {
+        let families = [family(
+            "up",
+            vec![Sample {
+                labels: Vec::new(),
+                value: 1.0,
+            }],
+        )];
+
+        assert_eq!(
+            encode(&families, &BTreeMap::new(), 1000),
+            b"\x0a\x1e\
+              \x0a\x0e\x0a\x08__name__\x12\x02up\
+              \x12\x0c\x09\x00\x00\x00\x00\x00\x00\xf0\x3f\x10\xe8\x07"
+        );
+    }
+
+    #[test]
+    fn series_carry_sorted_labels_with_the_configured_ones() {
+        let families = [
+            family(
+                "dhcp_snoop_messages_total",
+                vec![
+                    Sample {
+                        labels: vec![("type", "ack".to_owned()), ("interface", "eth0".to_owned())],
+                        value: 42.0,
+                    },
+                    Sample {
+                        labels: vec![("type", "nak".to_owned()), ("interface", "eth0".to_owned())],
+                        value: 0.5,
+                    },
+                ],
+            ),
+            family("dhcp_snoop_up", Vec::new()),
+        ];
+        let extra = BTreeMap::from([
+            ("instance".to_owned(), "router-1".to_owned()),
+            ("interface".to_owned(), "configured".to_owned()),
+        ]);
+
+        let timestamp = 1_667_384_100_000;
+        assert_eq!(
+            decode(&encode(&families, &extra, timestamp)),
+            [
+                (
+                    labels(&[
+                        ("__name__", "dhcp_snoop_messages_total"),
+                        ("instance", "router-1"),
+                        ("interface", "eth0"),
+                        ("type", "ack"),
+                    ]),
+                    42.0,
+                    timestamp
+                ),
+                (
+                    labels(&[
+                        ("__name__", "dhcp_snoop_messages_total"),
+                        ("instance", "router-1"),
+                        ("interface", "eth0"),
+                        ("type", "nak"),
+                    ]),
+                    0.5,
+                    timestamp
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn body_is_raw_snappy() {
+        let families = [family(
+            "dhcp_snoop_leases",
+            vec![Sample {
+                labels: vec![("interface", "eth0".to_owned())],
+                value: 12.0,
+            }],
+        )];
+        let extra = BTreeMap::new();
+
+        let body = body(&families, &extra, 1000).unwrap();
+        let request = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        assert_eq!(request, encode(&families, &extra, 1000));
+        assert_eq!(
+            decode(&request),
+            [(
+                labels(&[("__name__", "dhcp_snoop_leases"), ("interface", "eth0")]),
+                12.0,
+                1000
+            )]
+        );
+    }
+
+    #[test]
+    fn label_names() {
+        for name in ["instance", "_site", "rack2"] {
+            assert!(is_valid_label_name(name), "{}", name);
+        }
+        for name in ["", "2rack", "__name__", "__meta", "site-id", "café"] {
+            assert!(!is_valid_label_name(name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Puts DHCP messages back in the order the eBPF program saw them.
+//!
+//! The program runs on whichever CPU the NIC's RSS hashing steered a frame to, and every
+//! CPU has its own perf buffer, read by its own task. Messages from one CPU arrive in
+//! order, but nothing orders them against those from another. A client's messages and the
+//! server's replies usually hash to different queues, so a RELEASE can overtake the ACK it
+//! undoes and leave a lease behind the client gave up, or an ACK its REQUEST. Every message
+//! is held for `window` and let go in kernel timestamp order, ties going by arrival.
+
+use std::{cmp::Ordering, collections::BinaryHeap, time::Duration};
+
+use log::debug;
+use serde::Deserialize;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::{clock, config::deserialize_duration, message::DhcpMessage};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OrderingConfig {
+    /// How long messages are held back, longer than the perf buffers of two CPUs are
+    /// ever read apart. Zero passes them on as they come.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub window: Duration,
+}
+
+impl Default for OrderingConfig {
+    fn default() -> Self {
+        OrderingConfig {
+            window: Duration::from_millis(50),
+        }
+    }
+}
+
+struct Entry {
+    timestamp_ns: u64,
+    /// Order of arrival, which is the order on the CPU it came from
+    sequence: u64,
+    msg: DhcpMessage,
+}
+
+impl Entry {
+    fn key(&self) -> (u64, u64) {
+        (self.timestamp_ns, self.sequence)
+    }
+}
+
+// Reversed, for `BinaryHeap` to pop the earliest first
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Entry {}
+
+struct ReorderBuffer {
+    window_ns: u64,
+    heap: BinaryHeap<Entry>,
+    sequence: u64,
+    /// Timestamp of the last message let go
+    released_ns: u64,
+}
+
+impl ReorderBuffer {
+    fn new(window: Duration) -> ReorderBuffer {
+        ReorderBuffer {
+            window_ns: window.as_nanos() as u64,
+            heap: BinaryHeap::new(),
+            sequence: 0,
+            released_ns: 0,
+        }
+    }
+
+    fn push(&mut self, msg: DhcpMessage) {
+        if msg.timestamp_ns < self.released_ns {
+            debug!(
+                "{} with xid {:#010x} took longer than the reorder window to arrive, handled out \
+                 of order",
+                msg.message_type, msg.xid
+            );
+        }
+        self.sequence += 1;
+        self.heap.push(Entry {
+            timestamp_ns: msg.timestamp_ns,
+            sequence: self.sequence,
+            msg,
+        });
+    }
+
+    /// How long until the earliest message is due
+    fn next_due(&self, now_ns: u64) -> Option<Duration> {
+        let entry = self.heap.peek()?;
+        let due = entry.timestamp_ns.saturating_add(self.window_ns);
+        Some(Duration::from_nanos(due.saturating_sub(now_ns)))
+    }
+
+    /// The earliest message, once it's been held for the whole window by `now_ns`
+    fn pop_due(&mut self, now_ns: u64) -> Option<DhcpMessage> {
+        let entry = self.heap.peek()?;
+        if entry.timestamp_ns.saturating_add(self.window_ns) > now_ns {
+            return None;
+        }
+        self.pop()
+    }
+
+    fn pop(&mut self) -> Option<DhcpMessage> {
+        let entry = self.heap.pop()?;
+        self.released_ns = self.released_ns.max(entry.timestamp_ns);
+        Some(entry.msg)
+    }
+}
+
+/// Pass the messages from `rx` on to `tx` in order until either side closes
+pub async fn run(config: OrderingConfig, mut rx: Receiver<DhcpMessage>, tx: Sender<DhcpMessage>) {
+    let mut buffer = ReorderBuffer::new(config.window);
+
+    loop {
+        let next_due = buffer.next_due(clock::monotonic_ns());
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => buffer.push(msg),
+                None => break,
+            },
+            _ = tokio::time::sleep(next_due.unwrap_or_default()), if next_due.is_some() => {}
+        }
+
+        while let Some(msg) = buffer.pop_due(clock::monotonic_ns()) {
+            if tx.send(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Nothing more is coming, what's held is in order already
+    while let Some(msg) = buffer.pop() {
+        if tx.send(msg).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{mac::MacAddr, message::MessageType};
+
+    const WINDOW: Duration = Duration::from_nanos(1_000);
+
+    fn message(message_type: MessageType, xid: u32, timestamp_ns: u64) -> DhcpMessage {
+        let mut msg = DhcpMessage::test(message_type, MacAddr([0x02, 0, 0, 0, 0, 1]));
+        msg.xid = xid;
+        msg.timestamp_ns = timestamp_ns;
+        msg
+    }
+
+    /// Everything due by `now_ns`, as (type, xid, timestamp)
+    fn due(buffer: &mut ReorderBuffer, now_ns: u64) -> Vec<(MessageType, u32, u64)> {
+        std::iter::from_fn(|| buffer.pop_due(now_ns))
+            .map(|msg| (msg.message_type, msg.xid, msg.timestamp_ns))
+            .collect()
+    }
+
+    #[test]
+    fn out_of_order_messages_come_out_in_order() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        buffer.push(message(MessageType::Release, 2, 300));
+        buffer.push(message(MessageType::Request, 1, 100));
+        buffer.push(message(MessageType::Ack, 1, 200));
+
+        assert_eq!(
+            due(&mut buffer, 10_000),
+            [
+                (MessageType::Request, 1, 100),
+                (MessageType::Ack, 1, 200),
+                (MessageType::Release, 2, 300),
+            ]
+        );
+        assert!(due(&mut buffer, 10_000).is_empty());
+    }
+
+    #[test]
+    fn messages_are_held_for_the_window() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        assert_eq!(buffer.next_due(0), None);
+
+        buffer.push(message(MessageType::Request, 1, 100));
+        buffer.push(message(MessageType::Ack, 1, 400));
+        assert_eq!(buffer.next_due(600), Some(Duration::from_nanos(500)));
+
+        assert!(due(&mut buffer, 1_099).is_empty());
+        assert_eq!(due(&mut buffer, 1_100), [(MessageType::Request, 1, 100)]);
+        assert_eq!(buffer.next_due(1_100), Some(Duration::from_nanos(300)));
+        // Overdue, not negative
+        assert_eq!(buffer.next_due(5_000), Some(Duration::ZERO));
+        assert_eq!(due(&mut buffer, 5_000), [(MessageType::Ack, 1, 400)]);
+        assert_eq!(buffer.next_due(5_000), None);
+    }
+
+    #[test]
+    fn late_messages_are_passed_on_anyway() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        buffer.push(message(MessageType::Ack, 1, 2_000));
+        assert_eq!(due(&mut buffer, 3_000), [(MessageType::Ack, 1, 2_000)]);
+
+        // Older than what's been let go already
+        buffer.push(message(MessageType::Request, 1, 1_500));
+        assert_eq!(due(&mut buffer, 3_000), [(MessageType::Request, 1, 1_500)]);
+    }
+
+    #[test]
+    fn duplicate_xids_are_all_kept() {
+        let offer = |server: Ipv4Addr| {
+            let mut msg = message(MessageType::Offer, 7, 600);
+            msg.server_id = Some(server);
+            msg
+        };
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        // A retransmitted DISCOVER, and the OFFERs of two servers at the same instant
+        buffer.push(message(MessageType::Discover, 7, 500));
+        buffer.push(offer(Ipv4Addr::new(10, 0, 0, 1)));
+        buffer.push(message(MessageType::Discover, 7, 100));
+        buffer.push(offer(Ipv4Addr::new(10, 0, 0, 2)));
+
+        let released: Vec<_> = std::iter::from_fn(|| buffer.pop_due(10_000))
+            .map(|msg| (msg.message_type, msg.timestamp_ns, msg.server_id))
+            .collect();
+        // Ties go by arrival
+        assert_eq!(
+            released,
+            [
+                (MessageType::Discover, 100, None),
+                (MessageType::Discover, 500, None),
+                (MessageType::Offer, 600, Some(Ipv4Addr::new(10, 0, 0, 1))),
+                (MessageType::Offer, 600, Some(Ipv4Addr::new(10, 0, 0, 2))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn held_messages_are_flushed_in_order_when_the_input_closes() {
+        let (input, rx) = mpsc::channel(8);
+        let (tx, mut output) = mpsc::channel(8);
+        let config = OrderingConfig {
+            window: Duration::from_secs(3600),
+        };
+
+        let now = clock::monotonic_ns();
+        input
+            .send(message(MessageType::Ack, 1, now + 2))
+            .await
+            .unwrap();
+        input
+            .send(message(MessageType::Request, 1, now + 1))
+            .await
+            .unwrap();
+        drop(input);
+        run(config, rx, tx).await;
+
+        let mut released = Vec::new();
+        while let Some(msg) = output.recv().await {
+            released.push(msg.message_type);
+        }
+        assert_eq!(released, [MessageType::Request, MessageType::Ack]);
+    }
+}
@@ -0,0 +1,312 @@
+//! Holds what the eBPF program saw on each interface against what the kernel says arrived
+//! there. A driver that doesn't run the program on every frame, or a NIC stripping VLAN
+//! tags before the program gets to look at them, otherwise goes unnoticed.
+
+use std::{collections::HashMap, fs, time::Duration};
+
+use anyhow::Context;
+use dhcp_common::{Stat, STAT_COUNT};
+use futures::{future::BoxFuture, FutureExt};
+use log::warn;
+use serde::Deserialize;
+
+use crate::{
+    config::deserialize_duration,
+    iface,
+    metrics::{ERROR_STATS, MESSAGE_STATS},
+    packet::PacketSocket,
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const ETH_P_ALL: u16 = 0x0003;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88a8;
+const IPPROTO_UDP: u8 = 17;
+const VLAN_CONFIG: &str = "/proc/net/vlan/config";
+/// Fewer packets than this between two checks say nothing either way
+const MIN_PACKETS: u64 = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SanityCheckConfig {
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// Share of the packets the kernel received the program may not have seen before it's
+    /// reported
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+    /// Also capture DHCP this long on every interface at each check, and compare it with
+    /// what the program counted meanwhile. Off when zero.
+    #[serde(
+        default = "default_spot_check",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub spot_check: Duration,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+fn default_tolerance() -> f64 {
+    0.05
+}
+
+fn default_spot_check() -> Duration {
+    Duration::ZERO
+}
+
+/// Counter readings for one interface at one check
+#[derive(Clone, Copy)]
+struct Sample {
+    rx_packets: u64,
+    /// Of the VLAN interfaces on top of it
+    vlan_rx_packets: u64,
+    seen: u64,
+    tagged: u64,
+}
+
+impl Sample {
+    /// `None` when a counter went backwards, the interface was recreated or the program
+    /// reloaded
+    fn since(&self, earlier: &Sample) -> Option<Sample> {
+        Some(Sample {
+            rx_packets: self.rx_packets.checked_sub(earlier.rx_packets)?,
+            vlan_rx_packets: self.vlan_rx_packets.checked_sub(earlier.vlan_rx_packets)?,
+            seen: self.seen.checked_sub(earlier.seen)?,
+            tagged: self.tagged.checked_sub(earlier.tagged)?,
+        })
+    }
+}
+
+impl sinks::Sink for SanityCheckConfig {
+    fn name(&self) -> String {
+        "sanity check".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(*self, ctx.backend.clone()).map(Ok).boxed()
+    }
+}
+
+/// Check every attached interface each `interval` until the daemon exits
+async fn run(config: SanityCheckConfig, backend: Backend) {
+    let mut interval = tokio::time::interval(config.interval);
+    let mut previous: HashMap<u32, Sample> = HashMap::new();
+
+    loop {
+        interval.tick().await;
+
+        let names: Vec<String> = {
+            let state = backend.state.lock().unwrap();
+            state
+                .interfaces
+                .iter()
+                .map(|interface| interface.name.clone())
+                .collect()
+        };
+        let counters = match program_counters(&backend) {
+            Ok(counters) => counters,
+            Err(e) => {
+                warn!(
+                    "sanity check failed to read the program's counters: {:#}",
+                    e
+                );
+                continue;
+            }
+        };
+        let vlans = vlan_interfaces();
+
+        for name in names {
+            let ifindex = match iface::index(&name) {
+                Some(ifindex) => ifindex,
+                None => continue,
+            };
+            let program = counters.get(&ifindex).copied().unwrap_or([0; STAT_COUNT]);
+            let sample = match sample(&name, &program, &vlans) {
+                Ok(sample) => sample,
+                Err(e) => {
+                    warn!("sanity check of {} failed: {:#}", name, e);
+                    continue;
+                }
+            };
+            if let Some(delta) = previous
+                .insert(ifindex, sample)
+                .and_then(|earlier| sample.since(&earlier))
+            {
+                check(&config, &name, &delta);
+            }
+
+            if !config.spot_check.is_zero() {
+                if let Err(e) = spot_check(&config, &backend, &name, ifindex).await {
+                    warn!("spot check of {} failed: {:#}", name, e);
+                }
+            }
+        }
+    }
+}
+
+fn check(config: &SanityCheckConfig, name: &str, delta: &Sample) {
+    let missed = delta.rx_packets.saturating_sub(delta.seen);
+    if delta.rx_packets >= MIN_PACKETS && missed as f64 > delta.rx_packets as f64 * config.tolerance
+    {
+        warn!(
+            "{} received {} packets since the last check but the program only saw {}, \
+             it may be missing DHCP traffic",
+            name, delta.rx_packets, delta.seen
+        );
+    }
+
+    if delta.vlan_rx_packets >= MIN_PACKETS && delta.tagged == 0 {
+        warn!(
+            "{} packets arrived on the VLANs of {} but none the program saw carried a tag, \
+             the NIC is probably stripping them (see vlan-offload)",
+            delta.vlan_rx_packets, name
+        );
+    }
+}
+
+fn sample(
+    name: &str,
+    program: &[u64; STAT_COUNT],
+    vlans: &[(String, String)],
+) -> Result<Sample, anyhow::Error> {
+    let mut vlan_rx_packets = 0;
+    for (vlan, _) in vlans.iter().filter(|(_, parent)| parent == name) {
+        vlan_rx_packets += rx_packets(vlan)?;
+    }
+
+    Ok(Sample {
+        rx_packets: rx_packets(name)?,
+        vlan_rx_packets,
+        seen: program[Stat::Packets as usize],
+        tagged: program[Stat::VlanTagged as usize] + program[Stat::VlanRecovered as usize],
+    })
+}
+
+fn program_counters(backend: &Backend) -> Result<HashMap<u32, [u64; STAT_COUNT]>, anyhow::Error> {
+    Ok(backend
+        .stats
+        .read()?
+        .into_iter()
+        .map(|interface| (interface.ifindex, interface.counters))
+        .collect())
+}
+
+fn rx_packets(name: &str) -> Result<u64, anyhow::Error> {
+    let path = format!("/sys/class/net/{}/statistics/rx_packets", name);
+    fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path))?
+        .trim()
+        .parse()
+        .with_context(|| format!("failed to parse {}", path))
+}
+
+/// VLAN interfaces and the interface each sits on, none when the 8021q module isn't
+/// loaded
+fn vlan_interfaces() -> Vec<(String, String)> {
+    let contents = match fs::read_to_string(VLAN_CONFIG) {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(),
+    };
+
+    // Two header lines, then `eth0.100       | 100  | eth0`
+    contents
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let mut fields = line.split('|').map(str::trim);
+            let vlan = fields.next()?;
+            let _id = fields.next()?;
+            let parent = fields.next()?;
+            Some((vlan.to_owned(), parent.to_owned()))
+        })
+        .collect()
+}
+
+/// Capture DHCP on the interface for `spot_check` and compare it with what the program
+/// counted meanwhile. Frames the program drops never make it to the capture, so it may
+/// count more but never fewer.
+async fn spot_check(
+    config: &SanityCheckConfig,
+    backend: &Backend,
+    name: &str,
+    ifindex: u32,
+) -> Result<(), anyhow::Error> {
+    let packet = PacketSocket::open(ifindex, ETH_P_ALL)?;
+    let before = dhcp_counted(backend, ifindex)?;
+
+    let mut captured = 0;
+    let mut buf = [0; 2048];
+    let deadline = tokio::time::Instant::now() + config.spot_check;
+    while let Ok(len) = tokio::time::timeout_at(deadline, packet.recv_incoming(&mut buf)).await {
+        if is_dhcp(&buf[..len?]) {
+            captured += 1;
+        }
+    }
+
+    let counted = dhcp_counted(backend, ifindex)?.saturating_sub(before);
+    if captured > counted {
+        warn!(
+            "captured {} DHCP frames on {} in {} but the program counted {}",
+            captured,
+            name,
+            humantime::format_duration(config.spot_check),
+            counted
+        );
+    }
+
+    Ok(())
+}
+
+/// Every DHCP frame the program counted on the interface, parsed or not
+fn dhcp_counted(backend: &Backend, ifindex: u32) -> Result<u64, anyhow::Error> {
+    let counters = program_counters(backend)?
+        .remove(&ifindex)
+        .unwrap_or([0; STAT_COUNT]);
+    Ok(MESSAGE_STATS
+        .iter()
+        .chain(ERROR_STATS.iter())
+        .map(|stat| counters[*stat as usize])
+        .sum())
+}
+
+/// An IPv4 UDP datagram between ports 67 and 68, tagged or not
+fn is_dhcp(frame: &[u8]) -> bool {
+    let mut offset = 12;
+    let mut proto = match frame.get(offset..offset + 2) {
+        Some(proto) => u16::from_be_bytes([proto[0], proto[1]]),
+        None => return false,
+    };
+    while proto == ETH_P_8021Q || proto == ETH_P_8021AD {
+        offset += 4;
+        proto = match frame.get(offset..offset + 2) {
+            Some(proto) => u16::from_be_bytes([proto[0], proto[1]]),
+            None => return false,
+        };
+    }
+    if proto != ETH_P_IP {
+        return false;
+    }
+
+    let ip = offset + 2;
+    let header_len = match frame.get(ip) {
+        Some(byte) => (byte & 0x0f) as usize * 4,
+        None => return false,
+    };
+    if frame.get(ip + 9) != Some(&IPPROTO_UDP) {
+        return false;
+    }
+    let ports = match frame.get(ip + header_len..ip + header_len + 4) {
+        Some(ports) => ports,
+        None => return false,
+    };
+    let source = u16::from_be_bytes([ports[0], ports[1]]);
+    let dest = u16::from_be_bytes([ports[2], ports[3]]);
+    matches!((source, dest), (67, 68) | (68, 67) | (67, 67))
+}
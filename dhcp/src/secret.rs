@@ -0,0 +1,120 @@
+//! Credentials in the config file. Besides plain strings these can be pulled from the
+//! environment, a file, a systemd credential or the output of a command, so that they
+//! don't need to sit in the TOML.
+
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Where a secret comes from, as written in the config file
+#[derive(Clone, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum SecretSource {
+    Plain(String),
+    Env {
+        env: String,
+    },
+    File {
+        file: PathBuf,
+    },
+    /// Looked up in `$CREDENTIALS_DIRECTORY`, see `LoadCredential=` in systemd.exec(5)
+    Credential {
+        credential: String,
+    },
+    /// Run with `sh -c`, the secret is whatever it prints
+    Command {
+        command: String,
+    },
+}
+
+impl fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretSource::Plain(_) => f.write_str("Plain(<redacted>)"),
+            SecretSource::Env { env } => f.debug_struct("Env").field("env", env).finish(),
+            SecretSource::File { file } => f.debug_struct("File").field("file", file).finish(),
+            SecretSource::Credential { credential } => f
+                .debug_struct("Credential")
+                .field("credential", credential)
+                .finish(),
+            SecretSource::Command { command } => {
+                f.debug_struct("Command").field("command", command).finish()
+            }
+        }
+    }
+}
+
+impl SecretSource {
+    pub fn resolve(&self) -> Result<Secret, anyhow::Error> {
+        let value = match self {
+            SecretSource::Plain(value) => value.clone(),
+            SecretSource::Env { env } => env::var(env)
+                .with_context(|| format!("failed to read environment variable {}", env))?,
+            SecretSource::File { file } => {
+                read_trimmed(file).with_context(|| format!("failed to read {:?}", file))?
+            }
+            SecretSource::Credential { credential } => {
+                let directory = env::var_os("CREDENTIALS_DIRECTORY")
+                    .context("CREDENTIALS_DIRECTORY isn't set, not running under systemd?")?;
+                let path = PathBuf::from(directory).join(credential);
+                read_trimmed(&path)
+                    .with_context(|| format!("failed to read credential {}", credential))?
+            }
+            SecretSource::Command { command } => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .output()
+                    .with_context(|| format!("failed to run {:?}", command))?;
+                if !output.status.success() {
+                    anyhow::bail!("{:?} exited with {}", command, output.status);
+                }
+                String::from_utf8(output.stdout)
+                    .with_context(|| format!("{:?} printed invalid UTF-8", command))?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_owned()
+            }
+        };
+
+        Ok(Secret(value))
+    }
+}
+
+fn read_trimmed(path: &Path) -> Result<String, std::io::Error> {
+    let mut value = fs::read_to_string(path)?;
+    value.truncate(value.trim_end_matches(['\r', '\n']).len());
+    Ok(value)
+}
+
+/// A resolved credential, never printed
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Compare without bailing out at the first differing byte
+    pub fn matches(&self, other: &str) -> bool {
+        let (a, b) = (self.0.as_bytes(), other.as_bytes());
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
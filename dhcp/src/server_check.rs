@@ -0,0 +1,486 @@
+//! dhcping-style checks on the DHCP servers, for finding out a server stopped answering
+//! before the clients do. Every `interval` each server gets a DORA, or a DHCPINFORM, from
+//! a test MAC, and is reported down once `failures` checks in a row went unanswered.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, mem,
+    net::Ipv4Addr,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use dhcp_common::{BOOTREPLY, DHCP_DISCOVER, DHCP_INFORM, DHCP_RELEASE, DHCP_REQUEST};
+use futures::{future::BoxFuture, FutureExt};
+use log::{debug, info, warn};
+use serde::{de, Deserialize, Deserializer};
+use tokio::time::{interval, timeout_at, Instant};
+
+use crate::{
+    config::deserialize_duration,
+    events::{Event, ServerCheckEvent, ServerState},
+    ha::Role,
+    iface,
+    mac::MacAddr,
+    message::MessageType,
+    packet::{bootp, frame, PacketSocket, ETH_P_IP, MAGIC_COOKIE},
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const HEALTH_NAME: &str = "server-check";
+const IPPROTO_UDP: u8 = 17;
+/// Subnet mask, router, DNS servers and domain name, what an ordinary client asks for
+const PARAMETERS: [u8; 4] = [1, 3, 6, 15];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CheckMode {
+    /// DISCOVER, OFFER, REQUEST and ACK, after which the lease is released
+    #[default]
+    Dora,
+    /// A DHCPINFORM, which doesn't take an address from the pool
+    Inform,
+}
+
+impl FromStr for CheckMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "dora" => CheckMode::Dora,
+            "inform" => CheckMode::Inform,
+            _ => {
+                return Err(format!(
+                    "invalid server check mode {:?}, expected dora or inform",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+impl fmt::Display for CheckMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckMode::Dora => "dora",
+            CheckMode::Inform => "inform",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for CheckMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerCheckConfig {
+    /// Where the checks are sent, on the servers' side of the network
+    pub interface: String,
+    #[serde(default)]
+    pub mode: CheckMode,
+    /// chaddr of the test client, the daemon leaves its exchanges out of the lease table
+    #[serde(default = "default_mac")]
+    pub mac: MacAddr,
+    /// ciaddr of the DHCPINFORMs, an address of `interface`
+    pub address: Option<Ipv4Addr>,
+    /// The servers checked, the trusted servers when empty
+    #[serde(default)]
+    pub servers: Vec<Ipv4Addr>,
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// How long a server has to answer each message
+    #[serde(default = "default_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+    /// Checks failing in a row before the server is reported down
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+}
+
+fn default_mac() -> MacAddr {
+    MacAddr([0x02, 0x53, 0x43, 0x00, 0x00, 0x01])
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_failures() -> u32 {
+    3
+}
+
+/// How the checks on one server have gone, for the metrics
+#[derive(Debug, Clone, Default)]
+pub struct ServerStatus {
+    /// Whether it answered the last check
+    pub answering: bool,
+    /// Of the last check it answered, from the first message sent to the ACK
+    pub latency: Option<Duration>,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+/// A server's reply to the test client
+struct Reply {
+    message_type: MessageType,
+    xid: u32,
+    chaddr: MacAddr,
+    yiaddr: Ipv4Addr,
+    server_id: Option<Ipv4Addr>,
+    /// Of the frame, the server's or its relay's
+    source_mac: MacAddr,
+}
+
+impl sinks::Sink for ServerCheckConfig {
+    fn name(&self) -> String {
+        "server checks".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(*self, ctx.backend.clone()).boxed()
+    }
+}
+
+/// Check the servers every `interval` until the daemon exits
+async fn run(config: ServerCheckConfig, backend: Backend) -> Result<(), anyhow::Error> {
+    if config.mode == CheckMode::Inform && config.address.is_none() {
+        anyhow::bail!("server checks by DHCPINFORM need an address to send them from");
+    }
+    info!(
+        "checking DHCP servers with a {} from {} on {} every {}",
+        match config.mode {
+            CheckMode::Dora => "DORA",
+            CheckMode::Inform => "DHCPINFORM",
+        },
+        config.mac,
+        config.interface,
+        humantime::format_duration(config.interval)
+    );
+
+    let mut ticks = interval(config.interval);
+    // New xids on every start, for replies to an earlier run not to be taken for these
+    let mut xid = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    // Failed checks in a row by server
+    let mut streaks: HashMap<Ipv4Addr, u32> = HashMap::new();
+    let mut warned = false;
+
+    loop {
+        ticks.tick().await;
+        // The active node of an HA pair checks for both
+        if *backend.role.borrow() != Role::Active {
+            continue;
+        }
+
+        let servers = if config.servers.is_empty() {
+            backend.state.lock().unwrap().trusted_servers.list()
+        } else {
+            config.servers.clone()
+        };
+        if servers.is_empty() {
+            if !warned {
+                warn!("no servers to check, set trusted-servers or servers in [server-check]");
+                warned = true;
+            }
+            continue;
+        }
+
+        let mut outcomes = Vec::new();
+        let mut status = Ok(());
+        for &server in &servers {
+            xid = xid.wrapping_add(1);
+            match check(&config, server, xid).await {
+                Ok(outcome) => outcomes.push((server, outcome)),
+                Err(e) => {
+                    warn!("failed to check {}: {:#}", server, e);
+                    status = Err(format!("{}: {:#}", server, e));
+                }
+            }
+        }
+        backend.health.set_sink(HEALTH_NAME, status);
+
+        let mut state = backend.state.lock().unwrap();
+        state
+            .server_checks
+            .retain(|server, _| servers.contains(server));
+        streaks.retain(|server, _| servers.contains(server));
+        for (server, outcome) in outcomes {
+            let status = state.server_checks.entry(server).or_default();
+            let streak = streaks.entry(server).or_default();
+            let event = match outcome {
+                Ok(latency) => {
+                    status.answering = true;
+                    status.latency = Some(latency);
+                    status.successes += 1;
+                    let failures = mem::take(streak);
+                    (failures >= config.failures.max(1)).then(|| ServerCheckEvent {
+                        server,
+                        state: ServerState::Up,
+                        failures,
+                        reason: None,
+                        at: SystemTime::now(),
+                    })
+                }
+                Err(reason) => {
+                    debug!("check of {} failed, it {}", server, reason);
+                    status.answering = false;
+                    status.failures += 1;
+                    *streak += 1;
+                    (*streak == config.failures.max(1)).then(|| ServerCheckEvent {
+                        server,
+                        state: ServerState::Down,
+                        failures: *streak,
+                        reason: Some(reason),
+                        at: SystemTime::now(),
+                    })
+                }
+            };
+
+            if let Some(event) = event {
+                match event.state {
+                    ServerState::Down => warn!("{}", event),
+                    ServerState::Up => info!("{}", event),
+                }
+                state.emit(Event::ServerCheck(event));
+            }
+        }
+    }
+}
+
+/// One check of `server`, `Err` inside when it failed and why. The outer `Err` is for the
+/// check not getting as far as the server.
+async fn check(
+    config: &ServerCheckConfig,
+    server: Ipv4Addr,
+    xid: u32,
+) -> Result<Result<Duration, String>, anyhow::Error> {
+    let ifindex = iface::index(&config.interface)
+        .with_context(|| format!("no interface {}", config.interface))?;
+    let socket = PacketSocket::open(ifindex, ETH_P_IP)?;
+
+    match (config.mode, config.address) {
+        (CheckMode::Inform, Some(address)) => inform(config, &socket, server, address, xid).await,
+        _ => dora(config, &socket, server, xid).await,
+    }
+}
+
+async fn dora(
+    config: &ServerCheckConfig,
+    socket: &PacketSocket,
+    server: Ipv4Addr,
+    xid: u32,
+) -> Result<Result<Duration, String>, anyhow::Error> {
+    let broadcast = MacAddr([0xff; 6]);
+    let (any, all) = (Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST);
+    let started = Instant::now();
+
+    let discover = bootp(
+        1,
+        xid,
+        config.mac,
+        any,
+        any,
+        &[(53, &[DHCP_DISCOVER]), (55, &PARAMETERS)],
+    );
+    socket
+        .send(&frame(config.mac, broadcast, any, all, 68, 67, &discover))
+        .await
+        .context("failed to send DHCPDISCOVER")?;
+    let offer = match receive(config, socket, server, xid).await? {
+        Some(reply) if reply.message_type == MessageType::Offer => reply,
+        reply => return Ok(Err(unanswered(config, MessageType::Discover, reply))),
+    };
+
+    let request = bootp(
+        1,
+        xid,
+        config.mac,
+        any,
+        any,
+        &[
+            (53, &[DHCP_REQUEST]),
+            (50, &offer.yiaddr.octets()),
+            (54, &server.octets()),
+            (55, &PARAMETERS),
+        ],
+    );
+    socket
+        .send(&frame(config.mac, broadcast, any, all, 68, 67, &request))
+        .await
+        .context("failed to send DHCPREQUEST")?;
+    let ack = match receive(config, socket, server, xid).await? {
+        Some(reply) if reply.message_type == MessageType::Ack => reply,
+        reply => return Ok(Err(unanswered(config, MessageType::Request, reply))),
+    };
+    let latency = started.elapsed();
+
+    // Give the address back to the pool
+    let release = bootp(
+        1,
+        xid,
+        config.mac,
+        ack.yiaddr,
+        any,
+        &[(53, &[DHCP_RELEASE]), (54, &server.octets())],
+    );
+    socket
+        .send(&frame(
+            config.mac,
+            ack.source_mac,
+            ack.yiaddr,
+            server,
+            68,
+            67,
+            &release,
+        ))
+        .await
+        .context("failed to send DHCPRELEASE")?;
+
+    Ok(Ok(latency))
+}
+
+/// The ACK goes to `address` rather than the test MAC, so the Ethernet header carries the
+/// interface's own MAC for the switches to know where it is
+async fn inform(
+    config: &ServerCheckConfig,
+    socket: &PacketSocket,
+    server: Ipv4Addr,
+    address: Ipv4Addr,
+    xid: u32,
+) -> Result<Result<Duration, String>, anyhow::Error> {
+    let started = Instant::now();
+
+    let message = bootp(
+        1,
+        xid,
+        config.mac,
+        address,
+        Ipv4Addr::UNSPECIFIED,
+        &[(53, &[DHCP_INFORM]), (55, &PARAMETERS)],
+    );
+    socket
+        .send(&frame(
+            socket.mac,
+            MacAddr([0xff; 6]),
+            address,
+            Ipv4Addr::BROADCAST,
+            68,
+            67,
+            &message,
+        ))
+        .await
+        .context("failed to send DHCPINFORM")?;
+
+    Ok(match receive(config, socket, server, xid).await? {
+        Some(reply) if reply.message_type == MessageType::Ack => Ok(started.elapsed()),
+        reply => Err(unanswered(config, MessageType::Inform, reply)),
+    })
+}
+
+/// Why the server didn't give the answer `sent` asks for
+fn unanswered(config: &ServerCheckConfig, sent: MessageType, reply: Option<Reply>) -> String {
+    match reply {
+        Some(reply) => format!("answered the {} with a {}", sent, reply.message_type),
+        None => format!(
+            "didn't answer the {} within {}",
+            sent,
+            humantime::format_duration(config.timeout)
+        ),
+    }
+}
+
+/// The next reply from `server` to transaction `xid`, `None` when none comes within the
+/// timeout. Other servers answer the broadcasts too, those are ignored.
+async fn receive(
+    config: &ServerCheckConfig,
+    socket: &PacketSocket,
+    server: Ipv4Addr,
+    xid: u32,
+) -> Result<Option<Reply>, anyhow::Error> {
+    let deadline = Instant::now() + config.timeout;
+    let mut buf = [0; 2048];
+
+    loop {
+        let len = match timeout_at(deadline, socket.recv_incoming(&mut buf)).await {
+            Ok(len) => len.context("failed to receive")?,
+            Err(_) => return Ok(None),
+        };
+        match parse_reply(&buf[..len]) {
+            Some(reply)
+                if reply.xid == xid
+                    && reply.chaddr == config.mac
+                    && reply.server_id == Some(server) =>
+            {
+                return Ok(Some(reply))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A BOOTREPLY from port 67 with the options the check looks at
+fn parse_reply(frame: &[u8]) -> Option<Reply> {
+    if u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?) != ETH_P_IP {
+        return None;
+    }
+    let source_mac = MacAddr(frame.get(6..12)?.try_into().ok()?);
+    let ip = frame.get(14..)?;
+    let header_len = (*ip.first()? & 0x0f) as usize * 4;
+    if *ip.get(9)? != IPPROTO_UDP {
+        return None;
+    }
+    let udp = ip.get(header_len..)?;
+    if u16::from_be_bytes(udp.get(0..2)?.try_into().ok()?) != 67 {
+        return None;
+    }
+
+    let message = udp.get(8..)?;
+    if *message.first()? != BOOTREPLY || message.get(236..240)? != MAGIC_COOKIE {
+        return None;
+    }
+    let xid = u32::from_be_bytes(message.get(4..8)?.try_into().ok()?);
+    let yiaddr: [u8; 4] = message.get(16..20)?.try_into().ok()?;
+    let chaddr = MacAddr(message.get(28..34)?.try_into().ok()?);
+
+    let mut options = BTreeMap::new();
+    let mut rest = message.get(240..)?;
+    while let [code, tail @ ..] = rest {
+        match *code {
+            0 => rest = tail,
+            255 => break,
+            _ => {
+                let (&len, tail) = tail.split_first()?;
+                options.insert(*code, tail.get(..len as usize)?);
+                rest = &tail[len as usize..];
+            }
+        }
+    }
+
+    let message_type = MessageType::from(*options.get(&53)?.first()?);
+    let server_id = options
+        .get(&54)
+        .and_then(|id| <[u8; 4]>::try_from(*id).ok())
+        .map(Ipv4Addr::from);
+
+    Some(Reply {
+        message_type,
+        xid,
+        chaddr,
+        yiaddr: yiaddr.into(),
+        server_id,
+        source_mac,
+    })
+}
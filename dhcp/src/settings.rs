@@ -0,0 +1,229 @@
+//! `dhcp config get` and `dhcp config set`, which read and change the settings of the
+//! running program through its pinned maps
+
+use std::{fmt, net::Ipv4Addr, str::FromStr};
+
+use anyhow::Context;
+use aya::{
+    maps::{HashMap, MapRefMut},
+    Bpf,
+};
+use dhcp_common::{IfaceConfig, IFACE_LOG_ONLY, IFACE_RATE_LIMIT_DROP};
+
+use crate::{attach::Enforcement, iface, trusted::TrustedServers};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// Comma separated, `any` to trust every server
+    TrustedServers,
+    Enforcement,
+    /// Setting a rate sets its burst to twice the rate, like the configuration file does
+    ClientRate,
+    ClientBurst,
+    InterfaceRate,
+    InterfaceBurst,
+    RateLimitDrop,
+}
+
+impl Setting {
+    const ALL: [Setting; 7] = [
+        Setting::TrustedServers,
+        Setting::Enforcement,
+        Setting::ClientRate,
+        Setting::ClientBurst,
+        Setting::InterfaceRate,
+        Setting::InterfaceBurst,
+        Setting::RateLimitDrop,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Setting::TrustedServers => "trusted-servers",
+            Setting::Enforcement => "enforcement",
+            Setting::ClientRate => "client-rate",
+            Setting::ClientBurst => "client-burst",
+            Setting::InterfaceRate => "interface-rate",
+            Setting::InterfaceBurst => "interface-burst",
+            Setting::RateLimitDrop => "rate-limit-drop",
+        }
+    }
+}
+
+impl FromStr for Setting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Setting::ALL
+            .into_iter()
+            .find(|setting| setting.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Setting::ALL.iter().map(|setting| setting.name()).collect();
+                format!(
+                    "invalid setting {:?}, expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Padded, `get` lines them up
+        f.pad(self.name())
+    }
+}
+
+/// Print the trusted servers and the settings of `iface`, or of every interface. Values
+/// are printed the way `set` takes them.
+pub fn get(bpf: &Bpf, iface: Option<&str>) -> Result<(), anyhow::Error> {
+    if iface.is_none() {
+        let servers = TrustedServers::new(bpf)?.list();
+        println!("{:<16} {}", Setting::TrustedServers, fmt_servers(&servers));
+    }
+
+    let iface_configs = HashMap::try_from(bpf.map_mut("IFACES")?)?;
+    let interfaces = interfaces(&iface_configs, iface)?;
+    if interfaces.is_empty() {
+        println!("no interfaces configured, a standby daemon doesn't configure any");
+    }
+    for (ifindex, config) in interfaces {
+        println!("{}", iface::name(ifindex));
+        for setting in Setting::ALL {
+            if setting != Setting::TrustedServers {
+                println!("  {:<16} {}", setting, value(setting, &config));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Change `setting` on `iface`, or on every interface. The running program picks it up
+/// right away.
+pub fn set(
+    bpf: &Bpf,
+    setting: Setting,
+    value: &str,
+    iface: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    if setting == Setting::TrustedServers {
+        if iface.is_some() {
+            anyhow::bail!("{} isn't set per interface", setting);
+        }
+        let servers = parse_servers(value)?;
+        TrustedServers::new(bpf)?
+            .replace(&servers)
+            .context("failed to update the trusted servers")?;
+        return Ok(());
+    }
+
+    let mut iface_configs = HashMap::try_from(bpf.map_mut("IFACES")?)?;
+    let interfaces = interfaces(&iface_configs, iface)?;
+    if interfaces.is_empty() {
+        anyhow::bail!("no interfaces configured, a standby daemon doesn't configure any");
+    }
+    for (ifindex, mut config) in interfaces {
+        apply(setting, value, &mut config)?;
+        iface_configs
+            .insert(ifindex, config, 0)
+            .with_context(|| format!("failed to configure {}", iface::name(ifindex)))?;
+    }
+
+    Ok(())
+}
+
+/// The `IFACES` entry of `name`, or every entry sorted by ifindex
+fn interfaces(
+    iface_configs: &HashMap<MapRefMut, u32, IfaceConfig>,
+    name: Option<&str>,
+) -> Result<Vec<(u32, IfaceConfig)>, anyhow::Error> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let mut all = iface_configs.iter().collect::<Result<Vec<_>, _>>()?;
+            all.sort_by_key(|(ifindex, _)| *ifindex);
+            return Ok(all);
+        }
+    };
+
+    let ifindex = iface::index(name).with_context(|| format!("no interface named {}", name))?;
+    let config = iface_configs
+        .get(&ifindex, 0)
+        .with_context(|| format!("{} isn't configured in the running program", name))?;
+    Ok(vec![(ifindex, config)])
+}
+
+fn value(setting: Setting, config: &IfaceConfig) -> String {
+    match setting {
+        Setting::TrustedServers => unreachable!("not an interface setting"),
+        Setting::Enforcement if config.flags & IFACE_LOG_ONLY != 0 => {
+            Enforcement::LogOnly.to_string()
+        }
+        Setting::Enforcement => Enforcement::Drop.to_string(),
+        Setting::ClientRate => config.client_rate.to_string(),
+        Setting::ClientBurst => config.client_burst.to_string(),
+        Setting::InterfaceRate => config.interface_rate.to_string(),
+        Setting::InterfaceBurst => config.interface_burst.to_string(),
+        Setting::RateLimitDrop => (config.flags & IFACE_RATE_LIMIT_DROP != 0).to_string(),
+    }
+}
+
+fn apply(setting: Setting, value: &str, config: &mut IfaceConfig) -> Result<(), anyhow::Error> {
+    let invalid = || format!("invalid {} {:?}", setting, value);
+
+    match setting {
+        Setting::TrustedServers => unreachable!("not an interface setting"),
+        Setting::Enforcement => match value.parse().map_err(anyhow::Error::msg)? {
+            Enforcement::Drop => config.flags &= !IFACE_LOG_ONLY,
+            Enforcement::LogOnly => config.flags |= IFACE_LOG_ONLY,
+        },
+        Setting::ClientRate => {
+            config.client_rate = value.parse().with_context(invalid)?;
+            config.client_burst = config.client_rate.saturating_mul(2);
+        }
+        Setting::ClientBurst => config.client_burst = value.parse().with_context(invalid)?,
+        Setting::InterfaceRate => {
+            config.interface_rate = value.parse().with_context(invalid)?;
+            config.interface_burst = config.interface_rate.saturating_mul(2);
+        }
+        Setting::InterfaceBurst => config.interface_burst = value.parse().with_context(invalid)?,
+        Setting::RateLimitDrop => {
+            if value.parse().with_context(invalid)? {
+                config.flags |= IFACE_RATE_LIMIT_DROP;
+            } else {
+                config.flags &= !IFACE_RATE_LIMIT_DROP;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_servers(value: &str) -> Result<Vec<Ipv4Addr>, anyhow::Error> {
+    if value == "any" {
+        return Ok(Vec::new());
+    }
+
+    value
+        .split(',')
+        .map(|server| {
+            server
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid server address {:?}", server))
+        })
+        .collect()
+}
+
+fn fmt_servers(servers: &[Ipv4Addr]) -> String {
+    if servers.is_empty() {
+        return "any".to_owned();
+    }
+
+    servers
+        .iter()
+        .map(Ipv4Addr::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
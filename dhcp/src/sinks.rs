@@ -0,0 +1,179 @@
+//! Where events, messages and metrics go once the state has dealt with them. Each exporter
+//! lives in a module of its own and implements [`Sink`], as do the tasks watching the
+//! state, like alerts and server checks. `from_config` is the one place that knows which
+//! ones the config turns on.
+
+use futures::future::BoxFuture;
+use log::warn;
+use tokio::sync::broadcast;
+use tracing::Instrument;
+
+use crate::{
+    alerts::AlertSink, config::Config, events::Event, hooks::HookSink, http::MetricsEndpoint,
+    http_sink::HttpSink, loki::LokiSink, message::DhcpMessage, remote_write::RemoteWriteSink,
+    state::Backend, store::StateFile,
+};
+
+pub trait Sink: Send {
+    /// Shows up in logs
+    fn name(&self) -> String;
+
+    /// Subscribe to what the sink wants from `ctx` and return the future that feeds it,
+    /// which runs until the channels close
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>>;
+}
+
+/// What sinks get to work with
+pub struct SinkContext {
+    events: broadcast::Sender<Event>,
+    messages: broadcast::Sender<DhcpMessage>,
+    /// The same as `events` and `messages` once `[redaction]` is through with them
+    redacted_events: broadcast::Sender<Event>,
+    redacted_messages: broadcast::Sender<DhcpMessage>,
+    pub backend: Backend,
+}
+
+impl SinkContext {
+    pub fn new(
+        events: broadcast::Sender<Event>,
+        messages: broadcast::Sender<DhcpMessage>,
+        backend: Backend,
+    ) -> SinkContext {
+        let (redacted_events, redacted_messages) = match backend.redactor.clone() {
+            Some(redactor) => {
+                let redacted_events = broadcast::channel(1024).0;
+                let redacted_messages = broadcast::channel(1024).0;
+                tokio::spawn(redact(events.subscribe(), redacted_events.clone(), {
+                    let redactor = redactor.clone();
+                    move |event| redactor.event(event)
+                }));
+                tokio::spawn(redact(
+                    messages.subscribe(),
+                    redacted_messages.clone(),
+                    move |msg| Some(redactor.message(msg)),
+                ));
+                (redacted_events, redacted_messages)
+            }
+            None => (events.clone(), messages.clone()),
+        };
+
+        SinkContext {
+            events,
+            messages,
+            redacted_events,
+            redacted_messages,
+            backend,
+        }
+    }
+
+    /// Redacted, for sinks sending events off the host
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.redacted_events.subscribe()
+    }
+
+    /// Redacted, for sinks sending messages off the host
+    pub fn messages(&self) -> broadcast::Receiver<DhcpMessage> {
+        self.redacted_messages.subscribe()
+    }
+
+    /// The events as they are, for sinks that keep them on the host and need the real MACs
+    pub fn unredacted_events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn unredacted_messages(&self) -> broadcast::Receiver<DhcpMessage> {
+        self.messages.subscribe()
+    }
+}
+
+/// Pass what comes in on `rx` on to `tx` through `redact` until `rx` closes
+async fn redact<T: Clone>(
+    mut rx: broadcast::Receiver<T>,
+    tx: broadcast::Sender<T>,
+    redact: impl Fn(T) -> Option<T>,
+) {
+    loop {
+        match rx.recv().await {
+            Ok(item) => {
+                if let Some(item) = redact(item) {
+                    // Only fails when nobody is subscribed
+                    let _ = tx.send(item);
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("redaction fell behind, lost {} events or messages", n)
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Every sink `config` turns on, with their secrets resolved so that a missing one stops
+/// the daemon from starting
+pub fn from_config(config: &Config) -> Result<Vec<Box<dyn Sink>>, anyhow::Error> {
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(config.output)];
+
+    for sink in &config.http_sinks {
+        sinks.push(Box::new(HttpSink::new(sink.clone())?));
+    }
+    for hook in &config.hooks {
+        sinks.push(Box::new(HookSink::new(hook.clone())?));
+    }
+    if let Some(syslog) = &config.syslog {
+        sinks.push(Box::new(syslog.clone()));
+    }
+    if let Some(loki) = &config.loki {
+        sinks.push(Box::new(LokiSink::new(loki.clone())?));
+    }
+    if let Some(firewall_set) = &config.firewall_set {
+        sinks.push(Box::new(firewall_set.clone()));
+    }
+    if let Some(history) = &config.history {
+        sinks.push(Box::new(history.clone()));
+    }
+    if let Some(path) = &config.state_file {
+        sinks.push(Box::new(StateFile { path: path.clone() }));
+    }
+    if let Some(presence) = &config.presence {
+        sinks.push(Box::new(presence.clone()));
+    }
+    if !config.alerts.is_empty() {
+        sinks.push(Box::new(AlertSink {
+            alerts: config.alerts.clone(),
+        }));
+    }
+    if let Some(sanity_check) = &config.sanity_check {
+        sinks.push(Box::new(sanity_check.clone()));
+    }
+    if let Some(server_check) = &config.server_check {
+        sinks.push(Box::new(server_check.clone()));
+    }
+    if let Some(addr) = config.metrics_listen {
+        sinks.push(Box::new(MetricsEndpoint { addr }));
+    }
+    if let Some(remote_write) = &config.remote_write {
+        sinks.push(Box::new(RemoteWriteSink::new(remote_write.clone())?));
+    }
+    if let Some(statsd) = &config.statsd {
+        sinks.push(Box::new(statsd.clone()));
+    }
+
+    Ok(sinks)
+}
+
+/// Run every sink on a task of its own, one failing is logged and leaves the rest alone
+pub fn spawn_all(sinks: Vec<Box<dyn Sink>>, ctx: &SinkContext) {
+    for sink in sinks {
+        let name = sink.name();
+        let span = tracing::info_span!("sink", name = %name);
+        let run = span.in_scope(|| sink.start(ctx));
+        tokio::spawn(
+            async move {
+                if let Err(e) = run.await {
+                    warn!("{} failed: {:#}", name, e);
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Everything the daemon knows in one serializable document, for moving it to another
+//! host or seeding a standby
+
+use std::{net::Ipv4Addr, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{self, BootTime},
+    config::InterfaceConfig,
+    devices::Device,
+    events::Event,
+    leases::Lease,
+    mac::MacAddr,
+    message::RelayInfo,
+    state::{Backend, State},
+    stats::InterfaceStats,
+};
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub created_at: SystemTime,
+    /// Of the machine that took it, lease expiry on its boot clock only holds on it until
+    /// it reboots
+    #[serde(default)]
+    pub boot_id: Option<String>,
+    pub leases: Vec<LeaseRecord>,
+    pub devices: Vec<Device>,
+    pub trusted_servers: Vec<Ipv4Addr>,
+    /// Interface names are specific to the host the snapshot was taken on, these are kept
+    /// for reference and aren't applied on import
+    pub interfaces: Vec<InterfaceConfig>,
+    pub counters: Counters,
+}
+
+/// A `Lease` with its expiry on the wall clock, and on the boot clock for restoring it on
+/// the same machine without trusting the wall clock not to have been stepped meanwhile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaseRecord {
+    pub mac: MacAddr,
+    pub address: Ipv4Addr,
+    pub hostname: Option<String>,
+    pub ifindex: u32,
+    pub vlan: Option<u16>,
+    pub server_id: Option<Ipv4Addr>,
+    pub relay: Option<RelayInfo>,
+    // Missing from snapshots taken before devices were fingerprinted
+    #[serde(default)]
+    pub class: Option<String>,
+    pub expires_at: Option<SystemTime>,
+    /// CLOCK_BOOTTIME nanoseconds, missing from snapshots taken before it was kept
+    #[serde(default)]
+    pub expires_at_boot: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Counters {
+    pub rogue_offers: u64,
+    // Missing from snapshots taken before it was counted
+    #[serde(default)]
+    pub lease_conflicts: u64,
+    #[serde(default)]
+    pub protocol_violations: u64,
+    #[serde(default)]
+    pub lease_time_anomalies: u64,
+    /// eBPF counters, these start from zero with every load of the program and can't be
+    /// restored
+    pub interfaces: Vec<InterfaceStats>,
+}
+
+/// How much of a snapshot was taken over on import
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportSummary {
+    pub leases: usize,
+    pub devices: usize,
+    pub trusted_servers: usize,
+}
+
+impl LeaseRecord {
+    fn from_lease(lease: &Lease, now: BootTime, wall_now: SystemTime) -> LeaseRecord {
+        LeaseRecord {
+            mac: lease.mac,
+            address: lease.address,
+            hostname: lease.hostname.clone(),
+            ifindex: lease.ifindex,
+            vlan: lease.vlan,
+            server_id: lease.server_id,
+            relay: lease.relay.clone(),
+            class: lease.class.clone(),
+            expires_at: lease
+                .expires_at
+                .map(|at| wall_now + at.saturating_duration_since(now)),
+            expires_at_boot: lease.expires_at.map(BootTime::as_nanos),
+        }
+    }
+
+    /// `None` once the lease has run out. `same_boot` is whether the snapshot was taken
+    /// on this machine since it booted.
+    fn into_lease(self, now: BootTime, wall_now: SystemTime, same_boot: bool) -> Option<Lease> {
+        let expires_at = match (self.expires_at_boot, self.expires_at) {
+            (Some(at), Some(_)) if same_boot => Some(BootTime::from_nanos(at)),
+            (_, Some(at)) => Some(now + at.duration_since(wall_now).ok()?),
+            (_, None) => None,
+        };
+        if expires_at.map_or(false, |at| at <= now) {
+            return None;
+        }
+
+        Some(Lease {
+            mac: self.mac,
+            address: self.address,
+            hostname: self.hostname,
+            ifindex: self.ifindex,
+            vlan: self.vlan,
+            server_id: self.server_id,
+            relay: self.relay,
+            class: self.class,
+            expires_at,
+        })
+    }
+}
+
+pub fn export(backend: &Backend) -> Result<Snapshot, anyhow::Error> {
+    let interface_stats = backend.stats.read()?;
+    let state = backend.state.lock().unwrap();
+    let (now, wall_now) = (BootTime::now(), SystemTime::now());
+
+    Ok(Snapshot {
+        version: VERSION,
+        created_at: wall_now,
+        boot_id: clock::boot_id(),
+        leases: state
+            .leases
+            .iter()
+            .filter(|lease| lease.is_active(now))
+            .map(|lease| LeaseRecord::from_lease(lease, now, wall_now))
+            .collect(),
+        devices: state.devices.iter().cloned().collect(),
+        trusted_servers: state.trusted_servers.list(),
+        interfaces: state.interfaces.clone(),
+        counters: Counters {
+            rogue_offers: state.rogue_offers,
+            lease_conflicts: state.lease_conflicts,
+            protocol_violations: state.protocol_violations,
+            lease_time_anomalies: state.lease_time_anomalies,
+            interfaces: interface_stats,
+        },
+    })
+}
+
+/// Merge a snapshot into the running state. Whatever the daemon has seen itself wins over
+/// older information from the snapshot.
+pub fn import(backend: &Backend, snapshot: Snapshot) -> Result<ImportSummary, anyhow::Error> {
+    check_version(&snapshot)?;
+
+    let mut state = backend.state.lock().unwrap();
+    state.rogue_offers += snapshot.counters.rogue_offers;
+    state.lease_conflicts += snapshot.counters.lease_conflicts;
+    state.protocol_violations += snapshot.counters.protocol_violations;
+    state.lease_time_anomalies += snapshot.counters.lease_time_anomalies;
+    Ok(merge(&mut state, snapshot))
+}
+
+/// Like `import`, but for the periodic snapshots of an HA peer. Those carry the peer's
+/// running totals, so there is nothing to add up.
+pub fn sync(backend: &Backend, snapshot: Snapshot) -> Result<ImportSummary, anyhow::Error> {
+    check_version(&snapshot)?;
+
+    let mut state = backend.state.lock().unwrap();
+    state.rogue_offers = state.rogue_offers.max(snapshot.counters.rogue_offers);
+    state.lease_conflicts = state.lease_conflicts.max(snapshot.counters.lease_conflicts);
+    state.protocol_violations = state
+        .protocol_violations
+        .max(snapshot.counters.protocol_violations);
+    state.lease_time_anomalies = state
+        .lease_time_anomalies
+        .max(snapshot.counters.lease_time_anomalies);
+    Ok(merge(&mut state, snapshot))
+}
+
+pub fn check_version(snapshot: &Snapshot) -> Result<(), anyhow::Error> {
+    if snapshot.version != VERSION {
+        anyhow::bail!(
+            "unsupported snapshot version {}, expected {}",
+            snapshot.version,
+            VERSION
+        );
+    }
+
+    Ok(())
+}
+
+fn merge(state: &mut State, snapshot: Snapshot) -> ImportSummary {
+    let (now, wall_now) = (BootTime::now(), SystemTime::now());
+    let same_boot = same_boot(&snapshot);
+    let mut summary = ImportSummary {
+        leases: 0,
+        devices: 0,
+        trusted_servers: 0,
+    };
+
+    for record in snapshot.leases {
+        if let Some(lease) = record.into_lease(now, wall_now, same_boot) {
+            if state.leases.restore(lease) {
+                summary.leases += 1;
+            }
+        }
+    }
+    for device in snapshot.devices {
+        if state.devices.restore(device) {
+            summary.devices += 1;
+        }
+    }
+    for server in snapshot.trusted_servers {
+        if state.trusted_servers.insert(server) {
+            summary.trusted_servers += 1;
+        }
+    }
+
+    summary
+}
+
+/// Pick up what a previous run on this host left behind, the leases still in the
+/// program's map and the state file it saved, settling the bindings they disagree on
+pub fn restore(state: &mut State, snapshot: Option<Snapshot>, pinned: Vec<Lease>) -> ImportSummary {
+    let (now, wall_now) = (BootTime::now(), SystemTime::now());
+    let (stored, devices) = match snapshot {
+        Some(snapshot) => {
+            let same_boot = same_boot(&snapshot);
+            let leases = snapshot
+                .leases
+                .into_iter()
+                .filter_map(|record| record.into_lease(now, wall_now, same_boot))
+                .collect();
+            (leases, snapshot.devices)
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+
+    for reconciliation in state.leases.reconcile(pinned, stored) {
+        state.emit(Event::Reconciled(reconciliation));
+    }
+    let mut summary = ImportSummary {
+        leases: state.leases.iter().count(),
+        devices: 0,
+        trusted_servers: 0,
+    };
+    for device in devices {
+        if state.devices.restore(device) {
+            summary.devices += 1;
+        }
+    }
+
+    summary
+}
+
+/// Whether `snapshot` was taken on this machine since it booted
+fn same_boot(snapshot: &Snapshot) -> bool {
+    snapshot.boot_id.is_some() && snapshot.boot_id == clock::boot_id()
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::BTreeMap,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::{broadcast, mpsc::Receiver, watch};
+
+use crate::{
+    bindings::Bindings,
+    clock::BootTime,
+    config::{Config, InterfaceConfig},
+    devices::DeviceStore,
+    events::{ArpRejected, Event, RateLimited, RogueOffer},
+    fingerprint::FingerprintDb,
+    ha::Role,
+    health::Health,
+    lease_times::LeaseTimes,
+    leases::LeaseTable,
+    mac::MacAddr,
+    message::{DhcpMessage, MessageType},
+    redact::Redactor,
+    server_check::ServerStatus,
+    stats::Stats,
+    trusted::TrustedServers,
+    validate,
+};
+
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Everything the daemon learns from snooping, shared between the event loop and the
+/// control socket
+pub struct State {
+    pub leases: LeaseTable,
+    pub devices: DeviceStore,
+    /// Servers allowed to hand out leases, every server is trusted when empty
+    pub trusted_servers: TrustedServers,
+    pub interfaces: Vec<InterfaceConfig>,
+    /// Offers seen from servers outside of `trusted_servers`
+    pub rogue_offers: u64,
+    /// Addresses and hostnames leased to two clients and transactions ACKed by two servers
+    pub lease_conflicts: u64,
+    /// Messages with addresses RFC 2131 doesn't allow for their type
+    pub protocol_violations: u64,
+    /// ACKs with lease times far off what their server usually hands out
+    pub lease_time_anomalies: u64,
+    lease_times: LeaseTimes,
+    /// How the server checks have gone by server
+    pub server_checks: BTreeMap<Ipv4Addr, ServerStatus>,
+    /// chaddr of the server checks
+    server_check_mac: Option<MacAddr>,
+    /// How much of the options the eBPF program doesn't parse messages are passed on with
+    unknown_option_bytes: usize,
+    fingerprints: FingerprintDb,
+    events: broadcast::Sender<Event>,
+    /// Every message that arrives, for the outputs that want them all
+    messages: broadcast::Sender<DhcpMessage>,
+}
+
+pub type SharedState = Arc<Mutex<State>>;
+
+/// What the control socket and the metrics endpoint answer requests from
+#[derive(Clone)]
+pub struct Backend {
+    pub state: SharedState,
+    pub stats: Arc<Stats>,
+    /// Always active unless running as part of an HA pair
+    pub role: watch::Receiver<Role>,
+    pub health: Arc<Health>,
+    /// For what's logged and what the control socket tells observers
+    pub redactor: Option<Arc<Redactor>>,
+}
+
+impl State {
+    pub fn new(
+        events: broadcast::Sender<Event>,
+        messages: broadcast::Sender<DhcpMessage>,
+        config: &Config,
+        bindings: Bindings,
+        trusted_servers: TrustedServers,
+        fingerprints: FingerprintDb,
+        redactor: Option<Arc<Redactor>>,
+    ) -> State {
+        State {
+            leases: LeaseTable::new(
+                bindings,
+                redactor,
+                config.conflict_policy,
+                config
+                    .interfaces
+                    .iter()
+                    .filter(|interface| interface.trusted)
+                    .map(|interface| interface.name.clone())
+                    .collect(),
+            ),
+            devices: DeviceStore::default(),
+            trusted_servers,
+            interfaces: config.interfaces.clone(),
+            rogue_offers: 0,
+            lease_conflicts: 0,
+            protocol_violations: 0,
+            lease_time_anomalies: 0,
+            lease_times: LeaseTimes::new(config.lease_times.clone()),
+            server_checks: BTreeMap::new(),
+            server_check_mac: config.server_check.as_ref().map(|check| check.mac),
+            unknown_option_bytes: config.unknown_option_bytes,
+            fingerprints,
+            events,
+            messages,
+        }
+    }
+
+    fn handle(&mut self, msg: &DhcpMessage) {
+        let _span =
+            tracing::debug_span!("dispatch", xid = msg.xid, mac = %msg.client_mac).entered();
+        if msg.message_type == MessageType::Offer {
+            self.check_server(msg);
+        }
+        // The server checks' own exchanges, a rogue server answering one is still reported
+        if self.server_check_mac == Some(msg.client_mac) {
+            return;
+        }
+        for violation in validate::check(msg) {
+            self.protocol_violations += 1;
+            self.emit(Event::ProtocolViolation(violation));
+        }
+        if let Some(anomaly) = self.lease_times.observe(msg) {
+            self.lease_time_anomalies += 1;
+            self.emit(Event::LeaseTimeAnomaly(anomaly));
+        }
+
+        let class = self.fingerprints.classify(msg).map(str::to_owned);
+        for change in self.devices.observe(msg, class.as_deref()) {
+            self.emit(Event::Changed(change));
+        }
+        // Only clients send a fingerprint, what's known about the client goes for the
+        // server's replies too
+        let class = self
+            .devices
+            .get(&msg.client_mac)
+            .and_then(|device| device.class.clone());
+        let update = self.leases.handle(msg, class.as_deref());
+        for conflict in update.conflicts {
+            self.lease_conflicts += 1;
+            self.emit(Event::LeaseConflict(conflict));
+        }
+        for reconciliation in update.reconciliations {
+            self.emit(Event::Reconciled(reconciliation));
+        }
+        if let Some(lease) = update.lease {
+            self.emit(Event::Lease(lease));
+        }
+        let mut msg = msg.clone();
+        msg.class = class;
+        msg.truncate_unknown_options(self.unknown_option_bytes);
+        // Only fails when nobody is subscribed
+        let _ = self.messages.send(msg);
+    }
+
+    fn check_server(&mut self, msg: &DhcpMessage) {
+        let server = match msg.server_id {
+            Some(server) => server,
+            None => return,
+        };
+        if self.trusted_servers.is_empty() || self.trusted_servers.contains(server) {
+            return;
+        }
+
+        self.rogue_offers += 1;
+        self.emit(Event::RogueOffer(RogueOffer {
+            server,
+            client_mac: msg.client_mac,
+            offered: msg.your_address,
+            ifindex: msg.ifindex,
+            at: SystemTime::now(),
+        }));
+    }
+
+    fn handle_arp(&mut self, mut rejected: ArpRejected) {
+        rejected.leased_to = self
+            .leases
+            .by_address(rejected.sender_address)
+            .map(|lease| lease.mac);
+        self.emit(Event::ArpRejected(rejected));
+    }
+
+    fn expire(&mut self, now: BootTime) {
+        for lease in self.leases.expire(now) {
+            self.emit(Event::Lease(lease));
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        // Only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+}
+
+/// Feed decoded messages and rejected ARPs into the state until the channels close
+pub async fn run(
+    state: SharedState,
+    health: Arc<Health>,
+    mut messages: Receiver<DhcpMessage>,
+    mut arp: Receiver<ArpRejected>,
+    mut rate_limits: Receiver<RateLimited>,
+) {
+    let mut expiry = tokio::time::interval(EXPIRY_INTERVAL);
+
+    loop {
+        health.event_loop_alive();
+        tokio::select! {
+            msg = messages.recv() => match msg {
+                Some(msg) => state.lock().unwrap().handle(&msg),
+                None => break,
+            },
+            Some(rejected) = arp.recv() => state.lock().unwrap().handle_arp(rejected),
+            Some(limited) = rate_limits.recv() => {
+                state.lock().unwrap().emit(Event::RateLimited(limited))
+            }
+            _ = expiry.tick() => state.lock().unwrap().expire(BootTime::now()),
+        }
+    }
+}
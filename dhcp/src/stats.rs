@@ -0,0 +1,104 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use aya::{
+    maps::{MapRef, PerCpuHashMap},
+    Bpf,
+};
+use dhcp_common::{ArpRejectKey, Stat, StatsKey, STAT_COUNT};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{iface, mac::MacAddr};
+
+/// Counters of a single interface, summed over all CPUs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterfaceStats {
+    pub ifindex: u32,
+    pub interface: String,
+    #[serde(deserialize_with = "deserialize_counters")]
+    pub counters: [u64; STAT_COUNT],
+}
+
+/// Counters sent by another version have more or fewer of them, new ones are appended to
+/// `Stat` so the ones both know about line up
+fn deserialize_counters<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<[u64; STAT_COUNT], D::Error> {
+    let values = Vec::<u64>::deserialize(deserializer)?;
+    let mut counters = [0; STAT_COUNT];
+    for (counter, value) in counters.iter_mut().zip(values) {
+        *counter = value;
+    }
+    Ok(counters)
+}
+
+impl InterfaceStats {
+    pub fn get(&self, stat: Stat) -> u64 {
+        self.counters[stat as usize]
+    }
+}
+
+/// ARPs dropped from a single sender, summed over all CPUs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArpRejects {
+    pub mac: MacAddr,
+    pub count: u64,
+}
+
+/// Read side of the `STATS` and `ARP_REJECTS` maps
+pub struct Stats {
+    map: Mutex<PerCpuHashMap<MapRef, StatsKey, u64>>,
+    arp_rejects: Mutex<PerCpuHashMap<MapRef, ArpRejectKey, u64>>,
+}
+
+impl Stats {
+    pub fn new(bpf: &Bpf) -> Result<Stats, anyhow::Error> {
+        let map = PerCpuHashMap::try_from(bpf.map("STATS")?)?;
+        let arp_rejects = PerCpuHashMap::try_from(bpf.map("ARP_REJECTS")?)?;
+        Ok(Stats {
+            map: Mutex::new(map),
+            arp_rejects: Mutex::new(arp_rejects),
+        })
+    }
+
+    pub fn read(&self) -> Result<Vec<InterfaceStats>, anyhow::Error> {
+        let map = self.map.lock().unwrap();
+        let mut interfaces: BTreeMap<u32, [u64; STAT_COUNT]> = BTreeMap::new();
+
+        for entry in map.iter() {
+            let (key, values) = entry?;
+            let stat = key.stat as usize;
+            if stat >= STAT_COUNT {
+                continue;
+            }
+
+            let counters = interfaces.entry(key.ifindex).or_insert([0; STAT_COUNT]);
+            counters[stat] += values.iter().sum::<u64>();
+        }
+
+        Ok(interfaces
+            .into_iter()
+            .map(|(ifindex, counters)| InterfaceStats {
+                ifindex,
+                interface: iface::name(ifindex),
+                counters,
+            })
+            .collect())
+    }
+
+    /// Senders with the most dropped ARPs first
+    pub fn read_arp_rejects(&self) -> Result<Vec<ArpRejects>, anyhow::Error> {
+        let map = self.arp_rejects.lock().unwrap();
+        let mut rejects = Vec::new();
+
+        for entry in map.iter() {
+            let (key, values) = entry?;
+            rejects.push(ArpRejects {
+                mac: MacAddr(key.sender_mac),
+                count: values.iter().sum(),
+            });
+        }
+        rejects.sort_by(|a, b| b.count.cmp(&a.count).then(a.mac.cmp(&b.mac)));
+
+        Ok(rejects)
+    }
+}
@@ -0,0 +1,307 @@
+//! Sends the metrics served on `/metrics` to a statsd server as DogStatsD datagrams, for
+//! setups built around the Datadog agent
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::{
+    net::UdpSocket,
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{
+    config::deserialize_duration,
+    metrics::{self, Family, Kind},
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const HEALTH_SINK: &str = "statsd";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// Keeps datagrams under the MTU of a typical link
+const MAX_DATAGRAM: usize = 1432;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StatsdConfig {
+    pub address: SocketAddr,
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// Added to every metric, e.g. `env:prod`
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl StatsdConfig {
+    pub fn new(address: SocketAddr) -> StatsdConfig {
+        StatsdConfig {
+            address,
+            interval: DEFAULT_INTERVAL,
+            tags: Vec::new(),
+        }
+    }
+}
+
+fn default_interval() -> Duration {
+    DEFAULT_INTERVAL
+}
+
+impl sinks::Sink for StatsdConfig {
+    fn name(&self) -> String {
+        "statsd".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(*self, ctx.backend.clone()).boxed()
+    }
+}
+
+/// Send gauges as they are and counters as the increase since the last flush, every
+/// `interval`
+async fn run(config: StatsdConfig, backend: Backend) -> Result<(), anyhow::Error> {
+    let local: SocketAddr = match config.address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .context("failed to bind statsd socket")?;
+    info!(
+        "sending metrics to statsd at {} every {}",
+        config.address,
+        humantime::format_duration(config.interval)
+    );
+
+    let mut ticks = interval(config.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Counter values at the previous flush, by metric line without the value
+    let mut previous: HashMap<String, f64> = HashMap::new();
+
+    loop {
+        ticks.tick().await;
+
+        let families = match metrics::collect(&backend) {
+            Ok(families) => families,
+            Err(e) => {
+                warn!("failed to collect metrics: {:#}", e);
+                continue;
+            }
+        };
+
+        let mut status = Ok(());
+        for datagram in datagrams(&lines(&families, &config.tags, &mut previous)) {
+            if let Err(e) = socket.send_to(datagram.as_bytes(), config.address).await {
+                warn!("failed to send metrics to {}: {}", config.address, e);
+                status = Err(e.to_string());
+            }
+        }
+        backend.health.set_sink(HEALTH_SINK, status);
+    }
+}
+
+/// `name:value|type|#tag:value,...`, one per sample
+fn lines(
+    families: &[Family],
+    extra_tags: &[String],
+    previous: &mut HashMap<String, f64>,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for family in families {
+        for sample in &family.samples {
+            let tags = sample
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{}:{}", name, sanitize(value)))
+                .chain(extra_tags.iter().cloned())
+                .collect::<Vec<_>>()
+                .join(",");
+            let tags = if tags.is_empty() {
+                String::new()
+            } else {
+                format!("|#{}", tags)
+            };
+
+            let line = match family.kind {
+                Kind::Gauge => format!("{}:{}|g{}", family.name, sample.value, tags),
+                Kind::Counter => {
+                    let key = format!("{}{}", family.name, tags);
+                    let last = previous.insert(key, sample.value);
+                    let delta = match last {
+                        // A counter going backwards was reset, e.g. by a restart
+                        Some(last) if sample.value >= last => sample.value - last,
+                        Some(_) => sample.value,
+                        // Nothing to compare the first value against
+                        None => continue,
+                    };
+                    if delta == 0.0 {
+                        continue;
+                    }
+                    format!("{}:{}|c{}", family.name, delta, tags)
+                }
+            };
+            lines.push(line);
+        }
+    }
+
+    lines
+}
+
+/// Pack lines into as few datagrams as fit
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+
+    datagrams
+}
+
+/// Tag values can't contain the separators
+fn sanitize(value: &str) -> String {
+    value.replace(['|', ',', '#', '\n'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Sample;
+
+    fn family(name: &str, kind: Kind, samples: &[(&[(&'static str, &str)], f64)]) -> Family {
+        Family {
+            name: name.to_owned(),
+            help: "",
+            kind,
+            samples: samples
+                .iter()
+                .map(|(labels, value)| Sample {
+                    labels: labels
+                        .iter()
+                        .map(|(name, value)| (*name, value.to_string()))
+                        .collect(),
+                    value: *value,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn counters_send_the_increase() {
+        let mut previous = HashMap::new();
+        let mut flush = |value| {
+            let acks = family("dhcp_acks_total", Kind::Counter, &[(&[], value)]);
+            lines(&[acks], &[], &mut previous)
+        };
+
+        // Nothing to compare the first sample against
+        assert!(flush(5.0).is_empty());
+        assert_eq!(flush(8.0), ["dhcp_acks_total:3|c"]);
+        assert!(flush(8.0).is_empty());
+        // Reset, the whole value is new
+        assert_eq!(flush(2.0), ["dhcp_acks_total:2|c"]);
+    }
+
+    #[test]
+    fn counters_are_kept_apart_by_tags() {
+        let mut previous = HashMap::new();
+        let mut flush = |eth0, eth1| {
+            let acks = family(
+                "dhcp_acks_total",
+                Kind::Counter,
+                &[
+                    (&[("interface", "eth0")], eth0),
+                    (&[("interface", "eth1")], eth1),
+                ],
+            );
+            lines(&[acks], &[], &mut previous)
+        };
+
+        assert!(flush(1.0, 10.0).is_empty());
+        assert_eq!(
+            flush(2.0, 13.0),
+            [
+                "dhcp_acks_total:1|c|#interface:eth0",
+                "dhcp_acks_total:3|c|#interface:eth1",
+            ]
+        );
+    }
+
+    #[test]
+    fn gauges_are_sent_as_they_are() {
+        let mut previous = HashMap::new();
+        let leases = family("dhcp_leases", Kind::Gauge, &[(&[], 4.0)]);
+
+        for _ in 0..2 {
+            assert_eq!(
+                lines(&[leases.clone()], &[], &mut previous),
+                ["dhcp_leases:4|g"]
+            );
+        }
+    }
+
+    #[test]
+    fn tags_are_sanitized() {
+        let leases = family(
+            "dhcp_leases",
+            Kind::Gauge,
+            &[(&[("hostname", "a|b,c#d\ne")], 1.0)],
+        );
+
+        assert_eq!(
+            lines(&[leases], &["env:prod".to_owned()], &mut HashMap::new()),
+            ["dhcp_leases:1|g|#hostname:a_b_c_d_e,env:prod"]
+        );
+    }
+
+    #[test]
+    fn datagrams_stay_under_the_limit() {
+        let lines: Vec<String> = (0..30).map(|i| format!("{:0>100}", i)).collect();
+        let datagrams = datagrams(&lines);
+
+        // 14 lines and their separators fit, 15 don't
+        assert_eq!(
+            datagrams
+                .iter()
+                .map(|datagram| datagram.lines().count())
+                .collect::<Vec<_>>(),
+            [14, 14, 2]
+        );
+        assert!(datagrams
+            .iter()
+            .all(|datagram| datagram.len() <= MAX_DATAGRAM));
+        assert_eq!(datagrams.join("\n"), lines.join("\n"));
+    }
+
+    #[test]
+    fn an_oversized_line_goes_on_its_own() {
+        let oversized = "x".repeat(MAX_DATAGRAM + 1);
+        let lines = ["a".to_owned(), oversized.clone(), "b".to_owned()];
+
+        assert_eq!(
+            datagrams(&lines),
+            ["a".to_owned(), oversized, "b".to_owned()]
+        );
+        assert!(datagrams(&[]).is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+//! The state file, a snapshot written on exit and every minute so that a restart doesn't
+//! forget every lease
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use log::warn;
+
+use crate::{
+    sinks::{self, SinkContext},
+    snapshot::{self, Snapshot},
+    state::Backend,
+};
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `None` when there's no state file yet
+pub fn load(path: &Path) -> Result<Option<Snapshot>, anyhow::Error> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {:?}", path)),
+    };
+    let snapshot =
+        serde_json::from_slice(&contents).with_context(|| format!("failed to parse {:?}", path))?;
+    snapshot::check_version(&snapshot)?;
+
+    Ok(Some(snapshot))
+}
+
+/// Write the daemon's state to `path`, replacing it in one go so a crash halfway through
+/// leaves the previous state file in place
+pub fn save(path: &Path, backend: &Backend) -> Result<(), anyhow::Error> {
+    let contents = serde_json::to_vec(&snapshot::export(backend)?)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents).with_context(|| format!("failed to write {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace {:?}", path))?;
+
+    Ok(())
+}
+
+/// Keeps the state file up to date
+pub struct StateFile {
+    pub path: PathBuf,
+}
+
+impl sinks::Sink for StateFile {
+    fn name(&self) -> String {
+        "state file".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        run(self.path, ctx.backend.clone()).map(Ok).boxed()
+    }
+}
+
+/// Save the state every `SAVE_INTERVAL`
+async fn run(path: PathBuf, backend: Backend) {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    // The first tick completes right away, there's nothing to save yet
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = save(&path, &backend) {
+            warn!("failed to save state: {:#}", e);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Forwards events, and optionally every DHCP message, to a syslog collector as RFC 5424
+//! messages with the JSON record as the message body
+
+use std::{
+    ffi::CStr,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+    sync::broadcast,
+    time::timeout,
+};
+
+use crate::{
+    events::{AlertState, Event, PresenceState, ServerState},
+    ha::Role,
+    message::DhcpMessage,
+    output::MessageRecord,
+    sinks::{self, SinkContext},
+    state::Backend,
+};
+
+const HEALTH_SINK: &str = "syslog";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// local0
+const DEFAULT_FACILITY: u8 = 16;
+
+const SEVERITY_ALERT: u8 = 1;
+const SEVERITY_ERROR: u8 = 3;
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+const SEVERITY_INFO: u8 = 6;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SyslogConfig {
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Facility code, 16 to 23 for local0 to local7
+    #[serde(default = "default_facility")]
+    pub facility: u8,
+    #[serde(default = "default_app_name")]
+    pub app_name: String,
+    /// Forward every DHCP message too, not just events
+    #[serde(default)]
+    pub messages: bool,
+}
+
+impl SyslogConfig {
+    pub fn new(address: SocketAddr) -> SyslogConfig {
+        SyslogConfig {
+            address,
+            protocol: Protocol::default(),
+            facility: DEFAULT_FACILITY,
+            app_name: default_app_name(),
+            messages: false,
+        }
+    }
+}
+
+impl sinks::Sink for SyslogConfig {
+    fn name(&self) -> String {
+        "syslog forwarder".to_owned()
+    }
+
+    fn start(self: Box<Self>, ctx: &SinkContext) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+        let (messages, events) = (ctx.messages(), ctx.events());
+        run(*self, messages, events, ctx.backend.clone()).boxed()
+    }
+}
+
+fn default_facility() -> u8 {
+    DEFAULT_FACILITY
+}
+
+fn default_app_name() -> String {
+    "dhcp-snoop".to_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    #[default]
+    Udp,
+    /// Octet counted framing as in RFC 6587
+    Tcp,
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp(Option<TcpStream>),
+}
+
+struct Forwarder {
+    config: SyslogConfig,
+    hostname: String,
+    procid: u32,
+    transport: Transport,
+    backend: Backend,
+}
+
+async fn run(
+    config: SyslogConfig,
+    mut messages: broadcast::Receiver<DhcpMessage>,
+    mut events: broadcast::Receiver<Event>,
+    backend: Backend,
+) -> Result<(), anyhow::Error> {
+    anyhow::ensure!(
+        config.facility <= 23,
+        "invalid syslog facility {}",
+        config.facility
+    );
+
+    let transport = match config.protocol {
+        Protocol::Udp => {
+            let local: SocketAddr = match config.address {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = UdpSocket::bind(local)
+                .await
+                .context("failed to bind syslog socket")?;
+            Transport::Udp(socket)
+        }
+        Protocol::Tcp => Transport::Tcp(None),
+    };
+    info!(
+        "forwarding events to syslog at {} over {:?}",
+        config.address, config.protocol
+    );
+
+    let mut forwarder = Forwarder {
+        config,
+        hostname: hostname(),
+        procid: std::process::id(),
+        transport,
+        backend,
+    };
+
+    loop {
+        let (severity, msgid, at, body) = tokio::select! {
+            msg = messages.recv() => match msg {
+                Ok(msg) if forwarder.config.messages => {
+                    match serde_json::to_string(&MessageRecord::from(&msg)) {
+                        Ok(body) => (SEVERITY_INFO, "message", msg.seen_at, body),
+                        Err(e) => {
+                            warn!("failed to serialize message for syslog: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("syslog forwarder fell behind, lost {} messages", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let (severity, msgid) = match &event {
+                        Event::Changed(event) if event.frozen => (SEVERITY_ALERT, "frozen-changed"),
+                        Event::Changed(_) => (SEVERITY_NOTICE, "changed"),
+                        Event::RogueOffer(_) => (SEVERITY_WARNING, "rogue-offer"),
+                        Event::ArpRejected(_) => (SEVERITY_WARNING, "arp-rejected"),
+                        Event::RateLimited(_) => (SEVERITY_WARNING, "rate-limited"),
+                        Event::LeaseConflict(_) => (SEVERITY_WARNING, "lease-conflict"),
+                        Event::Reconciled(_) => (SEVERITY_NOTICE, "reconciled"),
+                        Event::ProtocolViolation(_) => (SEVERITY_WARNING, "protocol-violation"),
+                        Event::LeaseTimeAnomaly(_) => (SEVERITY_WARNING, "lease-time-anomaly"),
+                        Event::Lease(_) => (SEVERITY_INFO, "lease"),
+                        Event::Presence(event) => match event.state {
+                            PresenceState::Left => (SEVERITY_INFO, "presence"),
+                            PresenceState::StillOnline => (SEVERITY_NOTICE, "presence"),
+                        },
+                        Event::Alert(event) => match event.state {
+                            AlertState::Firing => (SEVERITY_WARNING, "alert"),
+                            AlertState::Resolved => (SEVERITY_NOTICE, "alert"),
+                        },
+                        Event::ServerCheck(event) => match event.state {
+                            ServerState::Down => (SEVERITY_ERROR, "server-check"),
+                            ServerState::Up => (SEVERITY_NOTICE, "server-check"),
+                        },
+                    };
+                    match serde_json::to_string(&event) {
+                        Ok(body) => (severity, msgid, SystemTime::now(), body),
+                        Err(e) => {
+                            warn!("failed to serialize event for syslog: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("syslog forwarder fell behind, lost {} events", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        // The active node of an HA pair reports for both
+        if *forwarder.backend.role.borrow() != Role::Active {
+            continue;
+        }
+
+        let line = forwarder.format(severity, msgid, at, &body);
+        let status = forwarder.send(&line).await.map_err(|e| {
+            warn!("failed to forward to syslog: {:#}", e);
+            format!("{:#}", e)
+        });
+        forwarder.backend.health.set_sink(HEALTH_SINK, status);
+    }
+}
+
+impl Forwarder {
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG`
+    fn format(&self, severity: u8, msgid: &str, at: SystemTime, body: &str) -> String {
+        format!(
+            "<{}>1 {} {} {} {} {} - {}",
+            self.config.facility * 8 + severity,
+            humantime::format_rfc3339_millis(at),
+            self.hostname,
+            self.config.app_name,
+            self.procid,
+            msgid,
+            body
+        )
+    }
+
+    async fn send(&mut self, line: &str) -> Result<(), anyhow::Error> {
+        match &mut self.transport {
+            Transport::Udp(socket) => {
+                socket.send_to(line.as_bytes(), self.config.address).await?;
+            }
+            Transport::Tcp(stream) => {
+                let frame = format!("{} {}", line.len(), line);
+                // Reconnect once if the collector dropped the connection since the last message
+                for _ in 0..2 {
+                    if stream.is_none() {
+                        let connect = TcpStream::connect(self.config.address);
+                        *stream = Some(
+                            timeout(CONNECT_TIMEOUT, connect)
+                                .await
+                                .context("timed out connecting")??,
+                        );
+                    }
+                    match stream.as_mut().unwrap().write_all(frame.as_bytes()).await {
+                        Ok(()) => return Ok(()),
+                        Err(e) => {
+                            *stream = None;
+                            warn!(
+                                "lost connection to syslog at {}: {}",
+                                self.config.address, e
+                            );
+                        }
+                    }
+                }
+                anyhow::bail!("failed to write to {}", self.config.address);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The HOSTNAME field, NILVALUE if it can't be determined
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return "-".to_owned();
+    }
+
+    match CStr::from_bytes_until_nul(&buf) {
+        Ok(name) if !name.to_bytes().is_empty() => name.to_string_lossy().into_owned(),
+        _ => "-".to_owned(),
+    }
+}
@@ -0,0 +1,257 @@
+//! The DHCP topology as the leases tell it: the relay ports clients sit behind, the relays
+//! forwarding for them, the interfaces and VLANs the daemon saw them on and the servers
+//! handing out their leases, with how many clients go each way. For holding against what
+//! the network diagram says.
+
+use std::{collections::BTreeMap, fmt, fs, net::Ipv4Addr, path::Path, str::FromStr};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{
+    control::{self, Request, Response},
+    iface,
+    snapshot::{self, LeaseRecord, Snapshot},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz, for `dot -Tsvg`
+    Dot,
+    Json,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "dot" => GraphFormat::Dot,
+            "json" => GraphFormat::Json,
+            _ => return Err(format!("invalid format {:?}, expected dot or json", s)),
+        })
+    }
+}
+
+impl fmt::Display for GraphFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GraphFormat::Dot => "dot",
+            GraphFormat::Json => "json",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum NodeKind {
+    /// A relay port, by option 82
+    Circuit,
+    /// A giaddr
+    Relay,
+    /// An interface and VLAN the daemon saw the traffic on
+    Segment,
+    Server,
+}
+
+#[derive(Debug, Serialize)]
+struct Node {
+    id: String,
+    kind: NodeKind,
+    label: String,
+    /// With an active lease that went through here
+    clients: usize,
+    /// For servers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trusted: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct Edge {
+    from: String,
+    to: String,
+    clients: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+impl Graph {
+    fn from_snapshot(snapshot: &Snapshot) -> Graph {
+        let names: BTreeMap<u32, &str> = snapshot
+            .counters
+            .interfaces
+            .iter()
+            .map(|interface| (interface.ifindex, interface.interface.as_str()))
+            .collect();
+        let trusted = |server: Ipv4Addr| {
+            snapshot.trusted_servers.is_empty() || snapshot.trusted_servers.contains(&server)
+        };
+
+        let mut nodes: BTreeMap<String, Node> = BTreeMap::new();
+        let mut edges: BTreeMap<(String, String), usize> = BTreeMap::new();
+        for lease in snapshot
+            .leases
+            .iter()
+            .filter(|lease| lease.expires_at.map_or(true, |at| at > snapshot.created_at))
+        {
+            // From the client's side to the server's
+            let mut path = Vec::new();
+            if let Some(relay) = &lease.relay {
+                if let Some(label) = circuit(lease) {
+                    path.push(Node::new(
+                        format!("circuit:{}/{}", relay.address, label),
+                        NodeKind::Circuit,
+                        label,
+                    ));
+                }
+                path.push(Node::new(
+                    format!("relay:{}", relay.address),
+                    NodeKind::Relay,
+                    format!("relay {}", relay.address),
+                ));
+            }
+            let interface = names
+                .get(&lease.ifindex)
+                .map_or_else(|| iface::name(lease.ifindex), |name| name.to_string());
+            path.push(match lease.vlan {
+                Some(vlan) => Node::new(
+                    format!("segment:{}.{}", interface, vlan),
+                    NodeKind::Segment,
+                    format!("{} vlan {}", interface, vlan),
+                ),
+                None => Node::new(
+                    format!("segment:{}", interface),
+                    NodeKind::Segment,
+                    interface,
+                ),
+            });
+            if let Some(server) = lease.server_id {
+                let mut node = Node::new(
+                    format!("server:{}", server),
+                    NodeKind::Server,
+                    format!("server {}", server),
+                );
+                node.trusted = Some(trusted(server));
+                path.push(node);
+            }
+
+            for pair in path.windows(2) {
+                *edges
+                    .entry((pair[0].id.clone(), pair[1].id.clone()))
+                    .or_default() += 1;
+            }
+            for node in path {
+                nodes.entry(node.id.clone()).or_insert(node).clients += 1;
+            }
+        }
+
+        Graph {
+            nodes: nodes.into_values().collect(),
+            edges: edges
+                .into_iter()
+                .map(|((from, to), clients)| Edge { from, to, clients })
+                .collect(),
+        }
+    }
+
+    fn dot(&self) -> String {
+        let mut out = String::from("digraph dhcp {\n    rankdir=LR;\n");
+        for node in &self.nodes {
+            let shape = match node.kind {
+                NodeKind::Circuit => "plaintext",
+                NodeKind::Relay => "diamond",
+                NodeKind::Segment => "ellipse",
+                NodeKind::Server => "box",
+            };
+            let color = match node.trusted {
+                Some(false) => ", color=red",
+                _ => "",
+            };
+            out.push_str(&format!(
+                "    {} [label={}, shape={}{}];\n",
+                quote(&node.id),
+                quote(&format!("{}\n{} clients", node.label, node.clients)),
+                shape,
+                color
+            ));
+        }
+        for edge in &self.edges {
+            out.push_str(&format!(
+                "    {} -> {} [label=\"{}\"];\n",
+                quote(&edge.from),
+                quote(&edge.to),
+                edge.clients
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+impl Node {
+    fn new(id: String, kind: NodeKind, label: String) -> Node {
+        Node {
+            id,
+            kind,
+            label,
+            clients: 0,
+            trusted: None,
+        }
+    }
+}
+
+/// The relay port a lease's client sits behind, `None` when option 82 doesn't say
+fn circuit(lease: &LeaseRecord) -> Option<String> {
+    let relay = lease.relay.as_ref()?;
+    let ids: Vec<String> = [
+        ("remote-id", &relay.remote_id),
+        ("circuit-id", &relay.circuit_id),
+    ]
+    .into_iter()
+    .filter_map(|(name, id)| id.as_ref().map(|id| format!("{} {}", name, id)))
+    .collect();
+    (!ids.is_empty()).then(|| ids.join(", "))
+}
+
+/// A DOT string
+fn quote(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+/// Print the topology of the leases in the snapshot at `path`, or in the running daemon
+pub async fn topology(
+    socket: &Path,
+    path: Option<&Path>,
+    format: GraphFormat,
+) -> Result<(), anyhow::Error> {
+    let snapshot: Snapshot = match path {
+        Some(path) => {
+            let contents = fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+            let snapshot = serde_json::from_slice(&contents)
+                .with_context(|| format!("failed to parse {:?}", path))?;
+            snapshot::check_version(&snapshot)?;
+            snapshot
+        }
+        None => match control::request(socket, &Request::ExportState).await? {
+            Response::State(snapshot) => snapshot,
+            response => anyhow::bail!("unexpected response {:?}", response),
+        },
+    };
+
+    let graph = Graph::from_snapshot(&snapshot);
+    match format {
+        GraphFormat::Dot => print!("{}", graph.dot()),
+        GraphFormat::Json => println!("{}", serde_json::to_string_pretty(&graph)?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use std::net::Ipv4Addr;
+
+use aya::{
+    maps::{HashMap, MapError, MapRefMut},
+    Bpf,
+};
+use log::warn;
+
+/// The `TRUSTED_SERVERS` map, pinned so `dhcp config set trusted-servers` can change it
+/// under the running daemon. Every server is trusted while it's empty.
+pub struct TrustedServers {
+    map: HashMap<MapRefMut, u32, u8>,
+}
+
+impl TrustedServers {
+    pub fn new(bpf: &Bpf) -> Result<TrustedServers, anyhow::Error> {
+        Ok(TrustedServers {
+            map: HashMap::try_from(bpf.map_mut("TRUSTED_SERVERS")?)?,
+        })
+    }
+
+    pub fn contains(&self, server: Ipv4Addr) -> bool {
+        self.map.get(&u32::from(server), 0).is_ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.keys().next().is_none()
+    }
+
+    pub fn list(&self) -> Vec<Ipv4Addr> {
+        let mut servers: Vec<Ipv4Addr> = self
+            .map
+            .keys()
+            .filter_map(Result::ok)
+            .map(Ipv4Addr::from)
+            .collect();
+        servers.sort();
+        servers
+    }
+
+    /// Whether `server` wasn't trusted before
+    pub fn insert(&mut self, server: Ipv4Addr) -> bool {
+        if self.contains(server) {
+            return false;
+        }
+        match self.map.insert(u32::from(server), 1, 0) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("failed to trust {}: {}", server, e);
+                false
+            }
+        }
+    }
+
+    /// Trust exactly `servers`, or every server when it's empty
+    pub fn replace(&mut self, servers: &[Ipv4Addr]) -> Result<(), MapError> {
+        for server in self.list() {
+            if !servers.contains(&server) {
+                self.map.remove(&u32::from(server))?;
+            }
+        }
+        for server in servers {
+            self.map.insert(u32::from(*server), 1, 0)?;
+        }
+
+        Ok(())
+    }
+}
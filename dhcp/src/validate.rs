@@ -0,0 +1,270 @@
+//! The addresses in a message held against what RFC 2131 allows for its type. A server
+//! offering an address no client can hold, or one filling in ciaddr where it mustn't, is
+//! broken or up to something.
+
+use std::{net::Ipv4Addr, time::SystemTime};
+
+use crate::{
+    events::{ProtocolViolation, Violation},
+    message::{DhcpMessage, Direction, MessageType},
+};
+
+/// Whether a server may hand `address` out to a client
+pub fn assignable(address: Ipv4Addr) -> bool {
+    let [first, ..] = address.octets();
+
+    // 0.0.0.0/8 is this network, 240.0.0.0/4 reserved for future use
+    !(first == 0
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_multicast()
+        || address.is_broadcast()
+        || first >= 240)
+}
+
+/// Everything wrong with the addresses in `msg`
+pub fn check(msg: &DhcpMessage) -> Vec<ProtocolViolation> {
+    let mut violations = Vec::new();
+    let mut violation = |violation| {
+        violations.push(ProtocolViolation {
+            violation,
+            message_type: msg.message_type,
+            client_mac: msg.client_mac,
+            server_id: match msg.direction {
+                Direction::ServerToClient => msg.server_id,
+                Direction::ClientToServer => None,
+            },
+            client_address: msg.client_address,
+            your_address: msg.your_address,
+            ifindex: msg.ifindex,
+            vlan: msg.vlan,
+            at: SystemTime::now(),
+        })
+    };
+
+    match msg.message_type {
+        MessageType::Offer | MessageType::Ack => {
+            if msg.your_address.is_unspecified() {
+                // Only an ACK to a DHCPINFORM comes without an address, and only the
+                // request says whether it is one
+                let inform = msg
+                    .answered
+                    .map_or(true, |answered| answered.request == MessageType::Inform);
+                if msg.message_type == MessageType::Offer || !inform {
+                    violation(Violation::MissingAddress);
+                }
+            } else if !assignable(msg.your_address) {
+                violation(Violation::UnassignableAddress);
+            }
+        }
+        _ => {}
+    }
+
+    let ciaddr_set = !msg.client_address.is_unspecified();
+    match msg.message_type {
+        MessageType::Discover | MessageType::Offer | MessageType::Decline | MessageType::Nak
+            if ciaddr_set =>
+        {
+            violation(Violation::UnexpectedClientAddress)
+        }
+        // A REQUEST with a server identifier selects an offer, the client holds no
+        // address yet
+        MessageType::Request if ciaddr_set && msg.server_id.is_some() => {
+            violation(Violation::UnexpectedClientAddress)
+        }
+        MessageType::Release | MessageType::Inform if !ciaddr_set => {
+            violation(Violation::MissingClientAddress)
+        }
+        _ => {}
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{mac::MacAddr, message::Answered};
+
+    const UNSET: Ipv4Addr = Ipv4Addr::UNSPECIFIED;
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 5);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    struct Case {
+        message_type: MessageType,
+        /// ciaddr
+        client_address: Ipv4Addr,
+        /// yiaddr
+        your_address: Ipv4Addr,
+        server_id: Option<Ipv4Addr>,
+        /// What the reply answers
+        answered: Option<MessageType>,
+        expected: &'static [Violation],
+    }
+
+    fn case(
+        message_type: MessageType,
+        client_address: Ipv4Addr,
+        your_address: Ipv4Addr,
+        expected: &'static [Violation],
+    ) -> Case {
+        Case {
+            message_type,
+            client_address,
+            your_address,
+            server_id: None,
+            answered: None,
+            expected,
+        }
+    }
+
+    impl Case {
+        fn server_id(self, server_id: Ipv4Addr) -> Case {
+            Case {
+                server_id: Some(server_id),
+                ..self
+            }
+        }
+
+        fn answering(self, request: MessageType) -> Case {
+            Case {
+                answered: Some(request),
+                ..self
+            }
+        }
+
+        fn message(&self) -> DhcpMessage {
+            let mut msg = DhcpMessage::test(self.message_type, MacAddr([0x02, 0, 0, 0, 0, 1]));
+            msg.client_address = self.client_address;
+            msg.your_address = self.your_address;
+            msg.server_id = self.server_id;
+            msg.answered = self.answered.map(|request| Answered {
+                request,
+                response_time: Duration::from_millis(5),
+            });
+            msg
+        }
+    }
+
+    #[test]
+    fn check_by_message_type() {
+        use MessageType::*;
+        use Violation::*;
+
+        for case in [
+            case(Discover, UNSET, UNSET, &[]),
+            case(Discover, CLIENT, UNSET, &[UnexpectedClientAddress]),
+            case(Offer, UNSET, CLIENT, &[]).server_id(SERVER),
+            case(Offer, UNSET, UNSET, &[MissingAddress]),
+            // Only ACKs are exempt for an INFORM
+            case(Offer, UNSET, UNSET, &[MissingAddress]).answering(Inform),
+            case(Offer, UNSET, Ipv4Addr::BROADCAST, &[UnassignableAddress]),
+            case(
+                Offer,
+                UNSET,
+                Ipv4Addr::new(224, 0, 0, 1),
+                &[UnassignableAddress],
+            ),
+            case(Offer, CLIENT, CLIENT, &[UnexpectedClientAddress]),
+            case(
+                Offer,
+                CLIENT,
+                Ipv4Addr::new(0, 1, 2, 3),
+                &[UnassignableAddress, UnexpectedClientAddress],
+            ),
+            // SELECTING
+            case(Request, UNSET, UNSET, &[]).server_id(SERVER),
+            case(Request, CLIENT, UNSET, &[UnexpectedClientAddress]).server_id(SERVER),
+            // RENEWING and REBINDING
+            case(Request, CLIENT, UNSET, &[]),
+            case(Decline, UNSET, UNSET, &[]).server_id(SERVER),
+            case(Decline, CLIENT, UNSET, &[UnexpectedClientAddress]),
+            case(Ack, UNSET, CLIENT, &[]).answering(Request),
+            case(Ack, CLIENT, CLIENT, &[]).answering(Request),
+            case(Ack, UNSET, UNSET, &[MissingAddress]).answering(Request),
+            case(Ack, CLIENT, UNSET, &[]).answering(Inform),
+            // The request went by elsewhere, it may have been an INFORM
+            case(Ack, CLIENT, UNSET, &[]),
+            case(Ack, UNSET, Ipv4Addr::LOCALHOST, &[UnassignableAddress]),
+            case(
+                Ack,
+                UNSET,
+                Ipv4Addr::new(169, 254, 1, 1),
+                &[UnassignableAddress],
+            ),
+            case(
+                Ack,
+                UNSET,
+                Ipv4Addr::new(250, 0, 0, 1),
+                &[UnassignableAddress],
+            ),
+            case(Nak, UNSET, UNSET, &[]).server_id(SERVER),
+            case(Nak, CLIENT, UNSET, &[UnexpectedClientAddress]),
+            case(Release, CLIENT, UNSET, &[]).server_id(SERVER),
+            case(Release, UNSET, UNSET, &[MissingClientAddress]),
+            case(Inform, CLIENT, UNSET, &[]),
+            case(Inform, UNSET, UNSET, &[MissingClientAddress]),
+            // BOOTP has no rules of its own here
+            case(BootRequest, CLIENT, UNSET, &[]),
+            case(BootReply, UNSET, UNSET, &[]),
+            case(Unknown(250), CLIENT, Ipv4Addr::BROADCAST, &[]),
+        ] {
+            let msg = case.message();
+            let violations: Vec<Violation> = check(&msg)
+                .iter()
+                .map(|violation| violation.violation)
+                .collect();
+            assert_eq!(
+                violations,
+                case.expected,
+                "{:?} ciaddr {} yiaddr {} server {:?} answering {:?}",
+                case.message_type,
+                case.client_address,
+                case.your_address,
+                case.server_id,
+                case.answered
+            );
+        }
+    }
+
+    #[test]
+    fn violations_name_the_server_only_for_replies() {
+        let offer = case(MessageType::Offer, UNSET, UNSET, &[]).server_id(SERVER);
+        let violations = check(&offer.message());
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].server_id, Some(SERVER));
+
+        // A client naming the server it picked isn't that server
+        let request = case(MessageType::Request, CLIENT, UNSET, &[]).server_id(SERVER);
+        let violations = check(&request.message());
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].server_id, None);
+        assert_eq!(violations[0].client_address, CLIENT);
+    }
+
+    #[test]
+    fn assignable_addresses() {
+        for address in [
+            [10, 0, 0, 5],
+            [192, 168, 1, 254],
+            [100, 64, 0, 1],
+            [223, 255, 255, 1],
+        ] {
+            assert!(assignable(address.into()), "{:?}", address);
+        }
+        for address in [
+            [0, 0, 0, 0],
+            [0, 1, 2, 3],
+            [127, 0, 0, 1],
+            [169, 254, 0, 1],
+            [224, 0, 0, 251],
+            [239, 255, 255, 250],
+            [240, 0, 0, 1],
+            [255, 255, 255, 255],
+        ] {
+            assert!(!assignable(address.into()), "{:?}", address);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Wake-on-LAN for devices the daemon has seen, the magic packet is broadcast on the
+//! interface and VLAN the device last did DHCP on
+
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::{
+    control::{self, Request, Response},
+    devices::Device,
+    iface,
+    mac::MacAddr,
+    packet::{PacketSocket, ETH_P_8021Q},
+};
+
+const ETH_P_WOL: u16 = 0x0842;
+
+/// Wake the device with MAC or hostname `target`
+pub async fn wake(socket: &Path, target: &str) -> Result<(), anyhow::Error> {
+    let device = find(socket, target).await?;
+    let ifindex = device.ifindex.with_context(|| {
+        format!(
+            "the interface {} was on isn't known, it was last seen by an older daemon",
+            device.mac
+        )
+    })?;
+
+    let packet = PacketSocket::open(ifindex, ETH_P_WOL)?;
+    packet
+        .send(&magic_packet(packet.mac, device.mac, device.vlan))
+        .await
+        .context("failed to send the magic packet")?;
+
+    match device.vlan {
+        Some(vlan) => println!(
+            "sent a magic packet to {} on {} vlan {}",
+            device.mac,
+            iface::name(ifindex),
+            vlan
+        ),
+        None => println!(
+            "sent a magic packet to {} on {}",
+            device.mac,
+            iface::name(ifindex)
+        ),
+    }
+
+    Ok(())
+}
+
+async fn find(socket: &Path, target: &str) -> Result<Device, anyhow::Error> {
+    if let Ok(mac) = target.parse::<MacAddr>() {
+        return match control::request(socket, &Request::Locate { mac }).await? {
+            Response::Device(Some(device)) => Ok(device),
+            Response::Device(None) => anyhow::bail!("{} hasn't been seen", mac),
+            response => anyhow::bail!("unexpected response {:?}", response),
+        };
+    }
+
+    let request = Request::FindHostname {
+        hostname: target.to_owned(),
+    };
+    let mut devices = match control::request(socket, &request).await? {
+        Response::Devices(devices) => devices,
+        response => anyhow::bail!("unexpected response {:?}", response),
+    };
+    match devices.len() {
+        0 => anyhow::bail!("no device goes by {}", target),
+        1 => Ok(devices.remove(0)),
+        _ => anyhow::bail!(
+            "{} devices go by {}, pick one by MAC: {}",
+            devices.len(),
+            target,
+            devices
+                .iter()
+                .map(|device| device.mac.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Broadcast, with six 0xff followed by the target MAC sixteen times as the payload
+fn magic_packet(source: MacAddr, target: MacAddr, vlan: Option<u16>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(120);
+    frame.extend_from_slice(&[0xff; 6]);
+    frame.extend_from_slice(&source.0);
+    if let Some(vlan) = vlan {
+        frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+        frame.extend_from_slice(&vlan.to_be_bytes());
+    }
+    frame.extend_from_slice(&ETH_P_WOL.to_be_bytes());
+
+    frame.extend_from_slice(&[0xff; 6]);
+    for _ in 0..16 {
+        frame.extend_from_slice(&target.0);
+    }
+
+    frame
+}
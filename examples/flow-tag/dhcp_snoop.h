@@ -0,0 +1,26 @@
+/* The BINDINGS map the dhcp-snoop daemon pins at /sys/fs/bpf/dhcp_snoop/BINDINGS, for
+ * eBPF programs that want to know which device an IPv4 address is leased to.
+ *
+ * The key is the leased address in host byte order, bpf_ntohl(iph->saddr). The value
+ * only ever grows at the end, check value_size before relying on fields added later.
+ */
+#ifndef DHCP_SNOOP_H
+#define DHCP_SNOOP_H
+
+#include <linux/types.h>
+
+#define DHCP_SNOOP_BINDINGS_MAX_ENTRIES 65536
+
+struct dhcp_snoop_binding {
+	/* The client holding the lease */
+	__u8 mac[6];
+	/* 802.1Q id the lease was handed out on, zero when untagged */
+	__u16 vlan;
+	/* Interface the lease was handed out on */
+	__u32 ifindex;
+	__u32 reserved;
+	/* bpf_ktime_get_ns() at which the lease runs out, zero for infinite leases */
+	__u64 expires_at;
+};
+
+#endif
@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: GPL-2.0
+/* Counts the bytes sent from each leased address by the MAC holding the lease, traffic
+ * from addresses that aren't leased, or whose lease ran out, goes under the zero MAC.
+ * Attach it to tc ingress with BINDINGS reused from the dhcp-snoop daemon, see README.md.
+ */
+#include <linux/bpf.h>
+#include <linux/if_ether.h>
+#include <linux/ip.h>
+#include <linux/pkt_cls.h>
+#include <bpf/bpf_endian.h>
+#include <bpf/bpf_helpers.h>
+
+#include "dhcp_snoop.h"
+
+/* Has to match the daemon's for bpftool to reuse the pinned map */
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, DHCP_SNOOP_BINDINGS_MAX_ENTRIES);
+	__type(key, __u32);
+	__type(value, struct dhcp_snoop_binding);
+} BINDINGS SEC(".maps");
+
+struct device_key {
+	__u8 mac[6];
+	__u16 vlan;
+};
+
+struct {
+	__uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+	__uint(max_entries, 65536);
+	__type(key, struct device_key);
+	__type(value, __u64);
+} DEVICE_BYTES SEC(".maps");
+
+SEC("tc")
+int flow_tag(struct __sk_buff *skb)
+{
+	void *data = (void *)(long)skb->data;
+	void *data_end = (void *)(long)skb->data_end;
+	struct ethhdr *eth = data;
+	struct iphdr *iph = data + sizeof(*eth);
+	struct dhcp_snoop_binding *binding;
+	struct device_key key = {};
+	__u64 *bytes, len = skb->len;
+
+	/* VLAN tags are usually stripped into skb->vlan_tci by the time tc sees the frame */
+	if ((void *)(iph + 1) > data_end || eth->h_proto != bpf_htons(ETH_P_IP))
+		return TC_ACT_OK;
+
+	binding = bpf_map_lookup_elem(&BINDINGS, &(__u32){ bpf_ntohl(iph->saddr) });
+	if (binding && (!binding->expires_at || binding->expires_at > bpf_ktime_get_ns())) {
+		__builtin_memcpy(key.mac, binding->mac, sizeof(key.mac));
+		key.vlan = binding->vlan;
+	}
+
+	bytes = bpf_map_lookup_elem(&DEVICE_BYTES, &key);
+	if (bytes)
+		*bytes += len;
+	else
+		bpf_map_update_elem(&DEVICE_BYTES, &key, &len, BPF_NOEXIST);
+
+	return TC_ACT_OK;
+}
+
+char LICENSE[] SEC("license") = "GPL";
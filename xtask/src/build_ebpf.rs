@@ -9,6 +9,19 @@ pub enum Architecture {
     BpfEb,
 }
 
+impl Architecture {
+    /// Built by default, userspace embeds both
+    pub const ALL: [Architecture; 2] = [Architecture::BpfEl, Architecture::BpfEb];
+
+    /// The feature of the userspace crate embedding the object built for this target
+    pub fn feature(&self) -> &'static str {
+        match self {
+            Architecture::BpfEl => "bpfel",
+            Architecture::BpfEb => "bpfeb",
+        }
+    }
+}
+
 impl std::str::FromStr for Architecture {
     type Err = String;
 
@@ -32,17 +45,28 @@ impl std::fmt::Display for Architecture {
 
 #[derive(Debug, Parser)]
 pub struct Options {
-    /// Set the endianness of the BPF target
-    #[clap(default_value = "bpfel-unknown-none", long)]
-    pub target: Architecture,
+    /// Only build for this endianness of the BPF target, rather than for both
+    #[clap(long)]
+    pub target: Option<Architecture>,
     /// Build the release target
     #[clap(long)]
     pub release: bool,
 }
 
 pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
+    let targets = match opts.target {
+        Some(target) => vec![target],
+        None => Architecture::ALL.to_vec(),
+    };
+    for target in targets {
+        build_target(target, opts.release);
+    }
+    Ok(())
+}
+
+fn build_target(target: Architecture, release: bool) {
     let dir = PathBuf::from("dhcp-ebpf");
-    let target = format!("--target={}", opts.target);
+    let target = format!("--target={}", target);
     let mut args = vec![
         "+nightly",
         "build",
@@ -51,7 +75,7 @@ pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
         "-Z",
         "build-std=core",
     ];
-    if opts.release {
+    if release {
         args.push("--release")
     }
     let status = Command::new("cargo")
@@ -60,5 +84,4 @@ pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
         .status()
         .expect("failed to build bpf program");
     assert!(status.success());
-    Ok(())
 }
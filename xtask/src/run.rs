@@ -7,9 +7,9 @@ use crate::build_ebpf::{build_ebpf, Architecture, Options as BuildOptions};
 
 #[derive(Debug, Parser)]
 pub struct Options {
-    /// Set the endianness of the BPF target
-    #[clap(default_value = "bpfel-unknown-none", long)]
-    pub bpf_target: Architecture,
+    /// Only build for this endianness of the BPF target, rather than for both
+    #[clap(long)]
+    pub bpf_target: Option<Architecture>,
     /// Build and run the release target
     #[clap(long)]
     pub release: bool,
@@ -27,6 +27,16 @@ fn build(opts: &Options) -> Result<(), anyhow::Error> {
     if opts.release {
         args.push("--release")
     }
+    // Only embed the eBPF object that was built
+    if let Some(target) = opts.bpf_target {
+        args.extend([
+            "-p",
+            "dhcp",
+            "--no-default-features",
+            "--features",
+            target.feature(),
+        ]);
+    }
     let status = Command::new("cargo")
         .args(&args)
         .status()